@@ -0,0 +1,92 @@
+//! Compares the allocations and time of reading lines into one reused buffer, as
+//! `process_input` does for percentage and reservoir sampling, against allocating a
+//! `String` per line with `BufRead::lines`.
+//!
+//! Run with `cargo bench --bench lines`.
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use sample::config::parse_args;
+use sample::{percentage_sample_iter, process_input, reservoir_sample};
+
+const LINES: usize = 2_000_000;
+
+/// Counts every allocation made through the global allocator
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn main() {
+    let input: Vec<u8> = (0..LINES)
+        .map(|i| format!("{},user{},{}\n", i, i % 1000, i * 7 % 10007))
+        .collect::<String>()
+        .into_bytes();
+    println!("input: {} lines, {} bytes", LINES, input.len());
+
+    for (name, args) in [
+        ("-p 10", vec!["sample", "-p", "10"]),
+        ("1000", vec!["sample", "1000"]),
+    ] {
+        let config = parse_args(args.into_iter().map(String::from)).unwrap();
+
+        let (reused_allocations, reused) = measure(|| {
+            let mut rng = StdRng::seed_from_u64(42);
+            process_input(&config, input.as_slice(), io::sink(), &mut rng).unwrap();
+        });
+
+        let (per_line_allocations, per_line) = measure(|| {
+            let mut rng = StdRng::seed_from_u64(42);
+            let lines = input.lines().map(Result::unwrap);
+            let mut output = io::sink();
+            match config.sample_size {
+                Some(k) => {
+                    for line in reservoir_sample(lines, k, &mut rng) {
+                        writeln!(output, "{}", line).unwrap();
+                    }
+                }
+                None => {
+                    for line in percentage_sample_iter(lines, 10.0, &mut rng) {
+                        writeln!(output, "{}", line).unwrap();
+                    }
+                }
+            }
+        });
+
+        println!(
+            "{:<6} reused buffer {:>9} allocations {:>8.1?}  String per line {:>9} allocations {:>8.1?}",
+            name, reused_allocations, reused, per_line_allocations, per_line
+        );
+    }
+}
+
+/// Returns the allocations made and the time taken by one run
+fn measure(run: impl FnOnce()) -> (usize, Duration) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    run();
+    let elapsed = start.elapsed();
+    (
+        black_box(ALLOCATIONS.load(Ordering::Relaxed) - before),
+        elapsed,
+    )
+}
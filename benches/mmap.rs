@@ -0,0 +1,72 @@
+//! Compares buffered and memory-mapped sampling of a large file.
+//!
+//! Run with `cargo bench --bench mmap`.
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::{Duration, Instant};
+
+use memmap2::Mmap;
+use sample::config::parse_args;
+use sample::{process_input, process_mapped};
+
+const LINES: usize = 2_000_000;
+const ROUNDS: usize = 5;
+
+fn main() -> io::Result<()> {
+    let mut file = tempfile::NamedTempFile::new()?;
+    {
+        let mut writer = BufWriter::new(file.as_file_mut());
+        writeln!(writer, "id,name,value")?;
+        for i in 0..LINES {
+            writeln!(writer, "{},user{},{}", i, i % 1000, i * 7 % 10007)?;
+        }
+    }
+    let size = file.as_file().metadata()?.len();
+    println!("input: {} lines, {} bytes", LINES, size);
+
+    for args in [
+        &["sample", "--csv", "-p", "10"][..],
+        &["sample", "--csv", "1000"],
+    ] {
+        let config = parse_args(args).expect("valid benchmark arguments");
+
+        let buffered = best_of(|| {
+            let mut rng = StdRng::seed_from_u64(42);
+            let input = File::open(file.path()).unwrap();
+            process_input(&config, input, io::sink(), &mut rng).unwrap();
+        });
+
+        let mapped = best_of(|| {
+            let mut rng = StdRng::seed_from_u64(42);
+            let input = File::open(file.path()).unwrap();
+            // SAFETY: the temporary file is not modified while mapped
+            let map = unsafe { Mmap::map(&input).unwrap() };
+            process_mapped(&config, &map, io::sink(), &mut rng).unwrap();
+        });
+
+        println!(
+            "{:<16} buffered {:>8.1?}  mmap {:>8.1?}  ({:.2}x)",
+            args[1..].join(" "),
+            buffered,
+            mapped,
+            buffered.as_secs_f64() / mapped.as_secs_f64()
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the fastest of several timed runs
+fn best_of(mut run: impl FnMut()) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
@@ -0,0 +1,50 @@
+//! Compares per-item and chunked (Algorithm L) reservoir sampling of a large slice.
+//!
+//! Run with `cargo bench --bench reservoir`.
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use sample::{reservoir_sample, reservoir_sample_chunked};
+
+const ITEMS: usize = 10_000_000;
+const ROUNDS: usize = 5;
+
+fn main() {
+    let items: Vec<u64> = (0..ITEMS as u64).collect();
+    println!("input: {} items", ITEMS);
+
+    for k in [10, 1_000, 100_000] {
+        let per_item = best_of(|| {
+            let mut rng = StdRng::seed_from_u64(42);
+            black_box(reservoir_sample(items.iter().copied(), k, &mut rng));
+        });
+
+        let chunked = best_of(|| {
+            let mut rng = StdRng::seed_from_u64(42);
+            black_box(reservoir_sample_chunked(&items, k, &mut rng));
+        });
+
+        println!(
+            "k = {:<8} per-item {:>8.1?}  chunked {:>8.1?}  ({:.2}x)",
+            k,
+            per_item,
+            chunked,
+            per_item.as_secs_f64() / chunked.as_secs_f64()
+        );
+    }
+}
+
+/// Returns the fastest of several timed runs
+fn best_of(mut run: impl FnMut()) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
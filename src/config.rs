@@ -1,8 +1,16 @@
-use clap::Parser;
+use clap::builder::{Resettable, ValueParser};
+use clap::parser::{ArgMatches, ValueSource};
+use clap::{Arg, ArgGroup, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use encoding_rs::Encoding;
+use regex::Regex;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use crate::error::{Error, Result};
+use crate::sampling::{KeyNormalization, ReservoirOrder};
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Parser)]
 #[command(
     name = "sample",
     about = "A command-line tool for random sampling of input data",
@@ -19,7 +27,8 @@ use crate::error::{Error, Result};
     cat data.csv | sample 10 --csv
 
     # Get reproducible output using a fixed seed
-    cat data.txt | sample 10 -s 42"
+    cat data.txt | sample 10 -s 42",
+    group(ArgGroup::new("hash_key").args(["hash_column", "hash_regex"]))
 )]
 pub struct Config {
     /// Number of lines to sample using reservoir sampling algorithm.
@@ -37,6 +46,14 @@ pub struct Config {
     #[arg(short = 'C', long = "csv")]
     pub csv_mode: bool,
 
+    /// Whether the first line is a header: yes (the same as --csv), no, or auto to
+    /// guess it from the first lines like --probe does, taking the first line as a
+    /// header if some column is text there but numeric on every following line.
+    /// Options that require --csv still need --csv; with --csv, auto fails unless
+    /// it detects a header.
+    #[arg(long = "header", value_name = "MODE", value_parser = header_mode_parser)]
+    pub header: Option<HeaderMode>,
+
     /// Set a fixed random seed for reproducible output.
     /// Using the same seed will produce the same sample for identical input.
     #[arg(short = 's', long, value_name = "NUMBER")]
@@ -44,13 +61,856 @@ pub struct Config {
 
     /// Column name to use for hash-based sampling.
     /// When specified, rows with the same value in this column will be either all included or all excluded.
-    /// Only works with --csv and --percentage options.
+    /// Only works with --csv and --percentage options, or with a sample size and --pin-keys.
     #[arg(long = "hash", value_name = "COLUMN_NAME")]
     pub hash_column: Option<String>,
+
+    /// Use the header column whose name matches this regular expression as the --hash
+    /// column, e.g. 'user_id_v\d+'. Fails unless exactly one column matches.
+    #[arg(long = "hash-regex", value_name = "PATTERN", value_parser = Regex::new)]
+    pub hash_regex: Option<Regex>,
+
+    /// Stop reading input after this many bytes.
+    /// Input is truncated at the last complete line within the limit.
+    #[arg(long = "max-bytes", value_name = "BYTES")]
+    pub max_bytes: Option<u64>,
+
+    /// For a fixed-size sample, keep retained lines beyond the --limit-memory budget
+    /// in a temporary file in DIR instead of in memory. Lines are streamed rather than
+    /// read into memory first.
+    #[arg(
+        long = "spill-dir",
+        value_name = "DIR",
+        conflicts_with_all = ["hash_key", "window", "shuffle_all", "one"]
+    )]
+    pub spill_dir: Option<PathBuf>,
+
+    /// Bytes of retained lines kept in memory with --spill-dir
+    #[arg(
+        long = "limit-memory",
+        value_name = "BYTES",
+        default_value_t = 64 << 20,
+        requires = "spill_dir"
+    )]
+    pub limit_memory: usize,
+
+    /// Order of the lines of a sample-size sample: slot (the reservoir's slot order),
+    /// input (the order they had in the input) or shuffled (a random order drawn
+    /// from the seeded RNG)
+    #[arg(
+        long = "reservoir-order",
+        value_name = "ORDER",
+        value_parser = reservoir_order_parser,
+        default_value = "slot",
+        conflicts_with = "spill_dir"
+    )]
+    pub reservoir_order: ReservoirOrder,
+
+    /// Retry an input read that was interrupted or timed out up to N times, with a short
+    /// exponential backoff, before giving up. Useful for inputs on flaky network
+    /// filesystems; other errors are reported at once.
+    #[arg(long = "retry", value_name = "N", default_value_t = 0)]
+    pub retry: u32,
+
+    /// File containing keys (one per line) to exclude before sampling.
+    /// Rows whose --hash column value is listed are never emitted.
+    #[arg(long = "exclude-keys", value_name = "PATH")]
+    pub exclude_keys: Option<PathBuf>,
+
+    /// File of `key,percentage` lines giving specific keys their own sampling
+    /// percentage in hash mode; other keys are sampled at -p.
+    #[arg(
+        long = "key-rate",
+        value_name = "PATH",
+        requires = "hash_key",
+        conflicts_with = "probability_report"
+    )]
+    pub key_rate: Option<PathBuf>,
+
+    /// File containing keys (one per line) whose rows are always emitted.
+    /// Used with --hash and a sample size; the rest of the budget is sampled at random.
+    #[arg(
+        long = "pin-keys",
+        value_name = "PATH",
+        requires = "hash_key",
+        conflicts_with_all = ["percentage", "first"]
+    )]
+    pub pin_keys: Option<PathBuf>,
+
+    /// Output only the first N data lines and stop reading.
+    /// Respects --csv header handling. Cannot be combined with other sampling modes.
+    #[arg(long = "first", value_name = "N", conflicts_with_all = ["sample_size", "percentage"])]
+    pub first: Option<usize>,
+
+    /// Output every line in a random order (a seeded Fisher-Yates shuffle).
+    /// Respects --csv header handling. Cannot be combined with other sampling modes.
+    #[arg(
+        long = "shuffle-all",
+        conflicts_with_all = ["sample_size", "percentage", "first", "assign", "expected_size"]
+    )]
+    pub shuffle_all: bool,
+
+    /// Output exactly one uniformly random line, chosen in a single streaming pass.
+    /// Respects --csv header handling. Cannot be combined with other sampling modes.
+    #[arg(
+        long = "one",
+        conflicts_with_all = [
+            "sample_size", "percentage", "first", "shuffle_all", "assign", "expected_size"
+        ]
+    )]
+    pub one: bool,
+
+    /// Take the sample size or percentage from the first input line, `#sample:N` or
+    /// `#sample:P%`, and sample the lines after it.
+    #[arg(
+        long = "size-from-header",
+        conflicts_with_all = [
+            "sample_size", "percentage", "first", "shuffle_all", "one", "assign",
+            "expected_size", "round_robin", "probe", "count_keys", "show_columns"
+        ]
+    )]
+    pub size_from_header: bool,
+
+    /// Guess the input's delimiter and whether it has a header from its first lines,
+    /// report the guess on stderr and exit without sampling.
+    #[arg(long = "probe")]
+    pub probe: bool,
+
+    /// Check the options and, in CSV mode, that the input header has every column they
+    /// name, then exit without reading any data rows or writing output.
+    #[arg(long = "dry-validate", conflicts_with = "probe")]
+    pub dry_validate: bool,
+
+    /// Run N independent samples in one invocation, writing each to its own file.
+    /// Replicate i uses the seed (base seed + i). Requires --output-template.
+    #[arg(long = "replicates", value_name = "N", requires = "output_template")]
+    pub replicates: Option<usize>,
+
+    /// File name template for --replicates and --round-robin; '{i}' is replaced by the
+    /// replicate or shard index.
+    #[arg(long = "output-template", value_name = "TEMPLATE")]
+    pub output_template: Option<String>,
+
+    /// Split the input into N files instead of sampling, assigning data lines in
+    /// rotation, or by key bucket with --hash. In CSV mode each file gets the header.
+    /// Requires --output-template.
+    #[arg(
+        long = "round-robin",
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "output_template",
+        conflicts_with_all = [
+            "sample_size", "percentage", "first", "shuffle_all", "one", "replicates", "assign",
+            "assign_columns", "expected_size", "window", "pin_keys", "sort", "sort_column",
+            "checkpoint", "manifest"
+        ]
+    )]
+    pub round_robin: Option<u64>,
+
+    /// Discard the first N data lines (after the header in CSV mode) before sampling.
+    #[arg(long = "skip", value_name = "N", default_value_t = 0)]
+    pub skip: usize,
+
+    /// Append the hash bucket (0..M) of COLUMN to every row instead of sampling.
+    /// Rows with the same key always receive the same bucket. Requires --csv.
+    #[arg(
+        long = "assign",
+        value_name = "COLUMN:M",
+        value_parser = bucket_assignment_parser,
+        conflicts_with_all = ["sample_size", "percentage", "first", "hash_key"]
+    )]
+    pub assign: Option<BucketAssignment>,
+
+    /// Append a true/false inclusion column for each listed column instead of sampling.
+    /// Each column hashes its own values with a seed derived from its name (and --seed),
+    /// so the flags are deterministic per key and independent across columns.
+    /// Requires --csv and --percentage.
+    #[arg(
+        long = "assign-columns",
+        value_name = "COLUMNS",
+        value_delimiter = ',',
+        requires = "csv_mode",
+        conflicts_with_all = [
+            "sample_size", "first", "shuffle_all", "assign", "expected_size", "hash_key",
+            "hash_index", "window", "antithetic", "checkpoint"
+        ]
+    )]
+    pub assign_columns: Option<Vec<String>>,
+
+    /// Split each line into fields on runs of whitespace for hash-based sampling.
+    /// Avoids CSV quoting rules for simple logs. Requires --hash-index and --percentage.
+    #[arg(
+        long = "whitespace-split",
+        requires = "hash_index",
+        conflicts_with = "hash_key"
+    )]
+    pub whitespace_split: bool,
+
+    /// 0-based index of the whitespace-separated field used as the hash key.
+    #[arg(long = "hash-index", value_name = "N", requires = "whitespace_split")]
+    pub hash_index: Option<usize>,
+
+    /// Expected number of rows for weighted (Poisson) sampling.
+    /// Each row is included with probability min(1, weight * K / total weight).
+    /// Requires --csv and --weight-column.
+    #[arg(
+        long = "expected-size",
+        value_name = "K",
+        value_parser = expected_size_parser,
+        requires = "weight_column",
+        conflicts_with_all = ["sample_size", "percentage", "first", "assign", "hash_key"]
+    )]
+    pub expected_size: Option<f64>,
+
+    /// Column holding the non-negative weight of each row for --expected-size.
+    #[arg(
+        long = "weight-column",
+        value_name = "NAME",
+        requires = "expected_size"
+    )]
+    pub weight_column: Option<String>,
+
+    /// Output the rows whose value in this numeric column falls in the
+    /// --percentile-range band. The input is buffered to rank the values.
+    #[arg(
+        long = "percentile-column",
+        value_name = "NAME",
+        requires_all = ["csv_mode", "percentile_range"],
+        conflicts_with_all = ["sample_size", "percentage", "first", "shuffle_all", "one", "hash_key"]
+    )]
+    pub percentile_column: Option<String>,
+
+    /// Percentile band LOW:HIGH of --percentile-column, 0 <= LOW < HIGH <= 100. A row is
+    /// kept when the share of values below its value is at least LOW% and under HIGH%,
+    /// so 95:100 keeps the top 5%.
+    #[arg(
+        long = "percentile-range",
+        value_name = "LOW:HIGH",
+        value_parser = percentile_range_parser,
+        requires = "percentile_column"
+    )]
+    pub percentile_range: Option<(f64, f64)>,
+
+    /// Also print the count, mean, minimum and maximum of the numeric column NAME over
+    /// every data row, sampled or not, to standard error at the end of the run.
+    #[arg(
+        long = "stats-column",
+        value_name = "NAME",
+        requires = "csv_mode",
+        conflicts_with_all = ["checkpoint", "replicates", "round_robin", "probe", "dry_validate"]
+    )]
+    pub stats_column: Option<String>,
+
+    /// Keep K randomly chosen columns, in their original order, of every row instead of
+    /// sampling rows. The same columns are kept for the whole file and the header.
+    #[arg(
+        long = "sample-columns",
+        value_name = "K",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "csv_mode",
+        conflicts_with_all = [
+            "sample_size",
+            "percentage",
+            "first",
+            "shuffle_all",
+            "one",
+            "hash_key",
+            "percentile_column"
+        ]
+    )]
+    pub sample_columns: Option<u64>,
+
+    /// Total weight of all rows for --expected-size. When given, rows are streamed;
+    /// otherwise the input is buffered once to compute it.
+    #[arg(long = "total-weight", value_name = "W", requires = "expected_size")]
+    pub total_weight: Option<f64>,
+
+    /// Only lines matching this regular expression are counted and eligible for sampling.
+    /// In CSV mode the header line is never filtered.
+    #[arg(long = "filter", value_name = "REGEX", value_parser = Regex::new)]
+    pub filter: Option<Regex>,
+
+    /// Suppress warnings, such as a sample size larger than the input.
+    /// Errors are still reported.
+    #[arg(short = 'q', long = "quiet")]
+    pub quiet: bool,
+
+    /// Flush the output after every emitted line, so a consumer of a live stream sees
+    /// each sampled record as soon as it is selected. Streaming modes only
+    /// (--percentage, --first and hash sampling), since the others wait for the end of input.
+    #[arg(long = "line-buffered")]
+    pub line_buffered: bool,
+
+    /// Split the input into records on this separator instead of on line feeds, so
+    /// records spanning several lines are sampled as units; '\n', '\r' and '\t' are
+    /// recognized, e.g. '\n\n' for blank-line-separated paragraphs. Empty records are
+    /// skipped and every output record ends with the separator. Line-based modes only.
+    #[arg(
+        long = "record-separator",
+        value_name = "STR",
+        value_parser = record_separator_parser,
+        conflicts_with_all = [
+            "csv_mode", "hash_index", "filter", "manifest", "line_buffered", "checkpoint"
+        ]
+    )]
+    pub record_separator: Option<String>,
+
+    /// Print the sample as a table with aligned columns instead of CSV, for reading
+    /// it in a terminal. The whole sample is buffered before anything is printed.
+    #[arg(
+        long = "pretty",
+        requires = "csv_mode",
+        conflicts_with_all = ["output_delimiter", "line_buffered", "manifest"]
+    )]
+    pub pretty: bool,
+
+    /// Cut --pretty cells longer than N characters short, ending them with an ellipsis
+    #[arg(
+        long = "pretty-width",
+        value_name = "N",
+        default_value_t = 40,
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "pretty"
+    )]
+    pub pretty_width: u64,
+
+    /// Stop after emitting N records (not counting the CSV header), whatever the mode.
+    /// Streaming modes stop reading the input there too.
+    #[arg(
+        long = "max-records",
+        value_name = "N",
+        conflicts_with_all = ["checkpoint", "manifest"]
+    )]
+    pub max_records: Option<u64>,
+
+    /// After the run, write a JSON summary with the total and sampled counts, the
+    /// sampled fraction, the seed, the sampling mode and the elapsed milliseconds to PATH.
+    /// Counts are input lines read and output lines written, not counting headers.
+    #[arg(
+        long = "stats-out",
+        value_name = "PATH",
+        conflicts_with_all = ["checkpoint", "replicates", "round_robin", "probe"]
+    )]
+    pub stats_out: Option<PathBuf>,
+
+    /// After the run, append one line to PATH recording the time, the seed that was
+    /// used, the sampling mode, the input and output line counts and the command line,
+    /// as a human-readable audit trail of runs.
+    #[arg(
+        long = "log-invocation",
+        value_name = "PATH",
+        conflicts_with_all = ["checkpoint", "replicates", "round_robin", "probe", "dry_validate"]
+    )]
+    pub log_invocation: Option<PathBuf>,
+
+    /// Command line the configuration was parsed from, for --log-invocation
+    #[arg(skip)]
+    pub invocation: Vec<String>,
+
+    /// Also write the 1-based line number of every emitted line to PATH, one per line
+    /// in output order, so the sample can be re-extracted from the original file.
+    /// In CSV mode the header is line 1. Line-based modes only.
+    #[arg(
+        long = "manifest",
+        value_name = "PATH",
+        conflicts_with_all = [
+            "hash_key", "hash_index", "filter", "assign", "assign_columns", "expected_size",
+            "window", "time_column", "pin_keys", "sort", "sort_column", "replicates",
+            "checkpoint", "percentile_column", "sample_columns", "show_columns", "count_keys",
+            "probability_report"
+        ]
+    )]
+    pub manifest: Option<PathBuf>,
+
+    /// Also write a 1 or 0 for every input data line to PATH, one per line in input
+    /// order, telling whether the line was sampled. Reads the whole input even when the
+    /// mode could stop early. Line-based modes only.
+    #[arg(
+        long = "decision-out",
+        value_name = "PATH",
+        conflicts_with_all = [
+            "hash_key", "hash_index", "filter", "assign", "assign_columns", "expected_size",
+            "window", "time_column", "pin_keys", "sort", "sort_column", "replicates",
+            "checkpoint", "max_records", "percentile_column", "sample_columns", "show_columns",
+            "count_keys", "probability_report"
+        ]
+    )]
+    pub decision_out: Option<PathBuf>,
+
+    /// Prepend a comment line recording the seed used (e.g. "# seed=42").
+    /// When no seed is given, the randomly chosen seed is recorded.
+    #[arg(long = "embed-seed")]
+    pub embed_seed: bool,
+
+    /// Character that starts comment lines written by the tool.
+    #[arg(long = "comment-char", value_name = "CHAR", default_value_t = '#')]
+    pub comment_char: char,
+
+    /// Fail unless the CSV header has exactly these columns, in this order.
+    #[arg(
+        long = "expect-columns",
+        value_name = "COLUMNS",
+        value_delimiter = ',',
+        requires = "csv_mode"
+    )]
+    pub expect_columns: Option<Vec<String>>,
+
+    /// Treat the first line as a header but do not write it to the output.
+    #[arg(long = "no-header-output", requires = "csv_mode")]
+    pub no_header_output: bool,
+
+    /// Rename columns in the output header, e.g. "old1=new1,old2=new2".
+    /// Every old name must exist in the input header.
+    #[arg(
+        long = "rename",
+        value_name = "OLD=NEW",
+        value_delimiter = ',',
+        value_parser = column_rename_parser,
+        requires = "csv_mode"
+    )]
+    pub rename: Option<Vec<ColumnRename>>,
+
+    /// Sort the sampled lines lexicographically before writing them.
+    /// The whole sample is buffered, so output only starts once the input is consumed.
+    #[arg(long = "sort")]
+    pub sort: bool,
+
+    /// Sort the sampled rows by this column (implies --sort). Requires --csv.
+    #[arg(long = "sort-column", value_name = "NAME", requires = "csv_mode")]
+    pub sort_column: Option<String>,
+
+    /// Read these CSV files, each already sorted by --sort-column, instead of standard
+    /// input, merging them into one stream in that column's order before sampling.
+    /// Every file must have the same header, which is output once.
+    #[arg(
+        long = "merge-sorted",
+        value_name = "PATHS",
+        value_delimiter = ',',
+        requires = "sort_column",
+        conflicts_with_all = ["encoding", "mmap"]
+    )]
+    pub merge_sorted: Option<Vec<PathBuf>>,
+
+    /// Normalize hash keys before hashing: none, trim, lowercase or trim+lowercase.
+    /// Trimming removes Unicode whitespace, such as non-breaking spaces, too.
+    /// Applies to --hash, --hash-index and --assign keys.
+    #[arg(
+        long = "normalize",
+        value_name = "MODE",
+        value_parser = key_normalization_parser,
+        default_value = "none"
+    )]
+    pub normalize: KeyNormalization,
+
+    /// Hash --hash keys by numeric value, so that 1, 01 and 1.0 are the same key. A key
+    /// that is not a number fails the run unless --numeric-key-fallback is given.
+    #[arg(
+        long = "numeric-key",
+        requires = "hash_key",
+        conflicts_with_all = ["pin_keys", "round_robin", "assign"]
+    )]
+    pub numeric_key: bool,
+
+    /// With --numeric-key, hash keys that are not numbers as text instead of failing
+    #[arg(long = "numeric-key-fallback", requires = "numeric_key")]
+    pub numeric_key_fallback: bool,
+
+    /// Pair each random draw u with 1-u for the next line in percentage sampling.
+    /// Lowers the variance of the sample size, at the cost of independent selections.
+    #[arg(long = "antithetic", conflicts_with_all = ["hash_key", "hash_index"])]
+    pub antithetic: bool,
+
+    /// Scale the -p inclusion probability by exp(-RATE * age), so older records are
+    /// kept less often. Age is the number of lines before the last line, or, with
+    /// --time-column, the seconds before the latest event time. Buffers the input.
+    #[arg(
+        long = "decay",
+        value_name = "RATE",
+        value_parser = decay_parser,
+        requires = "percentage",
+        conflicts_with_all = ["hash_key", "hash_index", "antithetic", "checkpoint"]
+    )]
+    pub decay: Option<f64>,
+
+    /// Start -p sampling at the given rate, then lower the rate as the number of kept
+    /// lines approaches N, aiming for about N lines from a stream of unknown length.
+    /// Approximate: never more than N lines, and fewer if the input runs out early.
+    #[arg(
+        long = "target-count",
+        value_name = "N",
+        requires = "percentage",
+        conflicts_with_all = [
+            "hash_key", "hash_index", "antithetic", "decay", "checkpoint",
+            "assign", "assign_columns", "count_keys", "probability_report"
+        ]
+    )]
+    pub target_count: Option<usize>,
+
+    /// Instead of sampling, print the number of distinct values in the --hash column.
+    /// Every distinct value is kept in memory while counting.
+    #[arg(
+        long = "count-keys",
+        requires = "hash_key",
+        conflicts_with_all = [
+            "sample_size", "percentage", "first", "shuffle_all", "assign", "expected_size",
+            "pin_keys", "window", "sort", "sort_column", "replicates", "checkpoint"
+        ]
+    )]
+    pub count_keys: bool,
+
+    /// Instead of sampling, print each distinct --hash value with its hash position in
+    /// [0, 1) and whether it is selected, followed by the fraction of keys selected.
+    #[arg(
+        long = "probability-report",
+        requires = "hash_key",
+        conflicts_with_all = [
+            "sample_size", "first", "shuffle_all", "assign", "expected_size", "pin_keys",
+            "window", "sort", "sort_column", "replicates", "checkpoint", "count_keys"
+        ]
+    )]
+    pub probability_report: bool,
+
+    /// Print each CSV column's 0-based index and name, tab-separated, and exit
+    /// without sampling.
+    #[arg(long = "show-columns", requires = "csv_mode")]
+    pub show_columns: bool,
+
+    /// Save the sampling progress to PATH every 10,000 lines so an interrupted run
+    /// can be continued with --resume. Percentage sampling only.
+    #[arg(
+        long = "checkpoint",
+        value_name = "PATH",
+        conflicts_with_all = [
+            "hash_key", "hash_index", "sort", "sort_column", "replicates", "embed_seed"
+        ]
+    )]
+    pub checkpoint: Option<PathBuf>,
+
+    /// Continue an interrupted --checkpoint run from the checkpoint at PATH.
+    /// Append the output to the interrupted output truncated to the checkpoint's output_bytes.
+    #[arg(long = "resume", value_name = "PATH", requires = "checkpoint")]
+    pub resume: Option<PathBuf>,
+
+    /// Read default option values from a TOML file at PATH, keyed by long option or
+    /// field name (e.g. `hash = "id"`). Options on the command line take precedence.
+    #[arg(long = "config", value_name = "PATH")]
+    pub config_file: Option<PathBuf>,
+
+    /// Memory-map the input when standard input is redirected from a regular file.
+    /// Pipes are read normally. The output is the same either way.
+    #[arg(long = "mmap")]
+    pub mmap: bool,
+
+    /// Column holding each row's event time, as RFC 3339 or epoch seconds, for --window
+    /// or --decay.
+    #[arg(long = "time-column", value_name = "NAME")]
+    pub time_column: Option<String>,
+
+    /// Sample only the rows within this many seconds of the latest event time.
+    /// Older rows are evicted as newer ones arrive. Requires --csv, --time-column and a sample size.
+    #[arg(
+        long = "window",
+        value_name = "SECONDS",
+        value_parser = window_parser,
+        requires = "time_column",
+        conflicts_with_all = ["percentage", "first", "shuffle_all", "assign", "expected_size", "pin_keys"]
+    )]
+    pub window: Option<f64>,
+
+    /// Sample only the last W lines (or rows): a uniform sample of a sliding window of
+    /// W records rather than of the whole input. Requires a sample size.
+    #[arg(
+        long = "window-count",
+        value_name = "W",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all = [
+            "percentage", "first", "shuffle_all", "one", "window", "spill_dir",
+            "reservoir_order", "assign", "expected_size", "pin_keys", "hash_key",
+            "weight_column"
+        ]
+    )]
+    pub window_count: Option<u64>,
+
+    /// Fail if the input does not end with a newline, as a truncated file would not.
+    /// Checked once the input has been read to its end, after the sample is written.
+    #[arg(
+        long = "require-trailing-newline",
+        conflicts_with_all = ["checkpoint", "round_robin"]
+    )]
+    pub require_trailing_newline: bool,
+
+    /// Fail on CSV rows whose field count differs from the header's, instead of
+    /// tolerating ragged rows. Only the modes that parse CSV records (such as --hash,
+    /// --weight-column or --sample-columns) can check this; with a plain sample size or
+    /// --percentage it is an error.
+    #[arg(long = "strict-columns", requires = "csv_mode")]
+    pub strict_columns: bool,
+
+    /// Fail on any CSV record longer than this many bytes, as soon as it is read that
+    /// far, so a pathologically wide record is never buffered whole. Applies to the
+    /// modes that parse CSV records.
+    #[arg(long = "max-record-bytes", value_name = "BYTES", requires = "csv_mode")]
+    pub max_record_bytes: Option<usize>,
+
+    /// Match the names given to --hash, --weight-column, --time-column, --sort-column,
+    /// --assign and --assign-columns against the header case-insensitively.
+    /// A name that matches more than one column is an error.
+    #[arg(long = "ignore-case-columns", requires = "csv_mode")]
+    pub ignore_case_columns: bool,
+
+    /// Decode the input from this encoding (e.g. windows-1252, shift_jis, utf-16le)
+    /// instead of reading it as UTF-8. Output is always UTF-8.
+    #[arg(long = "encoding", value_name = "NAME", value_parser = encoding_parser)]
+    pub encoding: Option<&'static Encoding>,
+
+    /// In hash-based sampling, emit only the first row of each selected key.
+    /// Every emitted key is kept in memory.
+    #[arg(
+        long = "first-per-key",
+        requires = "hash_key",
+        conflicts_with_all = ["pin_keys", "count_keys", "probability_report"]
+    )]
+    pub first_per_key: bool,
+
+    /// In hash-based sampling, also emit the first row of every key that is not
+    /// selected, so each key appears at least once. Every key is kept in memory.
+    #[arg(
+        long = "cover-keys",
+        requires = "hash_key",
+        conflicts_with_all = ["first_per_key", "pin_keys", "count_keys", "probability_report"]
+    )]
+    pub cover_keys: bool,
+
+    /// In hash-based sampling, keep the keys in an exact fraction of fine hash buckets
+    /// spread evenly over the hash space, instead of the lowest fraction of the hash
+    /// space, so the selected keys cover all of it.
+    #[arg(
+        long = "stratify-keys",
+        requires = "hash_key",
+        conflicts_with_all = ["key_rate", "pin_keys", "count_keys", "probability_report"]
+    )]
+    pub stratify_keys: bool,
+
+    /// In hash-based sampling, append a `hash` column holding the 64-bit hash of each
+    /// row's (normalized) key as 16 hex digits, the value the sampling decision compares.
+    #[arg(
+        long = "emit-hash",
+        requires = "hash_key",
+        conflicts_with_all = ["pin_keys", "count_keys", "probability_report", "round_robin"]
+    )]
+    pub emit_hash: bool,
+
+    /// Write CSV records with this single-character delimiter instead of a comma,
+    /// quoting fields as needed (use '\t' for tabs). Applies to the modes that parse
+    /// CSV records, such as --hash.
+    #[arg(
+        long = "output-delimiter",
+        value_name = "CHAR",
+        value_parser = delimiter_parser,
+        requires = "csv_mode"
+    )]
+    pub output_delimiter: Option<u8>,
+
+    /// Write the sampled CSV rows to a Parquet file at PATH instead of the output,
+    /// with one string column per header field. Requires the `parquet` feature.
+    #[cfg(feature = "parquet")]
+    #[arg(
+        long = "parquet-out",
+        value_name = "PATH",
+        requires = "csv_mode",
+        conflicts_with_all = ["checkpoint", "replicates", "stats_out", "log_invocation", "pretty"]
+    )]
+    pub parquet_out: Option<PathBuf>,
+
+    /// Gzip-compress everything written to the output. Requires the `gzip` feature.
+    #[cfg(feature = "gzip")]
+    #[arg(
+        long = "gzip-out",
+        conflicts_with_all = ["output_template", "line_buffered"]
+    )]
+    pub gzip_out: bool,
+
+    /// Show a progress bar of the bytes read on standard error. Only shown when standard
+    /// input is a file, whose size is known, and standard error is a terminal.
+    /// Requires the `progress` feature.
+    #[cfg(feature = "progress")]
+    #[arg(long = "progress-bar")]
+    pub progress_bar: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// How --header decides whether the first line is a header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderMode {
+    Yes,
+    No,
+    /// Guess from the first lines of the input
+    Auto,
+}
+
+/// Diagnostics that inspect the input instead of sampling it
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Check a CSV's --hash column before sampling: confirm it exists, is filled in
+    /// most rows, and report its cardinality over the first rows.
+    Check(CheckArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct CheckArgs {
+    /// Column to check as the hash key
+    #[arg(long = "hash", value_name = "COLUMN_NAME")]
+    pub hash_column: String,
+
+    /// Number of data rows to inspect
+    #[arg(long = "rows", value_name = "N", default_value_t = 1000)]
+    pub rows: usize,
+}
+
+/// Key column and bucket count for `--assign`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BucketAssignment {
+    pub column: String,
+    pub buckets: u64,
+}
+
+/// Old and new column name for `--rename`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnRename {
+    pub from: String,
+    pub to: String,
+}
+
+fn column_rename_parser(s: &str) -> std::result::Result<ColumnRename, String> {
+    let (from, to) = s
+        .split_once('=')
+        .ok_or("expected OLD=NEW, e.g. user_id=user")?;
+    Ok(ColumnRename {
+        from: from.to_string(),
+        to: to.to_string(),
+    })
+}
+
+fn key_normalization_parser(s: &str) -> std::result::Result<KeyNormalization, String> {
+    match s {
+        "none" => Ok(KeyNormalization::None),
+        "trim" => Ok(KeyNormalization::Trim),
+        "lowercase" => Ok(KeyNormalization::Lowercase),
+        "trim+lowercase" => Ok(KeyNormalization::TrimLowercase),
+        _ => Err("expected one of none, trim, lowercase, trim+lowercase".to_string()),
+    }
+}
+
+fn percentile_range_parser(s: &str) -> std::result::Result<(f64, f64), String> {
+    let (low, high) = s
+        .split_once(':')
+        .ok_or_else(|| "expected LOW:HIGH".to_string())?;
+    let parse = |bound: &str| {
+        bound
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("invalid percentile '{}'", bound))
+    };
+    let (low, high) = (parse(low)?, parse(high)?);
+    if !(0.0 <= low && low < high && high <= 100.0) {
+        return Err("expected 0 <= LOW < HIGH <= 100".to_string());
+    }
+    Ok((low, high))
+}
+
+fn header_mode_parser(s: &str) -> std::result::Result<HeaderMode, String> {
+    match s {
+        "yes" => Ok(HeaderMode::Yes),
+        "no" => Ok(HeaderMode::No),
+        "auto" => Ok(HeaderMode::Auto),
+        _ => Err("expected one of yes, no, auto".to_string()),
+    }
+}
+
+fn reservoir_order_parser(s: &str) -> std::result::Result<ReservoirOrder, String> {
+    match s {
+        "slot" => Ok(ReservoirOrder::Slot),
+        "input" => Ok(ReservoirOrder::Input),
+        "shuffled" => Ok(ReservoirOrder::Shuffled),
+        _ => Err("expected one of slot, input, shuffled".to_string()),
+    }
+}
+
+fn encoding_parser(s: &str) -> std::result::Result<&'static Encoding, String> {
+    Encoding::for_label(s.as_bytes()).ok_or_else(|| format!("unknown encoding '{}'", s))
+}
+
+fn delimiter_parser(s: &str) -> std::result::Result<u8, String> {
+    match s {
+        "\\t" | "tab" => Ok(b'\t'),
+        _ if s.len() == 1 => Ok(s.as_bytes()[0]),
+        _ => Err("delimiter must be a single ASCII character or '\\t'".to_string()),
+    }
+}
+
+fn record_separator_parser(s: &str) -> std::result::Result<String, String> {
+    let separator = s
+        .replace("\\n", "\n")
+        .replace("\\r", "\r")
+        .replace("\\t", "\t");
+    if separator.is_empty() {
+        return Err("record separator must not be empty".to_string());
+    }
+    Ok(separator)
+}
+
+fn window_parser(s: &str) -> std::result::Result<f64, String> {
+    check_window(s.parse::<f64>().unwrap_or(f64::NAN))
+}
+
+fn check_window(seconds: f64) -> std::result::Result<f64, String> {
+    Some(seconds)
+        .filter(|seconds| seconds.is_finite() && *seconds > 0.0)
+        .ok_or_else(|| "window must be a positive number of seconds".to_string())
+}
+
+fn decay_parser(s: &str) -> std::result::Result<f64, String> {
+    check_decay(s.parse::<f64>().unwrap_or(f64::NAN))
+}
+
+fn check_decay(rate: f64) -> std::result::Result<f64, String> {
+    Some(rate)
+        .filter(|rate| rate.is_finite() && *rate >= 0.0)
+        .ok_or_else(|| "decay rate must be a non-negative number".to_string())
+}
+
+fn expected_size_parser(s: &str) -> std::result::Result<f64, String> {
+    check_expected_size(s.parse::<f64>().unwrap_or(f64::NAN))
+}
+
+fn check_expected_size(size: f64) -> std::result::Result<f64, String> {
+    Some(size)
+        .filter(|size| size.is_finite() && *size > 0.0)
+        .ok_or_else(|| "expected size must be a positive number".to_string())
+}
+
+fn bucket_assignment_parser(s: &str) -> std::result::Result<BucketAssignment, String> {
+    let (column, buckets) = s
+        .rsplit_once(':')
+        .ok_or("expected COLUMN:M, e.g. user_id:3")?;
+    let buckets = buckets
+        .parse::<u64>()
+        .ok()
+        .filter(|&m| m > 0)
+        .ok_or("bucket count must be a positive integer")?;
+    Ok(BucketAssignment {
+        column: column.to_string(),
+        buckets,
+    })
 }
 
 fn percentage_validator(s: &str) -> std::result::Result<f64, String> {
-    let value = s.parse::<f64>().map_err(|_| "must be a number")?;
+    check_percentage(s.parse::<f64>().map_err(|_| "must be a number")?)
+}
+
+fn check_percentage(value: f64) -> std::result::Result<f64, String> {
     if !(0.0..=100.0).contains(&value) {
         return Err("percentage must be between 0 and 100".to_string());
     }
@@ -59,35 +919,337 @@ fn percentage_validator(s: &str) -> std::result::Result<f64, String> {
 
 impl Config {
     fn validate(&self) -> Result<()> {
+        // Subcommands do not sample, so none of the sampling options apply
+        if self.command.is_some() {
+            return Ok(());
+        }
+
         if let Some(size) = self.sample_size {
             if size == 0 {
                 return Err(Error::InvalidSampleSize);
             }
         }
 
-        if self.sample_size.is_none() && self.percentage.is_none() {
+        if self.csv_mode && self.header == Some(HeaderMode::No) {
+            return Err(Error::MissingRequiredOption(
+                "--header no contradicts --csv, which takes the first line as a header".to_string(),
+            ));
+        }
+
+        if self.assign.is_some() {
+            if !self.csv_mode {
+                return Err(Error::AssignRequiresCsvMode);
+            }
+        } else if self.expected_size.is_some() {
+            if !self.csv_mode {
+                return Err(Error::WeightedRequiresCsvMode);
+            }
+        } else if self.sample_size.is_none()
+            && self.percentage.is_none()
+            && self.first.is_none()
+            && !self.size_from_header
+            && !self.shuffle_all
+            && !self.one
+            && !self.count_keys
+            && self.percentile_column.is_none()
+            && self.sample_columns.is_none()
+            && !self.probe
+            && !self.show_columns
+            && self.round_robin.is_none()
+        {
             return Err(Error::MissingRequiredOption(
-                "either sample size or percentage must be specified".to_string(),
+                "either sample size, percentage, --first, --shuffle-all or --one must be specified"
+                    .to_string(),
             ));
         }
 
         // Validate hash-based sampling requirements
-        if self.hash_column.is_some() {
+        if self.has_hash_key() {
             // Hash-based sampling requires CSV mode
             if !self.csv_mode {
                 return Err(Error::HashRequiresCsvMode);
             }
 
-            // Hash-based sampling only works with percentage
-            if self.percentage.is_none() {
+            // Hash sampling keeps or drops whole keys, so it cannot honor modes that
+            // pick a number of rows. A sample size is only meaningful with --pin-keys.
+            let incompatible = if self.first.is_some() {
+                Some("--first")
+            } else if self.shuffle_all {
+                Some("--shuffle-all")
+            } else if self.one {
+                Some("--one")
+            } else if self.sample_size.is_some() && self.pin_keys.is_none() {
+                Some("a sample size")
+            } else {
+                None
+            };
+            if let Some(option) = incompatible {
+                return Err(Error::HashIncompatibleWith(option.to_string()));
+            }
+
+            // Hash-based sampling only works with percentage, or a sample size with --pin-keys
+            if self.percentage.is_none()
+                && self.pin_keys.is_none()
+                && !self.count_keys
+                && self.round_robin.is_none()
+                && !self.size_from_header
+            {
                 return Err(Error::HashRequiresPercentage);
             }
         }
 
+        if self.assign_columns.is_some() && self.percentage.is_none() {
+            return Err(Error::MissingRequiredOption(
+                "--assign-columns requires --percentage".to_string(),
+            ));
+        }
+
+        if self.target_count.is_some() && self.percentage.is_none() {
+            return Err(Error::MissingRequiredOption(
+                "--target-count requires --percentage".to_string(),
+            ));
+        }
+
+        if self.antithetic && self.percentage.is_none() {
+            return Err(Error::MissingRequiredOption(
+                "--antithetic requires --percentage".to_string(),
+            ));
+        }
+
+        if self.checkpoint.is_some() && self.percentage.is_none() {
+            return Err(Error::MissingRequiredOption(
+                "--checkpoint requires --percentage".to_string(),
+            ));
+        }
+
+        let parses_records = self.has_hash_key()
+            || self.assign.is_some()
+            || self.assign_columns.is_some()
+            || self.weight_column.is_some()
+            || self.window.is_some();
+        if self.output_delimiter.is_some() && !parses_records {
+            return Err(Error::MissingRequiredOption(
+                "--output-delimiter requires a mode that parses CSV records, such as --hash"
+                    .to_string(),
+            ));
+        }
+
+        let streams = (self.percentage.is_some() || self.first.is_some())
+            && self.sample_size.is_none()
+            && !self.shuffle_all
+            && !self.one
+            && !self.sort
+            && self.sort_column.is_none();
+        if self.line_buffered && !streams {
+            return Err(Error::MissingRequiredOption(
+                "--line-buffered requires a streaming mode (--percentage, --first or --hash) without sorting"
+                    .to_string(),
+            ));
+        }
+
+        if self.decay.is_some() && self.percentage.is_none() {
+            return Err(Error::MissingRequiredOption(
+                "--decay requires --percentage".to_string(),
+            ));
+        }
+
+        if self.time_column.is_some() {
+            if self.window.is_none() && self.decay.is_none() {
+                return Err(Error::MissingRequiredOption(
+                    "--time-column requires --window or --decay".to_string(),
+                ));
+            }
+            if self.decay.is_some() && !self.csv_mode {
+                return Err(Error::MissingRequiredOption(
+                    "--decay with --time-column requires --csv mode".to_string(),
+                ));
+            }
+        }
+
+        if self.window_count.is_some() && self.sample_size.is_none() {
+            return Err(Error::MissingRequiredOption(
+                "--window-count requires a sample size".to_string(),
+            ));
+        }
+
+        if self.window.is_some() && !self.csv_mode {
+            return Err(Error::WindowRequiresCsvMode);
+        }
+
+        if self.whitespace_split && self.percentage.is_none() {
+            return Err(Error::HashRequiresPercentage);
+        }
+
+        if self.exclude_keys.is_some() && !self.has_hash_key() {
+            return Err(Error::ExcludeKeysRequiresHash);
+        }
+
+        if self.spill_dir.is_some() && self.sample_size.is_none() {
+            return Err(Error::MissingRequiredOption(
+                "--spill-dir requires a sample size".to_string(),
+            ));
+        }
+
+        if self.stratify_keys && self.percentage.is_none() {
+            return Err(Error::MissingRequiredOption(
+                "--stratify-keys requires --percentage".to_string(),
+            ));
+        }
+
+        // Lines are passed through without being parsed, so there are no fields to count
+        if self.strict_columns && self.uses_line_path() {
+            return Err(Error::MissingRequiredOption(
+                "--strict-columns requires a mode that parses CSV records, such as --hash"
+                    .to_string(),
+            ));
+        }
+
+        if self.key_rate.is_some() && !self.has_hash_key() {
+            return Err(Error::MissingRequiredOption(
+                "--key-rate requires --hash".to_string(),
+            ));
+        }
+
+        if let Some(template) = &self.output_template {
+            if self.replicates.is_none() && self.round_robin.is_none() {
+                return Err(Error::MissingRequiredOption(
+                    "--output-template requires --replicates or --round-robin".to_string(),
+                ));
+            }
+            if !template.contains("{i}") {
+                return Err(Error::InvalidOutputTemplate);
+            }
+        }
+
         Ok(())
     }
 }
 
+impl Config {
+    /// Build a configuration from `SAMPLE_*` environment variables.
+    ///
+    /// `SAMPLE_SIZE` is the positional sample size, `SAMPLE_PERCENTAGE`,
+    /// `SAMPLE_SEED` and `SAMPLE_HASH_COLUMN` map to `--percentage`, `--seed`
+    /// and `--hash`, and `SAMPLE_CSV` enables `--csv` when set to `true` or
+    /// `1`, or disables it when set to `false` or `0`; any other value is an
+    /// `Error::InvalidEnvVar`. A value the command line parser rejects is an
+    /// `Error::InvalidArguments`. Unset or empty variables are ignored. The
+    /// command line parser never reads these variables, so there is no
+    /// precedence between the two: callers use either `from_env` or `parse_args`.
+    pub fn from_env() -> Result<Config> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    fn from_lookup<F>(lookup: F) -> Result<Config>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let var = |name: &str| lookup(name).filter(|value| !value.trim().is_empty());
+        let mut args = vec!["sample".to_string()];
+        if let Some(size) = var("SAMPLE_SIZE") {
+            args.push(size);
+        }
+        for (name, option) in [
+            ("SAMPLE_PERCENTAGE", "--percentage"),
+            ("SAMPLE_SEED", "--seed"),
+            ("SAMPLE_HASH_COLUMN", "--hash"),
+        ] {
+            if let Some(value) = var(name) {
+                args.push(option.to_string());
+                args.push(value);
+            }
+        }
+        if let Some(csv) = var("SAMPLE_CSV") {
+            match csv.trim().to_ascii_lowercase().as_str() {
+                "1" | "true" => args.push("--csv".to_string()),
+                "0" | "false" => {}
+                _ => return Err(Error::InvalidEnvVar("SAMPLE_CSV".to_string(), csv)),
+            }
+        }
+
+        parse_args_internal(args, |err| Err(Error::InvalidArguments(err)))
+    }
+
+    /// Whether --hash or --hash-regex selects a key column
+    pub fn has_hash_key(&self) -> bool {
+        self.hash_column.is_some() || self.hash_regex.is_some()
+    }
+
+    /// Returns true unless a CSV record mode (bucket assignment, weighted, windowed,
+    /// pinned or hash-based sampling) handles the input
+    pub fn uses_line_path(&self) -> bool {
+        let weighted = self.expected_size.is_some() && self.weight_column.is_some();
+        let pinned = self.pin_keys.is_some() && self.sample_size.is_some();
+        let windowed = self.window.is_some();
+        let hashed = self.csv_mode && self.percentage.is_some() && self.has_hash_key();
+        let reported = self.count_keys || self.probability_report || self.show_columns;
+        let time_decayed = self.decay.is_some() && self.time_column.is_some();
+        let assigned = self.assign.is_some() || self.assign_columns.is_some();
+        let banded = self.percentile_column.is_some();
+        let projected = self.sample_columns.is_some();
+        !assigned
+            && !banded
+            && !projected
+            && !weighted
+            && !pinned
+            && !hashed
+            && !windowed
+            && !reported
+            && !time_decayed
+    }
+
+    /// Returns the configuration with `--header auto` resolved to whether the input
+    /// was found to start with a header, failing if `--csv` needs one that is missing
+    pub fn with_detected_header(&self, has_header: bool) -> Result<Config> {
+        if !has_header && self.csv_mode {
+            return Err(Error::UnexpectedColumns(
+                "--header auto found no header, which --csv needs".to_string(),
+            ));
+        }
+        let header = if has_header {
+            HeaderMode::Yes
+        } else {
+            HeaderMode::No
+        };
+        Ok(Config {
+            header: Some(header),
+            csv_mode: has_header,
+            ..self.clone()
+        })
+    }
+
+    /// Returns the configuration with the sample size or percentage of a
+    /// --size-from-header directive line, `#sample:N` or `#sample:P%`, filled in
+    pub fn with_sample_directive(&self, line: &str) -> Result<Config> {
+        let invalid = || Error::InvalidDirective(line.trim().to_string());
+        let directive = line
+            .trim()
+            .strip_prefix("#sample:")
+            .ok_or_else(invalid)?
+            .trim();
+
+        let mut config = Config {
+            size_from_header: false,
+            ..self.clone()
+        };
+        match directive.strip_suffix('%') {
+            Some(percentage) => {
+                let percentage = percentage.trim();
+                percentage.parse::<f64>().map_err(|_| invalid())?;
+                let percentage =
+                    percentage_validator(percentage).map_err(|_| Error::InvalidPercentage)?;
+                config.percentage = Some(percentage);
+            }
+            None => {
+                let size = directive.parse().map_err(|_| invalid())?;
+                config.sample_size = Some(size);
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
 /// Parse command line arguments.
 pub fn parse_args<I, T>(args: I) -> Result<Config>
 where
@@ -106,33 +1268,288 @@ where
     T: AsRef<str>,
     F: FnOnce(clap::Error) -> Result<Config>,
 {
-    let string_args = args.into_iter().map(|s| s.as_ref().to_string());
-    let config = match Config::try_parse_from(string_args) {
-        Ok(config) => config,
+    let string_args: Vec<String> = args.into_iter().map(|s| s.as_ref().to_string()).collect();
+    // Only locate --config here: `requires` and conflicts are checked once the file's
+    // options are merged in, since the file may supply an option another one requires
+    let matches = match Config::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&string_args)
+    {
+        Ok(matches) => matches,
         Err(err) => return on_error(err),
     };
+    let invocation = string_args.clone();
+    let mut config = match matches.get_one::<PathBuf>("config_file") {
+        Some(path) => {
+            let (options, stand_ins) = read_config_file(path, &matches)?;
+            // The file may supply an option another one requires, so requirements are
+            // checked by `check_merged_options` instead of while parsing
+            let parsed = Config::command()
+                .mut_args(|arg| arg.requires(Resettable::Reset))
+                .try_get_matches_from(&string_args)
+                .and_then(|matches| Config::from_arg_matches(&matches));
+            let mut config = match parsed {
+                Ok(config) => config,
+                Err(err) => return on_error(err),
+            };
+            options.merge_into(&mut config).map_err(|msg| {
+                Error::InvalidConfigFile(format!("'{}': {}", path.display(), msg))
+            })?;
+            if let Err(err) = check_merged_options(&string_args, stand_ins) {
+                return on_error(err);
+            }
+            config
+        }
+        None => match Config::try_parse_from(&string_args) {
+            Ok(config) => config,
+            Err(err) => return on_error(err),
+        },
+    };
+    config.invocation = invocation;
+    if config.header == Some(HeaderMode::Yes) {
+        config.csv_mode = true;
+    }
 
     config.validate()?;
     Ok(config)
 }
 
-#[cfg(test)]
-/// Version of parse_args that returns errors instead of exiting for testing purposes
-pub fn parse_args_for_tests<I, T>(args: I) -> Result<Config>
-where
-    I: IntoIterator<Item = T>,
-    T: AsRef<str>,
-{
-    parse_args_internal(args, |err| {
-        Err(Error::MissingRequiredOption(err.to_string()))
-    })
-}
+/// Declares `FileOptions`, the options a `--config` file may set, by `Config` field.
+/// Each value deserializes to the given type and, where the command line parses or
+/// checks the option's value, goes through the given function too.
+macro_rules! file_options {
+    ($($(#[$attr:meta])* $field:ident: $ty:ty $(=> $convert:expr)?,)*) => {
+        #[derive(Debug, Default, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct FileOptions {
+            $($(#[$attr])* $field: Option<$ty>,)*
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        impl FileOptions {
+            /// Set every option the file gives on `config`
+            fn merge_into(self, config: &mut Config) -> std::result::Result<(), String> {
+                $(
+                    $(#[$attr])*
+                    if let Some(value) = self.$field {
+                        $(let value = ($convert)(value).map_err(|msg| {
+                            format!("invalid value for '{}': {}", stringify!($field), msg)
+                        })?;)?
+                        config.$field = value.into();
+                    }
+                )*
+                Ok(())
+            }
+        }
 
-    #[test]
+        #[cfg(test)]
+        const FILE_OPTIONS: &[&str] = &[$($(#[$attr])* stringify!($field),)*];
+    };
+}
+
+file_options! {
+    sample_size: usize,
+    percentage: f64 => check_percentage,
+    csv_mode: bool,
+    header: String => parsed(header_mode_parser),
+    seed: u64,
+    hash_column: String,
+    hash_regex: String => parsed(Regex::new),
+    max_bytes: u64,
+    spill_dir: PathBuf,
+    limit_memory: usize,
+    reservoir_order: String => parsed(reservoir_order_parser),
+    retry: u32,
+    exclude_keys: PathBuf,
+    key_rate: PathBuf,
+    pin_keys: PathBuf,
+    first: usize,
+    shuffle_all: bool,
+    one: bool,
+    size_from_header: bool,
+    probe: bool,
+    dry_validate: bool,
+    replicates: usize,
+    output_template: String,
+    round_robin: u64 => check_positive,
+    skip: usize,
+    assign: String => parsed(bucket_assignment_parser),
+    assign_columns: Vec<String>,
+    whitespace_split: bool,
+    hash_index: usize,
+    expected_size: f64 => check_expected_size,
+    weight_column: String,
+    percentile_column: String,
+    percentile_range: String => parsed(percentile_range_parser),
+    stats_column: String,
+    sample_columns: u64 => check_positive,
+    total_weight: f64,
+    filter: String => parsed(Regex::new),
+    quiet: bool,
+    line_buffered: bool,
+    record_separator: String => parsed(record_separator_parser),
+    pretty: bool,
+    pretty_width: u64 => check_positive,
+    max_records: u64,
+    stats_out: PathBuf,
+    log_invocation: PathBuf,
+    manifest: PathBuf,
+    decision_out: PathBuf,
+    embed_seed: bool,
+    comment_char: char,
+    expect_columns: Vec<String>,
+    no_header_output: bool,
+    rename: Vec<String> => each_parsed(column_rename_parser),
+    sort: bool,
+    sort_column: String,
+    merge_sorted: Vec<PathBuf>,
+    normalize: String => parsed(key_normalization_parser),
+    numeric_key: bool,
+    numeric_key_fallback: bool,
+    antithetic: bool,
+    decay: f64 => check_decay,
+    target_count: usize,
+    count_keys: bool,
+    probability_report: bool,
+    show_columns: bool,
+    checkpoint: PathBuf,
+    resume: PathBuf,
+    mmap: bool,
+    time_column: String,
+    window: f64 => check_window,
+    window_count: u64 => check_positive,
+    require_trailing_newline: bool,
+    strict_columns: bool,
+    max_record_bytes: usize,
+    ignore_case_columns: bool,
+    encoding: String => parsed(encoding_parser),
+    first_per_key: bool,
+    cover_keys: bool,
+    stratify_keys: bool,
+    emit_hash: bool,
+    output_delimiter: String => parsed(delimiter_parser),
+    #[cfg(feature = "parquet")]
+    parquet_out: PathBuf,
+    #[cfg(feature = "gzip")]
+    gzip_out: bool,
+    #[cfg(feature = "progress")]
+    progress_bar: bool,
+}
+
+/// Parse a config file string with the command line's parser for the option
+fn parsed<T, E: ToString>(
+    parse: impl Fn(&str) -> std::result::Result<T, E>,
+) -> impl Fn(String) -> std::result::Result<T, String> {
+    move |value| parse(&value).map_err(|e| e.to_string())
+}
+
+/// Parse each string of a config file array with the command line's parser
+fn each_parsed<T, E: ToString>(
+    parse: impl Fn(&str) -> std::result::Result<T, E>,
+) -> impl Fn(Vec<String>) -> std::result::Result<Vec<T>, String> {
+    move |values| {
+        values
+            .iter()
+            .map(|value| parse(value).map_err(|e| e.to_string()))
+            .collect()
+    }
+}
+
+fn check_positive(n: u64) -> std::result::Result<u64, String> {
+    if n == 0 {
+        return Err("must be at least 1".to_string());
+    }
+    Ok(n)
+}
+
+/// Read the options of a TOML config file.
+///
+/// Keys set on the command line, or conflicting with an option set there, are
+/// skipped so the command line always wins. Unknown keys are an error. Also returns
+/// arguments that stand in for the options the file sets, for `check_merged_options`.
+fn read_config_file(path: &Path, matches: &ArgMatches) -> Result<(FileOptions, Vec<String>)> {
+    let invalid = |msg: String| Error::InvalidConfigFile(format!("'{}': {}", path.display(), msg));
+    let contents = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    let table: toml::Table = contents.parse().map_err(|e| invalid(format!("{}", e)))?;
+
+    let command = Config::command();
+    let given: Vec<&Arg> = command
+        .get_arguments()
+        .filter(|arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine))
+        .collect();
+
+    let mut options = toml::Table::new();
+    let mut stand_ins = Vec::new();
+    for (key, value) in table {
+        let arg = command
+            .get_arguments()
+            .filter(|arg| arg.get_id() != "config_file")
+            .find(|arg| arg.get_id() == key.as_str() || arg.get_long() == Some(key.as_str()))
+            .ok_or_else(|| invalid(format!("unknown key '{}'", key)))?;
+
+        let overridden = given.iter().any(|other| {
+            other.get_id() == arg.get_id()
+                || command.get_arg_conflicts_with(arg).contains(other)
+                || command.get_arg_conflicts_with(other).contains(&arg)
+        });
+        if overridden {
+            continue;
+        }
+
+        // Only the presence of an option matters to its requirements and conflicts
+        match (arg.get_long(), arg.get_action().takes_values()) {
+            (Some(long), false) if value == toml::Value::Boolean(true) => {
+                stand_ins.push(format!("--{}", long))
+            }
+            (_, false) => {}
+            (Some(long), true) => stand_ins.push(format!("--{}=_", long)),
+            (None, true) => stand_ins.push("_".to_string()),
+        }
+        options.insert(arg.get_id().to_string(), value);
+    }
+
+    let options: FileOptions = toml::Value::Table(options)
+        .try_into()
+        .map_err(|e: toml::de::Error| invalid(e.to_string().trim_end().replace('\n', " ")))?;
+    Ok((options, stand_ins))
+}
+
+/// Check the requirements and conflicts of the command line options together with
+/// the `stand_ins` for the options of a config file
+fn check_merged_options(args: &[String], stand_ins: Vec<String>) -> clap::error::Result<()> {
+    let command = Config::command().mut_args(|arg| {
+        if arg.get_action().takes_values() {
+            arg.value_parser(ValueParser::string())
+        } else {
+            arg
+        }
+    });
+    let mut args = args.iter().cloned();
+    let args: Vec<String> = args
+        .next()
+        .into_iter()
+        .chain(stand_ins)
+        .chain(args)
+        .collect();
+    command.try_get_matches_from(args).map(|_| ())
+}
+
+#[cfg(test)]
+/// Version of parse_args that returns errors instead of exiting for testing purposes
+pub fn parse_args_for_tests<I, T>(args: I) -> Result<Config>
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<str>,
+{
+    parse_args_internal(args, |err| {
+        Err(Error::MissingRequiredOption(err.to_string()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
     fn test_parse_args_basic() {
         let config = parse_args_for_tests(["sample", "10"]).unwrap();
         assert_eq!(config.sample_size, Some(10));
@@ -241,4 +1658,1416 @@ mod tests {
         let result = parse_args_for_tests(["sample", "10", "--csv", "--hash", "user_id"]);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_parse_args_with_first() {
+        let config = parse_args_for_tests(["sample", "--first", "3"]).unwrap();
+        assert_eq!(config.first, Some(3));
+        assert_eq!(config.sample_size, None);
+        assert_eq!(config.percentage, None);
+    }
+
+    #[test]
+    fn test_first_conflicts_with_sample_size() {
+        let result = parse_args_for_tests(["sample", "10", "--first", "3"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_replicates() {
+        let config = parse_args_for_tests([
+            "sample",
+            "10",
+            "--replicates",
+            "3",
+            "--output-template",
+            "out_{i}.csv",
+        ])
+        .unwrap();
+        assert_eq!(config.replicates, Some(3));
+        assert_eq!(config.output_template, Some("out_{i}.csv".to_string()));
+    }
+
+    #[test]
+    fn test_replicates_require_output_template() {
+        let result = parse_args_for_tests(["sample", "10", "--replicates", "3"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_output_template_requires_placeholder() {
+        let result = parse_args_for_tests([
+            "sample",
+            "10",
+            "--replicates",
+            "3",
+            "--output-template",
+            "out.csv",
+        ]);
+        assert!(matches!(result, Err(Error::InvalidOutputTemplate)));
+    }
+
+    #[test]
+    fn test_parse_args_with_skip() {
+        let config = parse_args_for_tests(["sample", "10", "--skip", "5"]).unwrap();
+        assert_eq!(config.skip, 5);
+
+        let config = parse_args_for_tests(["sample", "10"]).unwrap();
+        assert_eq!(config.skip, 0);
+    }
+
+    #[test]
+    fn test_parse_args_with_assign() {
+        let config = parse_args_for_tests(["sample", "--csv", "--assign", "user:id:3"]).unwrap();
+        assert_eq!(
+            config.assign,
+            Some(BucketAssignment {
+                column: "user:id".to_string(),
+                buckets: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn test_assign_rejects_invalid_buckets() {
+        assert!(parse_args_for_tests(["sample", "--csv", "--assign", "id"]).is_err());
+        assert!(parse_args_for_tests(["sample", "--csv", "--assign", "id:0"]).is_err());
+    }
+
+    #[test]
+    fn test_assign_requires_csv_mode() {
+        let result = parse_args_for_tests(["sample", "--assign", "id:3"]);
+        assert!(matches!(result, Err(Error::AssignRequiresCsvMode)));
+    }
+
+    #[test]
+    fn test_parse_args_with_whitespace_split() {
+        let config = parse_args_for_tests([
+            "sample",
+            "-p",
+            "10",
+            "--whitespace-split",
+            "--hash-index",
+            "2",
+        ])
+        .unwrap();
+        assert!(config.whitespace_split);
+        assert_eq!(config.hash_index, Some(2));
+    }
+
+    #[test]
+    fn test_whitespace_split_requires_hash_index_and_percentage() {
+        assert!(parse_args_for_tests(["sample", "-p", "10", "--whitespace-split"]).is_err());
+        assert!(parse_args_for_tests(["sample", "-p", "10", "--hash-index", "1"]).is_err());
+
+        let result =
+            parse_args_for_tests(["sample", "10", "--whitespace-split", "--hash-index", "1"]);
+        assert!(matches!(result, Err(Error::HashRequiresPercentage)));
+    }
+
+    #[test]
+    fn test_parse_args_with_expected_size() {
+        let config = parse_args_for_tests([
+            "sample",
+            "--csv",
+            "--expected-size",
+            "100",
+            "--weight-column",
+            "w",
+            "--total-weight",
+            "5000",
+        ])
+        .unwrap();
+        assert_eq!(config.expected_size, Some(100.0));
+        assert_eq!(config.weight_column, Some("w".to_string()));
+        assert_eq!(config.total_weight, Some(5000.0));
+    }
+
+    #[test]
+    fn test_expected_size_requirements() {
+        let result = parse_args_for_tests(["sample", "--csv", "--expected-size", "100"]);
+        assert!(result.is_err());
+
+        let result =
+            parse_args_for_tests(["sample", "--expected-size", "100", "--weight-column", "w"]);
+        assert!(matches!(result, Err(Error::WeightedRequiresCsvMode)));
+
+        for size in ["0", "-5", "NaN", "inf", "x"] {
+            let result = parse_args_for_tests([
+                "sample",
+                "--csv",
+                "--expected-size",
+                size,
+                "--weight-column",
+                "w",
+            ]);
+            assert!(result.is_err(), "{}", size);
+        }
+    }
+
+    #[test]
+    fn test_parse_args_with_filter() {
+        let config = parse_args_for_tests(["sample", "10", "--filter", "^ERROR"]).unwrap();
+        assert_eq!(config.filter.unwrap().as_str(), "^ERROR");
+    }
+
+    #[test]
+    fn test_invalid_filter_regex() {
+        let result = parse_args_for_tests(["sample", "10", "--filter", "(unclosed"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_embed_seed() {
+        let config = parse_args_for_tests(["sample", "10", "--embed-seed"]).unwrap();
+        assert!(config.embed_seed);
+        assert_eq!(config.comment_char, '#');
+
+        let config = parse_args_for_tests(["sample", "10", "--comment-char", ";"]).unwrap();
+        assert!(!config.embed_seed);
+        assert_eq!(config.comment_char, ';');
+    }
+
+    #[test]
+    fn test_parse_args_with_expect_columns() {
+        let config =
+            parse_args_for_tests(["sample", "10", "--csv", "--expect-columns", "a,b,c"]).unwrap();
+        assert_eq!(
+            config.expect_columns,
+            Some(vec!["a".to_string(), "b".to_string(), "c".to_string()])
+        );
+
+        let result = parse_args_for_tests(["sample", "10", "--expect-columns", "a,b"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_no_header_output() {
+        let config = parse_args_for_tests(["sample", "10", "--csv", "--no-header-output"]).unwrap();
+        assert!(config.no_header_output);
+
+        let result = parse_args_for_tests(["sample", "10", "--no-header-output"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_pin_keys() {
+        let config =
+            parse_args_for_tests(["sample", "5", "--csv", "--hash", "id", "--pin-keys", "k"])
+                .unwrap();
+        assert_eq!(config.pin_keys, Some(PathBuf::from("k")));
+
+        let result = parse_args_for_tests(["sample", "5", "--csv", "--pin-keys", "k"]);
+        assert!(result.is_err());
+
+        let result = parse_args_for_tests([
+            "sample",
+            "-p",
+            "5",
+            "--csv",
+            "--hash",
+            "id",
+            "--pin-keys",
+            "k",
+        ]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_shuffle_all() {
+        let config = parse_args_for_tests(["sample", "--shuffle-all"]).unwrap();
+        assert!(config.shuffle_all);
+        assert_eq!(config.sample_size, None);
+        assert_eq!(config.percentage, None);
+
+        let result = parse_args_for_tests(["sample", "10", "--shuffle-all"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_window() {
+        let config = parse_args_for_tests([
+            "sample",
+            "5",
+            "--csv",
+            "--time-column",
+            "ts",
+            "--window",
+            "300",
+        ])
+        .unwrap();
+        assert_eq!(config.time_column, Some("ts".to_string()));
+        assert_eq!(config.window, Some(300.0));
+
+        let result = parse_args_for_tests(["sample", "5", "--time-column", "ts", "--window", "1"]);
+        assert!(matches!(result, Err(Error::WindowRequiresCsvMode)));
+
+        let result = parse_args_for_tests([
+            "sample",
+            "5",
+            "--csv",
+            "--time-column",
+            "ts",
+            "--window",
+            "0",
+        ]);
+        assert!(result.is_err());
+
+        let result = parse_args_for_tests(["sample", "5", "--csv", "--window", "10"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_rename() {
+        let config =
+            parse_args_for_tests(["sample", "10", "--csv", "--rename", "a=x,b=y"]).unwrap();
+        let renames = config.rename.unwrap();
+        assert_eq!(renames.len(), 2);
+        assert_eq!(renames[1].from, "b");
+        assert_eq!(renames[1].to, "y");
+
+        assert!(parse_args_for_tests(["sample", "10", "--csv", "--rename", "a"]).is_err());
+        assert!(parse_args_for_tests(["sample", "10", "--rename", "a=x"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_sort() {
+        let config = parse_args_for_tests(["sample", "10", "--sort"]).unwrap();
+        assert!(config.sort);
+        assert_eq!(config.sort_column, None);
+
+        let config = parse_args_for_tests(["sample", "10", "--csv", "--sort-column", "v"]).unwrap();
+        assert_eq!(config.sort_column, Some("v".to_string()));
+
+        assert!(parse_args_for_tests(["sample", "10", "--sort-column", "v"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_merge_sorted() {
+        let config = parse_args_for_tests([
+            "sample",
+            "10",
+            "--csv",
+            "--sort-column",
+            "v",
+            "--merge-sorted",
+            "a.csv,b.csv",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.merge_sorted,
+            Some(vec![PathBuf::from("a.csv"), PathBuf::from("b.csv")])
+        );
+        assert!(
+            parse_args_for_tests(["sample", "10", "--csv", "--merge-sorted", "a.csv"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_args_with_normalize() {
+        let config = parse_args_for_tests(["sample", "-p", "10"]).unwrap();
+        assert_eq!(config.normalize, KeyNormalization::None);
+
+        let config =
+            parse_args_for_tests(["sample", "-p", "10", "--normalize", "trim+lowercase"]).unwrap();
+        assert_eq!(config.normalize, KeyNormalization::TrimLowercase);
+
+        assert!(parse_args_for_tests(["sample", "-p", "10", "--normalize", "upper"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_antithetic() {
+        let config = parse_args_for_tests(["sample", "-p", "10", "--antithetic"]).unwrap();
+        assert!(config.antithetic);
+
+        assert!(parse_args_for_tests(["sample", "10", "--antithetic"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_checkpoint() {
+        let config =
+            parse_args_for_tests(["sample", "-p", "5", "--checkpoint", "c", "--resume", "c"])
+                .unwrap();
+        assert_eq!(config.checkpoint, Some(PathBuf::from("c")));
+        assert_eq!(config.resume, Some(PathBuf::from("c")));
+
+        assert!(parse_args_for_tests(["sample", "5", "--checkpoint", "c"]).is_err());
+        assert!(parse_args_for_tests(["sample", "-p", "5", "--resume", "c"]).is_err());
+    }
+
+    fn write_config_file(contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, contents.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_parse_args_with_config_file() {
+        let file = write_config_file(
+            "percentage = 5\ncsv = true\nseed = 7\nhash_column = \"id\"\nrename = [\"a=b\", \"c=d\"]\n",
+        );
+        let path = file.path().to_str().unwrap();
+
+        let config = parse_args_for_tests(["sample", "--config", path]).unwrap();
+        assert_eq!(config.percentage, Some(5.0));
+        assert!(config.csv_mode);
+        assert_eq!(config.seed, Some(7));
+        assert_eq!(config.hash_column.as_deref(), Some("id"));
+        assert_eq!(config.rename.map(|r| r.len()), Some(2));
+    }
+
+    #[test]
+    fn test_command_line_overrides_config_file() {
+        let file = write_config_file("percentage = 5\nseed = 7\n");
+        let path = file.path().to_str().unwrap();
+
+        let config = parse_args_for_tests(["sample", "--config", path, "-s", "9"]).unwrap();
+        assert_eq!(config.percentage, Some(5.0));
+        assert_eq!(config.seed, Some(9));
+
+        // A sample size on the command line replaces the conflicting file percentage
+        let config = parse_args_for_tests(["sample", "10", "--config", path]).unwrap();
+        assert_eq!(config.sample_size, Some(10));
+        assert_eq!(config.percentage, None);
+        assert_eq!(config.seed, Some(7));
+    }
+
+    #[test]
+    fn test_config_file_satisfies_requirements_of_command_line_options() {
+        let file = write_config_file("csv = true\n");
+        let path = file.path().to_str().unwrap();
+
+        let config =
+            parse_args_for_tests(["sample", "5", "--config", path, "--sort-column", "name"])
+                .unwrap();
+        assert!(config.csv_mode);
+        assert_eq!(config.sort_column.as_deref(), Some("name"));
+
+        // Without the file the requirement is still enforced
+        let result = parse_args_for_tests(["sample", "5", "--sort-column", "name"]);
+        assert!(result.is_err());
+
+        // The file's own options are checked against each other
+        let file = write_config_file("percentage = 5\nfirst = 3\n");
+        let result = parse_args_for_tests(["sample", "--config", file.path().to_str().unwrap()]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_file_rejects_unknown_keys_and_malformed_toml() {
+        let file = write_config_file("percentage = 5\nsample_rate = 3\n");
+        let result = parse_args_for_tests(["sample", "--config", file.path().to_str().unwrap()]);
+        assert!(
+            matches!(result, Err(Error::InvalidConfigFile(msg)) if msg.contains("sample_rate"))
+        );
+
+        let file = write_config_file("percentage = \n");
+        let result = parse_args_for_tests(["sample", "--config", file.path().to_str().unwrap()]);
+        assert!(matches!(result, Err(Error::InvalidConfigFile(_))));
+
+        let file = write_config_file("csv = \"yes\"\n");
+        let result = parse_args_for_tests(["sample", "--config", file.path().to_str().unwrap()]);
+        assert!(matches!(result, Err(Error::InvalidConfigFile(msg))
+            if msg.ends_with("expected a boolean in `csv_mode`")));
+
+        // Values are checked like the command line's
+        for contents in [
+            "percentage = 150\n",
+            "filter = \"(\"\n",
+            "round_robin = 0\n",
+        ] {
+            let file = write_config_file(contents);
+            let result =
+                parse_args_for_tests(["sample", "--config", file.path().to_str().unwrap()]);
+            assert!(
+                matches!(result, Err(Error::InvalidConfigFile(msg)) if msg.contains("invalid value")),
+                "{}",
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn test_config_file_values_are_typed() {
+        let file = write_config_file(
+            "csv = true\nexpect-columns = [\"id\", \"a,b\"]\npercentile_column = \"v\"\n\
+             percentile-range = \"95:100\"\nnormalize = \"trim\"\n",
+        );
+        let config =
+            parse_args_for_tests(["sample", "--config", file.path().to_str().unwrap()]).unwrap();
+        assert_eq!(
+            config.expect_columns,
+            Some(vec!["id".to_string(), "a,b".to_string()])
+        );
+        assert_eq!(config.percentile_range, Some((95.0, 100.0)));
+        assert_eq!(config.normalize, KeyNormalization::Trim);
+
+        let file = write_config_file("percentage = 0.1\n");
+        let config =
+            parse_args_for_tests(["sample", "--config", file.path().to_str().unwrap()]).unwrap();
+        assert_eq!(config.percentage, Some(0.1));
+    }
+
+    #[test]
+    fn test_config_file_accepts_every_option() {
+        for arg in Config::command().get_arguments() {
+            let id = arg.get_id().as_str();
+            assert!(
+                id == "config_file" || FILE_OPTIONS.contains(&id),
+                "{} is missing from file_options!",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_args_with_count_keys() {
+        let config = parse_args_for_tests(["sample", "--count-keys", "--csv", "--hash", "id"]);
+        assert!(config.unwrap().count_keys);
+
+        let result = parse_args_for_tests(["sample", "--count-keys", "--hash", "id"]);
+        assert!(matches!(result, Err(Error::HashRequiresCsvMode)));
+        assert!(parse_args_for_tests(["sample", "--count-keys", "--csv"]).is_err());
+        assert!(parse_args_for_tests([
+            "sample",
+            "-p",
+            "5",
+            "--count-keys",
+            "--csv",
+            "--hash",
+            "id"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_probability_report() {
+        let args = [
+            "sample",
+            "-p",
+            "5",
+            "--csv",
+            "--hash",
+            "id",
+            "--probability-report",
+        ];
+        assert!(parse_args_for_tests(args).unwrap().probability_report);
+
+        let result =
+            parse_args_for_tests(["sample", "--csv", "--hash", "id", "--probability-report"]);
+        assert!(result.is_err());
+        assert!(
+            parse_args_for_tests(["sample", "-p", "5", "--csv", "--probability-report"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_args_with_check_command() {
+        let config = parse_args_for_tests(["sample", "check", "--hash", "id"]).unwrap();
+        let Some(Command::Check(check)) = config.command else {
+            panic!("expected the check command");
+        };
+        assert_eq!(check.hash_column, "id");
+        assert_eq!(check.rows, 1000);
+
+        assert!(parse_args_for_tests(["sample", "check"]).is_err());
+        assert!(parse_args_for_tests(["sample", "10"])
+            .unwrap()
+            .command
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_args_with_strict_columns() {
+        let strict = [
+            "sample",
+            "-p",
+            "5",
+            "--csv",
+            "--hash",
+            "id",
+            "--strict-columns",
+        ];
+        assert!(parse_args_for_tests(strict).unwrap().strict_columns);
+        assert!(
+            !parse_args_for_tests(["sample", "-p", "5", "--csv", "--hash", "id"])
+                .unwrap()
+                .strict_columns
+        );
+        assert!(parse_args_for_tests(["sample", "-p", "5", "--strict-columns"]).is_err());
+
+        // The line-based modes do not parse fields, so they cannot check the count
+        for args in [
+            &["sample", "-p", "5", "--csv", "--strict-columns"][..],
+            &["sample", "3", "--csv", "--strict-columns"],
+            &[
+                "sample",
+                "-p",
+                "5",
+                "--csv",
+                "--sort-column",
+                "k",
+                "--merge-sorted",
+                "a,b",
+                "--strict-columns",
+            ],
+        ] {
+            assert!(matches!(
+                parse_args_for_tests(args),
+                Err(Error::MissingRequiredOption(msg)) if msg.contains("--strict-columns")
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_args_with_max_record_bytes() {
+        let config =
+            parse_args_for_tests(["sample", "-p", "5", "--csv", "--max-record-bytes", "4096"]);
+        assert_eq!(config.unwrap().max_record_bytes, Some(4096));
+        assert!(parse_args_for_tests(["sample", "-p", "5", "--max-record-bytes", "4096"]).is_err());
+        assert!(
+            parse_args_for_tests(["sample", "-p", "5", "--csv", "--max-record-bytes", "x"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_args_with_output_delimiter() {
+        for (value, expected) in [("\\t", b'\t'), ("tab", b'\t'), (";", b';')] {
+            let args = [
+                "sample",
+                "-p",
+                "5",
+                "--csv",
+                "--hash",
+                "id",
+                "--output-delimiter",
+                value,
+            ];
+            assert_eq!(
+                parse_args_for_tests(args).unwrap().output_delimiter,
+                Some(expected)
+            );
+        }
+
+        let args = [
+            "sample",
+            "-p",
+            "5",
+            "--csv",
+            "--hash",
+            "id",
+            "--output-delimiter",
+            ";;",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+        let args = ["sample", "-p", "5", "--csv", "--output-delimiter", ";"];
+        assert!(parse_args_for_tests(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_assign_columns() {
+        let args = ["sample", "-p", "20", "--csv", "--assign-columns", "a,b"];
+        let config = parse_args_for_tests(args).unwrap();
+        assert_eq!(
+            config.assign_columns,
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+
+        let result = parse_args_for_tests(["sample", "--csv", "--assign-columns", "a"]);
+        assert!(matches!(result, Err(Error::MissingRequiredOption(_))));
+        assert!(parse_args_for_tests(["sample", "-p", "20", "--assign-columns", "a"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_first_per_key() {
+        let args = [
+            "sample",
+            "-p",
+            "5",
+            "--csv",
+            "--hash",
+            "id",
+            "--first-per-key",
+        ];
+        assert!(parse_args_for_tests(args).unwrap().first_per_key);
+        assert!(parse_args_for_tests(["sample", "-p", "5", "--csv", "--first-per-key"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_cover_keys() {
+        let args = ["sample", "-p", "5", "--csv", "--hash", "id", "--cover-keys"];
+        assert!(parse_args_for_tests(args).unwrap().cover_keys);
+        let args = [
+            "sample",
+            "-p",
+            "5",
+            "--csv",
+            "--hash",
+            "id",
+            "--cover-keys",
+            "--first-per-key",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_encoding() {
+        let config = parse_args_for_tests(["sample", "5", "--encoding", "latin1"]).unwrap();
+        assert_eq!(config.encoding, Some(encoding_rs::WINDOWS_1252));
+        assert!(parse_args_for_tests(["sample", "5", "--encoding", "klingon"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_one() {
+        assert!(
+            parse_args_for_tests(["sample", "--one", "--csv"])
+                .unwrap()
+                .one
+        );
+        assert!(parse_args_for_tests(["sample", "3", "--one"]).is_err());
+        assert!(parse_args_for_tests(["sample", "--one", "--shuffle-all"]).is_err());
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parse_args_with_parquet_out() {
+        let config =
+            parse_args_for_tests(["sample", "-p", "5", "--csv", "--parquet-out", "out.parquet"]);
+        assert_eq!(
+            config.unwrap().parquet_out,
+            Some(PathBuf::from("out.parquet"))
+        );
+        assert!(
+            parse_args_for_tests(["sample", "-p", "5", "--parquet-out", "out.parquet"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_args_with_probe() {
+        assert!(parse_args_for_tests(["sample", "--probe"]).unwrap().probe);
+        assert!(!parse_args_for_tests(["sample", "-p", "5"]).unwrap().probe);
+    }
+
+    #[test]
+    fn test_parse_args_with_retry() {
+        assert_eq!(
+            parse_args_for_tests(["sample", "-p", "5", "--retry", "3"])
+                .unwrap()
+                .retry,
+            3
+        );
+        assert_eq!(
+            parse_args_for_tests(["sample", "-p", "5"]).unwrap().retry,
+            0
+        );
+    }
+
+    #[test]
+    fn test_parse_args_with_key_rate() {
+        let args = [
+            "sample",
+            "-p",
+            "5",
+            "--csv",
+            "--hash",
+            "id",
+            "--key-rate",
+            "rates.csv",
+        ];
+        assert_eq!(
+            parse_args_for_tests(args).unwrap().key_rate,
+            Some(PathBuf::from("rates.csv"))
+        );
+        assert!(parse_args_for_tests(["sample", "-p", "5", "--key-rate", "rates.csv"]).is_err());
+        // The report shows the -p decision, which the overrides would contradict
+        let report = [&args[..], &["--probability-report"]].concat();
+        assert!(parse_args_for_tests(report).is_err());
+    }
+
+    #[test]
+    fn test_line_buffered_requires_streaming_mode() {
+        assert!(
+            parse_args_for_tests(["sample", "-p", "5", "--line-buffered"])
+                .unwrap()
+                .line_buffered
+        );
+        assert!(parse_args_for_tests(["sample", "--first", "5", "--line-buffered"]).is_ok());
+        assert!(parse_args_for_tests(["sample", "10", "--line-buffered"]).is_err());
+        assert!(parse_args_for_tests(["sample", "--shuffle-all", "--line-buffered"]).is_err());
+        assert!(parse_args_for_tests(["sample", "-p", "5", "--sort", "--line-buffered"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_show_columns() {
+        assert!(
+            parse_args_for_tests(["sample", "--csv", "--show-columns"])
+                .unwrap()
+                .show_columns
+        );
+        assert!(parse_args_for_tests(["sample", "--show-columns"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_manifest() {
+        assert_eq!(
+            parse_args_for_tests(["sample", "10", "--manifest", "lines.txt"])
+                .unwrap()
+                .manifest,
+            Some(PathBuf::from("lines.txt"))
+        );
+        let args = [
+            "sample",
+            "-p",
+            "5",
+            "--csv",
+            "--hash",
+            "id",
+            "--manifest",
+            "lines.txt",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_decay() {
+        let config = parse_args_for_tests(["sample", "-p", "50", "--decay", "0.01"]).unwrap();
+        assert_eq!(config.decay, Some(0.01));
+        let args = [
+            "sample",
+            "-p",
+            "50",
+            "--csv",
+            "--decay",
+            "0.1",
+            "--time-column",
+            "ts",
+        ];
+        assert!(parse_args_for_tests(args).is_ok());
+
+        assert!(parse_args_for_tests(["sample", "-p", "50", "--decay", "-1"]).is_err());
+        assert!(parse_args_for_tests(["sample", "10", "--decay", "0.1"]).is_err());
+        let args = [
+            "sample",
+            "-p",
+            "50",
+            "--decay",
+            "0.1",
+            "--time-column",
+            "ts",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+        assert!(
+            parse_args_for_tests(["sample", "-p", "50", "--csv", "--time-column", "ts"]).is_err()
+        );
+        let args = ["sample", "-p", "50", "--decay", "0.1", "--checkpoint", "ck"];
+        assert!(parse_args_for_tests(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_ignore_case_columns() {
+        let args = [
+            "sample",
+            "-p",
+            "5",
+            "--csv",
+            "--hash",
+            "Id",
+            "--ignore-case-columns",
+        ];
+        assert!(parse_args_for_tests(args).unwrap().ignore_case_columns);
+        assert!(parse_args_for_tests(["sample", "-p", "5", "--ignore-case-columns"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_round_robin() {
+        let args = [
+            "sample",
+            "--round-robin",
+            "4",
+            "--output-template",
+            "part_{i}",
+        ];
+        assert_eq!(parse_args_for_tests(args).unwrap().round_robin, Some(4));
+        let args = [
+            "sample",
+            "--csv",
+            "--hash",
+            "id",
+            "--round-robin",
+            "2",
+            "--output-template",
+            "p{i}",
+        ];
+        assert!(parse_args_for_tests(args).is_ok());
+
+        assert!(parse_args_for_tests(["sample", "--round-robin", "4"]).is_err());
+        let args = [
+            "sample",
+            "--round-robin",
+            "0",
+            "--output-template",
+            "part_{i}",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+        let args = [
+            "sample",
+            "-p",
+            "5",
+            "--round-robin",
+            "2",
+            "--output-template",
+            "p{i}",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+        assert!(parse_args_for_tests(["sample", "10", "--output-template", "p{i}"]).is_err());
+    }
+
+    fn env_lookup(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: Vec<(String, String)> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone())
+    }
+
+    #[test]
+    fn test_from_env_reads_all_variables() {
+        let config = Config::from_lookup(env_lookup(&[
+            ("SAMPLE_PERCENTAGE", "12.5"),
+            ("SAMPLE_CSV", "true"),
+            ("SAMPLE_SEED", "7"),
+            ("SAMPLE_HASH_COLUMN", "user_id"),
+        ]))
+        .unwrap();
+        assert_eq!(config.percentage, Some(12.5));
+        assert!(config.csv_mode);
+        assert_eq!(config.seed, Some(7));
+        assert_eq!(config.hash_column.as_deref(), Some("user_id"));
+        assert_eq!(config.sample_size, None);
+    }
+
+    #[test]
+    fn test_from_env_sample_size() {
+        let config = Config::from_lookup(env_lookup(&[("SAMPLE_SIZE", "100")])).unwrap();
+        assert_eq!(config.sample_size, Some(100));
+        assert!(!config.csv_mode);
+    }
+
+    #[test]
+    fn test_from_env_validates() {
+        assert!(matches!(
+            Config::from_lookup(env_lookup(&[])),
+            Err(Error::MissingRequiredOption(_))
+        ));
+        assert!(matches!(
+            Config::from_lookup(env_lookup(&[
+                ("SAMPLE_PERCENTAGE", "10"),
+                ("SAMPLE_HASH_COLUMN", "id"),
+            ])),
+            Err(Error::HashRequiresCsvMode)
+        ));
+        let result = Config::from_lookup(env_lookup(&[("SAMPLE_PERCENTAGE", "150")]));
+        assert!(
+            matches!(&result, Err(error @ Error::InvalidArguments(_))
+                if error.to_string().starts_with("invalid value '150' for '--percentage")),
+            "{:?}",
+            result
+        );
+        let result = Config::from_lookup(env_lookup(&[
+            ("SAMPLE_SIZE", "10"),
+            ("SAMPLE_CSV", "maybe"),
+        ]));
+        assert!(
+            matches!(&result, Err(error @ Error::InvalidEnvVar(name, value))
+                if name == "SAMPLE_CSV" && value == "maybe"
+                    && error.to_string() == "invalid value 'maybe' for environment variable SAMPLE_CSV"),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_from_env_ignores_empty_variables() {
+        let config = Config::from_lookup(env_lookup(&[
+            ("SAMPLE_SIZE", "3"),
+            ("SAMPLE_PERCENTAGE", ""),
+            ("SAMPLE_HASH_COLUMN", "  "),
+            ("SAMPLE_CSV", ""),
+        ]))
+        .unwrap();
+        assert_eq!(config.sample_size, Some(3));
+        assert_eq!(config.percentage, None);
+        assert_eq!(config.hash_column, None);
+        assert!(!config.csv_mode);
+    }
+
+    #[test]
+    fn test_parse_args_with_log_invocation() {
+        let args = ["sample", "-p", "10", "--log-invocation", "runs.log"];
+        let config = parse_args_for_tests(args).unwrap();
+        assert_eq!(config.log_invocation, Some(PathBuf::from("runs.log")));
+        assert_eq!(config.invocation, args);
+        assert!(
+            parse_args_for_tests(["sample", "--probe", "--log-invocation", "runs.log"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_args_with_stats_out() {
+        let config = parse_args_for_tests(["sample", "-p", "10", "--stats-out", "stats.json"]);
+        assert_eq!(config.unwrap().stats_out, Some(PathBuf::from("stats.json")));
+        assert!(parse_args_for_tests([
+            "sample",
+            "-p",
+            "10",
+            "--stats-out",
+            "s.json",
+            "--checkpoint",
+            "c"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_stratify_keys() {
+        let args = [
+            "sample",
+            "-p",
+            "10",
+            "--csv",
+            "--hash",
+            "id",
+            "--stratify-keys",
+        ];
+        assert!(parse_args_for_tests(args).unwrap().stratify_keys);
+
+        assert!(parse_args_for_tests(["sample", "-p", "10", "--csv", "--stratify-keys"]).is_err());
+        let args = [
+            "sample",
+            "--csv",
+            "--hash",
+            "id",
+            "--count-keys",
+            "--stratify-keys",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+    }
+
+    #[test]
+    fn test_hash_incompatible_modes() {
+        for (mode, option) in [
+            (vec!["10"], "a sample size"),
+            (vec!["--first", "10"], "--first"),
+            (vec!["--shuffle-all"], "--shuffle-all"),
+            (vec!["--one"], "--one"),
+        ] {
+            let mut args = vec!["sample", "--csv", "--hash", "id"];
+            args.extend(mode);
+            match parse_args_for_tests(&args) {
+                Err(Error::HashIncompatibleWith(name)) => assert_eq!(name, option),
+                other => panic!("{:?} gave {:?}", args, other),
+            }
+        }
+
+        // A sample size and a percentage conflict before --hash is considered
+        let result = parse_args_for_tests(["sample", "10", "-p", "5", "--csv", "--hash", "id"]);
+        assert!(matches!(result, Err(Error::MissingRequiredOption(_))));
+
+        // The supported combinations
+        assert!(parse_args_for_tests(["sample", "-p", "5", "--csv", "--hash", "id"]).is_ok());
+        let args = [
+            "sample",
+            "10",
+            "--csv",
+            "--hash",
+            "id",
+            "--pin-keys",
+            "keys",
+        ];
+        assert!(parse_args_for_tests(args).is_ok());
+    }
+
+    #[test]
+    fn test_parse_args_with_pretty() {
+        let config = parse_args_for_tests(["sample", "-p", "10", "--csv", "--pretty"]).unwrap();
+        assert!(config.pretty);
+        assert_eq!(config.pretty_width, 40);
+
+        let args = [
+            "sample",
+            "-p",
+            "10",
+            "--csv",
+            "--pretty",
+            "--pretty-width",
+            "12",
+        ];
+        assert_eq!(parse_args_for_tests(args).unwrap().pretty_width, 12);
+
+        assert!(parse_args_for_tests(["sample", "-p", "10", "--pretty"]).is_err());
+        let args = [
+            "sample",
+            "-p",
+            "10",
+            "--csv",
+            "--pretty",
+            "--pretty-width",
+            "0",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+        assert!(
+            parse_args_for_tests(["sample", "-p", "10", "--csv", "--pretty-width", "5"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_with_sample_directive() {
+        let config = parse_args_for_tests(["sample", "--size-from-header"]).unwrap();
+        assert!(config.size_from_header);
+
+        let sized = config.with_sample_directive("#sample:25\n").unwrap();
+        assert_eq!(sized.sample_size, Some(25));
+        assert!(!sized.size_from_header);
+        let percent = config.with_sample_directive(" #sample: 12.5% ").unwrap();
+        assert_eq!(percent.percentage, Some(12.5));
+
+        assert!(matches!(
+            config.with_sample_directive("#sample:0"),
+            Err(Error::InvalidSampleSize)
+        ));
+        assert!(matches!(
+            config.with_sample_directive("#sample:150%"),
+            Err(Error::InvalidPercentage)
+        ));
+        for line in ["id,value\n", "#sample:ten", "#sample:x%"] {
+            assert!(matches!(
+                config.with_sample_directive(line),
+                Err(Error::InvalidDirective(directive)) if directive == line.trim()
+            ));
+        }
+
+        // The directive is checked against the other options like a command line value
+        let config =
+            parse_args_for_tests(["sample", "--size-from-header", "--csv", "--hash", "id"])
+                .unwrap();
+        assert!(config.with_sample_directive("#sample:10%").is_ok());
+        assert!(matches!(
+            config.with_sample_directive("#sample:10"),
+            Err(Error::HashIncompatibleWith(_))
+        ));
+        assert!(parse_args_for_tests(["sample", "5", "--size-from-header"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_record_separator() {
+        let args = ["sample", "3", "--record-separator", "\\n\\n"];
+        let config = parse_args_for_tests(args).unwrap();
+        assert_eq!(config.record_separator.as_deref(), Some("\n\n"));
+
+        let args = ["sample", "3", "--record-separator", "%%"];
+        let config = parse_args_for_tests(args).unwrap();
+        assert_eq!(config.record_separator.as_deref(), Some("%%"));
+
+        assert!(parse_args_for_tests(["sample", "3", "--record-separator", ""]).is_err());
+        let args = ["sample", "3", "--csv", "--record-separator", "\\n\\n"];
+        assert!(parse_args_for_tests(args).is_err());
+        let args = [
+            "sample",
+            "-p",
+            "50",
+            "--checkpoint",
+            "ck",
+            "--record-separator",
+            "%%",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_emit_hash() {
+        let args = ["sample", "-p", "10", "--csv", "--hash", "id", "--emit-hash"];
+        assert!(parse_args_for_tests(args).unwrap().emit_hash);
+        assert!(parse_args_for_tests(["sample", "-p", "10", "--csv", "--emit-hash"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_spill_dir() {
+        let config = parse_args_for_tests(["sample", "10", "--spill-dir", "/tmp"]).unwrap();
+        assert_eq!(config.spill_dir, Some(PathBuf::from("/tmp")));
+        assert_eq!(config.limit_memory, 64 << 20);
+
+        let args = [
+            "sample",
+            "10",
+            "--spill-dir",
+            "/tmp",
+            "--limit-memory",
+            "1024",
+        ];
+        assert_eq!(parse_args_for_tests(args).unwrap().limit_memory, 1024);
+
+        let result = parse_args_for_tests(["sample", "-p", "10", "--spill-dir", "/tmp"]);
+        assert!(matches!(result, Err(Error::MissingRequiredOption(_))));
+        assert!(parse_args_for_tests(["sample", "10", "--limit-memory", "1024"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_percentile_range() {
+        let args = [
+            "sample",
+            "--csv",
+            "--percentile-column",
+            "v",
+            "--percentile-range",
+            "95:100",
+        ];
+        let config = parse_args_for_tests(args).unwrap();
+        assert_eq!(config.percentile_column.as_deref(), Some("v"));
+        assert_eq!(config.percentile_range, Some((95.0, 100.0)));
+
+        for range in ["95", "50:10", "-1:5", "0:101", "a:b"] {
+            let args = [
+                "sample",
+                "--csv",
+                "--percentile-column",
+                "v",
+                "--percentile-range",
+                range,
+            ];
+            assert!(parse_args_for_tests(args).is_err(), "{}", range);
+        }
+        let args = [
+            "sample",
+            "--percentile-column",
+            "v",
+            "--percentile-range",
+            "0:5",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+        assert!(parse_args_for_tests(["sample", "--csv", "--percentile-column", "v"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_numeric_key() {
+        let args = [
+            "sample",
+            "-p",
+            "10",
+            "--csv",
+            "--hash",
+            "id",
+            "--numeric-key",
+        ];
+        let config = parse_args_for_tests(args).unwrap();
+        assert!(config.numeric_key);
+        assert!(!config.numeric_key_fallback);
+
+        assert!(parse_args_for_tests(["sample", "-p", "10", "--numeric-key"]).is_err());
+        let args = [
+            "sample",
+            "-p",
+            "10",
+            "--csv",
+            "--hash",
+            "id",
+            "--numeric-key-fallback",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_header_mode() {
+        let config = parse_args_for_tests(["sample", "10", "--header", "yes"]).unwrap();
+        assert!(config.csv_mode);
+        let config = parse_args_for_tests(["sample", "10", "--header", "no"]).unwrap();
+        assert!(!config.csv_mode);
+        let config = parse_args_for_tests(["sample", "10", "--header", "auto"]).unwrap();
+        assert_eq!(config.header, Some(HeaderMode::Auto));
+        assert!(!config.csv_mode);
+        assert!(config.with_detected_header(true).unwrap().csv_mode);
+        assert!(!config.with_detected_header(false).unwrap().csv_mode);
+
+        // With --csv, auto satisfies the options that require --csv but needs a header
+        let args = [
+            "sample", "-p", "5", "--csv", "--header", "auto", "--hash", "id",
+        ];
+        let config = parse_args_for_tests(args).unwrap();
+        assert!(config.with_detected_header(true).unwrap().csv_mode);
+        assert!(matches!(
+            config.with_detected_header(false),
+            Err(Error::UnexpectedColumns(_))
+        ));
+
+        assert!(parse_args_for_tests(["sample", "10", "--header", "maybe"]).is_err());
+        assert!(parse_args_for_tests(["sample", "10", "--csv", "--header", "no"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_sample_columns() {
+        let config = parse_args_for_tests(["sample", "--csv", "--sample-columns", "3"]).unwrap();
+        assert_eq!(config.sample_columns, Some(3));
+
+        assert!(parse_args_for_tests(["sample", "--sample-columns", "3"]).is_err());
+        assert!(parse_args_for_tests(["sample", "--csv", "--sample-columns", "0"]).is_err());
+        let args = ["sample", "-p", "10", "--csv", "--sample-columns", "3"];
+        assert!(parse_args_for_tests(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_dry_validate() {
+        let args = [
+            "sample",
+            "-p",
+            "10",
+            "--csv",
+            "--hash",
+            "id",
+            "--dry-validate",
+        ];
+        assert!(parse_args_for_tests(args).unwrap().dry_validate);
+        // The options are still validated in full
+        let result = parse_args_for_tests(["sample", "--dry-validate"]);
+        assert!(matches!(result, Err(Error::MissingRequiredOption(_))));
+    }
+
+    #[test]
+    fn test_parse_args_with_target_count() {
+        let config = parse_args_for_tests(["sample", "-p", "10", "--target-count", "500"]).unwrap();
+        assert_eq!(config.target_count, Some(500));
+        assert!(parse_args_for_tests(["sample", "10", "--target-count", "500"]).is_err());
+        assert!(parse_args_for_tests([
+            "sample",
+            "-p",
+            "10",
+            "--target-count",
+            "5",
+            "--antithetic"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_stats_column() {
+        let config = parse_args_for_tests(["sample", "5", "--csv", "--stats-column", "price"]);
+        assert_eq!(config.unwrap().stats_column, Some("price".to_string()));
+        assert!(parse_args_for_tests(["sample", "5", "--stats-column", "price"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_window_count() {
+        let config = parse_args_for_tests(["sample", "5", "--window-count", "100"]).unwrap();
+        assert_eq!(config.window_count, Some(100));
+        assert!(parse_args_for_tests(["sample", "--window-count", "100"]).is_err());
+        assert!(parse_args_for_tests(["sample", "5", "--window-count", "0"]).is_err());
+        assert!(parse_args_for_tests(["sample", "-p", "5", "--window-count", "100"]).is_err());
+        let args = [
+            "sample",
+            "1",
+            "--csv",
+            "--weight-column",
+            "w",
+            "--window-count",
+            "10",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_decision_out() {
+        let config = parse_args_for_tests(["sample", "5", "--decision-out", "flags.txt"]);
+        assert_eq!(
+            config.unwrap().decision_out,
+            Some(PathBuf::from("flags.txt"))
+        );
+        assert!(parse_args_for_tests([
+            "sample",
+            "-p",
+            "5",
+            "--csv",
+            "--hash",
+            "id",
+            "--decision-out",
+            "flags.txt"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_require_trailing_newline() {
+        let config = parse_args_for_tests(["sample", "5"]).unwrap();
+        assert!(!config.require_trailing_newline);
+        let config = parse_args_for_tests(["sample", "5", "--require-trailing-newline"]).unwrap();
+        assert!(config.require_trailing_newline);
+    }
+
+    #[test]
+    fn test_parse_args_with_reservoir_order() {
+        let config = parse_args_for_tests(["sample", "10"]).unwrap();
+        assert_eq!(config.reservoir_order, ReservoirOrder::Slot);
+        let config = parse_args_for_tests(["sample", "10", "--reservoir-order", "input"]).unwrap();
+        assert_eq!(config.reservoir_order, ReservoirOrder::Input);
+        assert!(parse_args_for_tests(["sample", "10", "--reservoir-order", "sorted"]).is_err());
+
+        let args = [
+            "sample",
+            "10",
+            "--reservoir-order",
+            "shuffled",
+            "--spill-dir",
+            "/tmp",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_with_hash_regex() {
+        let args = [
+            "sample",
+            "-p",
+            "10",
+            "--csv",
+            "--hash-regex",
+            "^user_id_v\\d+$",
+        ];
+        let config = parse_args_for_tests(args).unwrap();
+        assert!(config.has_hash_key());
+        assert_eq!(config.hash_column, None);
+        assert_eq!(config.hash_regex.unwrap().as_str(), "^user_id_v\\d+$");
+
+        // Options that need a hash column accept the regex too
+        let args = [
+            "sample",
+            "-p",
+            "10",
+            "--csv",
+            "--hash-regex",
+            "id",
+            "--cover-keys",
+        ];
+        assert!(parse_args_for_tests(args).is_ok());
+
+        let args = [
+            "sample",
+            "-p",
+            "10",
+            "--csv",
+            "--hash",
+            "id",
+            "--hash-regex",
+            "id",
+        ];
+        assert!(parse_args_for_tests(args).is_err());
+        assert!(
+            parse_args_for_tests(["sample", "-p", "10", "--csv", "--hash-regex", "("]).is_err()
+        );
+        let result = parse_args_for_tests(["sample", "-p", "10", "--hash-regex", "id"]);
+        assert!(matches!(result, Err(Error::HashRequiresCsvMode)));
+    }
+
+    #[test]
+    fn test_exclude_keys_requires_hash() {
+        let result = parse_args_for_tests(["sample", "-p", "10", "--csv", "--exclude-keys", "k"]);
+        assert!(matches!(result, Err(Error::ExcludeKeysRequiresHash)));
+    }
 }
@@ -1,4 +1,7 @@
-use std::io;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
 
 #[derive(Debug)]
 pub enum Error {
@@ -7,15 +10,285 @@ pub enum Error {
     InvalidPercentage,
     HashRequiresCsvMode,
     HashRequiresPercentage,
+    /// `--hash` combined with a mode it cannot honor, named by the offending option
+    HashIncompatibleWith(String),
+    ExcludeKeysRequiresHash,
+    InvalidOutputTemplate,
+    AssignRequiresCsvMode,
+    WeightedRequiresCsvMode,
+    WindowRequiresCsvMode,
     ColumnNotFound(String),
+    AmbiguousColumn(String),
+    UnexpectedColumns(String),
+    MostlyEmptyColumn(String),
+    /// A --numeric-key value that is not a number: the column, the value and its line
+    NonNumericKey(String, String, u64),
+    InvalidWeight(String),
+    /// A --percentile-column value that is not a number
+    InvalidNumber(String),
+    InvalidTimestamp(String),
+    /// With --require-trailing-newline, the input ended without a newline on this line
+    MissingTrailingNewline(u64),
+    InvalidConfigFile(String),
+    /// An environment variable read by `Config::from_env`, by name, and its invalid value
+    InvalidEnvVar(String, String),
+    /// A --size-from-header first line that is not a `#sample:N` or `#sample:P%` directive
+    InvalidDirective(String),
+    /// Arguments the command line parser rejected
+    InvalidArguments(clap::Error),
     MissingRequiredOption(String),
     IoError(io::Error),
+    /// Writing to the named output failed, see `OutputWriter`
+    OutputError(String, io::Error),
+    /// Malformed CSV input, with the position of the offending record
+    CsvError(csv::Error),
+}
+
+impl Error {
+    /// Process exit code reported for this error.
+    ///
+    /// - `2`: invalid or inconsistent options
+    /// - `3`: the CSV header is missing a requested column, matches it ambiguously or does not
+    ///   match the expected schema, a checked column is mostly empty, or a --numeric-key
+    ///   value is not a number
+    /// - `4`: reading input or writing output failed, or the input data is invalid
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::ColumnNotFound(_)
+            | Error::AmbiguousColumn(_)
+            | Error::UnexpectedColumns(_)
+            | Error::MostlyEmptyColumn(_)
+            | Error::NonNumericKey(..) => 3,
+            Error::IoError(_)
+            | Error::OutputError(..)
+            | Error::CsvError(_)
+            | Error::InvalidWeight(_)
+            | Error::InvalidNumber(_)
+            | Error::InvalidTimestamp(_)
+            | Error::MissingTrailingNewline(_) => 4,
+            Error::InvalidSampleSize
+            | Error::InvalidSeedValue
+            | Error::InvalidPercentage
+            | Error::HashRequiresCsvMode
+            | Error::HashRequiresPercentage
+            | Error::HashIncompatibleWith(_)
+            | Error::ExcludeKeysRequiresHash
+            | Error::InvalidOutputTemplate
+            | Error::AssignRequiresCsvMode
+            | Error::WeightedRequiresCsvMode
+            | Error::WindowRequiresCsvMode
+            | Error::InvalidConfigFile(_)
+            | Error::InvalidEnvVar(..)
+            | Error::InvalidDirective(_)
+            | Error::InvalidArguments(_)
+            | Error::MissingRequiredOption(_) => 2,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidSampleSize => write!(f, "sample size must be a positive integer"),
+            Error::InvalidSeedValue => write!(f, "seed must be a valid number"),
+            Error::InvalidPercentage => write!(f, "percentage must be between 0 and 100"),
+            Error::HashRequiresCsvMode => write!(f, "hash-based sampling requires --csv mode"),
+            Error::HashRequiresPercentage => {
+                write!(f, "hash-based sampling only works with --percentage option")
+            }
+            Error::HashIncompatibleWith(option) => write!(
+                f,
+                "--hash cannot be combined with {}; it samples with --percentage, \
+                 or with a sample size and --pin-keys",
+                option
+            ),
+            Error::ExcludeKeysRequiresHash => write!(f, "--exclude-keys requires --hash"),
+            Error::InvalidOutputTemplate => write!(f, "--output-template must contain '{{i}}'"),
+            Error::AssignRequiresCsvMode => write!(f, "--assign requires --csv mode"),
+            Error::WeightedRequiresCsvMode => write!(f, "--expected-size requires --csv mode"),
+            Error::WindowRequiresCsvMode => write!(f, "--window requires --csv mode"),
+            Error::ColumnNotFound(column) => {
+                write!(f, "column '{}' not found in CSV header", column)
+            }
+            Error::AmbiguousColumn(ambiguity) => write!(f, "ambiguous column: {}", ambiguity),
+            Error::UnexpectedColumns(mismatch) => {
+                write!(f, "unexpected CSV header: {}", mismatch)
+            }
+            Error::MostlyEmptyColumn(column) => {
+                write!(f, "column '{}' is empty in most checked rows", column)
+            }
+            Error::NonNumericKey(column, value, line) => write!(
+                f,
+                "--numeric-key value '{}' in column '{}' on line {} is not a number",
+                value, column, line
+            ),
+            Error::InvalidWeight(value) => write!(
+                f,
+                "invalid weight '{}' (must be a non-negative number)",
+                value
+            ),
+            Error::InvalidNumber(value) => {
+                write!(f, "invalid number '{}' in --percentile-column", value)
+            }
+            Error::InvalidTimestamp(value) => write!(
+                f,
+                "invalid timestamp '{}' (expected RFC 3339 or epoch seconds)",
+                value
+            ),
+            Error::MissingTrailingNewline(line) => write!(
+                f,
+                "input ends without a newline on line {} (truncated file?)",
+                line
+            ),
+            Error::InvalidConfigFile(msg) => write!(f, "invalid config file {}", msg),
+            Error::InvalidEnvVar(name, value) => {
+                write!(
+                    f,
+                    "invalid value '{}' for environment variable {}",
+                    value, name
+                )
+            }
+            Error::InvalidDirective(line) => write!(
+                f,
+                "--size-from-header expects a '#sample:N' or '#sample:P%' first line, got '{}'",
+                line
+            ),
+            Error::InvalidArguments(err) => {
+                // Only the first line of clap's message, without its "error: " prefix
+                let message = err.to_string();
+                let first_line = message.lines().next().unwrap_or_default();
+                write!(f, "{}", first_line.trim_start_matches("error: "))
+            }
+            Error::MissingRequiredOption(msg) => write!(f, "{}", msg),
+            Error::IoError(e) => write!(f, "reading input failed: {}", e),
+            Error::OutputError(target, e) => write!(f, "writing {} failed: {}", target, e),
+            Error::CsvError(e) => write!(f, "invalid CSV input: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(e) | Error::OutputError(_, e) => Some(e),
+            Error::CsvError(e) => Some(e),
+            Error::InvalidArguments(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
+        // An error tagged by an OutputWriter names the output it was writing
+        if err
+            .get_ref()
+            .is_some_and(|inner| inner.is::<OutputFailure>())
+        {
+            if let Ok(failure) = err.into_inner().unwrap().downcast::<OutputFailure>() {
+                return Error::OutputError(failure.target, failure.source);
+            }
+            unreachable!("the inner error was checked to be an OutputFailure");
+        }
+        // A CSV error passed up through an io::Error keeps its position
+        if err.get_ref().is_some_and(|inner| inner.is::<csv::Error>()) {
+            if let Ok(csv_error) = err.into_inner().unwrap().downcast::<csv::Error>() {
+                return Error::from(*csv_error);
+            }
+            unreachable!("the inner error was checked to be a csv::Error");
+        }
         Error::IoError(err)
     }
 }
 
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Self {
+        if !err.is_io_error() {
+            return Error::CsvError(err);
+        }
+        match err.into_kind() {
+            csv::ErrorKind::Io(err) => Error::from(err),
+            _ => unreachable!("is_io_error guarantees an I/O error kind"),
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// An error raised while writing to a named output, carried inside an `io::Error` so
+/// that it converts to `Error::OutputError` rather than an input error
+#[derive(Debug)]
+struct OutputFailure {
+    target: String,
+    source: io::Error,
+}
+
+impl fmt::Display for OutputFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "writing {} failed: {}", self.target, self.source)
+    }
+}
+
+impl std::error::Error for OutputFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Tags an error raised while writing to `target`, so that it converts to
+/// `Error::OutputError`. An error that is already tagged keeps its target.
+pub fn output_error(target: impl fmt::Display, err: io::Error) -> io::Error {
+    if err
+        .get_ref()
+        .is_some_and(|inner| inner.is::<OutputFailure>())
+    {
+        return err;
+    }
+    let kind = err.kind();
+    io::Error::new(
+        kind,
+        OutputFailure {
+            target: target.to_string(),
+            source: err,
+        },
+    )
+}
+
+/// A writer adapter that tags every error of the underlying writer with the name of
+/// the output, see `output_error`
+#[derive(Debug)]
+pub struct OutputWriter<W> {
+    inner: W,
+    target: String,
+}
+
+impl<W: Write> OutputWriter<W> {
+    pub fn new(inner: W, target: impl fmt::Display) -> Self {
+        OutputWriter {
+            inner,
+            target: target.to_string(),
+        }
+    }
+}
+
+impl OutputWriter<File> {
+    /// Creates the file at `path` to write output to, tagging a failure to create it too
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = File::create(path).map_err(|e| output_error(path.display(), e))?;
+        Ok(OutputWriter::new(file, path.display()))
+    }
+}
+
+impl<W: Write> Write for OutputWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner
+            .write(buf)
+            .map_err(|e| output_error(&self.target, e))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner
+            .flush()
+            .map_err(|e| output_error(&self.target, e))
+    }
+}
@@ -0,0 +1,1551 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use encoding_rs::{Decoder, Encoding};
+use memmap2::Mmap;
+use regex::Regex;
+#[cfg(unix)]
+use std::os::fd::AsFd;
+#[cfg(windows)]
+use std::os::windows::io::AsHandle;
+
+/// A reader adapter that returns EOF once a byte budget is exhausted.
+///
+/// The input is consumed line by line, so a line that would cross the limit
+/// is dropped entirely instead of being emitted partially. No more than one byte
+/// past the budget is read, however long that line is.
+#[derive(Debug)]
+pub struct ByteLimitReader<R> {
+    reader: R,
+    remaining: u64,
+    line: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: BufRead> ByteLimitReader<R> {
+    pub fn new(reader: R, limit: u64) -> Self {
+        ByteLimitReader {
+            reader,
+            remaining: limit,
+            line: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Loads the next complete line into the internal buffer if it fits within the budget
+    fn fill_line(&mut self) -> io::Result<()> {
+        self.line.clear();
+        self.pos = 0;
+
+        // One byte past the budget is enough to tell that the line does not fit
+        let read = (&mut self.reader)
+            .take(self.remaining.saturating_add(1))
+            .read_until(b'\n', &mut self.line)? as u64;
+        if read == 0 || read > self.remaining {
+            self.line.clear();
+            self.done = true;
+            return Ok(());
+        }
+
+        self.remaining -= read;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ByteLimitReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.line.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.fill_line()?;
+        }
+
+        Ok(copy_buffered(&self.line, &mut self.pos, buf))
+    }
+}
+
+/// A reader adapter that fails with `InvalidData` as soon as a CSV record runs past a
+/// byte limit, so an oversized record is never read whole. The records before it are
+/// passed on first.
+///
+/// Records end at line feeds outside quoted fields, and the line feed is not counted;
+/// fields are taken to be comma-delimited. Without a limit the input passes unchanged.
+///
+/// The first record, a CSV header, is passed on by a read of its own, so a limit set
+/// once a CSV reader has read the header applies to every record after it.
+#[derive(Debug)]
+pub struct RecordByteLimitReader<R> {
+    reader: BufReader<R>,
+    limit: Option<usize>,
+    record_bytes: usize,
+    record_line: u64,
+    line: u64,
+    quoting: Quoting,
+    header_read: bool,
+    /// The limit the record at `record_line` exceeded
+    exceeded: Option<usize>,
+}
+
+/// Where `RecordByteLimitReader` is within a record, to tell line feeds inside quoted
+/// fields from record ends
+#[derive(Debug, Clone, Copy)]
+enum Quoting {
+    FieldStart,
+    Unquoted,
+    Quoted,
+    QuoteInQuoted,
+}
+
+impl<R: Read> RecordByteLimitReader<R> {
+    pub fn new(reader: R, limit: Option<usize>) -> Self {
+        RecordByteLimitReader {
+            reader: BufReader::new(reader),
+            limit,
+            record_bytes: 0,
+            record_line: 1,
+            line: 1,
+            quoting: Quoting::FieldStart,
+            header_read: false,
+            exceeded: None,
+        }
+    }
+
+    /// Returns the byte limit per record, if any
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Limits the records read from now on to `limit` bytes, or lifts the limit
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    /// Follows `bytes` and returns how many of them to pass on: all of them, or those up
+    /// to the end of the header or up to the byte that takes a record past the limit
+    fn scan(&mut self, bytes: &[u8]) -> usize {
+        for (index, &byte) in bytes.iter().enumerate() {
+            if byte == b'\n' {
+                self.line += 1;
+            }
+            if self.ends_record(byte) {
+                self.record_bytes = 0;
+                self.record_line = self.line;
+                if !self.header_read {
+                    self.header_read = true;
+                    return index + 1;
+                }
+                continue;
+            }
+            self.record_bytes += 1;
+            if let Some(limit) = self.limit.filter(|&limit| self.record_bytes > limit) {
+                self.exceeded = Some(limit);
+                return index;
+            }
+        }
+        bytes.len()
+    }
+
+    /// Follows the quoting of one byte and returns true if it ends a record
+    fn ends_record(&mut self, byte: u8) -> bool {
+        let (quoting, ends) = match (self.quoting, byte) {
+            (Quoting::Quoted, b'"') => (Quoting::QuoteInQuoted, false),
+            (Quoting::Quoted, _) => (Quoting::Quoted, false),
+            (Quoting::QuoteInQuoted | Quoting::FieldStart, b'"') => (Quoting::Quoted, false),
+            (_, b',') => (Quoting::FieldStart, false),
+            (_, b'\n') => (Quoting::FieldStart, true),
+            _ => (Quoting::Unquoted, false),
+        };
+        self.quoting = quoting;
+        ends
+    }
+}
+
+impl<R: Read> Read for RecordByteLimitReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.exceeded.is_none() {
+            // Only the bytes passed on are consumed, so none are lost when stopping early
+            let available = self.reader.fill_buf()?;
+            let len = available.len().min(buf.len());
+            buf[..len].copy_from_slice(&available[..len]);
+            let passed = self.scan(&buf[..len]);
+            self.reader.consume(passed);
+            if self.exceeded.is_none() || passed > 0 {
+                return Ok(passed);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "record on line {} exceeds the limit of {} bytes",
+                self.record_line,
+                self.exceeded.unwrap_or_default()
+            ),
+        ))
+    }
+}
+
+/// A reader adapter that only passes through lines accepted by a predicate.
+///
+/// The predicate receives each line without its line terminator. When `pass_header`
+/// is set, the first line is always passed through unfiltered.
+pub struct LineFilterReader<R, F> {
+    reader: R,
+    predicate: F,
+    pass_header: bool,
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead, F: FnMut(&[u8]) -> bool> LineFilterReader<R, F> {
+    pub fn new(reader: R, pass_header: bool, predicate: F) -> Self {
+        LineFilterReader {
+            reader,
+            predicate,
+            pass_header,
+            line: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Loads the next accepted line into the internal buffer, leaving it empty at EOF
+    fn fill_line(&mut self) -> io::Result<()> {
+        self.pos = 0;
+        loop {
+            self.line.clear();
+            if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(());
+            }
+            if self.pass_header {
+                self.pass_header = false;
+                return Ok(());
+            }
+            if (self.predicate)(trim_line_terminator(&self.line)) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<R: BufRead, F: FnMut(&[u8]) -> bool> Read for LineFilterReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.line.len() {
+            self.fill_line()?;
+        }
+        Ok(copy_buffered(&self.line, &mut self.pos, buf))
+    }
+}
+
+/// A reader adapter that passes the input through unchanged while parsing it as CSV,
+/// handing each data record to a callback by the time its bytes are passed on.
+///
+/// A quoted field spanning lines is one field of one record. Fields that are not valid
+/// UTF-8 are read lossily.
+pub struct RecordTapReader<R, F> {
+    records: csv::Reader<RecordingReader<R>>,
+    callback: F,
+    record: csv::ByteRecord,
+    passed: u64,
+    chunk: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+/// Keeps every byte read through it until `RecordTapReader` passes them on
+struct RecordingReader<R> {
+    reader: R,
+    bytes: Vec<u8>,
+}
+
+impl<R: Read> Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<R: Read, F: FnMut(&csv::StringRecord)> RecordTapReader<R, F> {
+    pub fn new(reader: R, callback: F) -> Self {
+        RecordTapReader {
+            records: csv_reader(RecordingReader {
+                reader,
+                bytes: Vec::new(),
+            }),
+            callback,
+            record: csv::ByteRecord::new(),
+            passed: 0,
+            chunk: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Parses the next record and loads the bytes up to its end into the internal
+    /// buffer; at EOF the bytes after the last record are loaded instead
+    fn fill_chunk(&mut self) -> io::Result<()> {
+        let end = if self.records.read_byte_record(&mut self.record)? {
+            // The conversion takes the record's buffer, which is handed back for the
+            // next read, so a valid UTF-8 record is neither copied nor reallocated
+            let record = csv::StringRecord::from_byte_record_lossy(mem::take(&mut self.record));
+            (self.callback)(&record);
+            self.record = record.into_byte_record();
+            self.records.position().byte()
+        } else {
+            self.done = true;
+            self.passed + self.records.get_ref().bytes.len() as u64
+        };
+        let recorded = &mut self.records.get_mut().bytes;
+        self.chunk.clear();
+        self.chunk
+            .extend(recorded.drain(..(end - self.passed) as usize));
+        self.passed = end;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read, F: FnMut(&csv::StringRecord)> Read for RecordTapReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.fill_chunk()?;
+        }
+        Ok(copy_buffered(&self.chunk, &mut self.pos, buf))
+    }
+}
+
+/// A reader adapter that splits the input on a separator and yields each record as
+/// a single escaped line, so the line-based modes sample multi-line records as units.
+///
+/// Backslashes, line feeds and carriage returns inside a record are written as `\\`,
+/// `\n` and `\r`; `SeparatedRecordWriter` reverses this. A line feed before the end
+/// of the input is dropped, and empty records are skipped.
+pub struct SeparatedRecordReader<R> {
+    reader: R,
+    separator: Vec<u8>,
+    line: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: BufRead> SeparatedRecordReader<R> {
+    pub fn new(reader: R, separator: &[u8]) -> Self {
+        assert!(!separator.is_empty(), "Record separator must not be empty");
+        SeparatedRecordReader {
+            reader,
+            separator: separator.to_vec(),
+            line: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Loads the next non-empty record, escaped and terminated by a line feed, into
+    /// the internal buffer, leaving it empty at EOF
+    fn fill_line(&mut self) -> io::Result<()> {
+        self.pos = 0;
+        self.line.clear();
+        let last = self.separator[self.separator.len() - 1];
+        let mut record = Vec::new();
+        while !self.done {
+            record.clear();
+            loop {
+                if self.reader.read_until(last, &mut record)? == 0 {
+                    self.done = true;
+                    if record.ends_with(b"\n") {
+                        record.pop();
+                    }
+                    break;
+                }
+                if record.ends_with(&self.separator) {
+                    record.truncate(record.len() - self.separator.len());
+                    break;
+                }
+            }
+            if record.is_empty() {
+                continue;
+            }
+
+            for &byte in &record {
+                match byte {
+                    b'\\' => self.line.extend_from_slice(b"\\\\"),
+                    b'\n' => self.line.extend_from_slice(b"\\n"),
+                    b'\r' => self.line.extend_from_slice(b"\\r"),
+                    byte => self.line.push(byte),
+                }
+            }
+            self.line.push(b'\n');
+            return Ok(());
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for SeparatedRecordReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.line.len() {
+            self.fill_line()?;
+        }
+        Ok(copy_buffered(&self.line, &mut self.pos, buf))
+    }
+}
+
+/// A writer adapter that turns the escaped lines of `SeparatedRecordReader` back into
+/// records, ending each with the separator
+pub struct SeparatedRecordWriter<W> {
+    writer: W,
+    separator: Vec<u8>,
+    /// Whether the last byte written was an unpaired escaping backslash
+    escaped: bool,
+}
+
+impl<W: Write> SeparatedRecordWriter<W> {
+    pub fn new(writer: W, separator: &[u8]) -> Self {
+        SeparatedRecordWriter {
+            writer,
+            separator: separator.to_vec(),
+            escaped: false,
+        }
+    }
+}
+
+impl<W: Write> Write for SeparatedRecordWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut decoded = Vec::with_capacity(buf.len());
+        for &byte in buf {
+            if self.escaped {
+                self.escaped = false;
+                match byte {
+                    b'n' => decoded.push(b'\n'),
+                    b'r' => decoded.push(b'\r'),
+                    b'\\' => decoded.push(b'\\'),
+                    byte => decoded.extend_from_slice(&[b'\\', byte]),
+                }
+            } else if byte == b'\\' {
+                self.escaped = true;
+            } else if byte == b'\n' {
+                decoded.extend_from_slice(&self.separator);
+            } else {
+                decoded.push(byte);
+            }
+        }
+        self.writer.write_all(&decoded)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Removes a trailing `\n` or `\r\n` from a line
+fn trim_line_terminator(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Copies as much of `src[*pos..]` into `buf` as fits and advances `pos`
+fn copy_buffered(src: &[u8], pos: &mut usize, buf: &mut [u8]) -> usize {
+    let available = &src[*pos..];
+    let n = available.len().min(buf.len());
+    buf[..n].copy_from_slice(&available[..n]);
+    *pos += n;
+    n
+}
+
+/// A reader adapter that k-way merges CSV inputs that are each sorted by one column
+/// into a single CSV stream: the given header, then every data row of every input in
+/// order of that column's value, compared as text like `--sort-column` does.
+///
+/// Rows with equal keys are taken from the inputs in order. Only the next row of each
+/// input is held in memory. An input whose keys go down fails with `InvalidData`.
+pub struct SortedMergeReader<R> {
+    inputs: Vec<csv::Reader<R>>,
+    /// Index of the key column in each input
+    columns: Vec<usize>,
+    /// The next row of each input, queued in the heap by its key and input index
+    pending: Vec<csv::StringRecord>,
+    last_keys: Vec<Option<String>>,
+    heap: BinaryHeap<Reverse<(String, usize)>>,
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> SortedMergeReader<R> {
+    /// Merges `inputs`, whose headers have already been read, by the column at
+    /// `columns[i]` of input `i`
+    pub fn new(
+        header: &csv::StringRecord,
+        inputs: Vec<csv::Reader<R>>,
+        columns: Vec<usize>,
+    ) -> io::Result<Self> {
+        assert_eq!(inputs.len(), columns.len(), "one key column per input");
+        let mut merge = SortedMergeReader {
+            pending: vec![csv::StringRecord::new(); inputs.len()],
+            last_keys: vec![None; inputs.len()],
+            inputs,
+            columns,
+            heap: BinaryHeap::new(),
+            line: Vec::new(),
+            pos: 0,
+        };
+        for index in 0..merge.inputs.len() {
+            merge.advance(index)?;
+        }
+        merge.load_line(header)?;
+        Ok(merge)
+    }
+
+    /// Reads the next row of input `index` and queues it, unless the input is exhausted
+    fn advance(&mut self, index: usize) -> io::Result<()> {
+        let record = &mut self.pending[index];
+        // Read errors must be io::Errors here; `Error::from` unwraps the CSV error again
+        let read = self.inputs[index]
+            .read_record(record)
+            .map_err(io::Error::from)?;
+        if !read {
+            return Ok(());
+        }
+
+        let key = record
+            .get(self.columns[index])
+            .unwrap_or_default()
+            .to_string();
+        if let Some(last) = self.last_keys[index].as_ref().filter(|last| **last > key) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "input {} is not sorted: '{}' on line {} follows '{}'",
+                    index + 1,
+                    key,
+                    record.position().map_or(0, |p| p.line()),
+                    last
+                ),
+            ));
+        }
+        self.last_keys[index] = Some(key.clone());
+        self.heap.push(Reverse((key, index)));
+        Ok(())
+    }
+
+    /// Serializes a record into the internal line buffer
+    fn load_line(&mut self, record: &csv::StringRecord) -> io::Result<()> {
+        self.line.clear();
+        let mut writer = csv::WriterBuilder::new()
+            .terminator(csv::Terminator::Any(b'\n'))
+            .from_writer(std::mem::take(&mut self.line));
+        writer.write_record(record)?;
+        self.line = writer.into_inner().map_err(|e| e.into_error())?;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for SortedMergeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.line.len() {
+            let Some(Reverse((_, index))) = self.heap.pop() else {
+                return Ok(0);
+            };
+            let record = std::mem::take(&mut self.pending[index]);
+            self.load_line(&record)?;
+            self.pending[index] = record;
+            self.advance(index)?;
+        }
+        Ok(copy_buffered(&self.line, &mut self.pos, buf))
+    }
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// A reader adapter that notes whether the input ends in the middle of a line.
+#[derive(Debug)]
+pub struct TrailingNewlineReader<R> {
+    reader: R,
+    newlines: u64,
+    last: Option<u8>,
+    at_end: bool,
+}
+
+impl<R: Read> TrailingNewlineReader<R> {
+    pub fn new(reader: R) -> Self {
+        TrailingNewlineReader {
+            reader,
+            newlines: 0,
+            last: None,
+            at_end: false,
+        }
+    }
+
+    /// Returns the 1-based number of the final line if the input has been read to its
+    /// end and that line has no line feed; `None` otherwise, including for empty input
+    pub fn unterminated_line(&self) -> Option<u64> {
+        let unterminated = self.at_end && self.last.is_some_and(|byte| byte != b'\n');
+        unterminated.then_some(self.newlines + 1)
+    }
+}
+
+impl<R: Read> Read for TrailingNewlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.newlines += buf[..n].iter().filter(|&&byte| byte == b'\n').count() as u64;
+        match buf[..n].last() {
+            Some(&byte) => self.last = Some(byte),
+            None => self.at_end = !buf.is_empty(),
+        }
+        Ok(n)
+    }
+}
+
+/// A reader adapter that removes a leading UTF-8 byte order mark, if present.
+#[derive(Debug)]
+pub struct BomStrippingReader<R> {
+    reader: R,
+    prefix: Vec<u8>,
+    pos: usize,
+    checked: bool,
+}
+
+impl<R: Read> BomStrippingReader<R> {
+    pub fn new(reader: R) -> Self {
+        BomStrippingReader {
+            reader,
+            prefix: Vec::with_capacity(UTF8_BOM.len()),
+            pos: 0,
+            checked: false,
+        }
+    }
+
+    /// Reads enough leading bytes to tell whether the input starts with a BOM
+    fn check_prefix(&mut self) -> io::Result<()> {
+        let mut buf = [0; 3];
+        while self.prefix.len() < UTF8_BOM.len() {
+            let n = self
+                .reader
+                .read(&mut buf[..UTF8_BOM.len() - self.prefix.len()])?;
+            if n == 0 {
+                break;
+            }
+            self.prefix.extend_from_slice(&buf[..n]);
+        }
+        if self.prefix == UTF8_BOM {
+            self.prefix.clear();
+        }
+        self.checked = true;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BomStrippingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.checked {
+            self.check_prefix()?;
+        }
+
+        if self.pos < self.prefix.len() {
+            return Ok(copy_buffered(&self.prefix, &mut self.pos, buf));
+        }
+
+        self.reader.read(buf)
+    }
+}
+
+/// A reader adapter that decodes its input from a given encoding into UTF-8.
+///
+/// A leading BOM of that encoding is removed; malformed sequences become U+FFFD.
+pub struct TranscodingReader<R> {
+    reader: R,
+    decoder: Decoder,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<R: Read> TranscodingReader<R> {
+    pub fn new(reader: R, encoding: &'static Encoding) -> Self {
+        TranscodingReader {
+            reader,
+            decoder: encoding.new_decoder_with_bom_removal(),
+            input: vec![0; 8192],
+            output: Vec::new(),
+            pos: 0,
+            finished: false,
+        }
+    }
+
+    /// Decodes the next chunk of input into the output buffer
+    fn fill_output(&mut self) -> io::Result<()> {
+        let n = self.reader.read(&mut self.input)?;
+        let last = n == 0;
+        let capacity = self
+            .decoder
+            .max_utf8_buffer_length(n)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::OutOfMemory, "decoded chunk too large"))?;
+
+        self.output.resize(capacity, 0);
+        let (_, read, written, _) =
+            self.decoder
+                .decode_to_utf8(&self.input[..n], &mut self.output, last);
+        debug_assert_eq!(read, n, "the output buffer fits the whole chunk");
+        self.output.truncate(written);
+        self.pos = 0;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+// Implement Debug manually since encoding_rs::Decoder doesn't implement Debug
+impl<R> fmt::Debug for TranscodingReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TranscodingReader")
+            .field("encoding", &self.decoder.encoding())
+            .field("pos", &self.pos)
+            .field("finished", &self.finished)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Read> Read for TranscodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // A chunk may decode to nothing, e.g. when it ends inside a multi-byte sequence
+        while self.pos == self.output.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.fill_output()?;
+        }
+        Ok(copy_buffered(&self.output, &mut self.pos, buf))
+    }
+}
+
+/// Delay before the first retry of a failed read; it doubles with each further retry
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// A reader adapter that retries a read failing with a transient error up to a fixed
+/// number of times, sleeping with exponential backoff in between.
+///
+/// Only `Interrupted`, `TimedOut` and `WouldBlock` errors are retried; any other error,
+/// such as `NotFound` or `InvalidData`, is returned at once. The count applies to each
+/// read separately. Once it is exhausted, the last error is returned.
+#[derive(Debug)]
+pub struct RetryingReader<R> {
+    reader: R,
+    retries: u32,
+}
+
+impl<R: Read> RetryingReader<R> {
+    pub fn new(reader: R, retries: u32) -> Self {
+        RetryingReader { reader, retries }
+    }
+}
+
+impl<R: Read> Read for RetryingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut backoff = RETRY_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            match self.reader.read(buf) {
+                Err(e) if is_transient(&e) && attempt < self.retries => {
+                    attempt += 1;
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether a failed read may succeed when retried
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Wraps the reader in a decompressor when its leading magic bytes identify a
+/// supported compression format; otherwise the input is passed through unchanged.
+///
+/// zstd and bzip2 are recognized when the crate is built with the `zstd` and
+/// `bzip2` features respectively.
+pub fn decompress<'a, R: Read + 'a>(mut reader: R) -> io::Result<Box<dyn Read + 'a>> {
+    let mut magic = Vec::with_capacity(4);
+    (&mut reader).take(4).read_to_end(&mut magic)?;
+    let reader = io::Cursor::new(magic.clone()).chain(reader);
+
+    #[cfg(feature = "zstd")]
+    if magic.starts_with(b"\x28\xB5\x2F\xFD") {
+        return Ok(Box::new(zstd::Decoder::new(reader)?));
+    }
+
+    #[cfg(feature = "bzip2")]
+    if magic.starts_with(b"BZh") {
+        return Ok(Box::new(bzip2::read::MultiBzDecoder::new(reader)));
+    }
+
+    Ok(Box::new(reader))
+}
+
+/// Reads lines into one reused buffer, so a line that is looked at and dropped costs
+/// no allocation.
+///
+/// Lines end at `\n` and a trailing `\r\n` is removed, as with `BufRead::lines`.
+pub struct ReusedLines<B> {
+    reader: B,
+    buffer: Vec<u8>,
+}
+
+impl<B: BufRead> ReusedLines<B> {
+    pub fn new(reader: B) -> Self {
+        ReusedLines {
+            reader,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Reads the next line, or returns `None` at the end of the input. The line is only
+    /// valid until the next call. As with `BufRead::lines`, a line that is not UTF-8 is
+    /// an `InvalidData` error.
+    pub fn next_line(&mut self) -> Option<io::Result<&str>> {
+        self.buffer.clear();
+        match self.reader.read_until(b'\n', &mut self.buffer) {
+            Ok(0) => None,
+            Ok(_) => {
+                let line = match self.buffer.strip_suffix(b"\n") {
+                    Some(line) => line.strip_suffix(b"\r").unwrap_or(line),
+                    None => &self.buffer,
+                };
+                Some(std::str::from_utf8(line).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "stream did not contain valid UTF-8",
+                    )
+                }))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Memory-maps standard input when it is redirected from a non-empty regular file.
+///
+/// Returns `None` for pipes, terminals and empty files, which must be read normally.
+pub fn mmap_stdin() -> io::Result<Option<Mmap>> {
+    let file = stdin_file()?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        return Ok(None);
+    }
+
+    // SAFETY: the map is only read, and the file is not expected to change while sampling
+    let map = unsafe { Mmap::map(&file)? };
+    Ok(Some(map))
+}
+
+/// Returns the size in bytes of standard input when it is redirected from a regular
+/// file, and `None` for pipes and terminals, whose length is unknown
+pub fn stdin_len() -> io::Result<Option<u64>> {
+    let metadata = stdin_file()?.metadata()?;
+    Ok(metadata.is_file().then_some(metadata.len()))
+}
+
+/// Opens a second handle to standard input as a file, to inspect its metadata
+fn stdin_file() -> io::Result<File> {
+    #[cfg(unix)]
+    let file = File::from(io::stdin().as_fd().try_clone_to_owned()?);
+    #[cfg(windows)]
+    let file = File::from(io::stdin().as_handle().try_clone_to_owned()?);
+    Ok(file)
+}
+
+/// Creates a CSV reader with the settings shared by all CSV-aware modes
+pub fn csv_reader<R: Read>(reader: R) -> csv::Reader<R> {
+    csv_reader_with_strictness(reader, false)
+}
+
+/// Like `csv_reader`, but with `strict` a record whose field count differs from the
+/// header's is an error instead of being tolerated
+pub fn csv_reader_with_strictness<R: Read>(reader: R, strict: bool) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .has_headers(true)
+        .flexible(!strict)
+        .trim(csv::Trim::All) // Trim whitespace from fields
+        .from_reader(reader)
+}
+
+/// Finds the index of the named column in a CSV header, ignoring surrounding whitespace
+pub fn find_column(header: &csv::StringRecord, column_name: &str) -> Option<usize> {
+    header.iter().position(|h| h.trim() == column_name.trim())
+}
+
+/// Finds the index of the named column like `find_column`, but comparing names
+/// case-insensitively. Fails if more than one column matches.
+pub fn find_column_ignoring_case(
+    header: &csv::StringRecord,
+    column_name: &str,
+) -> Result<Option<usize>, AmbiguousColumn> {
+    let wanted = column_name.trim().to_lowercase();
+    let mut matches = header
+        .iter()
+        .enumerate()
+        .filter(|(_, h)| h.trim().to_lowercase() == wanted);
+    let Some((index, _)) = matches.next() else {
+        return Ok(None);
+    };
+    if matches.next().is_none() {
+        return Ok(Some(index));
+    }
+    Err(AmbiguousColumn {
+        name: column_name.trim().to_string(),
+        matches: header
+            .iter()
+            .map(str::trim)
+            .filter(|h| h.to_lowercase() == wanted)
+            .map(String::from)
+            .collect(),
+        by_pattern: false,
+    })
+}
+
+/// Finds the index of the only column whose (trimmed) name matches a regular
+/// expression. Fails if more than one column matches.
+pub fn find_column_matching(
+    header: &csv::StringRecord,
+    pattern: &Regex,
+) -> Result<Option<usize>, AmbiguousColumn> {
+    let matches: Vec<(usize, &str)> = header
+        .iter()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, h)| pattern.is_match(h))
+        .collect();
+    match matches[..] {
+        [] => Ok(None),
+        [(index, _)] => Ok(Some(index)),
+        _ => Err(AmbiguousColumn {
+            name: pattern.as_str().to_string(),
+            matches: matches.iter().map(|(_, h)| h.to_string()).collect(),
+            by_pattern: true,
+        }),
+    }
+}
+
+/// A column name that matches several header columns when case is ignored, or a
+/// pattern that matches several of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AmbiguousColumn {
+    pub name: String,
+    pub matches: Vec<String>,
+    /// Whether `name` is a regular expression rather than a case-insensitive name
+    pub by_pattern: bool,
+}
+
+impl fmt::Display for AmbiguousColumn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.by_pattern {
+            write!(
+                f,
+                "pattern '{}' matches columns {}",
+                self.name,
+                self.matches.join(", ")
+            )
+        } else {
+            write!(
+                f,
+                "'{}' matches columns {} ignoring case",
+                self.name,
+                self.matches.join(", ")
+            )
+        }
+    }
+}
+
+impl std::error::Error for AmbiguousColumn {}
+
+/// Compares a CSV header with the expected column names, in order.
+/// Returns a description of the difference, or `None` if they match.
+pub fn column_mismatch(header: &csv::StringRecord, expected: &[String]) -> Option<String> {
+    let found: Vec<&str> = header.iter().map(str::trim).collect();
+    if found.iter().copied().eq(expected.iter().map(|c| c.trim())) {
+        return None;
+    }
+
+    let missing: Vec<&str> = expected
+        .iter()
+        .map(|c| c.trim())
+        .filter(|c| !found.contains(c))
+        .collect();
+    let unexpected: Vec<&str> = found
+        .iter()
+        .copied()
+        .filter(|c| !expected.iter().any(|e| e.trim() == *c))
+        .collect();
+
+    let mut message = format!(
+        "expected columns [{}] but found [{}]",
+        expected.join(", "),
+        found.join(", ")
+    );
+    if !missing.is_empty() {
+        message.push_str(&format!("; missing: {}", missing.join(", ")));
+    }
+    if !unexpected.is_empty() {
+        message.push_str(&format!("; unexpected: {}", unexpected.join(", ")));
+    }
+    if missing.is_empty() && unexpected.is_empty() {
+        message.push_str("; columns are in a different order");
+    }
+    Some(message)
+}
+
+/// Reads a set of keys from a file containing one key per line.
+/// Surrounding whitespace is trimmed and blank lines are ignored.
+pub fn read_keys<P: AsRef<Path>>(path: P) -> io::Result<HashSet<String>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut keys = HashSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        let key = line.trim();
+        if !key.is_empty() {
+            keys.insert(key.to_string());
+        }
+    }
+    Ok(keys)
+}
+
+/// Delimiters `sniff_format` considers, in order of preference on a tie
+const CANDIDATE_DELIMITERS: [u8; 5] = [b',', b'\t', b';', b'|', b' '];
+
+/// The input format guessed by `sniff_format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SniffedFormat {
+    /// The field delimiter, or `None` if the lines look like plain text
+    pub delimiter: Option<u8>,
+    /// Whether the first line looks like a header
+    pub has_header: bool,
+}
+
+impl fmt::Display for SniffedFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let delimiter = match self.delimiter {
+            Some(b',') => "comma",
+            Some(b'\t') => "tab",
+            Some(b';') => "semicolon",
+            Some(b'|') => "pipe",
+            Some(b' ') => "space",
+            Some(_) => "other",
+            None => "none (plain lines)",
+        };
+        let header = if self.has_header { "yes" } else { "no" };
+        write!(f, "delimiter: {}\nheader: {}", delimiter, header)
+    }
+}
+
+/// Guesses the delimiter and header presence from the first lines of an input.
+///
+/// A delimiter qualifies if it splits every non-blank line into the same number
+/// (at least two) of fields, ignoring delimiters inside double quotes; among those,
+/// the one giving the most fields wins. The first line is taken as a header if some
+/// column (the whole line when no delimiter qualifies) is text there but numeric on
+/// every following line.
+pub fn sniff_format<S: AsRef<str>>(lines: &[S]) -> SniffedFormat {
+    let lines: Vec<&str> = lines
+        .iter()
+        .map(|line| line.as_ref().trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+
+    let mut delimiter = None;
+    let mut best_fields = 1;
+    for candidate in CANDIDATE_DELIMITERS {
+        let mut counts = lines.iter().map(|line| split_fields(line, candidate).len());
+        let Some(fields) = counts.next() else {
+            break;
+        };
+        if fields > best_fields && counts.all(|count| count == fields) {
+            delimiter = Some(candidate);
+            best_fields = fields;
+        }
+    }
+
+    // Without a delimiter, each line is a single column
+    let fields = |line| match delimiter {
+        Some(delimiter) => split_fields(line, delimiter),
+        None => vec![line],
+    };
+    let has_header = match lines.split_first() {
+        Some((first, rest)) if !rest.is_empty() => {
+            let header = fields(first);
+            let rows: Vec<Vec<&str>> = rest.iter().map(|&line| fields(line)).collect();
+            (0..header.len()).any(|column| {
+                !is_numeric(header[column]) && rows.iter().all(|row| is_numeric(row[column]))
+            })
+        }
+        _ => false,
+    };
+
+    SniffedFormat {
+        delimiter,
+        has_header,
+    }
+}
+
+/// Splits a line on `delimiter`, treating delimiters inside double quotes as text
+fn split_fields(line: &str, delimiter: u8) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (index, byte) in line.bytes().enumerate() {
+        if byte == b'"' {
+            quoted = !quoted;
+        } else if byte == delimiter && !quoted {
+            fields.push(&line[start..index]);
+            start = index + 1;
+        }
+    }
+    fields.push(&line[start..]);
+    fields
+}
+
+fn is_numeric(field: &str) -> bool {
+    field.trim().trim_matches('"').parse::<f64>().is_ok()
+}
+
+/// Reads per-key sampling percentages from a file with one `key,percentage` pair
+/// per line. The percentage follows the last comma, so keys may contain commas.
+/// Surrounding whitespace is trimmed and blank lines are ignored.
+pub fn read_key_rates<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, f64>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut rates = HashMap::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "key rate line {}: expected 'key,percentage' with a percentage between 0 and 100, got '{}'",
+                    index + 1,
+                    line
+                ),
+            )
+        };
+        let (key, percentage) = line.rsplit_once(',').ok_or_else(invalid)?;
+        let percentage: f64 = percentage.trim().parse().map_err(|_| invalid())?;
+        if !(0.0..=100.0).contains(&percentage) {
+            return Err(invalid());
+        }
+        rates.insert(key.trim().to_string(), percentage);
+    }
+    Ok(rates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_trailing_newline_reader_finds_unterminated_line() {
+        for (input, expected) in [
+            ("a\nb", Some(2)),
+            ("a\nb\n", None),
+            ("", None),
+            ("a", Some(1)),
+        ] {
+            let mut reader = TrailingNewlineReader::new(input.as_bytes());
+            assert_eq!(reader.unterminated_line(), None);
+            io::copy(&mut reader, &mut io::sink()).unwrap();
+            assert_eq!(reader.unterminated_line(), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_sorted_merge_reader_merges_by_column() {
+        let first = "k,v\na,1\nc,2\nc,3\n";
+        let second = "k,v\nb,4\n\" c\",5\n\"d,e\",6\n";
+        let mut inputs = vec![csv_reader(first.as_bytes()), csv_reader(second.as_bytes())];
+        let header = inputs[0].headers().unwrap().clone();
+        inputs[1].headers().unwrap();
+
+        let mut merged = String::new();
+        SortedMergeReader::new(&header, inputs, vec![0, 0])
+            .unwrap()
+            .read_to_string(&mut merged)
+            .unwrap();
+        assert_eq!(merged, "k,v\na,1\nb,4\nc,2\nc,3\nc,5\n\"d,e\",6\n");
+    }
+
+    #[test]
+    fn test_sorted_merge_reader_rejects_unsorted_input() {
+        let mut inputs = vec![csv_reader("k\nb\na\n".as_bytes())];
+        let header = inputs[0].headers().unwrap().clone();
+        let mut merged = SortedMergeReader::new(&header, inputs, vec![0]).unwrap();
+        let error = merged.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            error.to_string().contains("'a' on line 3 follows 'b'"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_separated_records_round_trip() {
+        let input = "a\nb\n\nc \\ d\r\n\n\n\ne\n";
+        let mut escaped = String::new();
+        SeparatedRecordReader::new(input.as_bytes(), b"\n\n")
+            .read_to_string(&mut escaped)
+            .unwrap();
+        assert_eq!(escaped, "a\\nb\nc \\\\ d\\r\ne\n");
+
+        let mut output = SeparatedRecordWriter::new(Vec::new(), b"\n\n");
+        // Split mid-escape to check that the state carries over between writes
+        let (head, tail) = escaped.as_bytes().split_at(8);
+        output.write_all(head).unwrap();
+        output.write_all(tail).unwrap();
+        assert_eq!(
+            String::from_utf8(output.writer).unwrap(),
+            "a\nb\n\nc \\ d\r\n\ne\n\n"
+        );
+    }
+
+    fn read_limited(input: &str, limit: u64) -> String {
+        let mut output = String::new();
+        ByteLimitReader::new(Cursor::new(input), limit)
+            .read_to_string(&mut output)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn test_byte_limit_truncates_at_line_boundary() {
+        assert_eq!(read_limited("aa\nbb\ncc\n", 7), "aa\nbb\n");
+    }
+
+    #[test]
+    fn test_byte_limit_exact_line_boundary() {
+        assert_eq!(read_limited("aa\nbb\ncc\n", 6), "aa\nbb\n");
+    }
+
+    #[test]
+    fn test_byte_limit_larger_than_input() {
+        assert_eq!(read_limited("aa\nbb", 100), "aa\nbb");
+    }
+
+    #[test]
+    fn test_byte_limit_smaller_than_first_line() {
+        assert_eq!(read_limited("aaaa\nbb\n", 3), "");
+    }
+
+    #[test]
+    fn test_byte_limit_stops_inside_endless_line() {
+        let input = BufReader::new(Cursor::new("aa\nbb").chain(io::repeat(b'x')));
+        let mut output = String::new();
+        ByteLimitReader::new(input, 1 << 20)
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output, "aa\n");
+    }
+
+    fn filter_lines(input: &str, pass_header: bool) -> String {
+        let mut output = String::new();
+        LineFilterReader::new(Cursor::new(input), pass_header, |line: &[u8]| {
+            line.ends_with(b"!")
+        })
+        .read_to_string(&mut output)
+        .unwrap();
+        output
+    }
+
+    #[test]
+    fn test_line_filter_keeps_matching_lines() {
+        assert_eq!(filter_lines("a!\nb\nc!\r\nd", false), "a!\nc!\r\n");
+    }
+
+    #[test]
+    fn test_record_tap_reader_passes_input_and_parses_records() {
+        let input = "id,note\n1,\"a,\nb\"\n2, c \n\n3\r\n";
+        let mut records = Vec::new();
+        let mut output = String::new();
+        RecordTapReader::new(input.as_bytes(), |record: &csv::StringRecord| {
+            records.push(record.iter().map(String::from).collect::<Vec<_>>())
+        })
+        .read_to_string(&mut output)
+        .unwrap();
+        assert_eq!(output, input);
+        assert_eq!(records, [vec!["1", "a,\nb"], vec!["2", "c"], vec!["3"]]);
+
+        // An invalid field is read lossily, and the records after it are unaffected
+        let input = b"id,note\n1,\xFF\n2,b\n";
+        let mut records = Vec::new();
+        let mut output = Vec::new();
+        RecordTapReader::new(&input[..], |record: &csv::StringRecord| {
+            records.push(record.iter().map(String::from).collect::<Vec<_>>())
+        })
+        .read_to_end(&mut output)
+        .unwrap();
+        assert_eq!(output, input);
+        assert_eq!(records, [vec!["1", "\u{FFFD}"], vec!["2", "b"]]);
+    }
+
+    #[test]
+    fn test_record_byte_limit_reader_counts_whole_records() {
+        let read = |input: &str, limit| {
+            let mut output = String::new();
+            RecordByteLimitReader::new(input.as_bytes(), limit)
+                .read_to_string(&mut output)
+                .map(|_| output)
+        };
+        let input = "id,v\n1,\"a\"\"\nb\"\n2,c\n";
+        assert_eq!(read(input, None).unwrap(), input);
+        assert_eq!(read(input, Some(9)).unwrap(), input);
+
+        // The quoted line feed belongs to the record on line 2
+        let err = read(input, Some(8)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "record on line 2 exceeds the limit of 8 bytes"
+        );
+
+        // A quote inside an unquoted field does not start quoting
+        let err = read("a\"b\nlong record\n", Some(5)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "record on line 2 exceeds the limit of 5 bytes"
+        );
+    }
+
+    #[test]
+    fn test_line_filter_passes_header() {
+        assert_eq!(filter_lines("h\na!\nb\n", true), "h\na!\n");
+        assert_eq!(filter_lines("h\na!\nb\n", false), "a!\n");
+    }
+
+    fn decompress_to_string(input: &[u8]) -> String {
+        let mut output = String::new();
+        decompress(input)
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn test_decompress_passes_plain_input_through() {
+        assert_eq!(decompress_to_string(b"a\nb\n"), "a\nb\n");
+        assert_eq!(decompress_to_string(b"a"), "a");
+        assert_eq!(decompress_to_string(b""), "");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_decompress_zstd() {
+        let compressed = zstd::encode_all(&b"a\nb\nc\n"[..], 0).unwrap();
+        assert_eq!(decompress_to_string(&compressed), "a\nb\nc\n");
+    }
+
+    #[cfg(feature = "bzip2")]
+    #[test]
+    fn test_decompress_bzip2() {
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        std::io::Write::write_all(&mut encoder, b"a\nb\nc\n").unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(decompress_to_string(&compressed), "a\nb\nc\n");
+    }
+
+    fn strip_bom(input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        BomStrippingReader::new(input)
+            .read_to_end(&mut output)
+            .unwrap();
+        output
+    }
+
+    /// A reader that returns one byte per read, to split multi-byte sequences
+    struct ByteByByte<R>(R);
+
+    impl<R: Read> Read for ByteByByte<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_transcoding_reader_decodes_windows_1252() {
+        let data = b"name\ncaf\xE9\nna\xEFve \x80\n";
+        let mut decoded = String::new();
+        TranscodingReader::new(&data[..], encoding_rs::WINDOWS_1252)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "name\ncafé\nnaïve €\n");
+    }
+
+    #[test]
+    fn test_transcoding_reader_handles_split_sequences() {
+        // UTF-16LE with a BOM, delivered one byte at a time
+        let data: Vec<u8> = b"\xFF\xFE"
+            .iter()
+            .copied()
+            .chain("é€\n".encode_utf16().flat_map(u16::to_le_bytes))
+            .collect();
+        let mut decoded = String::new();
+        TranscodingReader::new(ByteByByte(&data[..]), encoding_rs::UTF_16LE)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "é€\n");
+    }
+
+    #[test]
+    fn test_bom_is_stripped() {
+        assert_eq!(strip_bom(b"\xEF\xBB\xBFid,name\n1,a\n"), b"id,name\n1,a\n");
+    }
+
+    #[test]
+    fn test_input_without_bom_is_unchanged() {
+        assert_eq!(strip_bom(b"id,name\n"), b"id,name\n");
+        assert_eq!(strip_bom(b"id"), b"id");
+        assert_eq!(strip_bom(b"\xEF\xBB"), b"\xEF\xBB");
+        assert_eq!(strip_bom(b""), b"");
+    }
+
+    #[test]
+    fn test_find_column() {
+        let header = csv::StringRecord::from(vec!["id", " name ", "value"]);
+        assert_eq!(find_column(&header, "name"), Some(1));
+        assert_eq!(find_column(&header, "missing"), None);
+    }
+
+    fn columns(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_column_mismatch_matching_schema() {
+        let header = csv::StringRecord::from(vec!["a", " b", "c"]);
+        assert_eq!(column_mismatch(&header, &columns(&["a", "b", "c"])), None);
+    }
+
+    #[test]
+    fn test_column_mismatch_reordered_schema() {
+        let header = csv::StringRecord::from(vec!["a", "c", "b"]);
+        let message = column_mismatch(&header, &columns(&["a", "b", "c"])).unwrap();
+        assert_eq!(
+            message,
+            "expected columns [a, b, c] but found [a, c, b]; columns are in a different order"
+        );
+    }
+
+    #[test]
+    fn test_column_mismatch_missing_column() {
+        let header = csv::StringRecord::from(vec!["a", "x"]);
+        let message = column_mismatch(&header, &columns(&["a", "b"])).unwrap();
+        assert_eq!(
+            message,
+            "expected columns [a, b] but found [a, x]; missing: b; unexpected: x"
+        );
+    }
+
+    #[test]
+    fn test_find_column_ignoring_case() {
+        let header = csv::StringRecord::from(vec!["id", " UserId ", "score"]);
+        assert_eq!(find_column_ignoring_case(&header, "userid"), Ok(Some(1)));
+        assert_eq!(find_column_ignoring_case(&header, "SCORE"), Ok(Some(2)));
+        assert_eq!(find_column_ignoring_case(&header, "missing"), Ok(None));
+
+        let header = csv::StringRecord::from(vec!["userid", "UserId", "x"]);
+        let error = find_column_ignoring_case(&header, "USERID").unwrap_err();
+        assert_eq!(error.matches, ["userid", "UserId"]);
+        assert_eq!(
+            error.to_string(),
+            "'USERID' matches columns userid, UserId ignoring case"
+        );
+    }
+
+    #[test]
+    fn test_read_keys() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"a\n  b \n\na\n").unwrap();
+
+        let keys = read_keys(file.path()).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains("a"));
+        assert!(keys.contains("b"));
+    }
+
+    #[test]
+    fn test_read_key_rates() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"a, 10\n\nb,c ,2.5\n").unwrap();
+
+        let rates = read_key_rates(file.path()).unwrap();
+        assert_eq!(rates.len(), 2);
+        assert_eq!(rates["a"], 10.0);
+        assert_eq!(rates["b,c"], 2.5);
+
+        for invalid in ["a\n", "a,x\n", "a,101\n"] {
+            let mut file = tempfile::NamedTempFile::new().unwrap();
+            std::io::Write::write_all(&mut file, invalid.as_bytes()).unwrap();
+            let error = read_key_rates(file.path()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_sniff_format_comma() {
+        let format = sniff_format(&["id,name,score", "1,alice,3.5", "2,\"bob, jr\",4"]);
+        assert_eq!(format.delimiter, Some(b','));
+        assert!(format.has_header);
+    }
+
+    #[test]
+    fn test_sniff_format_tab() {
+        let format = sniff_format(&["1\tred apple\t10", "2\tgreen pear\t20"]);
+        assert_eq!(format.delimiter, Some(b'\t'));
+        assert!(!format.has_header);
+    }
+
+    #[test]
+    fn test_sniff_format_space() {
+        let format = sniff_format(&["x y z", "1 2 3", "4 5 6"]);
+        assert_eq!(format.delimiter, Some(b' '));
+        assert!(format.has_header);
+    }
+
+    #[test]
+    fn test_sniff_format_plain_lines() {
+        let format = sniff_format(&["hello world", "a single sentence here", "", "word"]);
+        assert_eq!(format.delimiter, None);
+        assert!(!format.has_header);
+        assert_eq!(
+            format.to_string(),
+            "delimiter: none (plain lines)\nheader: no"
+        );
+    }
+
+    #[test]
+    fn test_sniff_format_single_column() {
+        let format = sniff_format(&["price", "2", "4.5"]);
+        assert_eq!(format.delimiter, None);
+        assert!(format.has_header);
+        assert!(!sniff_format(&["1", "2", "4"]).has_header);
+    }
+}
@@ -1,7 +1,31 @@
+pub mod checkpoint;
 pub mod config;
 pub mod error;
+pub mod input;
+#[cfg(feature = "parquet")]
+pub mod parquet_out;
+pub mod process;
 pub mod sampling;
+pub mod stats;
 
 pub use config::Config;
 pub use error::{Error, Result};
-pub use sampling::{percentage_sample_iter, reservoir_sample, CsvHashSampler};
+pub use process::{
+    check_hash_column, count_prepared_input, decode_input, dry_validate, merge_sorted_inputs,
+    probe_format, process_checkpointed, process_input, process_mapped, sample_lines, sniff_header,
+    split_round_robin,
+};
+#[cfg(feature = "async")]
+pub use sampling::reservoir_sample_stream;
+pub use sampling::{
+    bucket_for, bucket_for_with, canonical_number, derive_seed, derive_seed_with, hash_position,
+    hash_position_with, key_hash, key_hash_with, percentage_sample_iter,
+    percentage_sample_iter_ref, poisson_sample_iter, poisson_sample_iter_ref,
+    predicate_sample_iter, reservoir_sample, reservoir_sample_bounded, reservoir_sample_chunked,
+    reservoir_sample_into, reservoir_sample_weighted_slices, reservoir_sample_with_status,
+    sample_one, should_include, should_include_stratified, should_include_stratified_with,
+    should_include_with, target_count_sample_iter, top_k_by_score, weighted_reservoir_sample,
+    whitespace_hash_sample_iter, CountWindowedReservoir, CsvHashSampler, DynamicReservoir,
+    KeyColumn, KeyNormalization, NumericKeys, Reservoir, ReservoirOrder, ReservoirSample,
+    SpillingReservoir, TargetCountSampleIter, WindowedReservoir,
+};
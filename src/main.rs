@@ -1,175 +1,359 @@
+#[cfg(feature = "gzip")]
+use flate2::{write::GzEncoder, Compression};
+#[cfg(feature = "progress")]
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{error, Level};
 use rand::rngs::StdRng;
 use rand::{thread_rng, Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
 use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
 use std::process;
+use std::time::Instant;
 
-use sample::{config, error::Error, percentage_sample_iter, reservoir_sample, CsvHashSampler};
+use sample::checkpoint::Checkpoint;
+use sample::input::mmap_stdin;
+#[cfg(feature = "progress")]
+use sample::input::stdin_len;
+use sample::stats::{LineCountingWriter, RunStats};
+use sample::{
+    check_hash_column, config, count_prepared_input, decode_input, dry_validate,
+    error::{output_error, Error, OutputWriter},
+    merge_sorted_inputs, probe_format, process_checkpointed, process_input, process_mapped,
+    sniff_header, split_round_robin,
+};
 
 /// Run the application with the given arguments, input, and output streams.
-pub fn run_app<I, O>(args: &[&str], input: I, mut output: O) -> sample::Result<()>
+pub fn run_app<I, O>(args: &[&str], input: I, output: O) -> sample::Result<()>
 where
     I: Read,
     O: Write,
 {
-    // Parse command line arguments
-    let args_owned: Vec<String> = args.iter().map(|s| s.to_string()).collect();
-    let config = config::parse_args(args_owned.iter().cloned())?;
-
-    // Handle hash-based sampling with CSV library
-    if config.csv_mode && config.percentage.is_some() && config.hash_column.is_some() {
-        return process_hash_based_sampling(config, input, output);
+    let config = parse_config(args)?;
+    #[cfg(feature = "gzip")]
+    if config.gzip_out {
+        return run_gzipped(&config, output, |config, output| {
+            run_with_config(config, input, output)
+        });
     }
+    run_with_config(&config, input, output)
+}
 
-    // For other sampling methods, use the existing code
-    let mut rng = if let Some(seed) = config.seed {
-        StdRng::seed_from_u64(seed)
-    } else {
-        StdRng::from_rng(thread_rng()).unwrap()
-    };
+/// Run the application on standard input
+fn run_stdin<O: Write>(args: &[&str], output: O) -> sample::Result<()> {
+    let config = parse_config(args)?;
+    init_logger(config.quiet);
 
-    let reader = io::BufReader::new(input);
-    let mut lines = reader.lines();
+    #[cfg(feature = "gzip")]
+    if config.gzip_out {
+        return run_gzipped(&config, output, |config, output| {
+            sample_stdin(config, output)
+        });
+    }
+    sample_stdin(&config, output)
+}
 
-    // Handle header if enabled
-    if config.csv_mode {
-        if let Some(header) = lines.next() {
-            let header_str = header?;
-            writeln!(output, "{}", header_str)?;
+/// Sample standard input, memory-mapping it when --mmap is given and standard input
+/// is a regular file.
+fn sample_stdin<O: Write>(config: &config::Config, output: O) -> sample::Result<()> {
+    #[cfg(feature = "progress")]
+    if config.progress_bar {
+        use std::io::IsTerminal;
+        if let Some(bar) = progress_bar(stdin_len()?, io::stderr().is_terminal()) {
+            let result = run_with_config(config, bar.wrap_read(io::stdin()), output);
+            bar.finish_and_clear();
+            return result;
         }
     }
 
-    // Create an iterator over the remaining lines
-    let lines_iter = lines.map_while(|line: std::io::Result<String>| line.ok());
-
-    // Perform sampling based on the configuration
-    match (config.sample_size, config.percentage) {
-        (Some(k), None) => process_reservoir_sampling(lines_iter, k, &mut rng, output)?,
-        (None, Some(percentage)) => {
-            process_percentage_sampling(lines_iter, percentage, rng, output)?
+    if config.mmap
+        && config.command.is_none()
+        && !config.probe
+        && !config.dry_validate
+        && config.round_robin.is_none()
+        && config.stats_out.is_none()
+        && config.log_invocation.is_none()
+        && !config.size_from_header
+        && config.header != Some(config::HeaderMode::Auto)
+    {
+        if let Some(map) = mmap_stdin()? {
+            if let (Some(replicates), Some(template)) = (config.replicates, &config.output_template)
+            {
+                return process_replicates(config, replicates, template, &map);
+            }
+            return sample_buffer(config, &map, output);
         }
-        _ => unreachable!("Config validation ensures one of sample_size or percentage is set"),
-    };
+    }
+
+    run_with_config(config, io::stdin(), output)
+}
+
+/// Create a --progress-bar for an input of `total` bytes, or `None` when the total is
+/// unknown, as for a pipe, or standard error is not a terminal to draw it on
+#[cfg(feature = "progress")]
+fn progress_bar(total: Option<u64>, stderr_is_terminal: bool) -> Option<ProgressBar> {
+    let total = total.filter(|_| stderr_is_terminal)?;
+    let style = ProgressStyle::with_template("{bar:40} {percent:>3}% {bytes}/{total_bytes} {eta}")
+        .expect("progress bar template is valid");
+    Some(ProgressBar::new(total).with_style(style))
+}
 
+/// Run `run` with its output gzip-compressed, then finish the stream so the gzip
+/// trailer is written before the output is closed.
+#[cfg(feature = "gzip")]
+fn run_gzipped<O, F>(config: &config::Config, output: O, run: F) -> sample::Result<()>
+where
+    O: Write,
+    F: FnOnce(&config::Config, &mut dyn Write) -> sample::Result<()>,
+{
+    let config = config::Config {
+        gzip_out: false,
+        ..config.clone()
+    };
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    run(&config, &mut encoder)?;
+    encoder.finish()?.flush()?;
     Ok(())
 }
 
-fn process_hash_based_sampling<I, O>(
-    config: config::Config,
-    input: I,
-    mut output: O,
-) -> sample::Result<()>
+/// Parse command line arguments
+fn parse_config(args: &[&str]) -> sample::Result<config::Config> {
+    let args_owned: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    config::parse_args(args_owned.iter().cloned())
+}
+
+fn run_with_config<I, O>(config: &config::Config, mut input: I, output: O) -> sample::Result<()>
 where
     I: Read,
     O: Write,
 {
-    let percentage = config.percentage.unwrap();
-    let column_name = config.hash_column.as_ref().unwrap();
-
-    // Create the CSV hash sampler
-    let sampler = match CsvHashSampler::new(input, percentage, column_name) {
-        Ok(s) => s,
-        Err(e) => {
-            if e.kind() == io::ErrorKind::InvalidInput {
-                return Err(Error::ColumnNotFound(column_name.clone()));
-            } else {
-                return Err(Error::IoError(e));
-            }
-        }
-    };
+    if let Some(config::Command::Check(check)) = &config.command {
+        return check_hash_column(config, check, input, output);
+    }
 
-    // Print the header
-    writeln!(
-        output,
-        "{}",
-        sampler.header().iter().collect::<Vec<_>>().join(",")
-    )?;
-
-    // Sample the data and print the results using the streaming iterator
-    for record_result in sampler {
-        match record_result {
-            Ok(record) => {
-                writeln!(output, "{}", record.iter().collect::<Vec<_>>().join(","))?;
-            }
-            Err(e) => return Err(Error::IoError(e)),
-        }
+    if let Some(paths) = &config.merge_sorted {
+        let mut merged = merge_sorted_inputs(config, paths)?;
+        let config = config::Config {
+            merge_sorted: None,
+            ..config.clone()
+        };
+        return run_with_config(&config, &mut merged as &mut dyn Read, output);
     }
 
-    Ok(())
+    if config.probe {
+        eprintln!("{}", probe_format(config, input)?);
+        return Ok(());
+    }
+
+    if config.dry_validate {
+        return dry_validate(config, input);
+    }
+
+    if config.header == Some(config::HeaderMode::Auto) {
+        let (has_header, mut input) = sniff_header(input)?;
+        let config = config.with_detected_header(has_header)?;
+        return run_with_config(&config, &mut input as &mut dyn Read, output);
+    }
+
+    if config.size_from_header {
+        // The directive is the first line of the decompressed, decoded input
+        let (decoded, input) = decode_input(config, input)?;
+        let mut input = io::BufReader::new(input);
+        let mut directive = String::new();
+        input.read_line(&mut directive)?;
+        let config = decoded.with_sample_directive(&directive)?;
+        return run_with_config(&config, &mut input as &mut dyn Read, output);
+    }
+
+    if let (Some(replicates), Some(template)) = (config.replicates, &config.output_template) {
+        let mut buffer = Vec::new();
+        input.read_to_end(&mut buffer)?;
+        return process_replicates(config, replicates, template, &buffer);
+    }
+
+    if let (Some(shards), Some(template)) = (config.round_robin, &config.output_template) {
+        return split_round_robin(config, input, shards, |i| {
+            let path = template.replace("{i}", &i.to_string());
+            Ok(io::BufWriter::new(OutputWriter::create(Path::new(&path))?))
+        });
+    }
+
+    if config.stats_out.is_some() || config.log_invocation.is_some() {
+        return sample_with_stats(config, input, output);
+    }
+
+    sample_input(config, input, output)
 }
 
-fn process_reservoir_sampling<I, O, R>(
-    lines_iter: I,
-    k: usize,
-    rng: &mut R,
-    mut output: O,
-) -> sample::Result<()>
+/// Run a single sampling pass, then write its --stats-out summary and append its
+/// --log-invocation line
+fn sample_with_stats<I, O>(config: &config::Config, input: I, output: O) -> sample::Result<()>
 where
-    I: Iterator<Item = String>,
+    I: Read,
     O: Write,
-    R: Rng,
 {
-    let lines: Vec<String> = lines_iter.collect();
-    let sampled_lines = reservoir_sample(lines.iter(), k, rng);
-    for line in sampled_lines {
-        writeln!(output, "{}", line)?;
+    let start = Instant::now();
+    // Resolve the seed here so the summary can report it
+    let seed = config.seed.unwrap_or_else(|| thread_rng().gen());
+    let sampling = config::Config {
+        seed: Some(seed),
+        stats_out: None,
+        log_invocation: None,
+        ..config.clone()
+    };
+
+    // Count the lines sampling reads after decompression and --filter, not the raw input
+    let (prepared, mut input) = count_prepared_input(&sampling, input)?;
+    let mut output = LineCountingWriter::new(output);
+    sample_input(&prepared, &mut input, &mut output)?;
+
+    let stats = RunStats::from_counts(
+        &sampling,
+        input.lines(),
+        output.lines(),
+        seed,
+        start.elapsed(),
+    );
+    if let Some(path) = &config.stats_out {
+        stats
+            .save(path)
+            .map_err(|e| output_error(path.display(), e))?;
+    }
+    if let Some(path) = &config.log_invocation {
+        stats
+            .append_invocation(path, &config.invocation)
+            .map_err(|e| output_error(path.display(), e))?;
     }
     Ok(())
 }
 
-fn process_percentage_sampling<I, O, R>(
-    lines_iter: I,
-    percentage: f64,
-    rng: R,
+/// Run a single sampling pass over the input according to the configuration.
+fn sample_input<I, O>(config: &config::Config, input: I, mut output: O) -> sample::Result<()>
+where
+    I: Read,
+    O: Write,
+{
+    if let Some(checkpoint) = &config.checkpoint {
+        return sample_checkpointed(config, input, output, checkpoint);
+    }
+
+    let mut rng = seeded_rng(config, &mut output)?;
+    process_input(config, input, output, &mut rng)
+}
+
+/// Run a single sampling pass over an in-memory copy of the input.
+fn sample_buffer<O: Write>(
+    config: &config::Config,
+    data: &[u8],
     mut output: O,
+) -> sample::Result<()> {
+    if let Some(checkpoint) = &config.checkpoint {
+        return sample_checkpointed(config, data, output, checkpoint);
+    }
+
+    let mut rng = seeded_rng(config, &mut output)?;
+    process_mapped(config, data, output, &mut rng)
+}
+
+/// Run a checkpointed percentage sample, continuing from the --resume checkpoint if given
+fn sample_checkpointed<I, O>(
+    config: &config::Config,
+    input: I,
+    output: O,
+    checkpoint: &Path,
 ) -> sample::Result<()>
 where
-    I: Iterator<Item = String>,
+    I: Read,
     O: Write,
-    R: Rng,
 {
-    let sampled_iter = percentage_sample_iter(lines_iter, percentage, rng);
-    for line in sampled_iter {
-        writeln!(output, "{}", line)?;
+    let resume = config.resume.as_ref().map(Checkpoint::load).transpose()?;
+    let mut rng = match &resume {
+        Some(resume) => resume.rng(),
+        None => ChaCha12Rng::seed_from_u64(config.seed.unwrap_or_else(|| thread_rng().gen())),
+    };
+    process_checkpointed(config, input, output, &mut rng, checkpoint, resume.as_ref())
+}
+
+/// Create the sampling RNG, recording its seed in the output if requested
+fn seeded_rng<O: Write>(config: &config::Config, output: &mut O) -> sample::Result<StdRng> {
+    // Resolve the seed up front so it can be reported alongside the output
+    let seed = config.seed.unwrap_or_else(|| thread_rng().gen());
+    if config.embed_seed {
+        writeln!(output, "{} seed={}", config.comment_char, seed)?;
     }
+    Ok(StdRng::seed_from_u64(seed))
+}
+
+/// Run the sampling `replicates` times over the same buffered input, writing each
+/// result to a file named by the template. Replicate `i` uses the seed `base + i`.
+fn process_replicates(
+    config: &config::Config,
+    replicates: usize,
+    template: &str,
+    buffer: &[u8],
+) -> sample::Result<()> {
+    let base_seed = config.seed.unwrap_or_else(|| thread_rng().gen());
+    for i in 0..replicates {
+        let replicate_config = config::Config {
+            seed: Some(base_seed.wrapping_add(i as u64)),
+            ..config.clone()
+        };
+        let path = template.replace("{i}", &i.to_string());
+        let mut output = io::BufWriter::new(OutputWriter::create(Path::new(&path))?);
+        sample_buffer(&replicate_config, buffer, &mut output)?;
+        output.flush()?;
+    }
+
     Ok(())
 }
 
+/// Route log records to stderr as "Error: ...", "Warning: ..." lines.
+///
+/// Shows warnings and errors, or only errors when `quiet`, and the --stats-column
+/// summary unless overridden with `RUST_LOG`. Only the first call installs the logger.
+fn init_logger(quiet: bool) {
+    let filter = if quiet {
+        "error,sample::stats=info"
+    } else {
+        "warn,sample::stats=info"
+    };
+    let _ = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter))
+        .format(|buf, record| {
+            let level = match record.level() {
+                Level::Error => "Error",
+                Level::Warn => "Warning",
+                Level::Info => "Info",
+                Level::Debug => "Debug",
+                Level::Trace => "Trace",
+            };
+            writeln!(buf, "{}: {}", level, record.args())
+        })
+        .try_init();
+}
+
 fn main() {
     let args: Vec<String> = std::env::args().collect();
     let args_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
-    let result = run_app(&args_str, io::stdin(), io::stdout());
+    // Standard output is line-buffered by itself; block-buffer it unless --line-buffered
+    // asks for every line, and flush it before exiting, which skips destructors
+    let mut output = io::BufWriter::new(OutputWriter::new(io::stdout().lock(), "standard output"));
+    let result = run_stdin(&args_str, &mut output);
+    let flushed = output.flush();
+    let result = result.and_then(|()| flushed.map_err(Error::from));
 
     if let Err(err) = result {
-        let error_message = match err {
-            Error::InvalidSampleSize => "sample size must be a positive integer",
-            Error::InvalidSeedValue => "seed must be a valid number",
-            Error::InvalidPercentage => "percentage must be between 0 and 100",
-            Error::HashRequiresCsvMode => "hash-based sampling requires --csv mode",
-            Error::HashRequiresPercentage => {
-                "hash-based sampling only works with --percentage option"
-            }
-            Error::MissingRequiredOption(msg) => {
-                eprintln!("Error: {}", msg);
-                process::exit(1);
-            }
-            Error::ColumnNotFound(column) => {
-                eprintln!("Error: column '{}' not found in CSV header", column);
-                process::exit(1);
-            }
-            Error::IoError(e) => {
-                eprintln!("Error reading input: {}", e);
-                process::exit(1);
-            }
-        };
-
-        eprintln!("Error: {}", error_message);
-        process::exit(1);
+        // The arguments may have failed to parse before the logger was installed
+        init_logger(false);
+        error!("{}", err);
+        process::exit(err.exit_code());
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use sample::bucket_for;
+    use sample::input::RetryingReader;
     use std::io::Cursor;
 
     #[test]
@@ -193,6 +377,1869 @@ mod tests {
         assert_eq!(result, "a,b\n0,0\n");
     }
 
+    #[test]
+    fn test_max_bytes_limits_eligible_lines() {
+        let result = run("10 --max-bytes 7 --seed 42", "aa\nbb\ncc\ndd\n");
+        assert_eq!(result, "aa\nbb\n");
+    }
+
+    #[test]
+    fn test_max_bytes_with_csv_header() {
+        let result = run("10 --csv --max-bytes 10 --seed 42", "a,b\n0,0\n1,1\n");
+        assert_eq!(result, "a,b\n0,0\n");
+    }
+
+    #[test]
+    fn test_exclude_keys() {
+        let mut keys = tempfile::NamedTempFile::new().unwrap();
+        keys.write_all(b"1\n3\n").unwrap();
+        let cmd = format!(
+            "-p 100 --csv --hash id --exclude-keys {}",
+            keys.path().display()
+        );
+
+        let result = run(&cmd, "id,v\n1,a\n2,b\n3,c\n4,d\n1,e\n");
+        assert_eq!(result, "id,v\n2,b\n4,d\n");
+    }
+
+    #[test]
+    fn test_exclude_keys_are_normalized() {
+        let mut keys = tempfile::NamedTempFile::new().unwrap();
+        keys.write_all(b"A\n").unwrap();
+        let cmd = format!(
+            "-p 100 --csv --hash id --normalize lowercase --exclude-keys {}",
+            keys.path().display()
+        );
+
+        let result = run(&cmd, "id,v\na,1\nb,2\nA,3\n");
+        assert_eq!(result, "id,v\nb,2\n");
+    }
+
+    #[test]
+    fn test_pin_keys_are_normalized() {
+        let mut keys = tempfile::NamedTempFile::new().unwrap();
+        keys.write_all(b"a\n").unwrap();
+        let cmd = format!(
+            "1 --csv --hash id --normalize lowercase --pin-keys {}",
+            keys.path().display()
+        );
+
+        let result = run(&cmd, "id,v\na,1\nb,2\nA,3\n");
+        assert_eq!(result, "id,v\na,1\nA,3\n");
+    }
+
+    #[test]
+    fn test_pin_keys_always_emitted() {
+        let mut keys = tempfile::NamedTempFile::new().unwrap();
+        keys.write_all(b"3\n17\n").unwrap();
+        let input: String = std::iter::once("id,v\n".to_string())
+            .chain((0..50).map(|i| format!("{},x\n", i)))
+            .collect();
+
+        for seed in 0..20 {
+            let cmd = format!(
+                "5 --csv --hash id --pin-keys {} --seed {}",
+                keys.path().display(),
+                seed
+            );
+            let result = run(&cmd, &input);
+            let rows: Vec<&str> = result.lines().skip(1).collect();
+            assert_eq!(rows.len(), 5);
+            assert_eq!(&rows[..2], &["3,x", "17,x"]);
+
+            // The rest of the budget comes from distinct non-pinned rows
+            let mut rest = rows[2..].to_vec();
+            rest.sort();
+            rest.dedup();
+            assert_eq!(rest.len(), 3);
+            assert!(rest.iter().all(|row| !["3,x", "17,x"].contains(row)));
+        }
+    }
+
+    #[test]
+    fn test_pin_keys_exceeding_budget() {
+        let mut keys = tempfile::NamedTempFile::new().unwrap();
+        keys.write_all(b"a\nb\nc\n").unwrap();
+        let cmd = format!("2 --csv --hash id --pin-keys {}", keys.path().display());
+
+        let result = run(&cmd, "id\na\nx\nb\ny\nc\n");
+        assert_eq!(result, "id\na\nb\nc\n");
+    }
+
+    #[test]
+    fn test_first_lines() {
+        let result = run("--first 2 --csv", "a,b\n0,0\n1,1\n2,2\n");
+        assert_eq!(result, "a,b\n0,0\n1,1\n");
+    }
+
+    #[test]
+    fn test_shuffle_all_is_a_seeded_permutation() {
+        let input: String = std::iter::once("h\n".to_string())
+            .chain((0..50).map(|i| format!("{}\n", i)))
+            .collect();
+
+        let result = run("--shuffle-all --csv --seed 7", &input);
+        let mut lines: Vec<&str> = result.lines().collect();
+        assert_eq!(lines.remove(0), "h");
+        assert_ne!(lines, input.lines().skip(1).collect::<Vec<_>>());
+
+        let mut sorted: Vec<i32> = lines.iter().map(|l| l.parse().unwrap()).collect();
+        sorted.sort();
+        assert_eq!(sorted, (0..50).collect::<Vec<_>>());
+
+        assert_eq!(run("--shuffle-all --csv --seed 7", &input), result);
+        assert_ne!(run("--shuffle-all --csv --seed 8", &input), result);
+    }
+
+    #[test]
+    fn test_rename_rewrites_header_only() {
+        let input = "id,v\n1,a\n2,b\n";
+        assert_eq!(
+            run("-p 100 --csv --rename v=value", input),
+            "id,value\n1,a\n2,b\n"
+        );
+        assert_eq!(
+            run("-p 100 --csv --hash id --rename id=user,v=value", input),
+            "user,value\n1,a\n2,b\n"
+        );
+
+        let result = run_app(
+            &["sample", "-p", "100", "--csv", "--rename", "x=y"],
+            Cursor::new(input),
+            Vec::new(),
+        );
+        assert!(matches!(result, Err(Error::ColumnNotFound(column)) if column == "x"));
+    }
+
+    #[test]
+    fn test_sort_output() {
+        let input: String = (0..30).map(|i| format!("{}\n", i)).collect();
+        let result = run("10 --sort --seed 3", &input);
+        let lines: Vec<&str> = result.lines().collect();
+        assert_eq!(lines.len(), 10);
+        assert!(
+            lines.windows(2).all(|pair| pair[0] <= pair[1]),
+            "{:?}",
+            lines
+        );
+
+        // Sorting only reorders the sample
+        let plain = run("10 --seed 3", &input);
+        let mut unsorted: Vec<&str> = plain.lines().collect();
+        unsorted.sort();
+        assert_eq!(lines, unsorted);
+    }
+
+    #[test]
+    fn test_sort_column_keeps_header_on_top() {
+        let input = "id,name\n3,b\n1,c\n2,a\n";
+        assert_eq!(
+            run("-p 100 --csv --sort-column name", input),
+            "id,name\n2,a\n3,b\n1,c\n"
+        );
+        assert_eq!(
+            run("-p 100 --csv --sort", input),
+            "id,name\n1,c\n2,a\n3,b\n"
+        );
+        assert_eq!(
+            run(
+                "-p 100 --csv --hash id --sort-column name --no-header-output",
+                input
+            ),
+            "2,a\n3,b\n1,c\n"
+        );
+
+        let result = run_app(
+            &["sample", "-p", "100", "--csv", "--sort-column", "x"],
+            Cursor::new(input),
+            Vec::new(),
+        );
+        assert!(matches!(result, Err(Error::ColumnNotFound(column)) if column == "x"));
+    }
+
+    #[test]
+    fn test_sort_keeps_records_and_bytes_whole() {
+        // A record whose quoted field spans lines is sorted as one row
+        let input = "id,note\n2,\"b\nz\"\n1,\"a, c\"\n3,\"a\nb\"\n";
+        assert_eq!(
+            run("-p 100 --csv --hash id --sort-column note", input),
+            "id,note\n3,a\nb\n1,a, c\n2,b\nz\n"
+        );
+        assert_eq!(
+            run("-p 100 --csv --hash id --sort --output-delimiter ;", input),
+            "id;note\n1;a, c\n2;\"b\nz\"\n3;\"a\nb\"\n"
+        );
+    }
+
+    #[test]
+    fn test_normalize_hash_keys() {
+        let input = "id,v\nFoo,1\nfoo,2\nFOO,3\n";
+        for percentage in ["20", "40", "60", "80"] {
+            let cmd = format!("-p {} --csv --hash id --normalize lowercase", percentage);
+            let rows = run(&cmd, input).lines().count() - 1;
+            assert!(rows == 0 || rows == 3, "{}", rows);
+        }
+    }
+
+    #[test]
+    fn test_count_keys() {
+        let input = "id,v\na,1\nb,2\na,3\nc,4\nb,5\nd,6\na,7\n";
+        assert_eq!(run("--count-keys --csv --hash id", input), "4\n");
+        assert_eq!(run("--count-keys --csv --hash id --skip 4", input), "3\n");
+    }
+
+    #[test]
+    fn test_probability_report() {
+        let input: String = std::iter::once("id,v".to_string())
+            .chain((0..40).map(|i| format!("k{},{}", i % 10, i)))
+            .map(|line| line + "\n")
+            .collect();
+
+        let report = run("-p 50 --csv --hash id --probability-report", &input);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "key,hash_position,selected");
+        assert_eq!(lines.len(), 12);
+
+        // The report agrees with the keys an actual hash sample keeps
+        let sampled: std::collections::HashSet<String> = run("-p 50 --csv --hash id", &input)
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap().to_string())
+            .collect();
+        for line in &lines[1..11] {
+            let fields: Vec<&str> = line.split(',').collect();
+            assert_eq!(fields[2] == "true", sampled.contains(fields[0]), "{}", line);
+        }
+        let expected = format!(
+            "# selected {} of 10 distinct keys ({:.2}%), requested 50%",
+            sampled.len(),
+            sampled.len() as f64 * 10.0
+        );
+        assert_eq!(lines[11], expected);
+
+        let report = run("-p 100 --csv --hash id --probability-report", &input);
+        assert!(report.ends_with("# selected 10 of 10 distinct keys (100.00%), requested 100%\n"));
+    }
+
+    #[test]
+    fn test_probability_report_quotes_keys() {
+        let input = "id,v\n\"a,b\",1\n\"say \"\"hi\"\"\",2\n\"two\nlines\",3\n";
+        let report = run("-p 100 --csv --hash id --probability-report", input);
+
+        let mut reader = csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .from_reader(report.as_bytes());
+        let keys: Vec<String> = reader
+            .records()
+            .map(|record| record.unwrap()[0].to_string())
+            .collect();
+        assert_eq!(keys, vec!["a,b", "say \"hi\"", "two\nlines"]);
+    }
+
+    #[test]
+    fn test_check_reports_hash_column() {
+        let input = "name,id\nx,1\ny,2\nz,\nw,1\nv,3\n";
+        let report = run("check --hash id", input);
+        assert_eq!(
+            report,
+            "column 'id' found at index 1\nrows checked: 5\nempty values: 1 (20.00%)\ndistinct values: 3\n"
+        );
+        assert!(run("check --hash id --rows 2", input).contains("rows checked: 2\n"));
+    }
+
+    #[test]
+    fn test_check_fails_on_missing_or_empty_column() {
+        let result = run_app(
+            &["sample", "check", "--hash", "user"],
+            Cursor::new("name,id\nx,1\n"),
+            Vec::new(),
+        );
+        assert!(matches!(result, Err(Error::ColumnNotFound(column)) if column == "user"));
+
+        let result = run_app(
+            &["sample", "check", "--hash", "id"],
+            Cursor::new("name,id\nx,\ny,\nz,1\n"),
+            Vec::new(),
+        );
+        assert!(matches!(result, Err(Error::MostlyEmptyColumn(column)) if column == "id"));
+    }
+
+    #[test]
+    fn test_max_record_bytes_stops_reading_an_oversized_record() {
+        let input = "id,v\n1,\"a\nb\"\n2,c\n";
+        for mode in ["-p 100 --hash id", "--assign v:2"] {
+            let cmd = format!("{} --csv --max-record-bytes 8", mode);
+            assert_eq!(run(&cmd, input).lines().count(), 4, "{}", mode);
+        }
+
+        // A record without end fails once it passes the limit
+        for mode in [&["-p", "100", "--hash", "id"][..], &["--assign", "v:2"]] {
+            let args = [&["sample", "--csv", "--max-record-bytes", "1000"], mode].concat();
+            let input = Cursor::new("id,v\n1,").chain(io::repeat(b'x'));
+            let result = run_app(&args, input, Vec::new());
+            let message = format!("{:?}", result.unwrap_err());
+            assert!(
+                message.contains("record on line 2 exceeds the limit of 1000 bytes"),
+                "{:?}: {}",
+                mode,
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_strict_columns_rejects_ragged_rows() {
+        let input = "id,v\na,1\nb\nc,3\n";
+        let cmd = "-p 100 --csv --hash id";
+        assert_eq!(run(cmd, input), input);
+
+        let args = [
+            "sample",
+            "-p",
+            "100",
+            "--csv",
+            "--hash",
+            "id",
+            "--strict-columns",
+        ];
+        let result = run_app(&args, Cursor::new(input), Vec::new());
+        assert!(
+            matches!(&result, Err(Error::CsvError(e)) if e.to_string().contains("line: 3")),
+            "{:?}",
+            result
+        );
+
+        // Modes that read CSV records directly honor it too
+        let input = "id,v\na,1\nb,2,extra\n";
+        let args = [
+            "sample",
+            "--csv",
+            "--expected-size",
+            "2",
+            "--weight-column",
+            "v",
+        ];
+        assert!(run_app(&args, Cursor::new(input), Vec::new()).is_ok());
+        let strict = [&args[..], &["--strict-columns"]].concat();
+        let result = run_app(&strict, Cursor::new(input), Vec::new());
+        assert!(matches!(result, Err(Error::CsvError(_))), "{:?}", result);
+
+        // Plain --csv sampling passes lines through unparsed, so it rejects the flag
+        // rather than letting the ragged row through
+        let args = ["sample", "3", "--csv", "--strict-columns"];
+        let result = run_app(&args, Cursor::new(input), Vec::new());
+        assert!(
+            matches!(&result, Err(Error::MissingRequiredOption(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_output_delimiter_requotes_fields() {
+        let input = "id,note\n1,\"a, b\"\n2,\"tab\there\"\n3,plain\n";
+        assert_eq!(
+            run("-p 100 --csv --hash id --output-delimiter \\t", input),
+            "id\tnote\n1\ta, b\n2\t\"tab\there\"\n3\tplain\n"
+        );
+        assert_eq!(
+            run("--csv --assign id:1 --output-delimiter ;", input),
+            "id;note;bucket\n1;a, b;0\n2;tab\there;0\n3;plain;0\n"
+        );
+    }
+
+    #[test]
+    fn test_assign_columns() {
+        let input: String = std::iter::once("user,region".to_string())
+            .chain((0..200).map(|i| format!("u{},r{}", i % 50, i % 7)))
+            .map(|line| line + "\n")
+            .collect();
+        let output = run("-p 50 --csv --assign-columns user,region", &input);
+        assert_eq!(
+            output,
+            run("-p 50 --csv --assign-columns user,region", &input)
+        );
+
+        let mut lines = output.lines();
+        assert_eq!(
+            lines.next(),
+            Some("user,region,user_included,region_included")
+        );
+        let mut flags = std::collections::HashMap::new();
+        let mut differing = 0;
+        for line in lines {
+            let fields: Vec<&str> = line.split(',').collect();
+            // Every row with the same key gets the same flag
+            assert_eq!(
+                *flags.entry(("user", fields[0])).or_insert(fields[2]),
+                fields[2]
+            );
+            assert_eq!(
+                *flags.entry(("region", fields[1])).or_insert(fields[3]),
+                fields[3]
+            );
+            differing += usize::from(fields[2] != fields[3]);
+        }
+        assert!(differing > 0);
+
+        // Another seed reassigns the keys
+        let reseeded = run("-p 50 --csv --assign-columns user,region -s 1", &input);
+        assert_ne!(output, reseeded);
+    }
+
+    #[test]
+    fn test_encoding_decodes_input() {
+        let input: &[u8] = b"name,city\nJos\xE9,M\xE1laga\nZo\xEB,Gen\xE8ve\n";
+        let mut output = Vec::new();
+        let args = ["sample", "-p", "100", "--csv", "--encoding", "windows-1252"];
+        run_app(&args, Cursor::new(input), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name,city\nJosé,Málaga\nZoë,Genève\n"
+        );
+
+        let mut output = Vec::new();
+        let args = [
+            "sample",
+            "-p",
+            "100",
+            "--csv",
+            "--hash",
+            "name",
+            "--encoding",
+            "cp1252",
+        ];
+        run_app(&args, Cursor::new(input), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name,city\nJosé,Málaga\nZoë,Genève\n"
+        );
+    }
+
+    #[test]
+    fn test_one_selects_each_line_uniformly() {
+        let input = "id\n0\n1\n2\n3\n4\n";
+        let mut counts = [0; 5];
+        for seed in 0..1000 {
+            let output = run(&format!("--one --csv -s {}", seed), input);
+            let mut lines = output.lines();
+            assert_eq!(lines.next(), Some("id"));
+            counts[lines.next().unwrap().parse::<usize>().unwrap()] += 1;
+            assert_eq!(lines.next(), None);
+        }
+        assert!(
+            counts.iter().all(|&c| (140..260).contains(&c)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn test_manifest_line_numbers_reproduce_sample() {
+        let input: String = std::iter::once("id,value\n".to_string())
+            .chain((0..200).map(|i| format!("{},v{}\n", i, i % 7)))
+            .collect();
+        let original: Vec<&str> = input.lines().collect();
+        let manifest = tempfile::NamedTempFile::new().unwrap();
+        let path = manifest.path().to_str().unwrap();
+
+        for mode in ["20", "-p 30", "--first 5", "--one"] {
+            let output = run(
+                &format!("{} --csv --skip 3 -s 11 --manifest {}", mode, path),
+                &input,
+            );
+            let numbers = std::fs::read_to_string(path).unwrap();
+            let extracted: Vec<&str> = numbers
+                .lines()
+                .map(|n| original[n.parse::<usize>().unwrap() - 1])
+                .collect();
+            let sampled: Vec<&str> = output.lines().skip(1).collect();
+            assert!(!sampled.is_empty(), "{}", mode);
+            assert_eq!(extracted, sampled, "{}", mode);
+            assert_eq!(
+                output,
+                run(&format!("{} --csv --skip 3 -s 11", mode), &input)
+            );
+        }
+    }
+
+    #[test]
+    fn test_decision_out_flags_every_data_line() {
+        let input: String = std::iter::once("id\n".to_string())
+            .chain((0..200).map(|i| format!("{}\n", i)))
+            .collect();
+        let decisions = tempfile::NamedTempFile::new().unwrap();
+        let path = decisions.path().to_str().unwrap();
+
+        for mode in [
+            "20",
+            "-p 30",
+            "--first 5",
+            "--shuffle-all",
+            "5 --window-count 50",
+        ] {
+            let output = run(
+                &format!("{} --csv --skip 3 -s 11 --decision-out {}", mode, path),
+                &input,
+            );
+            let flags = std::fs::read_to_string(path).unwrap();
+            let flags: Vec<&str> = flags.lines().collect();
+            assert_eq!(flags.len(), 200, "{}", mode);
+
+            let mut selected: Vec<usize> = (0..200).filter(|&i| flags[i] == "1").collect();
+            let mut sampled: Vec<usize> = output
+                .lines()
+                .skip(1)
+                .map(|line| line.parse().unwrap())
+                .collect();
+            sampled.sort();
+            selected.sort();
+            assert!(!sampled.is_empty(), "{}", mode);
+            assert_eq!(selected, sampled, "{}", mode);
+            assert!(flags.iter().all(|&flag| flag == "0" || flag == "1"));
+            assert_eq!(
+                output,
+                run(&format!("{} --csv --skip 3 -s 11", mode), &input)
+            );
+        }
+    }
+
+    #[test]
+    fn test_decay_keeps_recent_lines_more_often() {
+        let input: String = (0..4000).map(|i| format!("{}\n", i)).collect();
+        let output = run("-p 80 --decay 0.001 -s 5", &input);
+        let mut kept = [0; 4];
+        for line in output.lines() {
+            kept[line.parse::<usize>().unwrap() / 1000] += 1;
+        }
+        // Expected rates per quarter: about 4%, 11%, 29% and 80% * 0.79
+        assert!(kept.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", kept);
+        assert!((400..600).contains(&kept[3]), "{:?}", kept);
+
+        // Without decay every quarter is kept at the same rate
+        let output = run("-p 80 --decay 0 -s 5", &input);
+        assert!((3000..3400).contains(&output.lines().count()));
+    }
+
+    #[test]
+    fn test_decay_by_time_column() {
+        let mut input = String::from("id,ts\n");
+        for i in 0..2000 {
+            // Out of order, so the age comes from the time column and not the position
+            let ts = if i % 2 == 0 {
+                1_000_000
+            } else {
+                1_000_000 - 3600
+            };
+            input.push_str(&format!("{},{}\n", i, ts));
+        }
+        let output = run("-p 100 --decay 0.0005 --csv --time-column ts -s 9", &input);
+        let mut lines = output.lines();
+        assert_eq!(lines.next(), Some("id,ts"));
+        let (recent, old): (Vec<&str>, Vec<&str>) =
+            lines.partition(|line| line.ends_with(",1000000"));
+        assert_eq!(recent.len(), 1000);
+        // exp(-0.0005 * 3600) is about 16.5%
+        assert!((110..220).contains(&old.len()), "{}", old.len());
+    }
+
+    #[test]
+    fn test_ignore_case_columns_resolves_mismatched_header() {
+        let input = "userid,Score\na,1\nb,2\n";
+        assert_eq!(
+            run("-p 100 --csv --hash UserId --ignore-case-columns", input),
+            input
+        );
+        assert_eq!(
+            run(
+                "--csv --expected-size 5 --weight-column score --ignore-case-columns",
+                input
+            )
+            .lines()
+            .count(),
+            3
+        );
+
+        let result = run_app(
+            &["sample", "-p", "100", "--csv", "--hash", "UserId"],
+            Cursor::new(input),
+            Vec::new(),
+        );
+        assert!(
+            matches!(result, Err(Error::ColumnNotFound(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_ignore_case_columns_rejects_ambiguous_match() {
+        let input = "userid,UserID\na,1\n";
+        let hashed = "-p 100 --csv --hash USERID --ignore-case-columns";
+        let weighted = "--csv --expected-size 1 --weight-column USERID --ignore-case-columns";
+        for cmd in [hashed, weighted] {
+            let args: Vec<&str> = std::iter::once("sample")
+                .chain(cmd.split_whitespace())
+                .collect();
+            let result = run_app(&args, Cursor::new(input), Vec::new());
+            match result {
+                Err(Error::AmbiguousColumn(message)) => assert_eq!(
+                    message,
+                    "'USERID' matches columns userid, UserID ignoring case"
+                ),
+                other => panic!("expected an ambiguous column error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_show_columns_lists_header_with_indices() {
+        let input = "id, first name ,score\n1,alice,3\n2,bob,4\n";
+        assert_eq!(
+            run("--csv --show-columns", input),
+            "0\tid\n1\tfirst name\n2\tscore\n"
+        );
+    }
+
+    #[test]
+    fn test_key_rate_overrides_percentage_for_listed_keys() {
+        let mut rates = tempfile::NamedTempFile::new().unwrap();
+        rates.write_all(b"hot,0\ncold,100\n").unwrap();
+        let rates = rates.path().to_str().unwrap();
+
+        let input = "user,n\nhot,1\ncold,2\nhot,3\nwarm,4\ncold,5\n";
+        let args = ["--csv", "--hash", "user", "--key-rate", rates];
+        assert_eq!(
+            run(&format!("-p 100 {}", args.join(" ")), input),
+            "user,n\ncold,2\nwarm,4\ncold,5\n"
+        );
+        assert_eq!(
+            run(&format!("-p 0 {}", args.join(" ")), input),
+            "user,n\ncold,2\ncold,5\n"
+        );
+    }
+
+    #[test]
+    fn test_key_rate_with_normalized_keys() {
+        let mut rates = tempfile::NamedTempFile::new().unwrap();
+        rates.write_all(b"HOT,0\ncold,100\n").unwrap();
+        let cmd = format!(
+            "-p 0 --csv --hash user --normalize lowercase --key-rate {}",
+            rates.path().display()
+        );
+
+        let input = "user,n\nhot,1\nCold,2\nHot,3\ncold,4\nwarm,5\n";
+        assert_eq!(run(&cmd, input), "user,n\nCold,2\ncold,4\n");
+    }
+
+    #[test]
+    fn test_key_rate_conflicting_after_normalization() {
+        let mut rates = tempfile::NamedTempFile::new().unwrap();
+        rates.write_all(b"a,0\nA,100\n").unwrap();
+        let path = rates.path().to_str().unwrap();
+        let args = [
+            "sample",
+            "-p",
+            "50",
+            "--csv",
+            "--hash",
+            "id",
+            "--normalize",
+            "lowercase",
+            "--key-rate",
+            path,
+        ];
+
+        let result = run_app(&args, Cursor::new("id\na\nA\n"), Vec::new());
+        assert!(
+            matches!(&result, Err(Error::IoError(e)) if e.kind() == io::ErrorKind::InvalidData),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_malformed_csv_reports_record_position() {
+        let input = b"id,name\n1,alice\n2,b\xFFb\n3,carol\n";
+        for mode in [
+            &["-p", "100", "--csv", "--hash", "id"][..],
+            &["-p", "100", "--csv", "--assign-columns", "id"],
+        ] {
+            let args: Vec<&str> = std::iter::once("sample")
+                .chain(mode.iter().copied())
+                .collect();
+            let result = run_app(&args, Cursor::new(&input[..]), Vec::new());
+            match result {
+                Err(Error::CsvError(e)) => {
+                    assert_eq!(e.position().map(|p| p.line()), Some(3), "{:?}", mode);
+                    assert!(e.to_string().contains("line 3"), "{}", e);
+                }
+                other => panic!("expected a CSV error for {:?}, got {:?}", mode, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_stats_out_summarizes_run() {
+        let input: String = std::iter::once("id\n".to_string())
+            .chain((0..100).map(|i| format!("{}\n", i)))
+            .collect();
+        let stats = tempfile::NamedTempFile::new().unwrap();
+        let path = stats.path().to_str().unwrap();
+
+        let output = run(&format!("-p 30 --csv -s 5 --stats-out {}", path), &input);
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let sampled = output.lines().count() as u64 - 1;
+        assert_eq!(json["total"], 100);
+        assert_eq!(json["sampled"], sampled);
+        assert_eq!(json["fraction"], sampled as f64 / 100.0);
+        assert_eq!(json["seed"], 5);
+        assert_eq!(json["mode"], "percentage");
+        assert!(json["elapsed_ms"].is_u64());
+
+        // Without --seed the summary reports the seed that was drawn
+        let first = run(&format!("7 --stats-out {}", path), &input);
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(json["mode"], "reservoir");
+        assert_eq!(json["total"], 101);
+        let seed = json["seed"].as_u64().unwrap();
+        assert_eq!(run(&format!("7 -s {}", seed), &input), first);
+    }
+
+    #[test]
+    fn test_stats_out_counts_lines_sampled_from() {
+        let input: String = (1..=100_000).map(|i| format!("{}\n", i)).collect();
+        let stats = tempfile::NamedTempFile::new().unwrap();
+        let path = stats.path().to_str().unwrap();
+        let total = |args: &str, input: &str| {
+            run(&format!("{} --stats-out {}", args, path), input);
+            let json: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+            json["total"].as_u64().unwrap()
+        };
+
+        // An early stop counts only the lines used, not what was buffered ahead
+        assert_eq!(total("--first 3", &input), 3);
+        assert_eq!(total("--first 3 --skip 4", &input), 3);
+        assert_eq!(total("-p 10 -s 1 --skip 10", &input), 99_990);
+        assert_eq!(total("-p 10 -s 1 --filter 5$", &input), 10_000);
+        assert_eq!(total("--csv --first 1", "id\n1\n2\n3\n"), 1);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_stats_out_counts_decompressed_lines() {
+        let input: String = (0..1000).map(|i| format!("{}\n", i)).collect();
+        let compressed = zstd::encode_all(input.as_bytes(), 0).unwrap();
+        let stats = tempfile::NamedTempFile::new().unwrap();
+        let path = stats.path().to_str().unwrap();
+
+        let args = ["sample", "-p", "10", "-s", "2", "--stats-out", path];
+        let mut output = Vec::new();
+        run_app(&args, Cursor::new(compressed), &mut output).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(json["total"], 1000);
+        assert_eq!(
+            json["sampled"],
+            output.iter().filter(|&&b| b == b'\n').count()
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_size_from_header_reads_decompressed_directive() {
+        let data: String = (0..100).map(|i| format!("{}\n", i)).collect();
+        let input = format!("#sample:5\n{}", data);
+        let compressed = zstd::encode_all(input.as_bytes(), 0).unwrap();
+
+        let args = ["sample", "--size-from-header", "-s", "3"];
+        let mut output = Vec::new();
+        run_app(&args, Cursor::new(compressed), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), run("5 -s 3", &data));
+    }
+
+    #[test]
+    fn test_log_invocation_appends_a_line_per_run() {
+        let input: String = (0..20).map(|i| format!("{}\n", i)).collect();
+        let log = tempfile::NamedTempFile::new().unwrap();
+        let path = log.path().to_str().unwrap();
+
+        let output = run(&format!("-p 50 -s 3 --log-invocation {}", path), &input);
+        let first = run(&format!("4 --log-invocation {}", path), &input);
+
+        let log = std::fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let (time, fields) = lines[0].split_once(' ').unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(time).is_ok());
+        assert_eq!(
+            fields,
+            format!(
+                "seed=3 mode=percentage total=20 sampled={} \
+                 argv=[\"sample\",\"-p\",\"50\",\"-s\",\"3\",\"--log-invocation\",{:?}]",
+                output.lines().count(),
+                path
+            )
+        );
+
+        // Without --seed the line records the seed that was drawn
+        let seed = lines[1]
+            .split(' ')
+            .find_map(|field| field.strip_prefix("seed="))
+            .unwrap();
+        assert!(lines[1].contains(" mode=reservoir total=20 sampled=4 "));
+        assert_eq!(run(&format!("4 -s {}", seed), &input), first);
+    }
+
+    #[test]
+    fn test_log_invocation_counts_lines_sampled_from() {
+        let input: String = (0..100_000).map(|i| format!("{}\n", i)).collect();
+        let log = tempfile::NamedTempFile::new().unwrap();
+        let path = log.path().to_str().unwrap();
+
+        run(&format!("--first 3 --log-invocation {}", path), &input);
+        run(
+            &format!("-p 50 -s 3 --filter 7$ --log-invocation {}", path),
+            &input,
+        );
+
+        let log = std::fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert!(lines[0].contains(" mode=first total=3 sampled=3 "));
+        assert!(lines[1].contains(" mode=percentage total=10000 "));
+    }
+
+    #[test]
+    fn test_stratify_keys_tracks_key_fraction() {
+        let input: String = std::iter::once("id,row\n".to_string())
+            .chain((0..3000).map(|i| format!("k{},{}\n", i % 1000, i)))
+            .collect();
+        let output = run("-p 12.5 --csv --hash id --stratify-keys", &input);
+        let keys: std::collections::HashSet<&str> = output
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap())
+            .collect();
+        assert!((115..=135).contains(&keys.len()), "{}", keys.len());
+        assert_eq!(output.lines().count(), 1 + 3 * keys.len());
+        assert_eq!(
+            run("-p 12.5 --csv --hash id --stratify-keys", &input),
+            output
+        );
+    }
+
+    #[test]
+    fn test_pretty_aligns_columns() {
+        let input = "id,name,city\n1,Al,Paris\n22,Bartholomew Montgomery,Rome\n";
+        let output = run(
+            "--shuffle-all --sort --csv --pretty --pretty-width 12",
+            input,
+        );
+        assert_eq!(
+            output,
+            "id  name          city\n\
+             1   Al            Paris\n\
+             22  Bartholomew…  Rome\n"
+        );
+    }
+
+    #[test]
+    fn test_size_from_header_directive() {
+        let data: String = (0..100).map(|i| format!("{}\n", i)).collect();
+
+        let output = run("--size-from-header -s 3", &format!("#sample:5\n{}", data));
+        assert_eq!(output, run("5 -s 3", &data));
+        assert_eq!(output.lines().count(), 5);
+
+        let output = run("--size-from-header -s 3", &format!("#sample:20%\n{}", data));
+        assert_eq!(output, run("-p 20 -s 3", &data));
+
+        let csv = format!("#sample:3\nid\n{}", data);
+        let output = run("--size-from-header --csv -s 3", &csv);
+        assert!(output.starts_with("id\n"));
+        assert_eq!(output.lines().count(), 4);
+
+        // The directive is read after the byte order mark is stripped, and not filtered
+        let output = run(
+            "--size-from-header -s 3",
+            &format!("\u{feff}#sample:5\n{}", data),
+        );
+        assert_eq!(output, run("5 -s 3", &data));
+        let output = run(
+            "--size-from-header --filter 1 -s 3",
+            &format!("#sample:5\n{}", data),
+        );
+        assert_eq!(output, run("5 --filter 1 -s 3", &data));
+
+        let mut output = Vec::new();
+        let result = run_app(
+            &["sample", "--size-from-header"],
+            Cursor::new("1\n2\n"),
+            &mut output,
+        );
+        assert!(matches!(result, Err(Error::InvalidDirective(line)) if line == "1"));
+    }
+
+    #[test]
+    fn test_record_separator_samples_whole_records() {
+        let input: String = (0..50)
+            .map(|i| format!("record {}\nline two of {}\nline three of {}\n\n", i, i, i))
+            .collect();
+        for mode in ["7", "-p 30", "--first 4", "--one", "--shuffle-all"] {
+            let output = run(&format!("{} -s 9 --record-separator \\n\\n", mode), &input);
+            let records: Vec<&str> = output.split_terminator("\n\n").collect();
+            assert!(!records.is_empty(), "{}", mode);
+            for record in records {
+                let lines: Vec<&str> = record.lines().collect();
+                let i = lines[0].strip_prefix("record ").unwrap();
+                assert_eq!(
+                    lines,
+                    [
+                        format!("record {}", i),
+                        format!("line two of {}", i),
+                        format!("line three of {}", i)
+                    ],
+                    "{}",
+                    mode
+                );
+            }
+        }
+
+        let output = run("--first 2 --record-separator \\n\\n", &input);
+        assert_eq!(
+            output,
+            "record 0\nline two of 0\nline three of 0\n\nrecord 1\nline two of 1\nline three of 1\n\n"
+        );
+    }
+
+    #[test]
+    fn test_emit_hash_appends_key_hash() {
+        let input: String = std::iter::once("id,value\n".to_string())
+            .chain((0..200).map(|i| format!(" User{} ,{}\n", i % 40, i)))
+            .collect();
+        let output = run(
+            "-p 50 --csv --hash id --normalize trim+lowercase --emit-hash",
+            &input,
+        );
+        let mut lines = output.lines();
+        assert_eq!(lines.next(), Some("id,value,hash"));
+
+        let mut rows = 0;
+        for line in lines {
+            let fields: Vec<&str> = line.split(',').collect();
+            let key = fields[0].trim().to_lowercase();
+            assert_eq!(fields[2], format!("{:016x}", sample::key_hash(&key)));
+            assert!(sample::should_include(&key, 0.5, 0));
+            rows += 1;
+        }
+        assert!(rows > 0);
+    }
+
+    #[test]
+    fn test_spill_dir_matches_in_memory_sample() {
+        let dir = tempfile::tempdir().unwrap();
+        let input: String = std::iter::once("id,value\n".to_string())
+            .chain((0..500).map(|i| format!("{},value {}\n", i, i)))
+            .collect();
+        for mode in ["20 -s 4", "20 -s 4 --csv", "20 -s 4 --skip 7"] {
+            let spilled = run(
+                &format!(
+                    "{} --spill-dir {} --limit-memory 30",
+                    mode,
+                    dir.path().display()
+                ),
+                &input,
+            );
+            assert_eq!(spilled, run(mode, &input), "{}", mode);
+        }
+    }
+
+    #[test]
+    fn test_hash_regex_resolves_column() {
+        let input: String = std::iter::once("id,user_id_v2,value\n".to_string())
+            .chain((0..100).map(|i| format!("{},u{},{}\n", i, i % 13, i)))
+            .collect();
+        let pins = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(pins.path(), "u3\n").unwrap();
+        let pin_keys = format!("2 -s 1 --pin-keys {}", pins.path().display());
+        for args in [
+            "-p 40",
+            "-p 40 --stratify-keys",
+            "--count-keys",
+            "-p 40 --probability-report",
+            pin_keys.as_str(),
+        ] {
+            assert_eq!(
+                run(
+                    &format!("{} --csv --hash-regex ^user_id_v\\d+$", args),
+                    &input
+                ),
+                run(&format!("{} --csv --hash user_id_v2", args), &input),
+                "{}",
+                args
+            );
+        }
+
+        for (pattern, expected) in [("^account", "not found"), ("id", "ambiguous")] {
+            let mut output = Vec::new();
+            let args = ["sample", "-p", "40", "--csv", "--hash-regex", pattern];
+            let result = run_app(&args, Cursor::new(input.as_str()), &mut output);
+            match (result, expected) {
+                (Err(Error::ColumnNotFound(name)), "not found") => assert_eq!(name, "^account"),
+                (Err(Error::AmbiguousColumn(message)), "ambiguous") => {
+                    assert_eq!(message, "pattern 'id' matches columns id, user_id_v2")
+                }
+                (other, _) => panic!("{}: {:?}", pattern, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_numeric_key_groups_equal_numbers() {
+        let input: String = std::iter::once("id,v\n".to_string())
+            .chain((0..60).map(|i| {
+                let id = match i % 3 {
+                    0 => format!("{}", i / 3),
+                    1 => format!("0{}", i / 3),
+                    _ => format!("{}.0", i / 3),
+                };
+                format!("{},{}\n", id, i)
+            }))
+            .collect();
+        let output = run("-p 50 --csv --hash id --numeric-key", &input);
+        let rows: Vec<&str> = output.lines().skip(1).collect();
+        // Every number is kept in all three spellings or in none
+        assert_eq!(rows.len() % 3, 0);
+        assert!(!rows.is_empty() && rows.len() < 60);
+        for row in &rows {
+            let id: f64 = row.split(',').next().unwrap().parse().unwrap();
+            let spellings = rows
+                .iter()
+                .filter(|other| other.split(',').next().unwrap().parse::<f64>().unwrap() == id)
+                .count();
+            assert_eq!(spellings, 3, "{}", row);
+        }
+
+        let input = "id,v\n1,a\nabc,b\n";
+        let args = [
+            "sample",
+            "-p",
+            "100",
+            "--csv",
+            "--hash",
+            "id",
+            "--numeric-key",
+        ];
+        let result = run_app(&args, Cursor::new(input), Vec::new());
+        assert!(
+            matches!(&result, Err(Error::NonNumericKey(column, value, 3))
+                if column == "id" && value == "abc"),
+            "{:?}",
+            result
+        );
+        assert_eq!(
+            run(
+                "-p 100 --csv --hash id --numeric-key --numeric-key-fallback",
+                input
+            ),
+            input
+        );
+    }
+
+    #[test]
+    fn test_header_auto_detection() {
+        // A text header over numeric rows is kept on top of the sample
+        let headed: String = std::iter::once("id,score\n".to_string())
+            .chain((0..50).map(|i| format!("{},{}\n", i, i * 3)))
+            .collect();
+        let output = run("-p 40 --seed 1 --header auto", &headed);
+        assert!(output.starts_with("id,score\n"), "{}", output);
+        assert_eq!(output, run("-p 40 --seed 1 --csv", &headed));
+
+        // Numeric rows only: the first line is data and is sampled like the rest
+        let headerless: String = (0..50).map(|i| format!("{},{}\n", i, i * 3)).collect();
+        assert_eq!(
+            run("-p 40 --seed 1 --header auto", &headerless),
+            run("-p 40 --seed 1", &headerless)
+        );
+        assert_eq!(run("5 --header auto", "5,15\n"), "5,15\n");
+
+        // A single-column file has no delimiter but can still start with a header
+        assert_eq!(run("5 --header auto", "price\n2\n4\n"), "price\n2\n4\n");
+        assert_eq!(
+            run("-p 40 --seed 1 --csv --header auto --hash id", &headed),
+            run("-p 40 --seed 1 --csv --hash id", &headed)
+        );
+        let result = run_app(
+            &[
+                "sample", "-p", "40", "--csv", "--header", "auto", "--hash", "id",
+            ],
+            Cursor::new(headerless),
+            Vec::new(),
+        );
+        assert!(matches!(result, Err(Error::UnexpectedColumns(_))));
+    }
+
+    #[test]
+    fn test_sample_columns_keeps_same_columns() {
+        let header = "c0,c1,c2,c3,c4,c5,c6,c7";
+        let input: String = std::iter::once(format!("{}\n", header))
+            .chain((0..30).map(|row| {
+                let fields: Vec<String> = (0..8).map(|col| format!("r{}c{}", row, col)).collect();
+                format!("{}\n", fields.join(","))
+            }))
+            .collect();
+
+        for seed in 0..5 {
+            let output = run(&format!("--csv --sample-columns 3 --seed {}", seed), &input);
+            let mut lines = output.lines();
+            let kept: Vec<&str> = lines.next().unwrap().split(',').collect();
+            assert_eq!(kept.len(), 3);
+            assert!(kept.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", kept);
+
+            let rows: Vec<&str> = lines.collect();
+            assert_eq!(rows.len(), 30);
+            for (row, line) in rows.iter().enumerate() {
+                let expected: Vec<String> =
+                    kept.iter().map(|col| format!("r{}{}", row, col)).collect();
+                assert_eq!(*line, expected.join(","));
+            }
+        }
+
+        assert_eq!(run("--csv --sample-columns 10", "a,b\n1,2\n"), "a,b\n1,2\n");
+    }
+
+    /// A reader that serves the header line and fails any read past it
+    struct HeaderOnlyReader(Cursor<&'static str>);
+
+    impl Read for HeaderOnlyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.position() == self.0.get_ref().len() as u64 {
+                return Err(io::Error::other("a data row was read"));
+            }
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_dry_validate_reads_only_the_header() {
+        let header = || HeaderOnlyReader(Cursor::new("id,weight,ts\n"));
+        let mut output = Vec::new();
+        let args = [
+            "sample",
+            "-p",
+            "10",
+            "--csv",
+            "--hash",
+            "id",
+            "--dry-validate",
+        ];
+        run_app(&args, header(), &mut output).unwrap();
+        assert!(output.is_empty());
+
+        let args = [
+            "sample",
+            "--csv",
+            "--expected-size",
+            "5",
+            "--weight-column",
+            "weight",
+            "--dry-validate",
+        ];
+        run_app(&args, header(), &mut output).unwrap();
+
+        let args = [
+            "sample",
+            "-p",
+            "10",
+            "--csv",
+            "--hash",
+            "user",
+            "--dry-validate",
+        ];
+        let result = run_app(&args, header(), &mut output);
+        assert!(matches!(result, Err(Error::ColumnNotFound(name)) if name == "user"));
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_target_count_keeps_about_n_lines() {
+        let input: String = (0..10_000).map(|i| format!("{}\n", i)).collect();
+        let output = run("-p 50 --target-count 100 --seed 42", &input);
+        let count = output.lines().count();
+        assert!((95..=100).contains(&count), "{}", count);
+
+        // The rate falls to zero at the target, so a long input never yields more
+        let output = run("-p 100 --target-count 3 --seed 42", &input);
+        assert_eq!(output.lines().count(), 3);
+    }
+
+    #[test]
+    fn test_window_count_samples_last_lines() {
+        let input: String = std::iter::once("n\n".to_string())
+            .chain((0..100).map(|i| format!("{}\n", i)))
+            .collect();
+        let output = run("3 --csv --window-count 10 -s 1", &input);
+        let mut lines = output.lines();
+        assert_eq!(lines.next(), Some("n"));
+        let sample: Vec<u32> = lines.map(|line| line.parse().unwrap()).collect();
+        assert_eq!(sample.len(), 3);
+        assert!(sample.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(sample.iter().all(|&n| n >= 90), "{:?}", sample);
+    }
+
+    #[test]
+    fn test_merge_sorted_keeps_global_order() {
+        let mut even = tempfile::NamedTempFile::new().unwrap();
+        let mut odd = tempfile::NamedTempFile::new().unwrap();
+        writeln!(even, "key,file").unwrap();
+        writeln!(odd, "key,file").unwrap();
+        for i in 0..50 {
+            writeln!(even, "k{:03},even", 2 * i).unwrap();
+            writeln!(odd, "k{:03},odd", 2 * i + 1).unwrap();
+        }
+        let paths = format!(
+            "{},{}",
+            even.path().to_str().unwrap(),
+            odd.path().to_str().unwrap()
+        );
+
+        let merged = run(
+            &format!("-p 100 --csv --sort-column key --merge-sorted {}", paths),
+            "",
+        );
+        let expected: String = std::iter::once("key,file\n".to_string())
+            .chain((0..100).map(|i| {
+                let file = if i % 2 == 0 { "even" } else { "odd" };
+                format!("k{:03},{}\n", i, file)
+            }))
+            .collect();
+        assert_eq!(merged, expected);
+
+        for mode in ["-p 30", "20"] {
+            let output = run(
+                &format!(
+                    "{} --csv -s 9 --sort-column key --merge-sorted {}",
+                    mode, paths
+                ),
+                "",
+            );
+            let mut lines = output.lines();
+            assert_eq!(lines.next(), Some("key,file"));
+            let keys: Vec<&str> = lines.map(|line| &line[..4]).collect();
+            assert!(keys.len() > 10, "{}: {:?}", mode, keys);
+            assert!(keys.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", keys);
+            assert!(output.contains(",even\n") && output.contains(",odd\n"));
+        }
+    }
+
+    #[test]
+    fn test_missing_trailing_newline_is_handled_alike_in_every_mode() {
+        let terminated = "id,v\n1,a\n2,b\n3,c\n4,d\n";
+        let unterminated = terminated.trim_end();
+        let modes = [
+            "10",
+            "-p 100",
+            "--first 10",
+            "--first 2",
+            "-p 100 --target-count 1",
+            "-p 100 --max-records 1",
+            "--shuffle-all -s 1",
+            "--one -s 1",
+            "10 --sort",
+            "10 --csv",
+            "--csv --first 1",
+            "-p 100 --csv --hash id",
+            "-p 100 --csv --hash id --max-records 1",
+            "--csv --assign id:2",
+            "10 --csv --window-count 3",
+            "--csv --sample-columns 1 -s 1",
+            "--csv --percentile-column id --percentile-range 0:100",
+            "--csv --expected-size 10 --weight-column id",
+            "-p 100 --whitespace-split --hash-index 0",
+        ];
+        for mode in modes {
+            let expected = run(mode, terminated);
+            assert!(expected.ends_with('\n'), "{}", mode);
+            assert_eq!(run(mode, unterminated), expected, "{}", mode);
+
+            let strict = format!("{} --require-trailing-newline", mode);
+            assert_eq!(run(&strict, terminated), expected, "{}", mode);
+            let args: Vec<&str> = std::iter::once("sample")
+                .chain(strict.split_whitespace())
+                .collect();
+            let mut output = Vec::new();
+            let result = run_app(&args, Cursor::new(unterminated), &mut output);
+            assert!(
+                matches!(result, Err(Error::MissingTrailingNewline(5))),
+                "{}: {:?}",
+                mode,
+                result
+            );
+        }
+    }
+
+    /// A reader that fails `burst` reads in a row before each read that succeeds, which
+    /// returns at most 4 bytes. The failures time out unless given another kind.
+    struct FlakyReader {
+        data: Cursor<Vec<u8>>,
+        burst: u32,
+        failed: u32,
+        kind: io::ErrorKind,
+    }
+
+    impl FlakyReader {
+        fn new(data: &str, burst: u32) -> Self {
+            FlakyReader {
+                data: Cursor::new(data.as_bytes().to_vec()),
+                burst,
+                failed: 0,
+                kind: io::ErrorKind::TimedOut,
+            }
+        }
+
+        fn failing_with(mut self, kind: io::ErrorKind) -> Self {
+            self.kind = kind;
+            self
+        }
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.failed < self.burst {
+                self.failed += 1;
+                return Err(io::Error::new(self.kind, "read failed"));
+            }
+            self.failed = 0;
+            let len = buf.len().min(4);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_retrying_reader_recovers_from_transient_errors() {
+        let mut data = String::new();
+        RetryingReader::new(FlakyReader::new("a\nb\n", 3), 3)
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "a\nb\n");
+    }
+
+    #[test]
+    fn test_retrying_reader_gives_up_after_retries() {
+        let mut data = String::new();
+        let error = RetryingReader::new(FlakyReader::new("a\n", 3), 2)
+            .read_to_string(&mut data)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "read failed");
+    }
+
+    #[test]
+    fn test_retrying_reader_returns_permanent_errors_at_once() {
+        for kind in [
+            io::ErrorKind::InvalidData,
+            io::ErrorKind::NotFound,
+            io::ErrorKind::PermissionDenied,
+        ] {
+            let mut data = String::new();
+            let error = RetryingReader::new(FlakyReader::new("a\n", 1).failing_with(kind), 3)
+                .read_to_string(&mut data)
+                .unwrap_err();
+            assert_eq!(error.kind(), kind);
+        }
+    }
+
+    #[test]
+    fn test_retry_completes_sample_despite_transient_errors() {
+        let input = "id,name\n1,alice\n2,bob\n3,carol\n";
+        for args in [
+            vec!["sample", "-p", "100", "--retry", "1"],
+            vec![
+                "sample", "-p", "100", "--csv", "--hash", "id", "--retry", "1",
+            ],
+        ] {
+            let mut output = Vec::new();
+            run_app(&args, FlakyReader::new(input, 1), &mut output).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn test_read_errors_without_retry_are_io_errors() {
+        let reader = FlakyReader::new("id\n1\n", 1);
+        let args = [
+            "sample", "-p", "100", "--csv", "--hash", "id", "--retry", "0",
+        ];
+        let result = run_app(&args, reader, Vec::new());
+        assert!(matches!(result, Err(Error::IoError(_))), "{:?}", result);
+    }
+
+    /// A reader that panics if it is read after its data has been consumed
+    struct ExhaustibleReader(Cursor<&'static str>);
+
+    impl Read for ExhaustibleReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.read(buf)?;
+            assert!(n > 0, "reader was read past the requested lines");
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_first_lines_stops_reading() {
+        let input = ExhaustibleReader(Cursor::new("0\n1\n2\n"));
+        let mut output = Vec::new();
+
+        run_app(&["sample", "--first", "2"], input, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "0\n1\n");
+    }
+
+    #[test]
+    fn test_max_records_caps_every_mode() {
+        let input: String = std::iter::once("id,v\n".to_string())
+            .chain((0..50).map(|i| format!("{},{}\n", i, i % 3)))
+            .collect();
+        for mode in [
+            "20",
+            "-p 100",
+            "--first 10",
+            "--shuffle-all",
+            "-p 100 --hash id",
+            "--assign v:2",
+            "-p 100 --sort",
+            "--expected-size 40 --weight-column v",
+        ] {
+            let output = run(&format!("{} --csv --max-records 3 -s 1", mode), &input);
+            let mut lines = output.lines();
+            assert!(lines.next().unwrap().starts_with("id,v"), "{}", mode);
+            assert_eq!(lines.count(), 3, "{}", mode);
+        }
+        assert_eq!(run("--first 2 --max-records 5", "a\nb\nc\n"), "a\nb\n");
+        assert_eq!(run("-p 100 --max-records 0", "a\nb\n"), "");
+    }
+
+    #[test]
+    fn test_max_records_counts_records_not_line_feeds() {
+        let input = "id,v\n1,\"a\nb\"\n2,c\n3,d\n";
+        for mode in [
+            "-p 100 --hash id",
+            "-p 100 --hash id --sort",
+            "--assign v:2",
+        ] {
+            let output = run(
+                &format!("{} --csv --output-delimiter , --max-records 2 -s 1", mode),
+                input,
+            );
+            let records: Vec<_> = csv::ReaderBuilder::new()
+                .from_reader(output.as_bytes())
+                .records()
+                .collect::<std::result::Result<_, _>>()
+                .unwrap();
+            assert_eq!(records.len(), 2, "{}", mode);
+        }
+    }
+
+    #[test]
+    fn test_max_records_stops_reading() {
+        let input = ExhaustibleReader(Cursor::new("0\n1\n2\n"));
+        let mut output = Vec::new();
+        run_app(
+            &["sample", "-p", "100", "--max-records", "2"],
+            input,
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "0\n1\n");
+
+        let input = ExhaustibleReader(Cursor::new("id\n0\n1\n2\n"));
+        let mut output = Vec::new();
+        let args = [
+            "sample",
+            "-p",
+            "100",
+            "--csv",
+            "--hash",
+            "id",
+            "--max-records",
+            "2",
+        ];
+        run_app(&args, input, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "id\n0\n1\n");
+    }
+
+    #[test]
+    fn test_replicates_write_numbered_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("out_{i}.txt");
+        let input: String = (0..100).map(|i| format!("{}\n", i)).collect();
+        let cmd = format!(
+            "5 --seed 7 --replicates 3 --output-template {}",
+            template.display()
+        );
+
+        assert_eq!(run(&cmd, &input), "");
+        let read = |i: usize| std::fs::read_to_string(dir.path().join(format!("out_{}.txt", i)));
+        let first_run: Vec<String> = (0..3).map(|i| read(i).unwrap()).collect();
+        assert!(read(3).is_err());
+
+        // Each replicate is reproducible from the base seed
+        run(&cmd, &input);
+        let second_run: Vec<String> = (0..3).map(|i| read(i).unwrap()).collect();
+        assert_eq!(first_run, second_run);
+
+        // Replicate i matches a plain run seeded with base + i
+        assert_eq!(first_run[1], run("5 --seed 8", &input));
+
+        // Different replicates draw different samples
+        assert_ne!(first_run[0], first_run[1]);
+        assert_ne!(first_run[1], first_run[2]);
+    }
+
+    #[test]
+    fn test_round_robin_splits_into_balanced_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("part_{i}.csv");
+        let input: String = std::iter::once("id,group\n".to_string())
+            .chain((0..100).map(|i| format!("{},g{}\n", i, i % 10)))
+            .collect();
+        let read = |i: usize| std::fs::read_to_string(dir.path().join(format!("part_{}.csv", i)));
+
+        let cmd = format!(
+            "--csv --round-robin 3 --output-template {}",
+            template.display()
+        );
+        assert_eq!(run(&cmd, &input), "");
+        let mut rows = Vec::new();
+        for i in 0..3 {
+            let shard = read(i).unwrap();
+            let mut lines = shard.lines();
+            assert_eq!(lines.next(), Some("id,group"));
+            let shard_rows: Vec<String> = lines.map(String::from).collect();
+            assert!(
+                (33..=34).contains(&shard_rows.len()),
+                "{}",
+                shard_rows.len()
+            );
+            rows.extend(shard_rows);
+        }
+        assert!(read(3).is_err());
+        rows.sort_by_key(|row| row.split(',').next().unwrap().parse::<usize>().unwrap());
+        assert_eq!(rows, input.lines().skip(1).collect::<Vec<_>>());
+
+        // By key, every group lands in exactly one shard
+        let cmd = format!(
+            "--csv --hash group --round-robin 3 --output-template {}",
+            template.display()
+        );
+        run(&cmd, &input);
+        let shards: Vec<String> = (0..3).map(|i| read(i).unwrap()).collect();
+        assert_eq!(
+            shards
+                .iter()
+                .map(|shard| shard.lines().count() - 1)
+                .sum::<usize>(),
+            100
+        );
+        for group in 0..10 {
+            let suffix = format!(",g{}", group);
+            let holding = shards
+                .iter()
+                .filter(|shard| shard.lines().any(|line| line.ends_with(&suffix)))
+                .count();
+            assert_eq!(holding, 1, "g{}", group);
+        }
+    }
+
+    #[test]
+    fn test_skip_in_every_mode() {
+        let input = "h\n0\n1\n2\n3\n4\n";
+        assert_eq!(run("10 --csv --skip 3", input), "h\n3\n4\n");
+        assert_eq!(run("-p 100 --csv --skip 3", input), "h\n3\n4\n");
+        assert_eq!(run("-p 100 --csv --hash h --skip 3", input), "h\n3\n4\n");
+        assert_eq!(run("--first 10 --skip 4", input), "3\n4\n");
+    }
+
+    #[test]
+    fn test_assign_buckets() {
+        let input = "id,v\na,1\nb,2\na,3\nc,4\n";
+        let result = run("--csv --assign id:4", input);
+
+        let mut lines = result.lines();
+        assert_eq!(lines.next(), Some("id,v,bucket"));
+        let rows: Vec<Vec<&str>> = lines.map(|l| l.split(',').collect()).collect();
+        assert_eq!(rows.len(), 4);
+        for row in &rows {
+            assert_eq!(row[2], bucket_for(row[0], 4).to_string());
+        }
+        // The same key always lands in the same bucket
+        assert_eq!(rows[0][2], rows[2][2]);
+    }
+
+    #[test]
+    fn test_whitespace_split_hash_sampling() {
+        let input = "t1  u1 x\nt2\tu2 y\nt3 u1\t\tz\nt4   u2 w\n";
+        let all = run("-p 100 --whitespace-split --hash-index 1", input);
+        assert_eq!(all, input);
+
+        let half = run("-p 50 --whitespace-split --hash-index 1", input);
+        for user in ["u1", "u2"] {
+            let count = half.lines().filter(|l| l.contains(user)).count();
+            assert!(count == 0 || count == 2);
+        }
+        assert_eq!(half, run("-p 50 --whitespace-split --hash-index 1", input));
+    }
+
+    #[test]
+    fn test_weighted_sampling_hits_expected_size() {
+        let mut input = String::from("id,weight\n");
+        for i in 1..=5000 {
+            input.push_str(&format!("{},{}\n", i, i % 10 + 1));
+        }
+
+        for cmd in [
+            "--csv --expected-size 300 --weight-column weight --seed 42",
+            "--csv --expected-size 300 --weight-column weight --total-weight 27500 --seed 42",
+        ] {
+            let result = run(cmd, &input);
+            let sampled = result.lines().count() - 1;
+            assert!(sampled > 250 && sampled < 350, "{}: {}", cmd, sampled);
+        }
+    }
+
+    #[test]
+    fn test_weighted_sampling_rejects_invalid_weight() {
+        let args = [
+            "sample",
+            "--csv",
+            "--expected-size",
+            "1",
+            "--weight-column",
+            "w",
+        ];
+        let result = run_app(&args, Cursor::new("id,w\n1,abc\n"), Vec::new());
+        assert!(matches!(result, Err(Error::InvalidWeight(v)) if v == "abc"));
+    }
+
+    #[test]
+    fn test_percentile_band() {
+        // Values 1..=100 in shuffled order, so value v has percentile rank v - 1
+        let input: String = std::iter::once("id,v\n".to_string())
+            .chain((0..100).map(|i| format!("r{},{}\n", i, (i * 37) % 100 + 1)))
+            .collect();
+        let band = |range: &str| -> Vec<u32> {
+            let output = run(
+                &format!("--csv --percentile-column v --percentile-range {}", range),
+                &input,
+            );
+            let mut values: Vec<u32> = output
+                .lines()
+                .skip(1)
+                .map(|line| line.split(',').nth(1).unwrap().parse().unwrap())
+                .collect();
+            values.sort_unstable();
+            values
+        };
+        assert_eq!(band("95:100"), vec![96, 97, 98, 99, 100]);
+        assert_eq!(band("0:3"), vec![1, 2, 3]);
+        assert_eq!(band("49.5:52"), vec![51, 52]);
+
+        // Tied values share a rank: a quarter of the values lie below each 2
+        let output = run(
+            "--csv --percentile-column v --percentile-range 25:50",
+            "id,v\na,1\nb,2\nc,2\nd,3\n",
+        );
+        assert_eq!(output, "id,v\nb,2\nc,2\n");
+
+        let args = [
+            "sample",
+            "--csv",
+            "--percentile-column",
+            "v",
+            "--percentile-range",
+            "0:50",
+        ];
+        let result = run_app(&args, Cursor::new("id,v\n1,3\n2,n/a\n"), Vec::new());
+        assert!(matches!(result, Err(Error::InvalidNumber(v)) if v == "n/a"));
+    }
+
+    #[test]
+    fn test_bom_prefixed_header_resolves_hash_column() {
+        let result = run("-p 100 --csv --hash id", "\u{feff}id,v\n1,a\n2,b\n");
+        assert_eq!(result, "id,v\n1,a\n2,b\n");
+    }
+
+    #[test]
+    fn test_bom_is_stripped_in_line_mode() {
+        let result = run("--first 1 --csv", "\u{feff}id,v\n1,a\n");
+        assert_eq!(result, "id,v\n1,a\n");
+    }
+
+    #[test]
+    fn test_filter_only_samples_matching_lines() {
+        let input = "ERROR a\nINFO b\nERROR c\nINFO d\nERROR e\n";
+        assert_eq!(
+            run("10 --filter ^ERROR", input),
+            "ERROR a\nERROR c\nERROR e\n"
+        );
+        assert_eq!(run("2 --filter ^ERROR --seed 1", input).lines().count(), 2);
+        assert!(!run("-p 50 --filter ^ERROR --seed 1", input).contains("INFO"));
+        assert_eq!(run("--first 1 --filter ^INFO", input), "INFO b\n");
+    }
+
+    #[test]
+    fn test_filter_bypasses_csv_header() {
+        let input = "level,msg\nERROR,a\nINFO,b\nERROR,c\n";
+        assert_eq!(
+            run("-p 100 --csv --hash msg --filter ^ERROR", input),
+            "level,msg\nERROR,a\nERROR,c\n"
+        );
+        assert_eq!(run("10 --csv --filter ^INFO", input), "level,msg\nINFO,b\n");
+    }
+
+    #[test]
+    fn test_embed_seed_precedes_header() {
+        let result = run("1 --csv --seed 42 --embed-seed", "a,b\n0,0\n1,1\n");
+        assert_eq!(result, "# seed=42\na,b\n0,0\n");
+
+        let result = run(
+            "-p 100 --csv --hash a --embed-seed --comment-char %",
+            "a\n0\n",
+        );
+        let mut lines = result.lines();
+        let comment = lines.next().unwrap();
+        assert!(comment.starts_with("% seed="));
+        assert!(comment["% seed=".len()..].parse::<u64>().is_ok());
+        assert_eq!(lines.collect::<Vec<_>>(), vec!["a", "0"]);
+    }
+
+    #[test]
+    fn test_embedded_seed_reproduces_output() {
+        let input: String = (0..100).map(|i| format!("{}\n", i)).collect();
+        let result = run("5 --embed-seed", &input);
+        let seed = result
+            .lines()
+            .next()
+            .unwrap()
+            .strip_prefix("# seed=")
+            .unwrap();
+
+        let rerun = run(&format!("5 --seed {}", seed), &input);
+        assert_eq!(
+            result.lines().skip(1).collect::<Vec<_>>(),
+            rerun.lines().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_expect_columns_in_every_csv_path() {
+        let input = "a,b,c\n1,2,3\n";
+        for mode in ["10 --csv", "-p 100 --csv --hash a", "--csv --assign a:2"] {
+            let ok = run(&format!("{} --expect-columns a,b,c", mode), input);
+            assert!(ok.starts_with("a,b,c"));
+
+            let args: Vec<String> = format!("sample {} --expect-columns a,c,b", mode)
+                .split_whitespace()
+                .map(String::from)
+                .collect();
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            let result = run_app(&args, Cursor::new(input), Vec::new());
+            assert!(
+                matches!(result, Err(Error::UnexpectedColumns(_))),
+                "{}",
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn test_no_header_output_drops_header_only() {
+        let input = "id,v\n1,a\n2,b\n3,c\n";
+        assert_eq!(
+            run("-p 100 --csv --no-header-output", input),
+            "1,a\n2,b\n3,c\n"
+        );
+        assert_eq!(
+            run("-p 100 --csv --hash id --no-header-output", input),
+            "1,a\n2,b\n3,c\n"
+        );
+
+        // Sampled rows match the run with the header echoed
+        for mode in ["2 --csv --seed 7", "-p 50 --csv --hash id"] {
+            let with_header = run(mode, input);
+            let without_header = run(&format!("{} --no-header-output", mode), input);
+            assert_eq!(
+                with_header.strip_prefix("id,v\n"),
+                Some(without_header.as_str())
+            );
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_sampling_zstd_input() {
+        let compressed = zstd::encode_all(&b"id,v\n1,a\n2,b\n"[..], 0).unwrap();
+        let mut output = Vec::new();
+        run_app(
+            &["sample", "10", "--csv"],
+            compressed.as_slice(),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "id,v\n1,a\n2,b\n");
+    }
+
+    #[cfg(feature = "bzip2")]
+    #[test]
+    fn test_sampling_bzip2_input() {
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        encoder.write_all(b"id,v\n1,a\n2,b\n").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut output = Vec::new();
+        run_app(
+            &["sample", "-p", "100", "--csv", "--hash", "id"],
+            compressed.as_slice(),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "id,v\n1,a\n2,b\n");
+    }
+
+    #[cfg(feature = "progress")]
+    #[test]
+    fn test_progress_bar_needs_known_size_and_terminal() {
+        // A pipe has no known length, so no bar is drawn even on a terminal
+        assert!(progress_bar(None, true).is_none());
+        assert!(progress_bar(Some(1024), false).is_none());
+        assert_eq!(progress_bar(Some(1024), true).unwrap().length(), Some(1024));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip_output() {
+        let input: String = std::iter::once("id,v\n".to_string())
+            .chain((0..200).map(|i| format!("{},{}\n", i, i * 2)))
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sample.csv.gz");
+        run_app(
+            &["sample", "-p", "30", "--csv", "--seed", "5", "--gzip-out"],
+            Cursor::new(input.as_str()),
+            std::fs::File::create(&path).unwrap(),
+        )
+        .unwrap();
+
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(&path).unwrap())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, run("-p 30 --csv --seed 5", &input));
+        assert!(decompressed.lines().count() > 1);
+    }
+
     fn run(cmd: &str, input: &str) -> String {
         // Split the command string into arguments
         let args: Vec<&str> = std::iter::once("sample")
@@ -0,0 +1,118 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use parquet::basic::{LogicalType, Repetition, Type as PhysicalType};
+use parquet::data_type::{ByteArray, ByteArrayType};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+
+use csv::StringRecord;
+
+use crate::error::{OutputWriter, Result};
+
+/// Writes CSV records to a Parquet file at `path`, with one column per `header` field.
+///
+/// Every header field becomes an optional UTF-8 string column; a row that is short of
+/// fields gets nulls in the missing columns, and extra fields are dropped.
+pub fn write_parquet(header: &StringRecord, rows: &[StringRecord], path: &Path) -> Result<()> {
+    if header.is_empty() {
+        return Err(invalid("cannot write Parquet without a CSV header").into());
+    }
+
+    let fields = header
+        .iter()
+        .map(|name| {
+            Type::primitive_type_builder(name, PhysicalType::BYTE_ARRAY)
+                .with_repetition(Repetition::OPTIONAL)
+                .with_logical_type(Some(LogicalType::String))
+                .build()
+                .map(Arc::new)
+        })
+        .collect::<parquet::errors::Result<Vec<_>>>()
+        .map_err(io::Error::other)?;
+    let schema = Type::group_type_builder("schema")
+        .with_fields(fields)
+        .build()
+        .map_err(io::Error::other)?;
+
+    let properties = Arc::new(WriterProperties::builder().build());
+    let file = OutputWriter::create(path)?;
+    let mut writer =
+        SerializedFileWriter::new(file, Arc::new(schema), properties).map_err(io::Error::other)?;
+    let mut row_group = writer.next_row_group().map_err(io::Error::other)?;
+
+    for index in 0..header.len() {
+        // Definition level 1 marks a present value, 0 a null
+        let mut values = Vec::with_capacity(rows.len());
+        let mut definition_levels = Vec::with_capacity(rows.len());
+        for row in rows {
+            match row.get(index) {
+                Some(value) => {
+                    values.push(ByteArray::from(value));
+                    definition_levels.push(1);
+                }
+                None => definition_levels.push(0),
+            }
+        }
+
+        let mut column = row_group
+            .next_column()
+            .map_err(io::Error::other)?
+            .expect("the schema has a column for every header field");
+        column
+            .typed::<ByteArrayType>()
+            .write_batch(&values, Some(&definition_levels), None)
+            .map_err(io::Error::other)?;
+        column.close().map_err(io::Error::other)?;
+    }
+
+    row_group.close().map_err(io::Error::other)?;
+    writer.close().map_err(io::Error::other)?;
+    Ok(())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+
+    #[test]
+    fn test_write_parquet_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sample.parquet");
+        let header = StringRecord::from(vec!["id", "name"]);
+        let rows = [
+            StringRecord::from(vec!["1", "alice"]),
+            StringRecord::from(vec!["2", "bob, jr"]),
+            StringRecord::from(vec!["3"]),
+        ];
+        write_parquet(&header, &rows, &path).unwrap();
+
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 3);
+
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(rows[0].get_string(0).unwrap(), "1");
+        assert_eq!(rows[1].get_string(1).unwrap(), "bob, jr");
+        assert_eq!(rows[2].get_string(0).unwrap(), "3");
+        assert!(rows[2].get_string(1).is_err());
+    }
+
+    #[test]
+    fn test_write_parquet_requires_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty.parquet");
+        assert!(write_parquet(&StringRecord::new(), &[], &path).is_err());
+    }
+}
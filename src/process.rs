@@ -0,0 +1,2812 @@
+use log::{info, warn};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rand_chacha::ChaCha12Rng;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::checkpoint::Checkpoint;
+use crate::config::{BucketAssignment, CheckArgs, ColumnRename, Config};
+use crate::error::{output_error, Error, OutputWriter, Result};
+use crate::input::{
+    column_mismatch, csv_reader, csv_reader_with_strictness, decompress, find_column,
+    find_column_ignoring_case, find_column_matching, read_key_rates, read_keys, sniff_format,
+    BomStrippingReader, ByteLimitReader, LineFilterReader, RecordByteLimitReader, RecordTapReader,
+    RetryingReader, ReusedLines, SeparatedRecordReader, SeparatedRecordWriter, SniffedFormat,
+    SortedMergeReader, TrailingNewlineReader, TranscodingReader,
+};
+use crate::sampling::{
+    bucket_for, derive_seed, draw_included, hash_position_with, inclusion_threshold, key_hash_with,
+    percentage_sample_iter, poisson_include, poisson_scale, reservoir_sample, sample_one,
+    should_include, should_include_with, target_count_sample_iter, whitespace_hash_sample_iter,
+    CountWindowedReservoir, CsvHashSampler, KeyColumn, KeyNormalization, NumericKeys,
+    PercentageSampler, Reservoir, ReservoirOrder, SpillingReservoir, WindowedReservoir,
+};
+use crate::stats::{ColumnStats, LineCountingReader};
+
+/// Sample the input according to the configuration and write the result to `output`.
+///
+/// Randomized modes draw from `rng`; `config.seed` is not consulted here.
+pub fn process_input<I, O, R>(config: &Config, input: I, output: O, rng: &mut R) -> Result<()>
+where
+    I: Read,
+    O: Write,
+    R: Rng,
+{
+    #[cfg(feature = "parquet")]
+    if let Some(path) = &config.parquet_out {
+        return write_parquet_output(config, path, |csv_config, buffer| {
+            process_input(csv_config, input, buffer, rng)
+        });
+    }
+
+    if config.pretty {
+        return write_pretty(config, output, |csv_config, buffer| {
+            process_input(csv_config, input, buffer, rng)
+        });
+    }
+
+    if let Some(separator) = record_separator(config) {
+        return process_separated_records(config, separator, input, output, rng);
+    }
+
+    // CSV record modes count --max-records rows as they write them, see `RecordOutput`
+    if let Some(limit) = config.max_records.filter(|_| !emits_records(config)) {
+        let uncapped = Config {
+            max_records: None,
+            ..config.clone()
+        };
+        // Passing the writer or reader as a trait object, here and below, keeps the
+        // recursion from instantiating nested types
+        let mut output = RecordLimitWriter::new(output, limit + header_lines(config));
+        let result = process_input(&uncapped, input, &mut output as &mut dyn Write, rng);
+        return ignore_record_limit(result);
+    }
+
+    if config.line_buffered {
+        let unbuffered = Config {
+            line_buffered: false,
+            ..config.clone()
+        };
+        let mut output = LineFlushingWriter(output);
+        return process_input(&unbuffered, input, &mut output as &mut dyn Write, rng);
+    }
+
+    if sorts_output(config) {
+        return write_sorted(config, output, |unsorted, buffer| {
+            process_input(unsorted, input, buffer, rng)
+        });
+    }
+
+    let input = prepare_input(config, input)?;
+    if !config.require_trailing_newline {
+        return process_prepared_input(config, input, output, rng);
+    }
+
+    let mut input = TrailingNewlineReader::new(input);
+    ignore_record_limit(process_prepared_input(config, &mut input, output, rng))?;
+    // Modes that stop reading early leave the rest of the input to be checked
+    io::copy(&mut input, &mut io::sink())?;
+    match input.unterminated_line() {
+        Some(line) => Err(Error::MissingTrailingNewline(line)),
+        None => Ok(()),
+    }
+}
+
+/// Sample an input that `prepare_input` has already decoded and filtered
+fn process_prepared_input<I, O, R>(
+    config: &Config,
+    input: I,
+    mut output: O,
+    rng: &mut R,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+    R: Rng,
+{
+    if let Some(column) = &config.stats_column {
+        let stats = process_with_column_stats(config, column, input, output, rng)?;
+        // Logged under its own target, which the binary shows even when warnings are off
+        info!(target: "sample::stats", "{}", stats);
+        return Ok(());
+    }
+
+    if !config.uses_line_path() {
+        return ignore_record_limit(process_csv_records(config, input, output, rng));
+    }
+
+    let mut reader = io::BufReader::new(input);
+
+    // Handle header if enabled
+    if config.csv_mode {
+        if let Some(header) = (&mut reader).lines().next() {
+            process_header_line(config, &header?, &mut output)?;
+        }
+    }
+
+    if reuses_line_buffer(config) {
+        return process_reused_lines(config, ReusedLines::new(reader), output, rng);
+    }
+    process_lines(config, until_unreadable(reader.lines()), output, rng)
+}
+
+/// Sample a prepared CSV input while accumulating statistics of `column` over every
+/// data record, sampled or not, and return them.
+///
+/// The records are parsed as CSV whichever mode samples them, so a quoted value may
+/// hold the delimiter or span lines; values that are not numbers are counted as skipped.
+fn process_with_column_stats<I, O, R>(
+    config: &Config,
+    column: &str,
+    input: I,
+    output: O,
+    rng: &mut R,
+) -> Result<ColumnStats>
+where
+    I: Read,
+    O: Write,
+    R: Rng,
+{
+    let mut reader = io::BufReader::new(input);
+    let mut header = Vec::new();
+    reader.read_until(b'\n', &mut header)?;
+    let header_record = csv_reader(&header[..])
+        .headers()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        .clone();
+    let index = resolve_column(config, &header_record, column)?;
+
+    let untallied = Config {
+        stats_column: None,
+        ..config.clone()
+    };
+    let mut stats = ColumnStats::new(column);
+    let mut tallied = RecordTapReader::new(
+        io::Cursor::new(header).chain(reader),
+        |record: &csv::StringRecord| stats.add(record.get(index).unwrap_or("")),
+    );
+    process_prepared_input(&untallied, &mut tallied as &mut dyn Read, output, rng)?;
+    Ok(stats)
+}
+
+/// Whether `process_reused_lines` handles the line-based mode selected by the configuration
+fn reuses_line_buffer(config: &Config) -> bool {
+    let plain_percentage = config.percentage.is_some()
+        && config.hash_index.is_none()
+        && config.decay.is_none()
+        && config.target_count.is_none();
+    let plain_reservoir =
+        config.sample_size.is_some() && config.spill_dir.is_none() && config.window_count.is_none();
+    config.manifest.is_none()
+        && config.decision_out.is_none()
+        && config.first.is_none()
+        && !config.shuffle_all
+        && !config.one
+        && (plain_percentage || plain_reservoir)
+}
+
+/// Percentage or reservoir sample the data lines exactly like `sample_line_iter`, but
+/// read them into one reused buffer, so only retained lines are copied
+fn process_reused_lines<B, O, R>(
+    config: &Config,
+    mut lines: ReusedLines<B>,
+    mut output: O,
+    rng: &mut R,
+) -> Result<()>
+where
+    B: BufRead,
+    O: Write,
+    R: Rng,
+{
+    let mut index = 0;
+
+    if let Some(k) = config.sample_size {
+        let mut reservoir = Reservoir::new(k);
+        while let Some(line) = next_readable_line(&mut lines, &mut index) {
+            if index <= config.skip {
+                continue;
+            }
+            let line_index = index - 1 - config.skip;
+            reservoir.add_in_place(rng, |(slot_index, slot): &mut (usize, String)| {
+                *slot_index = line_index;
+                slot.clear();
+                slot.push_str(line);
+            });
+        }
+        return write_reservoir_sample(
+            reservoir.into_vec(),
+            k,
+            config.reservoir_order,
+            rng,
+            &mut output,
+        );
+    }
+
+    let percentage = config.percentage.unwrap();
+    let mut sampler = PercentageSampler::new(percentage, rng).with_antithetic(config.antithetic);
+    while let Some(line) = next_readable_line(&mut lines, &mut index) {
+        if index > config.skip && sampler.include() {
+            writeln!(output, "{}", line)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the next data line like `until_unreadable`: at a line that cannot be read, logs
+/// a warning and returns `None`. `index` counts the data lines read so far.
+fn next_readable_line<'a, B: BufRead>(
+    lines: &'a mut ReusedLines<B>,
+    index: &mut usize,
+) -> Option<&'a str> {
+    match lines.next_line()? {
+        Ok(line) => {
+            *index += 1;
+            Some(line)
+        }
+        Err(e) => {
+            warn!("stopped reading at data line {}: {}", *index + 1, e);
+            None
+        }
+    }
+}
+
+/// Percentage-sample the input like `process_input`, saving a checkpoint to
+/// `checkpoint_path` after every `CHECKPOINT_INTERVAL` data lines.
+///
+/// With `resume`, the data lines it covers are skipped, the header is not repeated and
+/// `rng` must have been restored from it. The output of the interrupted run truncated to
+/// the checkpoint's `output_bytes`, followed by the resumed output, equals the output of
+/// an uninterrupted run. Unlike `process_input`, an unreadable line is an error.
+pub fn process_checkpointed<I, O>(
+    config: &Config,
+    input: I,
+    mut output: O,
+    rng: &mut ChaCha12Rng,
+    checkpoint_path: &Path,
+    resume: Option<&Checkpoint>,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+{
+    let percentage = config
+        .percentage
+        .expect("Config validation ensures --checkpoint has a percentage");
+    let (mut lines_done, mut output_bytes) =
+        resume.map_or((0, 0), |resume| (resume.lines, resume.output_bytes));
+
+    let input = prepare_input(config, input)?;
+    let mut lines = io::BufReader::new(input).lines();
+
+    if config.csv_mode {
+        if let Some(header) = lines.next() {
+            let mut header_output = Vec::new();
+            process_header_line(config, &header?, &mut header_output)?;
+            if resume.is_none() {
+                output.write_all(&header_output)?;
+                output_bytes += header_output.len() as u64;
+            }
+        }
+    }
+
+    let mut lines = lines.skip(config.skip + lines_done as usize);
+    loop {
+        // An even interval keeps antithetic pairs from straddling checkpoints
+        let chunk = (&mut lines)
+            .take(CHECKPOINT_INTERVAL)
+            .collect::<io::Result<Vec<String>>>()?;
+        if chunk.is_empty() {
+            break;
+        }
+        lines_done += chunk.len() as u64;
+
+        let sampled = percentage_sample_iter(chunk.into_iter(), percentage, &mut *rng)
+            .with_antithetic(config.antithetic);
+        for line in sampled {
+            writeln!(output, "{}", line)?;
+            output_bytes += line.len() as u64 + 1;
+        }
+        output.flush()?;
+
+        Checkpoint::capture(rng, lines_done, output_bytes)
+            .save(checkpoint_path)
+            .map_err(|e| output_error(checkpoint_path.display(), e))?;
+    }
+
+    Ok(())
+}
+
+/// Number of leading lines `probe_format` inspects
+const PROBE_LINES: usize = 20;
+
+/// Guess the input's format from its first lines, after decompression and decoding.
+pub fn probe_format<I: Read>(config: &Config, input: I) -> Result<SniffedFormat> {
+    let input = prepare_input(config, input)?;
+    let lines = io::BufReader::new(input)
+        .lines()
+        .take(PROBE_LINES)
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok(sniff_format(&lines))
+}
+
+/// Check the configuration against the input's CSV header without reading any data
+/// rows: the header must satisfy --expect-columns, and every column that --hash,
+/// --hash-regex, --assign, --assign-columns, --weight-column, --time-column,
+/// --sort-column, --percentile-column and --rename name must exist in it. Input
+/// without a header (no --csv) has nothing to check.
+pub fn dry_validate<I: Read>(config: &Config, input: I) -> Result<()> {
+    if !config.csv_mode {
+        return Ok(());
+    }
+    let input = prepare_input(config, input)?;
+    let mut reader = csv_reader(input);
+    let header = reader.headers()?.clone();
+    check_expected_columns(config, &header)?;
+
+    if config.has_hash_key() {
+        resolve_key_column(config, &header)?;
+    }
+
+    let named_columns = [
+        config.assign.as_ref().map(|assignment| &assignment.column),
+        config.weight_column.as_ref(),
+        config.time_column.as_ref(),
+        config.sort_column.as_ref(),
+        config.percentile_column.as_ref(),
+    ];
+    let assigned_columns = config.assign_columns.iter().flatten();
+    for column in named_columns.into_iter().flatten().chain(assigned_columns) {
+        resolve_column(config, &header, column)?;
+    }
+    rename_columns(&header, config.rename.as_deref().unwrap_or_default())?;
+    Ok(())
+}
+
+/// Guess whether the input starts with a header from its first lines, as
+/// `probe_format` does, and return the guess with a reader over the whole input.
+///
+/// The lines are read as given, so compressed or transcoded input is taken as headerless.
+pub fn sniff_header<'a, I: Read + 'a>(input: I) -> Result<(bool, impl Read + 'a)> {
+    let mut input = io::BufReader::new(input);
+    let mut head = Vec::new();
+    for _ in 0..PROBE_LINES {
+        if input.read_until(b'\n', &mut head)? == 0 {
+            break;
+        }
+    }
+    let text = String::from_utf8_lossy(&head);
+    let lines: Vec<&str> = text.lines().collect();
+    let has_header = sniff_format(&lines).has_header;
+    Ok((has_header, io::Cursor::new(head).chain(input)))
+}
+
+/// Open the --merge-sorted files and merge them by --sort-column into one CSV stream,
+/// see `SortedMergeReader`.
+///
+/// Fails with `UnexpectedColumns` if a file's header differs from the first file's.
+pub fn merge_sorted_inputs(
+    config: &Config,
+    paths: &[PathBuf],
+) -> Result<SortedMergeReader<Box<dyn Read>>> {
+    let column = config
+        .sort_column
+        .as_deref()
+        .expect("--merge-sorted requires --sort-column");
+
+    let mut header: Option<csv::StringRecord> = None;
+    let mut inputs = Vec::new();
+    let mut columns = Vec::new();
+    for path in paths {
+        let mut reader = csv_reader(decompress(File::open(path)?)?);
+        let file_header = reader.headers()?.clone();
+        if let Some(first) = &header {
+            let expected: Vec<String> = first.iter().map(String::from).collect();
+            if let Some(mismatch) = column_mismatch(&file_header, &expected) {
+                return Err(Error::UnexpectedColumns(format!(
+                    "{}: {}",
+                    path.display(),
+                    mismatch
+                )));
+            }
+        }
+        columns.push(resolve_column(config, &file_header, column)?);
+        header.get_or_insert(file_header);
+        inputs.push(reader);
+    }
+
+    let header = header.unwrap_or_default();
+    Ok(SortedMergeReader::new(&header, inputs, columns)?)
+}
+
+/// Check the `check --hash` column over the first data rows and report where it is,
+/// how often it is empty and how many distinct values it holds, without sampling.
+///
+/// Fails with `ColumnNotFound` if the column is absent and `MostlyEmptyColumn` if it
+/// is empty in more than half of the checked rows, after writing the report.
+pub fn check_hash_column<I, O>(
+    config: &Config,
+    check: &CheckArgs,
+    input: I,
+    mut output: O,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+{
+    let input = prepare_input(config, input)?;
+    let sampler = open_hash_sampler(&keyed_by(config, &check.hash_column), input, 100.0)?;
+    let column_index = sampler.column_index();
+
+    let mut rows = 0;
+    let mut empty = 0;
+    let mut values = HashSet::new();
+    for record in sampler.take(check.rows) {
+        let record = record?;
+        rows += 1;
+        match record
+            .get(column_index)
+            .filter(|value| !value.trim().is_empty())
+        {
+            Some(value) => {
+                values.insert(value.to_string());
+            }
+            None => empty += 1,
+        }
+    }
+
+    let empty_percentage = match rows {
+        0 => 0.0,
+        rows => empty as f64 / rows as f64 * 100.0,
+    };
+    writeln!(
+        output,
+        "column '{}' found at index {}",
+        check.hash_column, column_index
+    )?;
+    writeln!(output, "rows checked: {}", rows)?;
+    writeln!(output, "empty values: {} ({:.2}%)", empty, empty_percentage)?;
+    writeln!(output, "distinct values: {}", values.len())?;
+
+    if empty * 2 > rows {
+        return Err(Error::MostlyEmptyColumn(check.hash_column.clone()));
+    }
+    Ok(())
+}
+
+/// Number of data lines between checkpoints in `process_checkpointed`
+const CHECKPOINT_INTERVAL: usize = 10_000;
+
+/// Returns the --record-separator, unless it is the line feed that lines end with anyway
+fn record_separator(config: &Config) -> Option<&str> {
+    config
+        .record_separator
+        .as_deref()
+        .filter(|&separator| separator != "\n")
+}
+
+/// Sample records split on `separator`, by sampling them as escaped single lines and
+/// turning the sampled lines back into records
+fn process_separated_records<I, O, R>(
+    config: &Config,
+    separator: &str,
+    input: I,
+    output: O,
+    rng: &mut R,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+    R: Rng,
+{
+    // The input is prepared here, before it is split, so the escaped lines are not
+    // decoded or limited a second time
+    let input = prepare_input(config, input)?;
+    let lines_config = Config {
+        record_separator: None,
+        retry: 0,
+        encoding: None,
+        max_bytes: None,
+        ..config.clone()
+    };
+    let mut records = SeparatedRecordReader::new(io::BufReader::new(input), separator.as_bytes());
+    let mut output = SeparatedRecordWriter::new(output, separator.as_bytes());
+    // Trait objects keep the recursion from instantiating nested reader and writer types
+    process_input(
+        &lines_config,
+        &mut records as &mut dyn Read,
+        &mut output as &mut dyn Write,
+        rng,
+    )?;
+    output.flush()?;
+    Ok(())
+}
+
+/// The decoded input of `count_prepared_input`, counting the lines read from it
+pub type CountedInput<'a> = LineCountingReader<io::BufReader<Box<dyn Read + 'a>>>;
+
+/// Decompress, normalize, limit and filter the input as `process_input` does, and
+/// count the lines that sampling reads from it, for --stats-out.
+///
+/// Sample the returned reader with the returned configuration, which does not decode
+/// or filter the input a second time.
+pub fn count_prepared_input<'a, I: Read + 'a>(
+    config: &'a Config,
+    input: I,
+) -> Result<(Config, CountedInput<'a>)> {
+    let input = prepare_input(config, input)?;
+    let prepared = Config {
+        retry: 0,
+        encoding: None,
+        max_bytes: None,
+        filter: None,
+        ..config.clone()
+    };
+    Ok((prepared, LineCountingReader::new(io::BufReader::new(input))))
+}
+
+/// Decompress and decode the input as `process_input` does, but without limiting or
+/// filtering it, to read a --size-from-header directive ahead of the data.
+///
+/// Sample the rest of the returned reader with the returned configuration, which does
+/// not decode the input a second time.
+pub fn decode_input<'a, I: Read + 'a>(
+    config: &Config,
+    input: I,
+) -> Result<(Config, Box<dyn Read + 'a>)> {
+    let decoded = Config {
+        retry: 0,
+        encoding: None,
+        ..config.clone()
+    };
+    Ok((decoded, decoded_input(config, input)?))
+}
+
+/// Retry, decompress, transcode and strip a byte order mark from the input
+fn decoded_input<'a, I: Read + 'a>(config: &Config, input: I) -> Result<Box<dyn Read + 'a>> {
+    let input: Box<dyn Read + 'a> = match config.retry {
+        0 => Box::new(input),
+        retries => Box::new(RetryingReader::new(input, retries)),
+    };
+    let input = decompress(input)?;
+    let input: Box<dyn Read + 'a> = match config.encoding {
+        Some(encoding) => Box::new(TranscodingReader::new(input, encoding)),
+        None => input,
+    };
+    Ok(Box::new(BomStrippingReader::new(input)))
+}
+
+/// Decompress, normalize and limit the input before any sampling mode sees the data
+fn prepare_input<'a, I: Read + 'a>(config: &'a Config, input: I) -> Result<Box<dyn Read + 'a>> {
+    let input = decoded_input(config, input)?;
+    let input: Box<dyn Read + 'a> = match config.max_bytes {
+        Some(limit) => Box::new(ByteLimitReader::new(io::BufReader::new(input), limit)),
+        None => Box::new(input),
+    };
+    let input: Box<dyn Read + 'a> = match &config.filter {
+        Some(regex) => Box::new(LineFilterReader::new(
+            io::BufReader::new(input),
+            config.csv_mode,
+            |line: &[u8]| regex.is_match(&String::from_utf8_lossy(line)),
+        )),
+        None => input,
+    };
+    Ok(input)
+}
+
+/// Sample an in-memory buffer, such as a memory-mapped file, into `output`.
+///
+/// The buffer is read in place through its `BufRead` implementation, so this
+/// produces exactly the output of `process_input` on the same bytes.
+pub fn process_mapped<O, R>(config: &Config, data: &[u8], output: O, rng: &mut R) -> Result<()>
+where
+    O: Write,
+    R: Rng,
+{
+    process_input(config, data, output, rng)
+}
+
+/// Yields data lines up to the first one that cannot be read, logging a warning there
+fn until_unreadable<I, T>(lines: I) -> impl Iterator<Item = T>
+where
+    I: Iterator<Item = io::Result<T>>,
+{
+    lines.enumerate().map_while(|(index, line)| {
+        line.map_err(|e| warn!("stopped reading at data line {}: {}", index + 1, e))
+            .ok()
+    })
+}
+
+/// Number of header lines the output starts with, which --max-records does not count
+fn header_lines(config: &Config) -> u64 {
+    u64::from(config.csv_mode && !config.no_header_output)
+}
+
+/// Signals that a `RecordLimitWriter` has passed through all the lines it allows, or a
+/// `RecordOutput` all the records
+#[derive(Debug)]
+struct RecordLimitReached;
+
+impl fmt::Display for RecordLimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the --max-records limit was reached")
+    }
+}
+
+impl std::error::Error for RecordLimitReached {}
+
+/// A writer that passes through the first `remaining` lines and then fails every write
+/// with `RecordLimitReached`, which stops whichever loop is producing output
+struct RecordLimitWriter<W> {
+    inner: W,
+    remaining: u64,
+}
+
+impl<W: Write> RecordLimitWriter<W> {
+    fn new(inner: W, lines: u64) -> Self {
+        RecordLimitWriter {
+            inner,
+            remaining: lines,
+        }
+    }
+}
+
+impl<W: Write> Write for RecordLimitWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Err(io::Error::other(RecordLimitReached));
+        }
+        // Pass through up to and including the last newline still allowed
+        let mut len = buf.len();
+        let mut newlines = 0;
+        for (index, _) in buf.iter().enumerate().filter(|(_, &byte)| byte == b'\n') {
+            newlines += 1;
+            if newlines == self.remaining {
+                len = index + 1;
+                break;
+            }
+        }
+        let written = self.inner.write(&buf[..len])?;
+        self.remaining -= buf[..written].iter().filter(|&&byte| byte == b'\n').count() as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer that flushes the inner writer whenever a line is complete, for
+/// --line-buffered.
+///
+/// Unlike `io::LineWriter`, it flushes through the inner writer, which may buffer too.
+struct LineFlushingWriter<W>(W);
+
+impl<W: Write> Write for LineFlushingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.0.write(buf)?;
+        if buf[..written].contains(&b'\n') {
+            self.0.flush()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Writes the rows of a CSV record mode, one per line, and fails with
+/// `RecordLimitReached` once --max-records rows have been written.
+///
+/// Rows are counted as they are written rather than by their line feeds, since a
+/// quoted field may hold line feeds of its own.
+struct RecordOutput<O> {
+    output: O,
+    remaining: Option<u64>,
+}
+
+impl<O: Write> RecordOutput<O> {
+    fn new(config: &Config, output: O) -> Self {
+        RecordOutput {
+            output,
+            remaining: config.max_records,
+        }
+    }
+
+    /// Write the CSV header with the --rename mappings applied; it is not counted
+    fn write_header(&mut self, config: &Config, header: &csv::StringRecord) -> Result<()> {
+        write_header(config, &mut self.output, header)
+    }
+
+    fn write_record(&mut self, config: &Config, record: &csv::StringRecord) -> Result<()> {
+        self.write_row(format_record(config, record)?.as_bytes())
+    }
+
+    /// Write an already formatted row
+    fn write_row(&mut self, row: &[u8]) -> Result<()> {
+        match &mut self.remaining {
+            Some(0) => return Err(io::Error::other(RecordLimitReached).into()),
+            Some(remaining) => *remaining -= 1,
+            None => {}
+        }
+        self.output.write_all(row)?;
+        self.output.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+/// Treats stopping at the --max-records limit as success, however the error that
+/// signals it was passed up
+fn ignore_record_limit(result: Result<()>) -> Result<()> {
+    let reached = match &result {
+        Err(Error::IoError(e)) => caused_by_record_limit(e),
+        Err(Error::CsvError(e)) => caused_by_record_limit(e),
+        _ => false,
+    };
+    if reached {
+        Ok(())
+    } else {
+        result
+    }
+}
+
+/// Whether `RecordLimitReached` is the error or any error it wraps
+fn caused_by_record_limit(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut error = Some(error);
+    while let Some(current) = error {
+        if current.is::<RecordLimitReached>() {
+            return true;
+        }
+        // An io::Error's source skips the error it wraps, so unwrap that first
+        error = match current.downcast_ref::<io::Error>() {
+            Some(io_error) => io_error
+                .get_ref()
+                .map(|inner| inner as &(dyn std::error::Error + 'static)),
+            None => current.source(),
+        };
+    }
+    false
+}
+
+/// Returns true if --sort or --sort-column is set
+fn sorts_output(config: &Config) -> bool {
+    config.sort || config.sort_column.is_some()
+}
+
+/// Run `sample` into a buffer as CSV, then write its records to the --parquet-out file.
+///
+/// Nothing is written to the regular output.
+#[cfg(feature = "parquet")]
+fn write_parquet_output<F>(config: &Config, path: &Path, sample: F) -> Result<()>
+where
+    F: FnOnce(&Config, &mut Vec<u8>) -> Result<()>,
+{
+    // The header names the Parquet columns, so it is always produced
+    let csv_config = Config {
+        parquet_out: None,
+        no_header_output: false,
+        ..config.clone()
+    };
+    let mut records = sample_records(&csv_config, sample)?.into_iter();
+    let header = records.next().unwrap_or_default();
+    let rows: Vec<_> = records.collect();
+    crate::parquet_out::write_parquet(&header, &rows, path)
+}
+
+/// Run `sample` into a buffer as CSV and read back every record it wrote, the header
+/// included. Parsed records are written quoted and comma-delimited for this, so a
+/// field holding a delimiter or a newline is read back whole.
+fn sample_records<F>(config: &Config, sample: F) -> Result<Vec<csv::StringRecord>>
+where
+    F: FnOnce(&Config, &mut Vec<u8>) -> Result<()>,
+{
+    let quoted = Config {
+        output_delimiter: Some(b','),
+        ..config.clone()
+    };
+    let mut buffer = Vec::new();
+    sample(&quoted, &mut buffer)?;
+
+    let records = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(&buffer[..])
+        .into_records()
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(records)
+}
+
+/// Splits a buffer into its lines, without their line feeds
+fn byte_lines(buffer: &[u8]) -> impl Iterator<Item = &[u8]> {
+    buffer
+        .split_inclusive(|&byte| byte == b'\n')
+        .map(|line| line.strip_suffix(b"\n").unwrap_or(line))
+}
+
+/// Run `sample` into a buffer as CSV, then write it as a table with aligned columns.
+///
+/// Cells longer than --pretty-width characters are cut short with an ellipsis. Columns
+/// are separated by two spaces and the last one is not padded.
+fn write_pretty<O, F>(config: &Config, mut output: O, sample: F) -> Result<()>
+where
+    O: Write,
+    F: FnOnce(&Config, &mut Vec<u8>) -> Result<()>,
+{
+    let csv_config = Config {
+        pretty: false,
+        ..config.clone()
+    };
+    let mut buffer = Vec::new();
+    sample(&csv_config, &mut buffer)?;
+
+    // The header, if written, is just the first row of the table
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(&buffer[..]);
+    let width = config.pretty_width as usize;
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let row: Vec<String> = record?
+            .iter()
+            .map(|cell| truncate_cell(cell, width))
+            .collect();
+        rows.push(row);
+    }
+
+    let mut widths: Vec<usize> = Vec::new();
+    for row in &rows {
+        for (index, cell) in row.iter().enumerate() {
+            let cell_width = cell.chars().count();
+            match widths.get_mut(index) {
+                Some(width) => *width = (*width).max(cell_width),
+                None => widths.push(cell_width),
+            }
+        }
+    }
+
+    for row in &rows {
+        let mut line = String::new();
+        for (index, cell) in row.iter().enumerate() {
+            if index + 1 < row.len() {
+                line.push_str(&format!("{:<width$}  ", cell, width = widths[index]));
+            } else {
+                line.push_str(cell);
+            }
+        }
+        writeln!(output, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Shortens a cell to at most `width` characters, ending it with an ellipsis if cut
+fn truncate_cell(cell: &str, width: usize) -> String {
+    if cell.chars().count() <= width {
+        return cell.to_string();
+    }
+    let mut truncated: String = cell.chars().take(width - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Run `sample` into a buffer without sorting, then write its rows sorted.
+///
+/// In CSV mode the header stays on top; rows are ordered by the --sort-column value
+/// if given, and by the whole row otherwise. The rows are the lines of a line-based
+/// mode and the whole records of a CSV record mode, compared as bytes.
+fn write_sorted<O, F>(config: &Config, mut output: O, sample: F) -> Result<()>
+where
+    O: Write,
+    F: FnOnce(&Config, &mut Vec<u8>) -> Result<()>,
+{
+    // Always produce the header so --sort-column can be resolved against it. A record
+    // mode's --max-records limit applies to the sorted rows.
+    let unsorted = Config {
+        sort: false,
+        sort_column: None,
+        no_header_output: false,
+        max_records: None,
+        ..config.clone()
+    };
+    let (header, mut rows) = if emits_records(config) {
+        sorted_record_rows(config, &unsorted, sample)?
+    } else {
+        sorted_line_rows(config, &unsorted, sample)?
+    };
+    rows.sort();
+
+    if let Some(header) = header {
+        if !config.no_header_output {
+            output.write_all(&header)?;
+            output.write_all(b"\n")?;
+        }
+    }
+    let limit = config
+        .max_records
+        .map_or(usize::MAX, |limit| limit as usize);
+    for (_, row) in rows.into_iter().take(limit) {
+        output.write_all(&row)?;
+        output.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// A row to sort, keyed by its --sort-column value, or by nothing to sort whole rows
+type SortedRow = (Vec<u8>, Vec<u8>);
+
+/// Sample into a buffer and return the header line and the data lines, keyed for `write_sorted`
+fn sorted_line_rows<F>(
+    config: &Config,
+    unsorted: &Config,
+    sample: F,
+) -> Result<(Option<Vec<u8>>, Vec<SortedRow>)>
+where
+    F: FnOnce(&Config, &mut Vec<u8>) -> Result<()>,
+{
+    let mut buffer = Vec::new();
+    sample(unsorted, &mut buffer)?;
+
+    let mut lines = byte_lines(&buffer);
+    let header = if config.csv_mode { lines.next() } else { None };
+    let index = match (&config.sort_column, header) {
+        (Some(column), Some(header)) => {
+            let mut header_reader = csv_reader(header);
+            Some(resolve_column(config, header_reader.headers()?, column)?)
+        }
+        _ => None,
+    };
+    let rows = lines
+        .map(|line| {
+            let key = index.map(|index| field_at(line, index)).transpose()?;
+            Ok((key.unwrap_or_default(), line.to_vec()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((header.map(<[u8]>::to_vec), rows))
+}
+
+/// Sample into a buffer and return the header and the data records, formatted as
+/// output lines and keyed for `write_sorted`
+fn sorted_record_rows<F>(
+    config: &Config,
+    unsorted: &Config,
+    sample: F,
+) -> Result<(Option<Vec<u8>>, Vec<SortedRow>)>
+where
+    F: FnOnce(&Config, &mut Vec<u8>) -> Result<()>,
+{
+    let mut records = sample_records(unsorted, sample)?.into_iter();
+    let header = records.next().unwrap_or_default();
+    let index = config
+        .sort_column
+        .as_ref()
+        .map(|column| resolve_column(config, &header, column))
+        .transpose()?;
+    let rows = records
+        .map(|record| {
+            let key = index
+                .and_then(|index| record.get(index))
+                .unwrap_or_default();
+            Ok((
+                key.as_bytes().to_vec(),
+                format_record(config, &record)?.into_bytes(),
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((Some(format_record(config, &header)?.into_bytes()), rows))
+}
+
+/// Returns the (trimmed) field at `index` of a single CSV line, or nothing if the row
+/// is short
+fn field_at(line: &[u8], index: usize) -> Result<Vec<u8>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(line);
+    let mut record = csv::ByteRecord::new();
+    reader.read_byte_record(&mut record)?;
+    Ok(record.get(index).unwrap_or_default().to_vec())
+}
+
+/// Returns true if a CSV record mode writes the sample as parsed records, whose quoted
+/// fields may hold newlines, rather than as lines; the reports of --count-keys,
+/// --probability-report and --show-columns are lines
+fn emits_records(config: &Config) -> bool {
+    !config.uses_line_path()
+        && !config.count_keys
+        && !config.probability_report
+        && !config.show_columns
+}
+
+/// Dispatch to the mode that parses the input as CSV records
+fn process_csv_records<I, O, R>(config: &Config, input: I, output: O, rng: &mut R) -> Result<()>
+where
+    I: Read,
+    O: Write,
+    R: Rng,
+{
+    if let Some(limit) = config.max_record_bytes {
+        let unlimited = Config {
+            max_record_bytes: None,
+            ..config.clone()
+        };
+        let mut input = RecordByteLimitReader::new(input, Some(limit));
+        return process_csv_records(&unlimited, &mut input as &mut dyn Read, output, rng);
+    }
+
+    if config.show_columns {
+        return process_column_listing(input, output);
+    }
+
+    if config.count_keys {
+        return process_key_count(config, input, output);
+    }
+
+    if config.probability_report {
+        return process_probability_report(config, input, output);
+    }
+
+    if let Some(columns) = &config.assign_columns {
+        return process_column_assignment(config, columns, input, output);
+    }
+
+    if let (Some(rate), Some(time_column)) = (config.decay, &config.time_column) {
+        return process_time_decay_sampling(config, rate, time_column, input, output, rng);
+    }
+
+    if let Some(assignment) = &config.assign {
+        return process_bucket_assignment(config, assignment, input, output);
+    }
+
+    if let Some(k) = config.sample_columns {
+        return process_column_sampling(config, k as usize, input, output, rng);
+    }
+
+    if let (Some(column), Some(range)) = (&config.percentile_column, config.percentile_range) {
+        return process_percentile_band(config, column, range, input, output);
+    }
+
+    if let (Some(expected_size), Some(weight_column)) =
+        (config.expected_size, &config.weight_column)
+    {
+        return process_weighted_sampling(config, expected_size, weight_column, input, output, rng);
+    }
+
+    if let (Some(time_column), Some(window), Some(k)) =
+        (&config.time_column, config.window, config.sample_size)
+    {
+        return process_windowed_sampling(config, time_column, window, k, input, output, rng);
+    }
+
+    if let (Some(pin_keys), Some(k)) = (&config.pin_keys, config.sample_size) {
+        return process_pinned_sampling(config, pin_keys, k, input, output, rng);
+    }
+
+    // Handle hash-based sampling with CSV library
+    process_hash_based_sampling(config, input, output)
+}
+
+/// Check the header line of the line-based modes and echo it
+fn process_header_line<O: Write>(config: &Config, header: &str, output: &mut O) -> Result<()> {
+    let mut header_reader = csv_reader(header.as_bytes());
+    let fields = header_reader.headers()?;
+    check_expected_columns(config, fields)?;
+
+    // Echo the header line verbatim unless columns are renamed
+    match config.rename {
+        Some(_) => write_header(config, output, fields),
+        None => write_header_line(config, output, header),
+    }
+}
+
+/// Sample the data lines with the line-based mode selected by the configuration
+fn process_lines<I, T, O, R>(config: &Config, lines: I, output: O, rng: &mut R) -> Result<()>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+    O: Write,
+    R: Rng,
+{
+    if config.manifest.is_none() && config.decision_out.is_none() {
+        return sample_line_iter(config, lines, output, rng);
+    }
+
+    // Tag every line with its number, sample the tagged lines, then split the tags off.
+    // None of the line-based modes read line contents, so the selection is unchanged.
+    let first_number = if config.csv_mode { 2 } else { 1 };
+    let mut line_count = 0;
+    let mut numbered = lines.enumerate().map(|(index, line)| {
+        line_count = index + 1;
+        format!("{}\t{}", first_number + index, line.as_ref())
+    });
+    let mut buffer = Vec::new();
+    sample_line_iter(config, numbered.by_ref(), &mut buffer, rng)?;
+    if let Some(path) = &config.decision_out {
+        // Modes such as --first stop early, but every line needs a decision
+        numbered.for_each(drop);
+        write_decisions(&buffer, line_count, first_number, path)?;
+    }
+    write_with_manifest(&buffer, config.manifest.as_deref(), output)
+}
+
+/// Write the line-number-tagged lines in `buffer` to `output`, and their numbers to
+/// the manifest file at `path`, if given
+fn write_with_manifest<O: Write>(buffer: &[u8], path: Option<&Path>, mut output: O) -> Result<()> {
+    let mut manifest = path
+        .map(|path| OutputWriter::create(path).map(io::BufWriter::new))
+        .transpose()?;
+    for line in buffer.split_inclusive(|&byte| byte == b'\n') {
+        let (number, line) = split_line_number(line);
+        if let Some(manifest) = &mut manifest {
+            manifest.write_all(number)?;
+            manifest.write_all(b"\n")?;
+        }
+        output.write_all(line)?;
+    }
+    if let Some(manifest) = &mut manifest {
+        manifest.flush()?;
+    }
+    Ok(())
+}
+
+/// Write a `1` for every one of the `line_count` data lines whose number is among the
+/// tagged lines in `buffer`, and a `0` for every other, one per line to the file at `path`
+fn write_decisions(
+    buffer: &[u8],
+    line_count: usize,
+    first_number: usize,
+    path: &Path,
+) -> Result<()> {
+    let mut decisions = vec![false; line_count];
+    for line in buffer.split_inclusive(|&byte| byte == b'\n') {
+        let number = std::str::from_utf8(split_line_number(line).0)
+            .ok()
+            .and_then(|number| number.parse::<usize>().ok())
+            .expect("every sampled line is tagged with its number");
+        decisions[number - first_number] = true;
+    }
+
+    let mut output = io::BufWriter::new(OutputWriter::create(path)?);
+    for decision in decisions {
+        output.write_all(if decision { b"1\n" } else { b"0\n" })?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Splits a line-number-tagged line into its number and the line itself
+fn split_line_number(line: &[u8]) -> (&[u8], &[u8]) {
+    let tab = line
+        .iter()
+        .position(|&byte| byte == b'\t')
+        .expect("every sampled line is tagged with its number");
+    (&line[..tab], &line[tab + 1..])
+}
+
+/// Split the input into `shards` partitions, writing partition `i` to the writer that
+/// `open(i)` returns.
+///
+/// Data lines go to the partitions in rotation, or with --hash to the partition given by
+/// the key's bucket, so all rows of a key land together. In CSV mode every partition
+/// gets the header.
+pub fn split_round_robin<I, W, F>(config: &Config, input: I, shards: u64, open: F) -> Result<()>
+where
+    I: Read,
+    W: Write,
+    F: FnMut(u64) -> io::Result<W>,
+{
+    let mut outputs = (0..shards).map(open).collect::<io::Result<Vec<W>>>()?;
+    let input = prepare_input(config, input)?;
+
+    if config.has_hash_key() {
+        // Every record passes at 100%; the hash only chooses the partition
+        let sampler = open_hash_sampler(config, input, 100.0)?
+            .with_skipped_rows(config.skip)
+            .with_normalization(config.normalize);
+        check_expected_columns(config, sampler.header())?;
+        for output in &mut outputs {
+            write_header(config, output, sampler.header())?;
+        }
+        let column_index = sampler.column_index();
+        for record in sampler {
+            let record = record?;
+            let key = config
+                .normalize
+                .apply(record.get(column_index).unwrap_or_default());
+            let shard = bucket_for(&key, shards) as usize;
+            writeln!(outputs[shard], "{}", format_record(config, &record)?)?;
+        }
+    } else {
+        let mut lines = io::BufReader::new(input).lines();
+        if config.csv_mode {
+            if let Some(header) = lines.next() {
+                let header = header?;
+                for output in &mut outputs {
+                    process_header_line(config, &header, output)?;
+                }
+            }
+        }
+        let shards = outputs.len();
+        for (index, line) in until_unreadable(lines).skip(config.skip).enumerate() {
+            writeln!(outputs[index % shards], "{}", line)?;
+        }
+    }
+
+    for output in &mut outputs {
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Sample data lines with the line-based mode selected by the configuration
+fn sample_line_iter<I, T, O, R>(config: &Config, lines: I, output: O, rng: &mut R) -> Result<()>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+    O: Write,
+    R: Rng,
+{
+    // Create an iterator over the remaining lines
+    let lines_iter = lines.skip(config.skip);
+
+    if config.shuffle_all {
+        return process_shuffle_all(lines_iter, rng, output);
+    }
+
+    if config.one {
+        return process_one_line(lines_iter, rng, output);
+    }
+
+    // Perform sampling based on the configuration
+    match (config.sample_size, config.percentage, config.first) {
+        (Some(k), None, None) => match (&config.spill_dir, config.window_count) {
+            (Some(dir), _) => process_spilled_reservoir_sampling(
+                lines_iter,
+                k,
+                dir,
+                config.limit_memory,
+                rng,
+                output,
+            )?,
+            (None, Some(window)) => {
+                process_count_window_sampling(lines_iter, k, window, rng, output)?
+            }
+            (None, None) => {
+                process_reservoir_sampling(lines_iter, k, config.reservoir_order, rng, output)?
+            }
+        },
+        (None, Some(percentage), None) => match config.hash_index {
+            Some(index) => process_whitespace_hash_sampling(
+                lines_iter,
+                percentage,
+                index,
+                config.normalize,
+                output,
+            )?,
+            None => match (config.decay, config.target_count) {
+                (Some(rate), _) => {
+                    process_decay_sampling(lines_iter, percentage, rate, rng, output)?
+                }
+                (None, Some(target)) => {
+                    process_target_count_sampling(lines_iter, percentage, target, rng, output)?
+                }
+                (None, None) => process_percentage_sampling(
+                    lines_iter,
+                    percentage,
+                    config.antithetic,
+                    rng,
+                    output,
+                )?,
+            },
+        },
+        (None, None, Some(n)) => process_first_lines(lines_iter, n, output)?,
+        _ => unreachable!("Config validation ensures exactly one sampling mode is set"),
+    };
+
+    Ok(())
+}
+
+/// Sample lines from a reader according to the configuration and return them.
+///
+/// Dispatches exactly like `process_input`; in CSV mode the header is the first line.
+/// A record of a CSV record mode is one entry even when a quoted field spans lines,
+/// and output that is not UTF-8 is an error.
+pub fn sample_lines<R, G>(reader: R, config: &Config, rng: &mut G) -> Result<Vec<String>>
+where
+    R: BufRead,
+    G: Rng,
+{
+    if emits_records(config) {
+        let records = sample_records(config, |config, buffer| {
+            process_input(config, reader, buffer, rng)
+        })?;
+        return records
+            .iter()
+            .map(|record| format_record(config, record))
+            .collect();
+    }
+
+    let mut output = Vec::new();
+    process_input(config, reader, &mut output, rng)?;
+    byte_lines(&output)
+        .map(|line| {
+            String::from_utf8(line.to_vec())
+                .map_err(|e| Error::from(io::Error::new(io::ErrorKind::InvalidData, e)))
+        })
+        .collect()
+}
+
+fn process_hash_based_sampling<I, O>(config: &Config, input: I, output: O) -> Result<()>
+where
+    I: Read,
+    O: Write,
+{
+    let sampler = hash_sampler(config, input)?;
+    write_hash_sample(config, sampler, output)
+}
+
+/// Create the --hash sampler with every option that applies to it
+fn hash_sampler<I: Read>(config: &Config, input: I) -> Result<CsvHashSampler<I>> {
+    let percentage = config.percentage.unwrap();
+
+    let mut sampler = open_hash_sampler(config, input, percentage)?;
+    if let Some(path) = &config.exclude_keys {
+        sampler = sampler.with_excluded_keys(read_hash_keys(config, path)?);
+    }
+    if let Some(path) = &config.key_rate {
+        sampler = sampler.with_key_rates(read_hash_key_rates(config, path)?);
+    }
+    Ok(sampler
+        .with_skipped_rows(config.skip)
+        .with_normalization(config.normalize)
+        .with_numeric_keys(numeric_keys(config))
+        .with_first_per_key(config.first_per_key)
+        .with_cover_keys(config.cover_keys)
+        .with_stratified_keys(config.stratify_keys))
+}
+
+/// How the --numeric-key options ask for keys to be hashed
+fn numeric_keys(config: &Config) -> NumericKeys {
+    match (config.numeric_key, config.numeric_key_fallback) {
+        (false, _) => NumericKeys::Off,
+        (true, false) => NumericKeys::Required,
+        (true, true) => NumericKeys::Fallback,
+    }
+}
+
+/// The form of a raw --hash key that is hashed and compared, after --normalize and
+/// the --numeric-key options
+fn hash_key<'a>(config: &Config, raw: &'a str) -> Cow<'a, str> {
+    numeric_keys(config).apply(config.normalize.apply(raw))
+}
+
+/// Read a key list file, normalized like the --hash keys it is compared with
+fn read_hash_keys(config: &Config, path: &Path) -> Result<HashSet<String>> {
+    Ok(read_keys(path)?
+        .iter()
+        .map(|key| hash_key(config, key).into_owned())
+        .collect())
+}
+
+/// Read a --key-rate file, normalized like the --hash keys it is compared with.
+///
+/// Keys that normalize to the same key must share a rate, since every row of a key
+/// is sampled alike.
+fn read_hash_key_rates(config: &Config, path: &Path) -> Result<HashMap<String, f64>> {
+    let mut rates: HashMap<String, (String, f64)> = HashMap::new();
+    for (raw, rate) in read_key_rates(path)? {
+        let key = hash_key(config, &raw).into_owned();
+        match rates.get(&key) {
+            Some((other, other_rate)) if *other_rate != rate => {
+                return Err(Error::from(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "key rates for '{}' and '{}' differ, but both normalize to '{}'",
+                        other, raw, key
+                    ),
+                )));
+            }
+            _ => {
+                rates.insert(key, (raw, rate));
+            }
+        }
+    }
+    Ok(rates
+        .into_iter()
+        .map(|(key, (_, rate))| (key, rate))
+        .collect())
+}
+
+/// Write the header and every record accepted by a hash sampler
+fn write_hash_sample<I, O, H>(
+    config: &Config,
+    sampler: CsvHashSampler<I, H>,
+    output: O,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+    H: Hasher + Default,
+{
+    let mut output = RecordOutput::new(config, output);
+    let column_index = sampler.column_index();
+
+    // Print the header, with the appended --emit-hash column
+    check_expected_columns(config, sampler.header())?;
+    let mut header = sampler.header().clone();
+    if config.emit_hash {
+        header.push_field("hash");
+    }
+    output.write_header(config, &header)?;
+
+    // Sample the data and print the results using the streaming iterator
+    for record_result in sampler {
+        let mut record = record_result?;
+        if config.emit_hash {
+            let key = record.get(column_index).unwrap_or_default();
+            let hash = key_hash_with::<H>(&hash_key(config, key));
+            record.push_field(&format!("{:016x}", hash));
+        }
+        output.write_record(config, &record)?;
+    }
+
+    Ok(())
+}
+
+/// Print the index and name of every CSV header column instead of a sample
+fn process_column_listing<I, O>(input: I, mut output: O) -> Result<()>
+where
+    I: Read,
+    O: Write,
+{
+    let mut reader = csv_reader(input);
+    let header = reader.headers()?;
+    for (index, name) in header.iter().enumerate() {
+        writeln!(output, "{}\t{}", index, name)?;
+    }
+    Ok(())
+}
+
+/// Print the number of distinct --hash keys instead of a sample
+fn process_key_count<I, O>(config: &Config, input: I, mut output: O) -> Result<()>
+where
+    I: Read,
+    O: Write,
+{
+    // The percentage is irrelevant since no sampling decision is made
+    let mut sampler = open_hash_sampler(config, input, 100.0)?;
+    if let Some(path) = &config.exclude_keys {
+        sampler = sampler.with_excluded_keys(read_hash_keys(config, path)?);
+    }
+    check_expected_columns(config, sampler.header())?;
+
+    let count = sampler
+        .with_skipped_rows(config.skip)
+        .with_normalization(config.normalize)
+        .with_numeric_keys(numeric_keys(config))
+        .count_distinct_keys()?;
+    writeln!(output, "{}", count)?;
+    Ok(())
+}
+
+/// Print every distinct --hash key with its hash position and selection, then the
+/// fraction of distinct keys selected against the requested percentage.
+///
+/// Row-level output can differ from the requested percentage when a few keys cover
+/// many rows; this shows the key-level fraction that hash sampling actually controls.
+fn process_probability_report<I, O>(config: &Config, input: I, output: O) -> Result<()>
+where
+    I: Read,
+    O: Write,
+{
+    let percentage = config.percentage.unwrap();
+
+    let mut sampler = open_hash_sampler(config, input, percentage)?;
+    if let Some(path) = &config.exclude_keys {
+        sampler = sampler.with_excluded_keys(read_hash_keys(config, path)?);
+    }
+    check_expected_columns(config, sampler.header())?;
+    let sampler = sampler
+        .with_skipped_rows(config.skip)
+        .with_normalization(config.normalize)
+        .with_numeric_keys(numeric_keys(config));
+    write_probability_report(sampler, percentage, output)
+}
+
+/// Write the --probability-report of the distinct keys a hash sampler reads, with the
+/// positions and decisions of the sampler's own hasher
+fn write_probability_report<I, O, H>(
+    sampler: CsvHashSampler<I, H>,
+    percentage: f64,
+    mut output: O,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+    H: Hasher + Default,
+{
+    let probability = sampler.probability();
+    let mut keys: Vec<String> = sampler.distinct_keys()?.into_iter().collect();
+    keys.sort_unstable();
+
+    // Keys are quoted as needed, since they may hold commas, quotes or line breaks
+    let mut writer = csv::WriterBuilder::new()
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(&mut output);
+    writer.write_record(["key", "hash_position", "selected"])?;
+    let mut selected = 0;
+    for key in &keys {
+        let position = hash_position_with::<H>(key);
+        let is_selected = should_include_with::<H>(key, probability, 0);
+        selected += usize::from(is_selected);
+        writer.write_record([
+            key.as_str(),
+            &format!("{:.6}", position),
+            &is_selected.to_string(),
+        ])?;
+    }
+    writer.flush()?;
+    drop(writer);
+
+    let fraction = match keys.len() {
+        0 => 0.0,
+        total => selected as f64 / total as f64 * 100.0,
+    };
+    writeln!(
+        output,
+        "# selected {} of {} distinct keys ({:.2}%), requested {}%",
+        selected,
+        keys.len(),
+        fraction,
+        percentage
+    )?;
+    Ok(())
+}
+
+/// Emit every row whose --hash key is pinned, then fill the rest of the `k` budget
+/// with a uniform sample of the remaining rows.
+///
+/// All pinned rows are emitted even when they exceed `k`.
+fn process_pinned_sampling<I, O, R>(
+    config: &Config,
+    pin_keys: &Path,
+    k: usize,
+    input: I,
+    output: O,
+    rng: &mut R,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+    R: Rng,
+{
+    let mut output = RecordOutput::new(config, output);
+    let pinned_keys = read_hash_keys(config, pin_keys)?;
+    let excluded_keys = match &config.exclude_keys {
+        Some(path) => read_hash_keys(config, path)?,
+        None => HashSet::new(),
+    };
+
+    let mut reader = csv_reader_with_strictness(input, config.strict_columns);
+    let header = reader.headers()?.clone();
+    check_expected_columns(config, &header)?;
+    let key_index = resolve_key_column(config, &header)?;
+    output.write_header(config, &header)?;
+
+    // Keep a full k-sized reservoir since the number of pinned rows is only known at the end
+    let mut pinned = Vec::new();
+    let mut reservoir = Reservoir::new(k);
+    for record in reader.into_records().skip(config.skip) {
+        let record = record?;
+        let key = hash_key(config, record.get(key_index).unwrap_or_default());
+        if excluded_keys.contains(key.as_ref()) {
+            continue;
+        }
+        let line = format_record(config, &record)?;
+        if pinned_keys.contains(key.as_ref()) {
+            pinned.push(line);
+        } else {
+            reservoir.add(line, rng);
+        }
+    }
+
+    // A uniform subset of a uniform sample is itself uniform
+    let mut sampled = reservoir.into_vec();
+    let budget = k.saturating_sub(pinned.len()).min(sampled.len());
+    let (sampled, _) = sampled.partial_shuffle(rng, budget);
+
+    for line in pinned.iter().chain(sampled.iter()) {
+        output.write_row(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Tag every row with the hash bucket of its key column without filtering
+fn process_bucket_assignment<I, O>(
+    config: &Config,
+    assignment: &BucketAssignment,
+    input: I,
+    output: O,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+{
+    let mut output = RecordOutput::new(config, output);
+    let sampler = open_hash_sampler(&keyed_by(config, &assignment.column), input, 100.0)?
+        .with_skipped_rows(config.skip);
+    let column_index = sampler.column_index();
+
+    // Print the header with the appended bucket column
+    check_expected_columns(config, sampler.header())?;
+    let mut header = sampler.header().clone();
+    header.push_field("bucket");
+    output.write_header(config, &header)?;
+
+    for record_result in sampler {
+        let mut record = record_result?;
+        let key = record.get(column_index).unwrap_or_default();
+        let bucket = bucket_for(&config.normalize.apply(key), assignment.buckets);
+        record.push_field(&bucket.to_string());
+        output.write_record(config, &record)?;
+    }
+
+    Ok(())
+}
+
+/// Append a true/false inclusion flag per --assign-columns column to every CSV row.
+///
+/// Each column's flag is a hash decision salted with a seed derived from the column
+/// name and --seed, so rows sharing a value share its flag and columns are independent.
+fn process_column_assignment<I, O>(
+    config: &Config,
+    columns: &[String],
+    input: I,
+    output: O,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+{
+    let mut output = RecordOutput::new(config, output);
+    let percentage = config.percentage.unwrap();
+    let base_seed = config.seed.unwrap_or_default();
+
+    let mut reader = csv_reader_with_strictness(input, config.strict_columns);
+    let mut header = reader.headers()?.clone();
+    check_expected_columns(config, &header)?;
+    let assigned = columns
+        .iter()
+        .map(|column| {
+            let index = resolve_column(config, &header, column)?;
+            Ok((index, derive_seed(base_seed, column.trim())))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Print the header with one appended flag column per assigned column
+    for column in columns {
+        header.push_field(&format!("{}_included", column.trim()));
+    }
+    output.write_header(config, &header)?;
+
+    for record in reader.into_records().skip(config.skip) {
+        let mut record = record?;
+        let flags: Vec<bool> = assigned
+            .iter()
+            .map(|&(index, seed)| {
+                let key = record.get(index).unwrap_or_default();
+                should_include(&config.normalize.apply(key), percentage / 100.0, seed)
+            })
+            .collect();
+        for flag in flags {
+            record.push_field(if flag { "true" } else { "false" });
+        }
+        output.write_record(config, &record)?;
+    }
+
+    Ok(())
+}
+
+/// Sample CSV rows with probability proportional to a weight column (Poisson sampling).
+///
+/// Rows stream straight through when the total weight is given; otherwise the rows are
+/// buffered once to compute it.
+fn process_weighted_sampling<I, O, R>(
+    config: &Config,
+    expected_size: f64,
+    weight_column: &str,
+    input: I,
+    output: O,
+    rng: &mut R,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+    R: Rng,
+{
+    let mut output = RecordOutput::new(config, output);
+    let mut reader = csv_reader_with_strictness(input, config.strict_columns);
+    let header = reader.headers()?.clone();
+    check_expected_columns(config, &header)?;
+    let weight_index = resolve_column(config, &header, weight_column)?;
+    output.write_header(config, &header)?;
+
+    let mut rows =
+        reader
+            .into_records()
+            .skip(config.skip)
+            .map(|record| -> Result<(csv::StringRecord, f64)> {
+                let record = record?;
+                let weight = parse_weight(record.get(weight_index).unwrap_or_default())?;
+                Ok((record, weight))
+            });
+
+    let mut emit = |scale: f64, (record, weight): (csv::StringRecord, f64)| {
+        if poisson_include(weight, scale, rng) {
+            output.write_record(config, &record)?;
+        }
+        Result::Ok(())
+    };
+
+    match config.total_weight {
+        Some(total_weight) => {
+            let scale = poisson_scale(expected_size, total_weight);
+            rows.try_for_each(|row| emit(scale, row?))
+        }
+        None => {
+            let rows: Vec<_> = rows.collect::<Result<_>>()?;
+            let total_weight = rows.iter().map(|(_, weight)| weight).sum();
+            let scale = poisson_scale(expected_size, total_weight);
+            rows.into_iter().try_for_each(|row| emit(scale, row))
+        }
+    }
+}
+
+/// Keep `k` randomly chosen columns of every CSV row, in their header order. The columns
+/// are chosen once from the header, so every row keeps the same ones.
+fn process_column_sampling<I, O, R>(
+    config: &Config,
+    k: usize,
+    input: I,
+    output: O,
+    rng: &mut R,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+    R: Rng,
+{
+    let mut output = RecordOutput::new(config, output);
+    let mut reader = csv_reader_with_strictness(input, config.strict_columns);
+    let header = reader.headers()?.clone();
+    check_expected_columns(config, &header)?;
+
+    let mut columns = reservoir_sample(0..header.len(), k, rng);
+    columns.sort_unstable();
+    if columns.len() < k {
+        warn!(
+            "requested {} columns, but the header has only {}",
+            k,
+            columns.len()
+        );
+    }
+    let project = |record: &csv::StringRecord| -> csv::StringRecord {
+        columns
+            .iter()
+            .map(|&index| record.get(index).unwrap_or_default())
+            .collect()
+    };
+
+    output.write_header(config, &project(&header))?;
+    for record in reader.into_records().skip(config.skip) {
+        output.write_record(config, &project(&record?))?;
+    }
+    Ok(())
+}
+
+/// Write the CSV rows whose value in `column` has a percentile rank in `[low, high)`,
+/// in input order. The rank of a value is the percentage of all values below it, so
+/// tied values share a rank and fall in the same band.
+fn process_percentile_band<I, O>(
+    config: &Config,
+    column: &str,
+    (low, high): (f64, f64),
+    input: I,
+    output: O,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+{
+    let mut output = RecordOutput::new(config, output);
+    let mut reader = csv_reader_with_strictness(input, config.strict_columns);
+    let header = reader.headers()?.clone();
+    check_expected_columns(config, &header)?;
+    let value_index = resolve_column(config, &header, column)?;
+    output.write_header(config, &header)?;
+
+    let rows = reader
+        .into_records()
+        .skip(config.skip)
+        .map(|record| -> Result<(csv::StringRecord, f64)> {
+            let record = record?;
+            let value = parse_number(record.get(value_index).unwrap_or_default())?;
+            Ok((record, value))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut sorted: Vec<f64> = rows.iter().map(|(_, value)| *value).collect();
+    sorted.sort_unstable_by(f64::total_cmp);
+    let total = sorted.len() as f64;
+    for (record, value) in &rows {
+        let rank = 100.0 * sorted.partition_point(|other| other < value) as f64 / total;
+        if low <= rank && rank < high {
+            output.write_record(config, record)?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_number(value: &str) -> Result<f64> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|number| !number.is_nan())
+        .ok_or_else(|| Error::InvalidNumber(value.to_string()))
+}
+
+/// Keep a fixed-size sample of the CSV rows whose event time lies within `window`
+/// seconds of the latest event time seen
+fn process_windowed_sampling<I, O, R>(
+    config: &Config,
+    time_column: &str,
+    window: f64,
+    k: usize,
+    input: I,
+    output: O,
+    rng: &mut R,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+    R: Rng,
+{
+    let mut output = RecordOutput::new(config, output);
+    let mut reader = csv_reader_with_strictness(input, config.strict_columns);
+    let header = reader.headers()?.clone();
+    check_expected_columns(config, &header)?;
+    let time_index = resolve_column(config, &header, time_column)?;
+    output.write_header(config, &header)?;
+
+    let mut reservoir = WindowedReservoir::new(k, window);
+    for record in reader.into_records().skip(config.skip) {
+        let record = record?;
+        let timestamp = parse_timestamp(record.get(time_index).unwrap_or_default())?;
+        reservoir.add(timestamp, record, rng);
+    }
+
+    for record in reservoir.into_vec() {
+        output.write_record(config, &record)?;
+    }
+    Ok(())
+}
+
+/// Parse an event time given as RFC 3339 or as (possibly fractional) epoch seconds
+fn parse_timestamp(value: &str) -> Result<f64> {
+    let seconds = match value.parse::<f64>() {
+        Ok(seconds) => Some(seconds).filter(|seconds| seconds.is_finite()),
+        Err(_) => chrono::DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|time| time.timestamp() as f64 + time.timestamp_subsec_nanos() as f64 / 1e9),
+    };
+    seconds.ok_or_else(|| Error::InvalidTimestamp(value.to_string()))
+}
+
+/// Parse a row weight, which must be a finite non-negative number
+fn parse_weight(value: &str) -> Result<f64> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|weight| weight.is_finite() && *weight >= 0.0)
+        .ok_or_else(|| Error::InvalidWeight(value.to_string()))
+}
+
+/// Write the CSV header with the --rename mappings applied
+fn write_header<O: Write>(
+    config: &Config,
+    output: &mut O,
+    header: &csv::StringRecord,
+) -> Result<()> {
+    let header = rename_columns(header, config.rename.as_deref().unwrap_or_default())?;
+    write_header_line(config, output, &format_record(config, &header)?)
+}
+
+/// Format a parsed record as an output line: its fields joined with commas as read, or
+/// written by a CSV writer with --output-delimiter so quoting follows the new delimiter
+fn format_record(config: &Config, record: &csv::StringRecord) -> Result<String> {
+    let Some(delimiter) = config.output_delimiter else {
+        return Ok(record.iter().collect::<Vec<_>>().join(","));
+    };
+
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(Vec::new());
+    writer.write_record(record)?;
+    let mut line = writer.into_inner().map_err(|e| e.into_error())?;
+    line.pop();
+    Ok(String::from_utf8(line).expect("fields of a StringRecord are valid UTF-8"))
+}
+
+/// Write the CSV header line unless --no-header-output is set
+fn write_header_line<O: Write>(config: &Config, output: &mut O, header: &str) -> Result<()> {
+    if !config.no_header_output {
+        writeln!(output, "{}", header)?;
+    }
+    Ok(())
+}
+
+/// Rename header columns, reporting an unknown old name as `Error::ColumnNotFound`
+fn rename_columns(
+    header: &csv::StringRecord,
+    renames: &[ColumnRename],
+) -> Result<csv::StringRecord> {
+    let mut names: Vec<&str> = header.iter().collect();
+    for rename in renames {
+        let index = find_column(header, &rename.from)
+            .ok_or_else(|| Error::ColumnNotFound(rename.from.clone()))?;
+        names[index] = &rename.to;
+    }
+    Ok(csv::StringRecord::from(names))
+}
+
+/// Verify the CSV header against --expect-columns, if given
+fn check_expected_columns(config: &Config, header: &csv::StringRecord) -> Result<()> {
+    match config
+        .expect_columns
+        .as_deref()
+        .and_then(|expected| column_mismatch(header, expected))
+    {
+        Some(mismatch) => Err(Error::UnexpectedColumns(mismatch)),
+        None => Ok(()),
+    }
+}
+
+/// Find a column by name, ignoring case with --ignore-case-columns
+fn resolve_column(config: &Config, header: &csv::StringRecord, column_name: &str) -> Result<usize> {
+    let index = if config.ignore_case_columns {
+        find_column_ignoring_case(header, column_name)
+            .map_err(|ambiguity| Error::AmbiguousColumn(ambiguity.to_string()))?
+    } else {
+        find_column(header, column_name)
+    };
+    index.ok_or_else(|| Error::ColumnNotFound(column_name.to_string()))
+}
+
+/// Name of the --hash column, or the --hash-regex pattern that selects it
+fn key_column_name(config: &Config) -> &str {
+    match &config.hash_regex {
+        Some(pattern) => pattern.as_str(),
+        None => config
+            .hash_column
+            .as_deref()
+            .expect("hash modes require --hash or --hash-regex"),
+    }
+}
+
+/// The configuration with `column` as the --hash key column, for modes that hash a
+/// column named by another option
+fn keyed_by(config: &Config, column: &str) -> Config {
+    Config {
+        hash_column: Some(column.to_string()),
+        hash_regex: None,
+        ..config.clone()
+    }
+}
+
+/// Find the --hash column, or the only column matching --hash-regex, in the header.
+/// Resolves the same column as `open_hash_sampler`.
+fn resolve_key_column(config: &Config, header: &csv::StringRecord) -> Result<usize> {
+    match &config.hash_regex {
+        Some(pattern) => find_column_matching(header, pattern)
+            .map_err(|ambiguity| Error::AmbiguousColumn(ambiguity.to_string()))?
+            .ok_or_else(|| Error::ColumnNotFound(pattern.as_str().to_string())),
+        None => resolve_column(config, header, key_column_name(config)),
+    }
+}
+
+/// Create a CSV hash sampler on the --hash or --hash-regex key column, reporting a
+/// missing key column as `Error::ColumnNotFound` and an ambiguous one as
+/// `Error::AmbiguousColumn`
+fn open_hash_sampler<I: Read>(
+    config: &Config,
+    input: I,
+    percentage: f64,
+) -> Result<CsvHashSampler<I>> {
+    let column = if let Some(pattern) = &config.hash_regex {
+        KeyColumn::Matching(pattern)
+    } else if config.ignore_case_columns {
+        KeyColumn::NamedIgnoringCase(key_column_name(config))
+    } else {
+        KeyColumn::Named(key_column_name(config))
+    };
+    CsvHashSampler::open(input, percentage, column, config.strict_columns)
+}
+
+/// Reservoir sampling that streams the lines and keeps retained ones beyond
+/// `memory_budget` bytes in a spill file in `dir`; selects exactly like
+/// `process_reservoir_sampling`
+fn process_spilled_reservoir_sampling<I, T, O, R>(
+    lines_iter: I,
+    k: usize,
+    dir: &Path,
+    memory_budget: usize,
+    rng: &mut R,
+    mut output: O,
+) -> Result<()>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+    O: Write,
+    R: Rng,
+{
+    let mut reservoir = SpillingReservoir::new(k, memory_budget, dir)?;
+    for line in lines_iter {
+        reservoir.add(line.as_ref().to_string(), rng)?;
+    }
+    warn_if_undersized(k, reservoir.seen());
+    for line in reservoir.into_items() {
+        writeln!(output, "{}", line?)?;
+    }
+    Ok(())
+}
+
+fn process_reservoir_sampling<I, T, O, R>(
+    lines_iter: I,
+    k: usize,
+    order: ReservoirOrder,
+    rng: &mut R,
+    mut output: O,
+) -> Result<()>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+    O: Write,
+    R: Rng,
+{
+    let lines: Vec<T> = lines_iter.collect();
+    // The index rides along without changing the draws, so every order selects the same lines
+    let sampled = reservoir_sample(lines.iter().enumerate(), k, rng);
+    write_reservoir_sample(sampled, k, order, rng, &mut output)
+}
+
+/// Write the lines of a reservoir sample, tagged with their input indices, in `order`
+fn write_reservoir_sample<T, O, R>(
+    mut sampled: Vec<(usize, T)>,
+    k: usize,
+    order: ReservoirOrder,
+    rng: &mut R,
+    output: &mut O,
+) -> Result<()>
+where
+    T: AsRef<str>,
+    O: Write,
+    R: Rng,
+{
+    match order {
+        ReservoirOrder::Slot => {}
+        ReservoirOrder::Input => sampled.sort_unstable_by_key(|(index, _)| *index),
+        ReservoirOrder::Shuffled => sampled.shuffle(rng),
+    }
+    warn_if_undersized(k, sampled.len());
+    for (_, line) in sampled {
+        writeln!(output, "{}", line.as_ref())?;
+    }
+    Ok(())
+}
+
+/// Warn that a sample of `k` lines came out short because the input has only `seen`
+fn warn_if_undersized(k: usize, seen: usize) {
+    if seen < k {
+        warn!(
+            "requested a sample of {} lines, but the input has only {}",
+            k, seen
+        );
+    }
+}
+
+/// Reservoir sample the last `window` lines, in input order
+fn process_count_window_sampling<I, T, O, R>(
+    lines_iter: I,
+    k: usize,
+    window: u64,
+    rng: &mut R,
+    mut output: O,
+) -> Result<()>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+    O: Write,
+    R: Rng,
+{
+    let mut reservoir = CountWindowedReservoir::new(k, window);
+    for line in lines_iter {
+        reservoir.add(line, rng);
+    }
+    for line in reservoir.into_vec() {
+        writeln!(output, "{}", line.as_ref())?;
+    }
+    Ok(())
+}
+
+fn process_percentage_sampling<I, T, O, R>(
+    lines_iter: I,
+    percentage: f64,
+    antithetic: bool,
+    rng: R,
+    mut output: O,
+) -> Result<()>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+    O: Write,
+    R: Rng,
+{
+    let sampled_iter =
+        percentage_sample_iter(lines_iter, percentage, rng).with_antithetic(antithetic);
+    for line in sampled_iter {
+        writeln!(output, "{}", line.as_ref())?;
+    }
+    Ok(())
+}
+
+/// Percentage sampling that slows down near `target` kept lines, see `TargetCountSampleIter`
+fn process_target_count_sampling<I, T, O, R>(
+    lines_iter: I,
+    percentage: f64,
+    target: usize,
+    rng: R,
+    mut output: O,
+) -> Result<()>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+    O: Write,
+    R: Rng,
+{
+    for line in target_count_sample_iter(lines_iter, percentage, target, rng) {
+        writeln!(output, "{}", line.as_ref())?;
+    }
+    Ok(())
+}
+
+/// Percentage sampling whose inclusion probability decays with each line's distance
+/// from the last line, so recent lines are kept more often than old ones
+fn process_decay_sampling<I, T, O, R>(
+    lines_iter: I,
+    percentage: f64,
+    rate: f64,
+    rng: &mut R,
+    mut output: O,
+) -> Result<()>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+    O: Write,
+    R: Rng,
+{
+    // The age of a line is only known once the last line has been read
+    let lines: Vec<T> = lines_iter.collect();
+    let last = lines.len().saturating_sub(1);
+    for (index, line) in lines.iter().enumerate() {
+        let age = (last - index) as f64;
+        if decay_include(percentage, rate, age, rng) {
+            writeln!(output, "{}", line.as_ref())?;
+        }
+    }
+    Ok(())
+}
+
+/// Percentage sampling of CSV records whose inclusion probability decays with each
+/// record's age, in seconds, relative to the latest time in --time-column
+fn process_time_decay_sampling<I, O, R>(
+    config: &Config,
+    rate: f64,
+    time_column: &str,
+    input: I,
+    output: O,
+    rng: &mut R,
+) -> Result<()>
+where
+    I: Read,
+    O: Write,
+    R: Rng,
+{
+    let mut output = RecordOutput::new(config, output);
+    let percentage = config.percentage.unwrap();
+    let mut reader = csv_reader_with_strictness(input, config.strict_columns);
+    let header = reader.headers()?.clone();
+    check_expected_columns(config, &header)?;
+    let time_index = resolve_column(config, &header, time_column)?;
+    output.write_header(config, &header)?;
+
+    let mut records = Vec::new();
+    for record in reader.into_records().skip(config.skip) {
+        let record = record?;
+        let timestamp = parse_timestamp(record.get(time_index).unwrap_or_default())?;
+        records.push((timestamp, record));
+    }
+
+    let latest = records
+        .iter()
+        .map(|(timestamp, _)| *timestamp)
+        .fold(f64::NEG_INFINITY, f64::max);
+    for (timestamp, record) in &records {
+        if decay_include(percentage, rate, latest - timestamp, rng) {
+            output.write_record(config, record)?;
+        }
+    }
+    Ok(())
+}
+
+/// Draws whether an item of the given age is included at `percentage * exp(-rate * age)`,
+/// comparing a `u64` draw as percentage sampling does
+fn decay_include<R: Rng>(percentage: f64, rate: f64, age: f64, rng: &mut R) -> bool {
+    let probability = percentage / 100.0 * (-rate * age).exp();
+    draw_included(rng.gen::<u64>(), inclusion_threshold(probability))
+}
+
+fn process_whitespace_hash_sampling<I, T, O>(
+    lines_iter: I,
+    percentage: f64,
+    index: usize,
+    normalization: KeyNormalization,
+    mut output: O,
+) -> Result<()>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+    O: Write,
+{
+    for line in whitespace_hash_sample_iter(lines_iter, percentage, index, normalization) {
+        writeln!(output, "{}", line.as_ref())?;
+    }
+    Ok(())
+}
+
+fn process_shuffle_all<I, T, O, R>(lines_iter: I, rng: &mut R, mut output: O) -> Result<()>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+    O: Write,
+    R: Rng,
+{
+    let mut lines: Vec<T> = lines_iter.collect();
+    lines.shuffle(rng);
+    for line in lines {
+        writeln!(output, "{}", line.as_ref())?;
+    }
+    Ok(())
+}
+
+fn process_one_line<I, T, O, R>(lines_iter: I, rng: &mut R, mut output: O) -> Result<()>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+    O: Write,
+    R: Rng,
+{
+    if let Some(line) = sample_one(lines_iter, rng) {
+        writeln!(output, "{}", line.as_ref())?;
+    }
+    Ok(())
+}
+
+fn process_first_lines<I, T, O>(lines_iter: I, n: usize, mut output: O) -> Result<()>
+where
+    I: Iterator<Item = T>,
+    T: AsRef<str>,
+    O: Write,
+{
+    for line in lines_iter.take(n) {
+        writeln!(output, "{}", line.as_ref())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_args_for_tests;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::io::Cursor;
+    use std::sync::Mutex;
+
+    fn sample(args: &[&str], input: &str) -> Vec<String> {
+        let args = std::iter::once("sample").chain(args.iter().copied());
+        let config = parse_args_for_tests(args).unwrap();
+        let mut rng = StdRng::seed_from_u64(42);
+        sample_lines(Cursor::new(input), &config, &mut rng).unwrap()
+    }
+
+    #[test]
+    fn test_sample_lines_reservoir() {
+        let lines = sample(&["3"], "a\nb\nc\nd\ne\n");
+        assert_eq!(lines.len(), 3);
+        assert!(lines
+            .iter()
+            .all(|l| ["a", "b", "c", "d", "e"].contains(&l.as_str())));
+    }
+
+    #[test]
+    fn test_sample_lines_keeps_multi_line_records_whole() {
+        let input = "id,v\n1,\"a\nb\"\n2,\"c, d\"\n";
+        assert_eq!(
+            sample(&["-p", "100", "--csv", "--hash", "id"], input),
+            vec!["id,v", "1,a\nb", "2,c, d"]
+        );
+        assert_eq!(sample(&["-p", "100"], "a\n\nb"), vec!["a", "", "b"]);
+    }
+
+    #[test]
+    fn test_sample_lines_reservoir_order() {
+        let input = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        let orders: Vec<Vec<String>> = ["slot", "input", "shuffled"]
+            .iter()
+            .map(|order| sample(&["4", "--reservoir-order", order], input))
+            .collect();
+        assert_eq!(orders[0], vec!["g", "b", "f", "h"]);
+        assert_eq!(orders[1], vec!["b", "f", "g", "h"]);
+        assert_eq!(orders[2], vec!["h", "g", "f", "b"]);
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet_out_keeps_quoted_fields() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+        use parquet::record::RowAccessor;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sample.parquet");
+        let path_arg = path.to_str().unwrap();
+        let input = "id,v\n1,\"a, b\"\n2,c\n";
+        for args in [
+            &["-p", "100"][..],
+            &["-p", "100", "--hash", "id"],
+            &["-p", "100", "--hash", "id", "--output-delimiter", ";"],
+        ] {
+            let config = parse_args_for_tests(
+                ["sample", "--csv", "--parquet-out", path_arg]
+                    .into_iter()
+                    .chain(args.iter().copied()),
+            )
+            .unwrap();
+            let mut rng = StdRng::seed_from_u64(1);
+            process_input(&config, input.as_bytes(), io::sink(), &mut rng).unwrap();
+
+            let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+            let rows: Vec<_> = reader
+                .get_row_iter(None)
+                .unwrap()
+                .map(|row| row.unwrap())
+                .collect();
+            assert_eq!(rows.len(), 2, "{:?}", args);
+            assert_eq!(rows[0].get_string(1).unwrap(), "a, b", "{:?}", args);
+            assert_eq!(rows[1].get_string(1).unwrap(), "c", "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_write_sorted_keeps_bytes_that_are_not_utf8() {
+        let config = parse_args_for_tests(["sample", "5", "--sort"]).unwrap();
+        let mut output = Vec::new();
+        write_sorted(&config, &mut output, |_, buffer| {
+            buffer.extend_from_slice(b"b\xff\na\xfe\nc\n");
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(output, b"a\xfe\nb\xff\nc\n");
+    }
+
+    #[test]
+    fn test_column_stats_cover_every_row() {
+        let input =
+            "id,price,note\n1,4,a\n2,\"10\",\"b,c\"\n3,-2,d\n4,,e\n5,x,f\n6,0.5,g\n7,8,\"h\n,7\"\n";
+        for args in [&["-p", "30"][..], &["2"], &["--sample-columns", "1"]] {
+            let config = parse_args_for_tests(
+                ["sample", "--csv", "--stats-column", "price", "-s", "4"]
+                    .into_iter()
+                    .chain(args.iter().copied()),
+            )
+            .unwrap();
+            let mut rng = StdRng::seed_from_u64(4);
+            let mut output = Vec::new();
+            let stats = process_with_column_stats(
+                &config,
+                "price",
+                input.as_bytes(),
+                &mut output,
+                &mut rng,
+            )
+            .unwrap();
+            assert_eq!(stats.count, 5, "{:?}", args);
+            assert_eq!(stats.mean(), Some(4.1));
+            assert_eq!((stats.min, stats.max), (-2.0, 10.0));
+            assert_eq!(stats.skipped, 1);
+
+            // The sample itself is unaffected
+            let plain = Config {
+                stats_column: None,
+                ..config.clone()
+            };
+            let mut expected = Vec::new();
+            let mut rng = StdRng::seed_from_u64(4);
+            process_input(&plain, input.as_bytes(), &mut expected, &mut rng).unwrap();
+            assert_eq!(output, expected, "{:?}", args);
+        }
+
+        let config =
+            parse_args_for_tests(["sample", "2", "--csv", "--stats-column", "cost"]).unwrap();
+        let mut rng = StdRng::seed_from_u64(4);
+        let result = process_input(&config, input.as_bytes(), io::sink(), &mut rng);
+        assert!(matches!(result, Err(Error::ColumnNotFound(column)) if column == "cost"));
+    }
+
+    #[test]
+    fn test_reused_line_buffer_matches_line_iterator() {
+        let mut input: Vec<u8> = (0..500)
+            .map(|i| format!("line {}\r\n", i))
+            .collect::<String>()
+            .into_bytes();
+        input.extend_from_slice(b"bad \xff line\nafter\n");
+
+        let cases: [&[&str]; 9] = [
+            &["-p", "30"],
+            &["-p", "30", "--antithetic"],
+            &["-p", "30", "--skip", "7"],
+            &["-p", "50", "--csv"],
+            &["25"],
+            &["25", "--skip", "490"],
+            &["600"],
+            &["25", "--reservoir-order", "input"],
+            &["25", "--reservoir-order", "shuffled"],
+        ];
+        for args in cases {
+            let config =
+                parse_args_for_tests(std::iter::once("sample").chain(args.iter().copied()))
+                    .unwrap();
+            assert!(reuses_line_buffer(&config), "{:?}", args);
+
+            let mut reused = Vec::new();
+            let mut rng = StdRng::seed_from_u64(3);
+            process_input(&config, input.as_slice(), &mut reused, &mut rng).unwrap();
+
+            // The String-per-line path the reused buffer replaces
+            let mut expected = Vec::new();
+            let mut rng = StdRng::seed_from_u64(3);
+            let mut lines = input.lines();
+            if config.csv_mode {
+                let header = lines.next().unwrap().unwrap();
+                process_header_line(&config, &header, &mut expected).unwrap();
+            }
+            process_lines(&config, until_unreadable(lines), &mut expected, &mut rng).unwrap();
+
+            assert_eq!(reused, expected, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_sample_lines_percentage() {
+        assert_eq!(sample(&["-p", "100"], "a\nb\nc\n"), vec!["a", "b", "c"]);
+        assert!(sample(&["-p", "0"], "a\nb\nc\n").is_empty());
+    }
+
+    #[test]
+    fn test_sample_lines_csv_hash() {
+        let lines = sample(&["-p", "100", "--csv", "--hash", "id"], "id,v\n1,a\n2,b\n");
+        assert_eq!(lines, vec!["id,v", "1,a", "2,b"]);
+    }
+
+    #[test]
+    fn test_process_mapped_matches_process_input() {
+        let lines: String = (0..200).map(|i| format!("{} {}\r\n", i % 13, i)).collect();
+        let inputs = [
+            format!("k v\n{}", lines),
+            format!("\u{FEFF}k,v\n{}tail", lines),
+            "k v\n".to_string(),
+            String::new(),
+        ];
+        let modes: [&[&str]; 7] = [
+            &["7"],
+            &["7", "--csv", "--skip", "3"],
+            &["-p", "30"],
+            &["-p", "30", "--csv", "--no-header-output"],
+            &["-p", "50", "--whitespace-split", "--hash-index", "0"],
+            &["--first", "5", "--csv"],
+            &["-p", "50", "--csv", "--hash", "k", "--filter", "1"],
+        ];
+
+        for input in &inputs {
+            for mode in modes {
+                let args = std::iter::once("sample").chain(mode.iter().copied());
+                let config = parse_args_for_tests(args).unwrap();
+
+                let mut buffered = Vec::new();
+                let mut rng = StdRng::seed_from_u64(42);
+                let buffered_ok =
+                    process_input(&config, input.as_bytes(), &mut buffered, &mut rng).is_ok();
+
+                let mut mapped = Vec::new();
+                let mut rng = StdRng::seed_from_u64(42);
+                let mapped_ok =
+                    process_mapped(&config, input.as_bytes(), &mut mapped, &mut rng).is_ok();
+
+                assert_eq!(mapped_ok, buffered_ok, "{:?} on {:?}", mode, input);
+                assert_eq!(mapped, buffered, "{:?} on {:?}", mode, input);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1700000000").unwrap(), 1_700_000_000.0);
+        assert_eq!(parse_timestamp("1700000000.5").unwrap(), 1_700_000_000.5);
+        assert_eq!(
+            parse_timestamp("2023-11-14T22:13:20Z").unwrap(),
+            1_700_000_000.0
+        );
+        assert_eq!(
+            parse_timestamp("2023-11-14T23:13:20.25+01:00").unwrap(),
+            1_700_000_000.25
+        );
+        assert!(matches!(
+            parse_timestamp("yesterday"),
+            Err(Error::InvalidTimestamp(_))
+        ));
+        assert!(parse_timestamp("NaN").is_err());
+    }
+
+    #[test]
+    fn test_windowed_sampling_evicts_old_rows() {
+        let mut input = "id,ts\n".to_string();
+        for i in 0..100 {
+            input.push_str(&format!("{},{}\n", i, 1_700_000_000 + i * 10));
+        }
+        let lines = sample(
+            &["4", "--csv", "--time-column", "ts", "--window", "95"],
+            &input,
+        );
+        assert_eq!(lines[0], "id,ts");
+        assert_eq!(lines.len(), 5);
+        for line in &lines[1..] {
+            let id: u32 = line.split(',').next().unwrap().parse().unwrap();
+            assert!(id >= 90, "{}", line);
+        }
+
+        let lines = sample(
+            &["4", "--csv", "--time-column", "ts", "--window", "15"],
+            &input,
+        );
+        assert_eq!(lines, vec!["id,ts", "98,1700000980", "99,1700000990"]);
+    }
+
+    #[test]
+    fn test_rename_columns() {
+        let header = csv::StringRecord::from(vec!["id", " name ", "v"]);
+        let renames = [
+            ColumnRename {
+                from: "name".to_string(),
+                to: "user".to_string(),
+            },
+            ColumnRename {
+                from: "v".to_string(),
+                to: "value".to_string(),
+            },
+        ];
+        let renamed = rename_columns(&header, &renames).unwrap();
+        assert_eq!(renamed, vec!["id", "user", "value"]);
+
+        let missing = [ColumnRename {
+            from: "x".to_string(),
+            to: "y".to_string(),
+        }];
+        assert!(matches!(
+            rename_columns(&header, &missing),
+            Err(Error::ColumnNotFound(column)) if column == "x"
+        ));
+    }
+
+    /// Records every log message so tests can assert on warnings
+    struct CapturingLogger(Mutex<Vec<String>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let message = format!("{}: {}", record.level(), record.args());
+            self.0.lock().unwrap().push(message);
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+
+    /// Installs the capturing logger (once per test binary) and returns its messages
+    fn captured_logs() -> &'static Mutex<Vec<String>> {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+        &LOGGER.0
+    }
+
+    #[test]
+    fn test_decay_include_compares_integer_draws() {
+        let mut rng = StdRng::seed_from_u64(7);
+        assert!((0..1000).all(|_| decay_include(100.0, 0.5, 0.0, &mut rng)));
+        assert!((0..1000).all(|_| !decay_include(0.0, 0.5, 0.0, &mut rng)));
+
+        // Each decision is the percentage sampling decision for the decayed probability
+        let probability = 0.5 * (-0.1f64 * 3.0).exp();
+        let (mut decay_rng, mut draw_rng) = (StdRng::seed_from_u64(3), StdRng::seed_from_u64(3));
+        for _ in 0..1000 {
+            assert_eq!(
+                decay_include(50.0, 0.1, 3.0, &mut decay_rng),
+                draw_included(draw_rng.gen(), inclusion_threshold(probability))
+            );
+        }
+    }
+
+    #[test]
+    fn test_stats_column_logs_stats() {
+        let logs = captured_logs();
+        let config =
+            parse_args_for_tests(["sample", "-p", "100", "--csv", "--stats-column", "price"])
+                .unwrap();
+
+        let mut output = Vec::new();
+        let mut rng = StdRng::seed_from_u64(42);
+        process_input(&config, &b"price\n2\n4\n"[..], &mut output, &mut rng).unwrap();
+        assert_eq!(output, b"price\n2\n4\n");
+
+        let logs = logs.lock().unwrap();
+        assert!(
+            logs.iter()
+                .any(|m| m == "INFO: price: count=2 mean=3 min=2 max=4"),
+            "{:?}",
+            logs
+        );
+    }
+
+    #[test]
+    fn test_unreadable_line_logs_warning() {
+        let logs = captured_logs();
+        let input = b"h\na\nb\n\xFF\nc\n";
+        let config = parse_args_for_tests(["sample", "-p", "100", "--csv"]).unwrap();
+
+        let mut output = Vec::new();
+        let mut rng = StdRng::seed_from_u64(42);
+        process_input(&config, &input[..], &mut output, &mut rng).unwrap();
+        assert_eq!(output, b"h\na\nb\n");
+
+        let mut mapped = Vec::new();
+        process_mapped(&config, input, &mut mapped, &mut rng).unwrap();
+        assert_eq!(mapped, output);
+
+        let warnings = logs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.starts_with("WARN: stopped reading at data line 3:"))
+            .count();
+        assert_eq!(warnings, 2);
+    }
+
+    /// A reader that fails after `limit` bytes, like a run that is interrupted
+    struct InterruptedReader {
+        data: Cursor<Vec<u8>>,
+        limit: u64,
+    }
+
+    impl Read for InterruptedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let remaining = self.limit.saturating_sub(self.data.position()) as usize;
+            if remaining == 0 {
+                return Err(io::Error::other("interrupted"));
+            }
+            let len = buf.len().min(remaining);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_resume_matches_uninterrupted_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checkpoint");
+        let path_arg = path.to_str().unwrap();
+        let input: String = std::iter::once("id\n".to_string())
+            .chain((0..25_000).map(|i| format!("line{}\n", i)))
+            .collect();
+        let args = [
+            "sample",
+            "-p",
+            "30",
+            "--csv",
+            "--antithetic",
+            "--skip",
+            "3",
+            "--checkpoint",
+            path_arg,
+        ];
+        let config = parse_args_for_tests(args).unwrap();
+
+        let mut uninterrupted = Vec::new();
+        let mut rng = ChaCha12Rng::seed_from_u64(9);
+        process_checkpointed(
+            &config,
+            input.as_bytes(),
+            &mut uninterrupted,
+            &mut rng,
+            &path,
+            None,
+        )
+        .unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap().lines, 24_997);
+
+        // Same output as a run without checkpoints
+        let mut plain = Vec::new();
+        let mut rng = StdRng::seed_from_u64(9);
+        process_input(&config, input.as_bytes(), &mut plain, &mut rng).unwrap();
+        assert_eq!(uninterrupted, plain);
+
+        // Interrupt the run partway through the second chunk
+        let reader = InterruptedReader {
+            data: Cursor::new(input.clone().into_bytes()),
+            limit: input.len() as u64 / 2,
+        };
+        let mut partial = Vec::new();
+        let mut rng = ChaCha12Rng::seed_from_u64(9);
+        let result = process_checkpointed(&config, reader, &mut partial, &mut rng, &path, None);
+        assert!(result.is_err());
+
+        let checkpoint = Checkpoint::load(&path).unwrap();
+        assert_eq!(checkpoint.lines, 10_000);
+        assert!(partial.len() as u64 >= checkpoint.output_bytes);
+
+        let mut resumed = Vec::new();
+        let mut rng = checkpoint.rng();
+        process_checkpointed(
+            &config,
+            input.as_bytes(),
+            &mut resumed,
+            &mut rng,
+            &path,
+            Some(&checkpoint),
+        )
+        .unwrap();
+
+        partial.truncate(checkpoint.output_bytes as usize);
+        partial.extend(resumed);
+        assert_eq!(partial, uninterrupted);
+    }
+
+    #[test]
+    fn test_sample_lines_uses_given_rng() {
+        let input: String = (0..100).map(|i| format!("{}\n", i)).collect();
+        let config = parse_args_for_tests(["sample", "5"]).unwrap();
+
+        let first = sample_lines(Cursor::new(&input), &config, &mut StdRng::seed_from_u64(1));
+        let second = sample_lines(Cursor::new(&input), &config, &mut StdRng::seed_from_u64(1));
+        assert_eq!(first.unwrap(), second.unwrap());
+    }
+}
@@ -0,0 +1,181 @@
+use rand::Rng;
+
+/// A bounded uniform sample of a population that supports deletions as well as
+/// insertions, using random pairing (Gemulla, Lehner and Haas, 2006).
+///
+/// Each deletion is remembered as uncompensated, split by whether the deleted item
+/// was in the sample. Later insertions are paired with those deletions: an insertion
+/// enters the sample with the probability that its paired deletion left it, so the
+/// sample stays a uniform random subset of the items still in the population. With
+/// no pending deletions, insertions fall back to plain reservoir sampling.
+///
+/// The sample can shrink below `capacity` after deletions; it grows back as new
+/// items are inserted.
+#[derive(Debug, Clone)]
+pub struct DynamicReservoir<T> {
+    items: Vec<T>,
+    capacity: usize,
+    population: usize,
+    /// Uncompensated deletions of items that were in the sample
+    deleted_in_sample: usize,
+    /// Uncompensated deletions of items that were not in the sample
+    deleted_outside: usize,
+}
+
+impl<T: PartialEq> DynamicReservoir<T> {
+    pub fn new(capacity: usize) -> Self {
+        DynamicReservoir {
+            items: Vec::with_capacity(capacity),
+            capacity,
+            population: 0,
+            deleted_in_sample: 0,
+            deleted_outside: 0,
+        }
+    }
+
+    /// Adds an item to the population
+    pub fn insert<R: Rng>(&mut self, item: T, rng: &mut R) {
+        self.population += 1;
+        let pending = self.deleted_in_sample + self.deleted_outside;
+
+        if pending == 0 {
+            if self.items.len() < self.capacity {
+                self.items.push(item);
+            } else {
+                let j = rng.gen_range(0..self.population);
+                if j < self.capacity {
+                    self.items[j] = item;
+                }
+            }
+        } else if rng.gen_range(0..pending) < self.deleted_in_sample {
+            self.deleted_in_sample -= 1;
+            self.items.push(item);
+        } else {
+            self.deleted_outside -= 1;
+        }
+    }
+
+    /// Removes an item from the population and returns whether it was in the sample.
+    ///
+    /// The item must have been inserted and not removed since; the reservoir only
+    /// keeps the sampled items, so it cannot tell otherwise.
+    pub fn remove(&mut self, item: &T) -> bool {
+        assert!(
+            self.population > 0,
+            "Cannot remove from an empty population"
+        );
+        self.population -= 1;
+
+        match self.items.iter().position(|sampled| sampled == item) {
+            Some(index) => {
+                self.items.swap_remove(index);
+                self.deleted_in_sample += 1;
+                true
+            }
+            None => {
+                self.deleted_outside += 1;
+                false
+            }
+        }
+    }
+
+    /// Returns the items currently sampled
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// Returns the number of items inserted and not removed
+    pub fn population(&self) -> usize {
+        self.population
+    }
+
+    /// Consumes the reservoir and returns the sampled items
+    pub fn into_vec(self) -> Vec<T> {
+        self.items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_dynamic_reservoir_fills_and_caps() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut reservoir = DynamicReservoir::new(5);
+        for i in 0..3 {
+            reservoir.insert(i, &mut rng);
+        }
+        assert_eq!(reservoir.items(), &[0, 1, 2]);
+
+        for i in 3..100 {
+            reservoir.insert(i, &mut rng);
+        }
+        assert_eq!(reservoir.items().len(), 5);
+        assert_eq!(reservoir.population(), 100);
+    }
+
+    #[test]
+    fn test_dynamic_reservoir_never_keeps_removed_items() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut reservoir = DynamicReservoir::new(10);
+        for i in 0..100 {
+            reservoir.insert(i, &mut rng);
+        }
+        for i in (0..100).filter(|i| i % 2 == 0) {
+            reservoir.remove(&i);
+        }
+        assert!(reservoir.items().iter().all(|i| i % 2 == 1));
+        assert_eq!(reservoir.population(), 50);
+
+        // New insertions compensate for the deletions and refill the sample
+        for i in 100..200 {
+            reservoir.insert(i, &mut rng);
+        }
+        assert_eq!(reservoir.items().len(), 10);
+        assert!(reservoir.items().iter().all(|i| i % 2 == 1 || *i >= 100));
+    }
+
+    #[test]
+    fn test_dynamic_reservoir_uniform_over_survivors() {
+        let trials = 20_000;
+        let mut counts = [0usize; 20];
+        for seed in 0..trials {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut reservoir = DynamicReservoir::new(4);
+            for i in 0..12 {
+                reservoir.insert(i, &mut rng);
+            }
+            for i in [0, 3, 5, 6, 9, 11] {
+                reservoir.remove(&i);
+            }
+            for i in 12..20 {
+                reservoir.insert(i, &mut rng);
+            }
+            for &i in reservoir.items() {
+                counts[i] += 1;
+            }
+        }
+
+        let survivors: Vec<usize> = (0..20)
+            .filter(|i| ![0, 3, 5, 6, 9, 11].contains(i))
+            .collect();
+        let total: usize = survivors.iter().map(|&i| counts[i]).sum();
+        let expected = total as f64 / survivors.len() as f64;
+        for &i in &survivors {
+            let deviation = (counts[i] as f64 - expected).abs() / expected;
+            assert!(
+                deviation < 0.05,
+                "item {} sampled {} times, expected {}",
+                i,
+                counts[i],
+                expected
+            );
+        }
+        for i in [0, 3, 5, 6, 9, 11] {
+            assert_eq!(counts[i], 0);
+        }
+    }
+}
@@ -1,145 +1,648 @@
+use std::borrow::Cow;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::io::{self, Read};
+use std::io::Read;
+use std::marker::PhantomData;
 
-/// A streaming iterator that performs hash-based sampling on CSV data
-pub struct CsvHashSampler<R: Read> {
-    reader: csv::Reader<R>,
+use super::percentage::{draw_included, inclusion_threshold};
+use crate::error::{Error, Result};
+use crate::input::{
+    csv_reader_with_strictness, find_column, find_column_ignoring_case, find_column_matching,
+    RecordByteLimitReader,
+};
+use regex::Regex;
+
+/// How a key is normalized before it is hashed, so that logically equal keys collide
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyNormalization {
+    /// Hash the key as is
+    #[default]
+    None,
+    /// Remove leading and trailing whitespace, including Unicode whitespace such as
+    /// non-breaking spaces
+    Trim,
+    /// Convert to lowercase
+    Lowercase,
+    /// Remove surrounding whitespace, then convert to lowercase
+    TrimLowercase,
+}
+
+impl KeyNormalization {
+    /// Returns the normalized form of `key`
+    pub fn apply<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match self {
+            KeyNormalization::None => Cow::Borrowed(key),
+            KeyNormalization::Trim => Cow::Borrowed(key.trim()),
+            KeyNormalization::Lowercase => Cow::Owned(key.to_lowercase()),
+            KeyNormalization::TrimLowercase => Cow::Owned(key.trim().to_lowercase()),
+        }
+    }
+}
+
+/// Whether keys are hashed by their numeric value, so that `1`, `01` and `1.0` collide
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NumericKeys {
+    /// Hash keys as text
+    #[default]
+    Off,
+    /// Hash keys by value; a key that is not a number is an error
+    Required,
+    /// Hash keys that are numbers by value and any other key as text
+    Fallback,
+}
+
+impl NumericKeys {
+    /// Returns the form of a normalized key that is hashed: its canonical number when
+    /// numeric keys are enabled and it is a number, and the key itself otherwise
+    pub fn apply<'a>(&self, key: Cow<'a, str>) -> Cow<'a, str> {
+        if *self == NumericKeys::Off {
+            return key;
+        }
+        canonical_number(&key).map_or(key, Cow::Owned)
+    }
+}
+
+/// Returns the canonical text of a numeric key, or `None` if it is not a number.
+///
+/// The key is parsed as an `f64` and printed in its shortest form, so integers beyond
+/// 2^53 that round to the same `f64` share a canonical form too.
+pub fn canonical_number(key: &str) -> Option<String> {
+    let value = key
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|value| !value.is_nan())?;
+    // Adding zero turns -0 into 0
+    Some((value + 0.0).to_string())
+}
+
+/// How `CsvHashSampler::open` finds the key column in the CSV header
+#[derive(Debug, Clone, Copy)]
+pub enum KeyColumn<'a> {
+    /// The column with this name, ignoring surrounding whitespace
+    Named(&'a str),
+    /// The column with this name, ignoring case; several such columns are ambiguous
+    NamedIgnoringCase(&'a str),
+    /// The only column whose name matches this pattern
+    Matching(&'a Regex),
+}
+
+impl KeyColumn<'_> {
+    /// The name or pattern the column is looked up by
+    pub fn name(&self) -> &str {
+        match self {
+            KeyColumn::Named(name) | KeyColumn::NamedIgnoringCase(name) => name,
+            KeyColumn::Matching(pattern) => pattern.as_str(),
+        }
+    }
+}
+
+/// A streaming iterator that performs hash-based sampling on CSV data.
+///
+/// Keys are hashed with `H`, a fresh `H::default()` per key. The default
+/// `DefaultHasher` is what the `sample` binary uses, but its algorithm may change
+/// between Rust releases; plug in a stable hasher with `with_hasher` when selections
+/// must be reproducible across toolchains.
+pub struct CsvHashSampler<R: Read, H = DefaultHasher> {
+    reader: csv::Reader<RecordByteLimitReader<R>>,
     probability: f64,
     column_index: usize,
     header: csv::StringRecord,
-    current_record: Option<csv::StringRecord>,
+    /// Buffer reused for every record read; only accepted records are cloned out
+    current_record: csv::StringRecord,
+    excluded_keys: HashSet<String>,
+    /// Inclusion probabilities that override `probability` for specific keys
+    key_probabilities: HashMap<String, f64>,
+    rows_to_skip: usize,
+    normalization: KeyNormalization,
+    numeric_keys: NumericKeys,
+    /// Keys already emitted, tracked only when emitting the first row per key
+    emitted_keys: Option<HashSet<String>>,
+    /// Keys seen so far, tracked only when every key's first row is emitted
+    covered_keys: Option<HashSet<String>>,
+    /// Whether keys are selected by stratified hash buckets instead of a hash threshold
+    stratified: bool,
     done: bool,
+    hasher: PhantomData<fn() -> H>,
 }
 
 // Implement Debug manually since csv::Reader doesn't implement Debug
-impl<R: Read> fmt::Debug for CsvHashSampler<R> {
+impl<R: Read, H> fmt::Debug for CsvHashSampler<R, H> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("CsvHashSampler")
             .field("probability", &self.probability)
             .field("column_index", &self.column_index)
             .field("header", &self.header)
+            .field("excluded_keys", &self.excluded_keys.len())
+            .field("key_probabilities", &self.key_probabilities.len())
+            .field("rows_to_skip", &self.rows_to_skip)
+            .field("max_record_bytes", &self.reader.get_ref().limit())
+            .field("normalization", &self.normalization)
+            .field("numeric_keys", &self.numeric_keys)
+            .field(
+                "emitted_keys",
+                &self.emitted_keys.as_ref().map(HashSet::len),
+            )
+            .field(
+                "covered_keys",
+                &self.covered_keys.as_ref().map(HashSet::len),
+            )
             .field("done", &self.done)
             .finish_non_exhaustive() // Indicates there are fields not shown (reader)
     }
 }
 
 impl<R: Read> CsvHashSampler<R> {
-    pub fn new(reader: R, percentage: f64, column_name: &str) -> io::Result<Self> {
+    pub fn new(reader: R, percentage: f64, column_name: &str) -> Result<Self> {
+        Self::open(reader, percentage, KeyColumn::Named(column_name), false)
+    }
+
+    /// Like `new`, but matches the column name case-insensitively. An ambiguous name
+    /// fails with `Error::AmbiguousColumn`.
+    pub fn new_ignoring_case(reader: R, percentage: f64, column_name: &str) -> Result<Self> {
+        Self::open(
+            reader,
+            percentage,
+            KeyColumn::NamedIgnoringCase(column_name),
+            false,
+        )
+    }
+
+    /// Like `new`, but uses the only column whose name matches `pattern`. Several
+    /// matches fail with `Error::AmbiguousColumn`.
+    pub fn new_matching(reader: R, percentage: f64, pattern: &Regex) -> Result<Self> {
+        Self::open(reader, percentage, KeyColumn::Matching(pattern), false)
+    }
+
+    /// Reads the header and finds the key `column` in it, failing with
+    /// `Error::ColumnNotFound` if it is missing. With `strict_columns`, a record whose
+    /// field count differs from the header's fails with `Error::CsvError` instead of
+    /// being tolerated.
+    pub fn open(
+        reader: R,
+        percentage: f64,
+        column: KeyColumn,
+        strict_columns: bool,
+    ) -> Result<Self> {
         assert!(
             (0.0..=100.0).contains(&percentage),
             "Percentage must be between 0 and 100"
         );
 
-        let mut csv_reader = csv::ReaderBuilder::new()
-            .has_headers(true)
-            .flexible(true) // Be flexible with the number of fields
-            .trim(csv::Trim::All) // Trim whitespace from fields
-            .from_reader(reader);
-
-        // Read the header
-        let header = match csv_reader.headers() {
-            Ok(h) => h.clone(),
-            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-        };
-
-        // Find the column index
-        let column_index = match header.iter().position(|h| h.trim() == column_name.trim()) {
-            Some(idx) => idx,
-            None => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    format!("Column '{}' not found in CSV header", column_name),
-                ))
-            }
-        };
+        let mut csv_reader =
+            csv_reader_with_strictness(RecordByteLimitReader::new(reader, None), strict_columns);
+        let header = csv_reader.headers()?.clone();
+        let column_index = match column {
+            KeyColumn::Named(name) => Ok(find_column(&header, name)),
+            KeyColumn::NamedIgnoringCase(name) => find_column_ignoring_case(&header, name),
+            KeyColumn::Matching(pattern) => find_column_matching(&header, pattern),
+        }
+        .map_err(|ambiguity| Error::AmbiguousColumn(ambiguity.to_string()))?
+        .ok_or_else(|| Error::ColumnNotFound(column.name().to_string()))?;
 
         Ok(CsvHashSampler {
             reader: csv_reader,
             probability: percentage / 100.0,
             column_index,
             header,
-            current_record: None,
+            current_record: csv::StringRecord::new(),
+            excluded_keys: HashSet::new(),
+            key_probabilities: HashMap::new(),
+            rows_to_skip: 0,
+            normalization: KeyNormalization::None,
+            numeric_keys: NumericKeys::Off,
+            emitted_keys: None,
+            covered_keys: None,
+            stratified: false,
             done: false,
+            hasher: PhantomData,
         })
     }
 
+    /// Hashes keys with `H2` instead of `DefaultHasher`, e.g. a stable FNV or xxHash
+    /// implementation, so selections do not depend on the Rust version
+    pub fn with_hasher<H2: Hasher + Default>(self) -> CsvHashSampler<R, H2> {
+        CsvHashSampler {
+            reader: self.reader,
+            probability: self.probability,
+            column_index: self.column_index,
+            header: self.header,
+            current_record: self.current_record,
+            excluded_keys: self.excluded_keys,
+            key_probabilities: self.key_probabilities,
+            rows_to_skip: self.rows_to_skip,
+            normalization: self.normalization,
+            numeric_keys: self.numeric_keys,
+            emitted_keys: self.emitted_keys,
+            covered_keys: self.covered_keys,
+            stratified: self.stratified,
+            done: self.done,
+            hasher: PhantomData,
+        }
+    }
+}
+
+impl<R: Read, H: Hasher + Default> CsvHashSampler<R, H> {
+    /// Drops every row whose key is in the given set before the sampling decision.
+    /// Keys are compared after normalization, so give them in normalized form.
+    pub fn with_excluded_keys(mut self, keys: HashSet<String>) -> Self {
+        self.excluded_keys = keys;
+        self
+    }
+
+    /// Samples the listed keys at their own percentage instead of the global one.
+    /// Keys are compared after normalization, like excluded keys.
+    pub fn with_key_rates(mut self, rates: HashMap<String, f64>) -> Self {
+        self.key_probabilities = rates
+            .into_iter()
+            .map(|(key, percentage)| {
+                assert!(
+                    (0.0..=100.0).contains(&percentage),
+                    "Percentage must be between 0 and 100"
+                );
+                (key, percentage / 100.0)
+            })
+            .collect();
+        self
+    }
+
+    /// Discards the first `rows` data records before any of them reach the sampler
+    pub fn with_skipped_rows(mut self, rows: usize) -> Self {
+        self.rows_to_skip = rows;
+        self
+    }
+
+    /// Fails with `InvalidData` on any record longer than `bytes` bytes, not counting its
+    /// line feed, as soon as it is read that far; see `RecordByteLimitReader`
+    pub fn with_max_record_bytes(mut self, bytes: usize) -> Self {
+        self.reader.get_mut().set_limit(Some(bytes));
+        self
+    }
+
+    /// Normalizes each key before hashing it and comparing it with the key sets
+    pub fn with_normalization(mut self, normalization: KeyNormalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// Hashes normalized keys by their numeric value, see `canonical_number`. With
+    /// `NumericKeys::Required`, a key that is not a number fails with `InvalidData`.
+    pub fn with_numeric_keys(mut self, numeric_keys: NumericKeys) -> Self {
+        self.numeric_keys = numeric_keys;
+        self
+    }
+
+    /// Emits only the first row of each selected key. Every emitted key is kept in
+    /// memory to recognize its later rows.
+    pub fn with_first_per_key(mut self, first_per_key: bool) -> Self {
+        self.emitted_keys = first_per_key.then(HashSet::new);
+        self
+    }
+
+    /// Emits the first row of every key that is not excluded, whether or not the key is
+    /// selected, so each key is represented at least once. Every key is kept in memory.
+    pub fn with_cover_keys(mut self, cover_keys: bool) -> Self {
+        self.covered_keys = cover_keys.then(HashSet::new);
+        self
+    }
+
+    /// Selects keys by the evenly spaced hash buckets of `should_include_stratified`
+    /// instead of the lowest fraction of the hash space
+    pub fn with_stratified_keys(mut self, stratified: bool) -> Self {
+        self.stratified = stratified;
+        self
+    }
+
     /// Returns the header record
     pub fn header(&self) -> &csv::StringRecord {
         &self.header
     }
 
+    /// Returns the inclusion probability derived from the requested percentage
+    pub fn probability(&self) -> f64 {
+        self.probability
+    }
+
+    /// Returns the index of the column used as the hash key
+    pub fn column_index(&self) -> usize {
+        self.column_index
+    }
+
+    /// Reads every remaining record and returns the number of distinct keys, after
+    /// skipping, exclusion and normalization, without sampling.
+    ///
+    /// Every distinct key is held in memory, so memory grows with the key cardinality.
+    pub fn count_distinct_keys(self) -> Result<usize> {
+        Ok(self.distinct_keys()?.len())
+    }
+
+    /// Reads every remaining record and returns its distinct keys, after skipping,
+    /// exclusion and normalization, without sampling
+    pub fn distinct_keys(mut self) -> Result<HashSet<String>> {
+        let mut keys = HashSet::new();
+        while let Some(result) = self.read_next_record() {
+            result?;
+            if self.rows_to_skip > 0 {
+                self.rows_to_skip -= 1;
+                continue;
+            }
+            self.check_numeric_key()?;
+            let Some(key) = self.current_record.get(self.column_index) else {
+                continue;
+            };
+            let key = self.key(key);
+            if self.excluded_keys.contains(key.as_ref()) {
+                continue;
+            }
+            // Only allocate for keys not seen before
+            if !keys.contains(key.as_ref()) {
+                keys.insert(key.into_owned());
+            }
+        }
+        Ok(keys)
+    }
+
     /// Samples the CSV data and returns all records that pass the sampling criteria
-    pub fn collect_all(self) -> io::Result<Vec<csv::StringRecord>> {
-        self.collect::<io::Result<Vec<_>>>()
+    pub fn collect_all(self) -> Result<Vec<csv::StringRecord>> {
+        self.collect::<Result<Vec<_>>>()
     }
 
-    /// Reads the next record from the CSV reader
-    fn read_next_record(&mut self) -> Option<io::Result<csv::StringRecord>> {
+    /// Reads the next record into the reused buffer.
+    ///
+    /// Returns `None` at the end of the input and an error for malformed or oversized records.
+    fn read_next_record(&mut self) -> Option<Result<()>> {
         if self.done {
             return None;
         }
 
-        match self.reader.read_record(
-            self.current_record
-                .get_or_insert_with(csv::StringRecord::new),
-        ) {
-            Ok(has_record) => {
-                if !has_record {
-                    self.done = true;
-                    return None;
-                }
-                Some(Ok(self.current_record.as_ref().unwrap().clone()))
+        match self.reader.read_record(&mut self.current_record) {
+            Ok(false) => {
+                self.done = true;
+                None
             }
+            Ok(true) => Some(Ok(())),
             Err(e) => {
                 self.done = true;
-                Some(Err(io::Error::new(io::ErrorKind::InvalidData, e)))
+                Some(Err(Error::from(e)))
             }
         }
     }
+
+    /// Returns the form of a raw key that is hashed and compared
+    fn key<'a>(&self, raw: &'a str) -> Cow<'a, str> {
+        self.numeric_keys.apply(self.normalization.apply(raw))
+    }
+
+    /// Fails if numeric keys are required and the key of the record in the buffer is
+    /// not a number
+    fn check_numeric_key(&mut self) -> Result<()> {
+        if self.numeric_keys != NumericKeys::Required {
+            return Ok(());
+        }
+        let Some(key) = self.current_record.get(self.column_index) else {
+            return Ok(());
+        };
+        if canonical_number(&self.normalization.apply(key)).is_some() {
+            return Ok(());
+        }
+        self.done = true;
+        Err(Error::NonNumericKey(
+            self.header.get(self.column_index).unwrap_or("").to_string(),
+            key.to_string(),
+            self.current_record.position().map_or(0, |p| p.line()),
+        ))
+    }
+
+    /// Decides whether the record in the buffer is emitted
+    fn accepts_current_record(&self) -> bool {
+        // Validation in new() guarantees the column exists in the header, but rows may be short
+        let Some(column_value) = self.current_record.get(self.column_index) else {
+            return true;
+        };
+        let key = self.key(column_value);
+        if self.excluded_keys.contains(key.as_ref()) {
+            return false;
+        }
+
+        let probability = self
+            .key_probabilities
+            .get(key.as_ref())
+            .copied()
+            .unwrap_or(self.probability);
+        if self.stratified {
+            return should_include_stratified_with::<H>(&key, probability);
+        }
+        should_include_with::<H>(&key, probability, 0)
+    }
+
+    /// Records the key of the record in the buffer and tells whether it is the first row
+    /// of a key that is not excluded; always false unless covering every key
+    fn is_first_of_uncovered_key(&mut self) -> bool {
+        let Some(covered_keys) = &mut self.covered_keys else {
+            return false;
+        };
+        let key = self
+            .current_record
+            .get(self.column_index)
+            .unwrap_or_default();
+        let key = self.numeric_keys.apply(self.normalization.apply(key));
+        if self.excluded_keys.contains(key.as_ref()) {
+            return false;
+        }
+        if covered_keys.contains(key.as_ref()) {
+            return false;
+        }
+        covered_keys.insert(key.into_owned());
+        true
+    }
+
+    /// Records the key of the accepted record in the buffer and tells whether it is the
+    /// first row with that key; always true unless emitting the first row per key
+    fn is_first_of_key(&mut self) -> bool {
+        let Some(emitted_keys) = &mut self.emitted_keys else {
+            return true;
+        };
+        let key = self
+            .current_record
+            .get(self.column_index)
+            .unwrap_or_default();
+        let key = self.numeric_keys.apply(self.normalization.apply(key));
+        if emitted_keys.contains(key.as_ref()) {
+            return false;
+        }
+        emitted_keys.insert(key.into_owned());
+        true
+    }
 }
 
 /// Implement Iterator for CsvHashSampler to enable streaming access to sampled records
-impl<R: Read> Iterator for CsvHashSampler<R> {
-    type Item = io::Result<csv::StringRecord>;
+impl<R: Read, H: Hasher + Default> Iterator for CsvHashSampler<R, H> {
+    type Item = Result<csv::StringRecord>;
 
     fn next(&mut self) -> Option<Self::Item> {
         // Keep reading records until we find one that should be included or reach the end
         loop {
-            // Get the next record from the CSV reader
-            let record_result = self.read_next_record()?;
-
-            // Handle any errors reading the record
-            let record = match record_result {
-                Ok(r) => r,
-                Err(e) => return Some(Err(e)),
-            };
-
-            // Get the column value
-            let column_value = match record.get(self.column_index) {
-                Some(value) => value.to_string(),
-                None => {
-                    // This shouldn't happen due to the validation in new(), but just in case
-                    return Some(Ok(record));
-                }
-            };
+            if let Err(e) = self.read_next_record()? {
+                return Some(Err(e));
+            }
 
-            // Calculate hash and make decision directly
-            let hash_value = calculate_hash(&column_value);
-            let include = (hash_value as f64 / u64::MAX as f64) < self.probability;
+            if self.rows_to_skip > 0 {
+                self.rows_to_skip -= 1;
+                continue;
+            }
+            if let Err(e) = self.check_numeric_key() {
+                return Some(Err(e));
+            }
 
-            if include {
-                return Some(Ok(record));
+            // Register the key before the sampling decision so its first row is covered
+            let uncovered = self.is_first_of_uncovered_key();
+            if uncovered || (self.accepts_current_record() && self.is_first_of_key()) {
+                return Some(Ok(self.current_record.clone()));
             }
-            // If not included, continue to the next record
         }
     }
 }
 
-/// Calculate a hash value for a string
-fn calculate_hash<T: Hash>(t: &T) -> u64 {
-    let mut s = DefaultHasher::new();
+/// Performs hash-based sampling on lines split into fields on runs of whitespace.
+///
+/// The field at `index`, normalized as given, is used as the key; lines with fewer
+/// fields are keyed by an empty string. Unlike `CsvHashSampler`, no quoting rules
+/// are applied.
+pub fn whitespace_hash_sample_iter<T, I>(
+    iter: I,
+    percentage: f64,
+    index: usize,
+    normalization: KeyNormalization,
+) -> impl Iterator<Item = T>
+where
+    T: AsRef<str>,
+    I: Iterator<Item = T>,
+{
+    assert!(
+        (0.0..=100.0).contains(&percentage),
+        "Percentage must be between 0 and 100"
+    );
+    let probability = percentage / 100.0;
+    iter.filter(move |line| {
+        let key = line.as_ref().split_whitespace().nth(index).unwrap_or("");
+        should_include(&normalization.apply(key), probability, 0)
+    })
+}
+
+/// Returns where a (normalized) key falls on `[0, 1)`; hash sampling keeps the keys
+/// whose position is below the requested fraction
+pub fn hash_position(key: &str) -> f64 {
+    hash_position_with::<DefaultHasher>(key)
+}
+
+/// Like `hash_position`, but hashes the key with `H`
+pub fn hash_position_with<H: Hasher + Default>(key: &str) -> f64 {
+    normalize_hash(key_hash_with::<H>(key))
+}
+
+/// Returns the 64-bit hash of a (normalized) key that `--hash` sampling compares
+/// against the selection threshold
+pub fn key_hash(key: &str) -> u64 {
+    key_hash_with::<DefaultHasher>(key)
+}
+
+/// Like `key_hash`, but hashes the key with `H`
+pub fn key_hash_with<H: Hasher + Default>(key: &str) -> u64 {
+    hash_with::<H, _>(&key)
+}
+
+/// Decides whether a key is selected at the given `probability` (0.0 to 1.0), exactly as
+/// `sample` decides it, so other code can reproduce a hash sample key by key.
+///
+/// Seed 0 is the unsalted hash used by `--hash` and `--hash-index`; any other seed salts
+/// the hash, so decisions under different seeds are independent of each other.
+pub fn should_include(key: &str, probability: f64, seed: u64) -> bool {
+    should_include_with::<DefaultHasher>(key, probability, seed)
+}
+
+/// Like `should_include`, but hashes the key with `H`, as a `CsvHashSampler` built
+/// `with_hasher::<H>()` does
+pub fn should_include_with<H: Hasher + Default>(key: &str, probability: f64, seed: u64) -> bool {
+    let hash_value = match seed {
+        0 => key_hash_with::<H>(key),
+        seed => hash_with::<H, _>(&(seed, key)),
+    };
+    is_included(hash_value, probability)
+}
+
+/// Number of equal buckets the hash space is cut into for stratified selection
+const STRATA: u64 = 1 << 16;
+
+/// Decides whether a key is selected at `probability` (0.0 to 1.0) by stratified hash
+/// sampling.
+///
+/// The hash space is cut into 65536 equal buckets and exactly
+/// `floor(65536 * probability)` of them are selected, evenly spaced like systematic
+/// sampling over the bucket indices; a key is selected with its bucket. Unlike
+/// `should_include`, which keeps the lowest fraction of the hash space, the selected
+/// keys are spread over all of it, and the fraction of keys selected closely tracks
+/// the fraction of buckets.
+pub fn should_include_stratified(key: &str, probability: f64) -> bool {
+    should_include_stratified_with::<DefaultHasher>(key, probability)
+}
+
+/// Like `should_include_stratified`, but hashes the key with `H`
+pub fn should_include_stratified_with<H: Hasher + Default>(key: &str, probability: f64) -> bool {
+    let bucket = key_hash_with::<H>(key) / (u64::MAX / STRATA + 1);
+    is_bucket_selected(bucket, probability)
+}
+
+/// Derives a seed for `label` from a base seed, e.g. one per column
+pub fn derive_seed(base: u64, label: &str) -> u64 {
+    derive_seed_with::<DefaultHasher>(base, label)
+}
+
+/// Like `derive_seed`, but hashes with `H`
+pub fn derive_seed_with<H: Hasher + Default>(base: u64, label: &str) -> u64 {
+    hash_with::<H, _>(&(base, label))
+}
+
+/// Returns the bucket in `0..buckets` that a key is deterministically assigned to
+pub fn bucket_for(key: &str, buckets: u64) -> u64 {
+    bucket_for_with::<DefaultHasher>(key, buckets)
+}
+
+/// Like `bucket_for`, but hashes the key with `H`
+pub fn bucket_for_with<H: Hasher + Default>(key: &str, buckets: u64) -> u64 {
+    key_hash_with::<H>(key) % buckets
+}
+
+/// Decides whether a key with the given hash falls inside the sampled fraction.
+///
+/// The hash is compared as an integer against `probability * 2^64`, so all 64 bits
+/// count and tiny percentages keep their resolution. The comparison is `<`, so a
+/// probability of 1.0 includes every key and 0.0 includes none.
+fn is_included(hash_value: u64, probability: f64) -> bool {
+    draw_included(hash_value, inclusion_threshold(probability))
+}
+
+/// Decides whether a stratified bucket is selected: bucket `i` is kept when
+/// `i * probability` crosses an integer between `i` and `i + 1`
+fn is_bucket_selected(bucket: u64, probability: f64) -> bool {
+    assert!(
+        (0.0..=1.0).contains(&probability),
+        "Probability must be between 0 and 1"
+    );
+    let bucket = bucket as f64;
+    ((bucket + 1.0) * probability).floor() > (bucket * probability).floor()
+}
+
+/// Maps a hash value onto `[0, 1)` using its 53 most significant bits,
+/// which an `f64` represents exactly.
+fn normalize_hash(hash_value: u64) -> f64 {
+    (hash_value >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Calculate a hash value with a fresh hasher of type `H`
+fn hash_with<H: Hasher + Default, T: Hash + ?Sized>(t: &T) -> u64 {
+    let mut s = H::default();
     t.hash(&mut s);
     s.finish()
 }
@@ -147,7 +650,12 @@ fn calculate_hash<T: Hash>(t: &T) -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Cursor;
+    use std::io::{self, Cursor};
+
+    /// Calculate a hash value with the default hasher, as the CLI does
+    fn calculate_hash<T: Hash>(t: &T) -> u64 {
+        hash_with::<DefaultHasher, T>(t)
+    }
 
     #[test]
     fn test_csv_hash_sampler() {
@@ -216,7 +724,7 @@ id,name,value
         let column_name = "id";
 
         let sampler = CsvHashSampler::new(cursor, percentage, column_name).unwrap();
-        let samples: Vec<csv::StringRecord> = sampler.collect::<Result<Vec<_>, _>>().unwrap();
+        let samples: Vec<csv::StringRecord> = sampler.collect::<Result<Vec<_>>>().unwrap();
 
         // Check that rows with the same id are either all included or all excluded
         let has_id_1 = samples.iter().any(|row| row.get(0) == Some("1"));
@@ -259,9 +767,695 @@ id,name,value
         let column_name = "non_existent_column";
 
         let result = CsvHashSampler::new(cursor, percentage, column_name);
-        assert!(result.is_err());
-        let err = result.unwrap_err();
-        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(matches!(result, Err(Error::ColumnNotFound(name)) if name == column_name));
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_matching_column() {
+        let data = "id,user_id_v2,name\n1,a,x\n";
+        let pattern = Regex::new(r"^user_id_v\d+$").unwrap();
+        let sampler = CsvHashSampler::new_matching(data.as_bytes(), 50.0, &pattern).unwrap();
+        assert_eq!(sampler.column_index(), 1);
+
+        let pattern = Regex::new(r"^account").unwrap();
+        let err = CsvHashSampler::new_matching(data.as_bytes(), 50.0, &pattern).unwrap_err();
+        assert!(matches!(err, Error::ColumnNotFound(name) if name == "^account"));
+
+        let pattern = Regex::new(r"id").unwrap();
+        let err = CsvHashSampler::new_matching(data.as_bytes(), 50.0, &pattern).unwrap_err();
+        assert!(
+            matches!(&err, Error::AmbiguousColumn(ambiguity)
+                if ambiguity == "pattern 'id' matches columns id, user_id_v2"),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_accessors() {
+        let cursor = Cursor::new("id,name,value\n1,Alice,100");
+        let sampler = CsvHashSampler::new(cursor, 25.0, "name").unwrap();
+
+        assert_eq!(sampler.probability(), 0.25);
+        assert_eq!(sampler.column_index(), 1);
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_excluded_keys() {
+        let csv_data = "id,value\n1,a\n2,b\n3,c\n1,d\n4,e";
+        let excluded: HashSet<String> = ["1".to_string(), "3".to_string()].into();
+
+        let sampler = CsvHashSampler::new(Cursor::new(csv_data), 100.0, "id")
+            .unwrap()
+            .with_excluded_keys(excluded);
+        let samples = sampler.collect_all().unwrap();
+
+        let ids: Vec<_> = samples.iter().map(|row| row.get(0).unwrap()).collect();
+        assert_eq!(ids, vec!["2", "4"]);
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_excluded_keys_match_normalized_keys() {
+        let csv_data = "id,value\nA,a\n b ,b\na,c\nc,d";
+        let excluded: HashSet<String> = ["a".to_string(), "b".to_string()].into();
+
+        let sampler = CsvHashSampler::new(Cursor::new(csv_data), 100.0, "id")
+            .unwrap()
+            .with_normalization(KeyNormalization::TrimLowercase)
+            .with_excluded_keys(excluded);
+        let samples = sampler.collect_all().unwrap();
+
+        let ids: Vec<_> = samples.iter().map(|row| row.get(0).unwrap()).collect();
+        assert_eq!(ids, vec!["c"]);
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_skipped_rows() {
+        let csv_data = "id,value\n1,a\n2,b\n3,c\n4,d";
+
+        let sampler = CsvHashSampler::new(Cursor::new(csv_data), 100.0, "id")
+            .unwrap()
+            .with_skipped_rows(2);
+        let samples = sampler.collect_all().unwrap();
+
+        let ids: Vec<_> = samples.iter().map(|row| row.get(0).unwrap()).collect();
+        assert_eq!(ids, vec!["3", "4"]);
+    }
+
+    fn wide_csv(fields: usize, rows: usize) -> String {
+        let header: Vec<String> = (0..fields).map(|i| format!("c{}", i)).collect();
+        let mut csv = header.join(",") + "\n";
+        for row in 0..rows {
+            let values: Vec<String> = (0..fields).map(|i| format!("{}-{}", row, i)).collect();
+            csv.push_str(&values.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_wide_records() {
+        let data = wide_csv(10_000, 20);
+        let sampler = CsvHashSampler::new(Cursor::new(data), 100.0, "c9999").unwrap();
+        assert_eq!(sampler.column_index(), 9999);
+
+        let records = sampler.collect_all().unwrap();
+        assert_eq!(records.len(), 20);
+        for (row, record) in records.iter().enumerate() {
+            assert_eq!(record.len(), 10_000);
+            assert_eq!(&record[9999], format!("{}-9999", row));
+        }
+
+        // Rejected records are never cloned out, and the hash decisions are unchanged
+        let data = wide_csv(10_000, 60);
+        let sampled = CsvHashSampler::new(Cursor::new(data), 50.0, "c9999")
+            .unwrap()
+            .collect_all()
+            .unwrap();
+        let expected = (0..60)
+            .filter(|row| is_included(calculate_hash(&format!("{}-9999", row)), 0.5))
+            .count();
+        assert_eq!(sampled.len(), expected);
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_max_record_bytes() {
+        let data = "id,v\n1,a\n2,".to_string() + &"x".repeat(1000) + "\n3,c\n";
+
+        let mut sampler = CsvHashSampler::new(Cursor::new(data.clone()), 100.0, "id")
+            .unwrap()
+            .with_max_record_bytes(100);
+        assert_eq!(&sampler.next().unwrap().unwrap()[0], "1");
+        let err = sampler.next().unwrap().unwrap_err();
+        assert!(matches!(&err, Error::IoError(e) if e.kind() == io::ErrorKind::InvalidData));
+        assert!(
+            err.to_string()
+                .contains("record on line 3 exceeds the limit of 100 bytes"),
+            "{}",
+            err
+        );
+        assert!(sampler.next().is_none());
+
+        // Rows within the limit are unaffected
+        let sampler = CsvHashSampler::new(Cursor::new(data), 100.0, "id")
+            .unwrap()
+            .with_max_record_bytes(2000);
+        assert_eq!(sampler.collect_all().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_count_distinct_keys() {
+        let data = "id,v\na,1\nb,2\na,3\nc,4\nB,5\nb,6\n";
+        let sampler = CsvHashSampler::new(data.as_bytes(), 100.0, "id").unwrap();
+        assert_eq!(sampler.count_distinct_keys().unwrap(), 4);
+
+        let sampler = CsvHashSampler::new(data.as_bytes(), 100.0, "id")
+            .unwrap()
+            .with_normalization(KeyNormalization::Lowercase)
+            .with_excluded_keys(HashSet::from(["c".to_string()]));
+        assert_eq!(sampler.count_distinct_keys().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_hash_position_matches_sampling_decision() {
+        let data: String = std::iter::once("id".to_string())
+            .chain((0..200).map(|i| format!("k{}", i)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let sampled: HashSet<String> = CsvHashSampler::new(data.as_bytes(), 30.0, "id")
+            .unwrap()
+            .map(|record| record.unwrap()[0].to_string())
+            .collect();
+        for i in 0..200 {
+            let key = format!("k{}", i);
+            assert_eq!(hash_position(&key) < 0.3, sampled.contains(&key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_strict_columns() {
+        let data = "id,v\na,1\nb\nc,3,extra\n";
+
+        let sampler = CsvHashSampler::new(data.as_bytes(), 100.0, "id").unwrap();
+        assert_eq!(sampler.collect_all().unwrap().len(), 3);
+
+        let column = KeyColumn::Named("id");
+        let sampler = CsvHashSampler::open(data.as_bytes(), 100.0, column, true).unwrap();
+        let err = sampler.collect_all().unwrap_err();
+        // The same error as every other mode that parses records strictly
+        let expected = csv_reader_with_strictness(data.as_bytes(), true)
+            .records()
+            .find_map(|record| record.err())
+            .unwrap();
+        assert_eq!(err.to_string(), Error::from(expected).to_string());
+        assert!(matches!(err, Error::CsvError(_)));
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_first_per_key() {
+        let data: String = std::iter::once("id,v".to_string())
+            .chain((0..300).map(|i| format!("k{},{}", i % 30, i)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let all: Vec<_> = CsvHashSampler::new(data.as_bytes(), 40.0, "id")
+            .unwrap()
+            .collect_all()
+            .unwrap();
+        let first: Vec<_> = CsvHashSampler::new(data.as_bytes(), 40.0, "id")
+            .unwrap()
+            .with_first_per_key(true)
+            .collect_all()
+            .unwrap();
+
+        let selected: HashSet<String> = all.iter().map(|r| r[0].to_string()).collect();
+        let emitted: Vec<String> = first.iter().map(|r| r[0].to_string()).collect();
+        assert!(!selected.is_empty());
+        // Each selected key appears exactly once, on its first row, and no other key appears
+        assert_eq!(emitted.len(), selected.len());
+        assert_eq!(emitted.iter().cloned().collect::<HashSet<_>>(), selected);
+        for record in &first {
+            let index: usize = record[1].parse().unwrap();
+            assert!(index < 30, "{:?}", record);
+        }
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_cover_keys() {
+        let data: String = std::iter::once("id,v".to_string())
+            .chain((0..2000).map(|i| format!("k{},{}", i % 100, i)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let sampled: Vec<_> = CsvHashSampler::new(data.as_bytes(), 30.0, "id")
+            .unwrap()
+            .with_excluded_keys(HashSet::from(["k7".to_string()]))
+            .with_cover_keys(true)
+            .collect_all()
+            .unwrap();
+
+        // The first row of every key but the excluded one is there
+        let keys: HashSet<&str> = sampled.iter().map(|r| r.get(0).unwrap()).collect();
+        assert_eq!(keys.len(), 99);
+        assert!(!keys.contains("k7"));
+        let first_rows = sampled
+            .iter()
+            .filter(|r| r[1].parse::<usize>().unwrap() < 100);
+        assert_eq!(first_rows.count(), 99);
+
+        // The other 1881 eligible rows are sampled at about the requested rate
+        let additional = sampled.len() - 99;
+        assert!((380..750).contains(&additional), "{}", additional);
+        for record in sampled
+            .iter()
+            .filter(|r| r[1].parse::<usize>().unwrap() >= 100)
+        {
+            assert!(should_include(&record[0], 0.3, 0), "{:?}", record);
+        }
+    }
+
+    #[test]
+    fn test_key_normalization() {
+        let hash = |n: KeyNormalization, key| calculate_hash(&n.apply(key).as_ref());
+
+        assert_eq!(
+            hash(KeyNormalization::TrimLowercase, "Foo "),
+            hash(KeyNormalization::TrimLowercase, "foo")
+        );
+        assert_ne!(
+            hash(KeyNormalization::None, "Foo "),
+            hash(KeyNormalization::None, "foo")
+        );
+        assert_eq!(KeyNormalization::Trim.apply(" Foo "), "Foo");
+        assert_eq!(KeyNormalization::Lowercase.apply(" Foo "), " foo ");
+
+        // Unnormalized keys hash exactly as before
+        assert_eq!(hash(KeyNormalization::None, "foo"), calculate_hash(&"foo"));
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_normalization() {
+        let data = "id,v\nFoo,1\nfoo,2\nFOO,3\nbar,4\n";
+        for percentage in [10.0, 30.0, 50.0, 70.0, 90.0] {
+            let sampled = CsvHashSampler::new(Cursor::new(data), percentage, "id")
+                .unwrap()
+                .with_normalization(KeyNormalization::Lowercase)
+                .collect_all()
+                .unwrap();
+            let foo_rows = sampled.iter().filter(|r| r[0].eq_ignore_ascii_case("foo"));
+            assert!([0, 3].contains(&foo_rows.count()));
+        }
+    }
+
+    #[test]
+    fn test_canonical_number() {
+        for key in ["1", "01", "1.0", " +1.00 ", "1e0"] {
+            assert_eq!(canonical_number(key).as_deref(), Some("1"), "{}", key);
+        }
+        assert_eq!(canonical_number("-0.0").as_deref(), Some("0"));
+        assert_eq!(canonical_number("2.50").as_deref(), Some("2.5"));
+        assert_eq!(canonical_number("abc"), None);
+        assert_eq!(canonical_number("NaN"), None);
+        assert_eq!(canonical_number(""), None);
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_numeric_keys() {
+        let data = "id,v\n1,a\n01,b\n1.0,c\n2,d\n";
+        for percentage in [10.0, 30.0, 50.0, 70.0, 90.0] {
+            let sampled = CsvHashSampler::new(Cursor::new(data), percentage, "id")
+                .unwrap()
+                .with_numeric_keys(NumericKeys::Required)
+                .collect_all()
+                .unwrap();
+            let one_rows = sampled.iter().filter(|r| &r[0] != "2");
+            assert!([0, 3].contains(&one_rows.count()));
+        }
+
+        let data = "id,v\n1,a\nx,b\n";
+        let result = CsvHashSampler::new(Cursor::new(data), 100.0, "id")
+            .unwrap()
+            .with_numeric_keys(NumericKeys::Required)
+            .collect_all();
+        assert!(matches!(
+            result,
+            Err(Error::NonNumericKey(column, value, 3)) if column == "id" && value == "x"
+        ));
+
+        let sampled = CsvHashSampler::new(Cursor::new(data), 100.0, "id")
+            .unwrap()
+            .with_numeric_keys(NumericKeys::Fallback)
+            .collect_all()
+            .unwrap();
+        assert_eq!(sampled.len(), 2);
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_trims_unicode_whitespace() {
+        let data = "id,v\nabc\u{00a0},1\nabc,2\n\u{2003}abc,3\n";
+
+        // Field trimming already strips Unicode whitespace, so the keys collide by default
+        let records = CsvHashSampler::new(Cursor::new(data), 100.0, "id")
+            .unwrap()
+            .collect_all()
+            .unwrap();
+        assert!(records.iter().all(|record| &record[0] == "abc"));
+
+        for normalization in [KeyNormalization::None, KeyNormalization::Trim] {
+            for percentage in [10.0, 30.0, 50.0, 70.0, 90.0] {
+                let sampled = CsvHashSampler::new(Cursor::new(data), percentage, "id")
+                    .unwrap()
+                    .with_normalization(normalization)
+                    .collect_all()
+                    .unwrap();
+                assert_eq!(
+                    sampled.len(),
+                    if should_include("abc", percentage / 100.0, 0) {
+                        3
+                    } else {
+                        0
+                    },
+                    "{:?} at {}%",
+                    normalization,
+                    percentage
+                );
+            }
+        }
+        assert_eq!(KeyNormalization::Trim.apply("abc\u{00a0}"), "abc");
+    }
+
+    /// 64-bit FNV-1a, a stable hasher for the custom hasher tests
+    struct Fnv1a(u64);
+
+    impl Default for Fnv1a {
+        fn default() -> Self {
+            Fnv1a(0xcbf2_9ce4_8422_2325)
+        }
+    }
+
+    impl Hasher for Fnv1a {
+        fn write(&mut self, bytes: &[u8]) {
+            for &byte in bytes {
+                self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+
+        fn finish(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_key_rates_match_normalized_keys() {
+        let csv_data = "id\nA\na\nb\nB\nc";
+        let rates = HashMap::from([("a".to_string(), 0.0), ("b".to_string(), 100.0)]);
+
+        let ids: Vec<String> = CsvHashSampler::new(Cursor::new(csv_data), 0.0, "id")
+            .unwrap()
+            .with_normalization(KeyNormalization::Lowercase)
+            .with_key_rates(rates)
+            .map(|record| record.unwrap()[0].to_string())
+            .collect();
+        assert_eq!(ids, vec!["b", "B"]);
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_custom_hasher() {
+        let data: String = std::iter::once("id,v\n".to_string())
+            .chain((0..400).map(|i| format!("user{},{}\n", i % 100, i)))
+            .collect();
+        let sample = || {
+            CsvHashSampler::new(Cursor::new(data.as_str()), 50.0, "id")
+                .unwrap()
+                .with_hasher::<Fnv1a>()
+                .collect_all()
+                .unwrap()
+        };
+
+        // The selection is deterministic and follows the custom hash key by key
+        let records = sample();
+        assert_eq!(records, sample());
+        let selected: HashSet<&str> = records.iter().map(|record| &record[0]).collect();
+        for key in (0..100).map(|i| format!("user{}", i)) {
+            let included = should_include_with::<Fnv1a>(&key, 0.5, 0);
+            assert_eq!(selected.contains(key.as_str()), included, "{}", key);
+        }
+        assert_eq!(records.len(), selected.len() * 4);
+
+        // ...and differs from the selection under the default hasher
+        let default: HashSet<String> = CsvHashSampler::new(Cursor::new(data.as_str()), 50.0, "id")
+            .unwrap()
+            .map(|record| record.unwrap()[0].to_string())
+            .collect();
+        assert_ne!(
+            selected,
+            default.iter().map(String::as_str).collect::<HashSet<_>>()
+        );
+    }
+
+    #[test]
+    fn test_hash_helpers_follow_custom_hasher() {
+        for key in (0..100).map(|i| format!("user{}", i)) {
+            let hash = key_hash_with::<Fnv1a>(&key);
+            assert_eq!(hash, hash_with::<Fnv1a, _>(&key.as_str()));
+            assert_eq!(hash_position_with::<Fnv1a>(&key), normalize_hash(hash));
+            assert_eq!(
+                hash_position_with::<Fnv1a>(&key) < 0.5,
+                should_include_with::<Fnv1a>(&key, 0.5, 0),
+                "{}",
+                key
+            );
+            assert_eq!(bucket_for_with::<Fnv1a>(&key, 7), hash % 7);
+        }
+    }
+
+    #[test]
+    fn test_whitespace_hash_sample_normalization() {
+        let lines = ["x Foo", "y foo", "z FOO"];
+        for percentage in [10.0, 30.0, 50.0, 70.0, 90.0] {
+            let sampled: Vec<_> = whitespace_hash_sample_iter(
+                lines.iter(),
+                percentage,
+                1,
+                KeyNormalization::Lowercase,
+            )
+            .collect();
+            assert!([0, 3].contains(&sampled.len()));
+        }
+    }
+
+    #[test]
+    fn test_stratified_buckets_select_exact_fraction() {
+        for (probability, expected) in [(0.1, 6553), (0.125, 8192), (0.333, 21823), (0.0, 0)] {
+            let selected = (0..STRATA)
+                .filter(|&bucket| is_bucket_selected(bucket, probability))
+                .count();
+            assert_eq!(selected, expected, "{}", probability);
+        }
+        assert!((0..STRATA).all(|bucket| is_bucket_selected(bucket, 1.0)));
+    }
+
+    #[test]
+    fn test_stratified_keys_track_requested_fraction() {
+        let keys: Vec<String> = (0..100_000).map(|i| format!("key{}", i)).collect();
+        for probability in [0.01, 0.1, 0.25, 0.5, 0.9] {
+            let selected = keys
+                .iter()
+                .filter(|key| should_include_stratified(key, probability))
+                .count();
+            let fraction = selected as f64 / keys.len() as f64;
+            assert!(
+                (fraction - probability).abs() < 0.005,
+                "{}: {}",
+                probability,
+                fraction
+            );
+
+            // The same keys are selected every time
+            let again = keys
+                .iter()
+                .filter(|key| should_include_stratified(key, probability))
+                .count();
+            assert_eq!(selected, again);
+        }
+    }
+
+    #[test]
+    fn test_stratified_keys_spread_over_hash_space() {
+        let keys: Vec<String> = (0..100_000).map(|i| format!("key{}", i)).collect();
+
+        // Every tenth of the hash space contributes its share of the selection
+        let mut per_decile = [(0, 0); 10];
+        for key in &keys {
+            let decile = &mut per_decile[(hash_position(key) * 10.0) as usize];
+            decile.0 += 1;
+            decile.1 += usize::from(should_include_stratified(key, 0.1));
+        }
+        for (total, selected) in per_decile {
+            let fraction = selected as f64 / total as f64;
+            assert!((fraction - 0.1).abs() < 0.02, "{}", fraction);
+        }
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_stratified_keys() {
+        let data: String = std::iter::once("id,v\n".to_string())
+            .chain((0..4000).map(|i| format!("user{},{}\n", i % 1000, i)))
+            .collect();
+        let records = CsvHashSampler::new(data.as_bytes(), 30.0, "id")
+            .unwrap()
+            .with_stratified_keys(true)
+            .collect_all()
+            .unwrap();
+
+        let selected: HashSet<&str> = records.iter().map(|record| &record[0]).collect();
+        for key in (0..1000).map(|i| format!("user{}", i)) {
+            let included = should_include_stratified(&key, 0.3);
+            assert_eq!(selected.contains(key.as_str()), included, "{}", key);
+        }
+        assert_eq!(records.len(), selected.len() * 4);
+    }
+
+    #[test]
+    fn test_key_hash_matches_sampling_decision() {
+        for key in ["alice", "bob", "carol", "dave", ""] {
+            let hash = key_hash(key);
+            assert_eq!(hash_position(key), normalize_hash(hash));
+            assert_eq!(should_include(key, 0.3, 0), is_included(hash, 0.3));
+        }
+    }
+
+    #[test]
+    fn test_normalize_hash_is_half_open() {
+        assert_eq!(normalize_hash(0), 0.0);
+        assert!(normalize_hash(u64::MAX) < 1.0);
+    }
+
+    #[test]
+    fn test_is_included_tracks_tiny_probabilities() {
+        // About 184.5 of the first 4096 hash values at this rate
+        let probability = 1e-17;
+        let included = (0..4096u64)
+            .filter(|&hash| is_included(hash, probability))
+            .count();
+        assert_eq!(included, 184);
+
+        // Comparing the normalized float position selects a whole 2^11 block instead
+        let by_position = (0..4096u64)
+            .filter(|&hash| normalize_hash(hash) < probability)
+            .count();
+        assert_eq!(by_position, 2048);
+    }
+
+    #[test]
+    fn test_is_included_at_extremes() {
+        assert!(is_included(u64::MAX, 1.0));
+        assert!(is_included(0, 1.0));
+        assert!(!is_included(0, 0.0));
+        assert!(!is_included(u64::MAX, 0.0));
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_full_and_empty_percentage() {
+        let csv_data = "id\na\nb\nc\nd\ne\nf\ng\nh";
+
+        let all = CsvHashSampler::new(Cursor::new(csv_data), 100.0, "id")
+            .unwrap()
+            .collect_all()
+            .unwrap();
+        assert_eq!(all.len(), 8);
+
+        let none = CsvHashSampler::new(Cursor::new(csv_data), 0.0, "id")
+            .unwrap()
+            .collect_all()
+            .unwrap();
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn test_csv_hash_sampler_key_rates_override_global_rate() {
+        let data: String = std::iter::once("id".to_string())
+            .chain((0..1000).map(|i| format!("key{}", i)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        // Even keys are downsampled to 10%, odd keys keep the global 60%
+        let rates = (0..1000)
+            .step_by(2)
+            .map(|i| (format!("key{}", i), 10.0))
+            .collect();
+        let sampled: HashSet<String> = CsvHashSampler::new(data.as_bytes(), 60.0, "id")
+            .unwrap()
+            .with_key_rates(rates)
+            .map(|record| record.unwrap()[0].to_string())
+            .collect();
+
+        let mut included = [0, 0];
+        for i in 0..1000 {
+            let key = format!("key{}", i);
+            let probability = if i % 2 == 0 { 0.1 } else { 0.6 };
+            assert_eq!(
+                sampled.contains(&key),
+                should_include(&key, probability, 0),
+                "{}",
+                key
+            );
+            included[i % 2] += usize::from(sampled.contains(&key));
+        }
+        assert!((25..75).contains(&included[0]), "{:?}", included);
+        assert!((250..350).contains(&included[1]), "{:?}", included);
+    }
+
+    #[test]
+    fn test_bucket_for_is_stable_and_in_range() {
+        for key in ["a", "b", "user_42", ""] {
+            let bucket = bucket_for(key, 3);
+            assert!(bucket < 3);
+            assert_eq!(bucket, bucket_for(key, 3));
+        }
+    }
+
+    #[test]
+    fn test_should_include_matches_sampler_selection() {
+        let data: String = std::iter::once("id".to_string())
+            .chain((0..500).map(|i| format!("key{}", i)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        for percentage in [0.0, 12.5, 50.0, 100.0] {
+            let sampled: HashSet<String> = CsvHashSampler::new(data.as_bytes(), percentage, "id")
+                .unwrap()
+                .map(|record| record.unwrap()[0].to_string())
+                .collect();
+            for i in 0..500 {
+                let key = format!("key{}", i);
+                let included = should_include(&key, percentage / 100.0, 0);
+                assert_eq!(
+                    included,
+                    sampled.contains(&key),
+                    "{} at {}%",
+                    key,
+                    percentage
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_should_include_is_independent_across_seeds() {
+        let (a, b) = (derive_seed(0, "a"), derive_seed(0, "b"));
+        assert_ne!(a, b);
+
+        let keys: Vec<String> = (0..4000).map(|i| format!("user{}", i)).collect();
+        let mut both = 0;
+        let mut first = 0;
+        for key in &keys {
+            let in_a = should_include(key, 0.5, a);
+            assert_eq!(in_a, should_include(key, 0.5, a));
+            first += usize::from(in_a);
+            both += usize::from(in_a && should_include(key, 0.5, b));
+        }
+        // Independent decisions at 50% overlap on about a quarter of the keys
+        assert!((1800..2200).contains(&first), "{}", first);
+        assert!((850..1150).contains(&both), "{}", both);
+    }
+
+    #[test]
+    fn test_whitespace_hash_sample_iter_uses_indexed_field() {
+        let lines = [
+            "1   alice\tx",
+            "2 bob  x",
+            "3\t\tcarol x",
+            "4 alice   y",
+            "5 bob y",
+            "6  carol\ty",
+        ];
+
+        let sampled: Vec<_> =
+            whitespace_hash_sample_iter(lines.iter(), 50.0, 1, KeyNormalization::None).collect();
+
+        // Rows sharing the second field are either all kept or all dropped
+        for name in ["alice", "bob", "carol"] {
+            let count = sampled.iter().filter(|l| l.contains(name)).count();
+            let included = is_included(calculate_hash(&name), 0.5);
+            assert_eq!(count, if included { 2 } else { 0 });
+        }
     }
 
     #[test]
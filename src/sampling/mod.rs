@@ -1,7 +1,34 @@
+mod dynamic;
 mod hash;
 mod percentage;
+mod poisson;
+mod predicate;
 mod reservoir;
+mod spill;
+mod target;
+mod weighted;
+mod window;
 
-pub use hash::CsvHashSampler;
-pub use percentage::percentage_sample_iter;
-pub use reservoir::reservoir_sample;
+pub use dynamic::DynamicReservoir;
+pub use hash::{
+    bucket_for, bucket_for_with, canonical_number, derive_seed, derive_seed_with, hash_position,
+    hash_position_with, key_hash, key_hash_with, should_include, should_include_stratified,
+    should_include_stratified_with, should_include_with, whitespace_hash_sample_iter,
+    CsvHashSampler, KeyColumn, KeyNormalization, NumericKeys,
+};
+pub use percentage::{
+    draw_included, inclusion_threshold, percentage_sample_iter, percentage_sample_iter_ref,
+    PercentageSampler,
+};
+pub use poisson::{poisson_include, poisson_sample_iter, poisson_sample_iter_ref, poisson_scale};
+pub use predicate::{predicate_sample_iter, PredicateSampleIter};
+#[cfg(feature = "async")]
+pub use reservoir::reservoir_sample_stream;
+pub use reservoir::{
+    reservoir_sample, reservoir_sample_bounded, reservoir_sample_chunked, reservoir_sample_into,
+    reservoir_sample_with_status, sample_one, Reservoir, ReservoirOrder, ReservoirSample,
+};
+pub use spill::{SpilledItems, SpillingReservoir};
+pub use target::{target_count_sample_iter, TargetCountSampleIter};
+pub use weighted::{reservoir_sample_weighted_slices, top_k_by_score, weighted_reservoir_sample};
+pub use window::{CountWindowedReservoir, WindowedReservoir};
@@ -1,24 +1,48 @@
 use rand::Rng;
 
-/// A streaming iterator that performs random sampling based on a percentage
+/// A streaming iterator that performs random sampling based on a percentage.
+///
+/// Each decision draws a `u64` and compares it against an integer threshold rather
+/// than comparing floats, so a fixed seed selects the same items regardless of
+/// floating-point rounding in the RNG's `f64` conversion.
+///
+/// With antithetic draws enabled, every other item reuses the previous draw `u` as
+/// `1 - u` instead of drawing afresh. Each item is still included with the requested
+/// probability, but the decisions within a pair are negatively correlated, which
+/// lowers the variance of the realized sample fraction. Use it when the sample size
+/// matters more than the independence of individual selections.
 pub struct PercentageSampleIter<I, R> {
     iter: I,
-    rng: R,
-    probability: f64,
+    sampler: PercentageSampler<R>,
+    seen: usize,
+    emitted: usize,
 }
 
 impl<I, R> PercentageSampleIter<I, R> {
     pub fn new(iter: I, percentage: f64, rng: R) -> Self {
-        assert!(
-            (0.0..=100.0).contains(&percentage),
-            "Percentage must be between 0 and 100"
-        );
         PercentageSampleIter {
             iter,
-            rng,
-            probability: percentage / 100.0,
+            sampler: PercentageSampler::new(percentage, rng),
+            seen: 0,
+            emitted: 0,
         }
     }
+
+    /// Pairs each draw `u` with `1 - u` for the following item
+    pub fn with_antithetic(mut self, antithetic: bool) -> Self {
+        self.sampler = self.sampler.with_antithetic(antithetic);
+        self
+    }
+
+    /// Returns the number of items taken from the underlying iterator so far
+    pub fn seen(&self) -> usize {
+        self.seen
+    }
+
+    /// Returns the number of items yielded so far
+    pub fn emitted(&self) -> usize {
+        self.emitted
+    }
 }
 
 impl<T, I: Iterator<Item = T>, R: Rng> Iterator for PercentageSampleIter<I, R> {
@@ -28,7 +52,9 @@ impl<T, I: Iterator<Item = T>, R: Rng> Iterator for PercentageSampleIter<I, R> {
         loop {
             match self.iter.next() {
                 Some(item) => {
-                    if self.rng.gen::<f64>() < self.probability {
+                    self.seen += 1;
+                    if self.sampler.include() {
+                        self.emitted += 1;
                         return Some(item);
                     }
                 }
@@ -38,7 +64,76 @@ impl<T, I: Iterator<Item = T>, R: Rng> Iterator for PercentageSampleIter<I, R> {
     }
 }
 
-/// Creates a streaming percentage sampler that returns an iterator
+/// The per-item decisions of `PercentageSampleIter`, for callers whose items cannot be
+/// yielded by an iterator, such as lines read into a reused buffer. The same seed and
+/// settings make the same sequence of decisions.
+pub struct PercentageSampler<R> {
+    rng: R,
+    threshold: u128,
+    antithetic: bool,
+    pending_draw: Option<u64>,
+}
+
+impl<R> PercentageSampler<R> {
+    pub fn new(percentage: f64, rng: R) -> Self {
+        assert!(
+            (0.0..=100.0).contains(&percentage),
+            "Percentage must be between 0 and 100"
+        );
+        PercentageSampler {
+            rng,
+            threshold: inclusion_threshold(percentage / 100.0),
+            antithetic: false,
+            pending_draw: None,
+        }
+    }
+
+    /// Pairs each draw `u` with `1 - u` for the following item
+    pub fn with_antithetic(mut self, antithetic: bool) -> Self {
+        self.antithetic = antithetic;
+        self
+    }
+}
+
+impl<R: Rng> PercentageSampler<R> {
+    /// Decides whether the next item is included
+    pub fn include(&mut self) -> bool {
+        draw_included(self.next_draw(), self.threshold)
+    }
+
+    /// Returns the next draw, which is the complement of the previous one in antithetic mode
+    fn next_draw(&mut self) -> u64 {
+        if let Some(draw) = self.pending_draw.take() {
+            return draw;
+        }
+        let draw = self.rng.gen::<u64>();
+        if self.antithetic {
+            // !draw == u64::MAX - draw, the integer counterpart of 1 - u
+            self.pending_draw = Some(!draw);
+        }
+        draw
+    }
+}
+
+/// Converts a probability into the number of `u64` draws or hash values (out of 2^64)
+/// that select an item. Scaling by a power of two is exact, so only the final truncation
+/// rounds, and a probability of 1.0 maps to 2^64, so every draw is below the threshold.
+pub fn inclusion_threshold(probability: f64) -> u128 {
+    (probability * (1u128 << 64) as f64) as u128
+}
+
+/// Decides whether a uniform `u64` draw selects an item: exactly the draws below
+/// `threshold`, from `inclusion_threshold`, do. This is the decision percentage
+/// sampling makes for every item.
+pub fn draw_included(draw: u64, threshold: u128) -> bool {
+    (draw as u128) < threshold
+}
+
+/// Creates a streaming percentage sampler that returns an iterator.
+///
+/// The iterator owns `rng`, since it draws lazily after this call returns. To keep
+/// using the same RNG afterwards, as `reservoir_sample` and the other eager samplers
+/// do, use `percentage_sample_iter_ref`.
 pub fn percentage_sample_iter<T, I, R>(
     iter: I,
     percentage: f64,
@@ -51,6 +146,20 @@ where
     PercentageSampleIter::new(iter, percentage, rng)
 }
 
+/// Like `percentage_sample_iter`, but borrows `rng` for the iterator's lifetime, so
+/// the caller can draw from it again once the iterator is dropped
+pub fn percentage_sample_iter_ref<T, I, R>(
+    iter: I,
+    percentage: f64,
+    rng: &mut R,
+) -> PercentageSampleIter<I, &mut R>
+where
+    I: Iterator<Item = T>,
+    R: Rng,
+{
+    PercentageSampleIter::new(iter, percentage, rng)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -75,6 +184,150 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_percentage_sample_iter_fixed_sequence() {
+        // Pinned selection for seed 42 at 50%; changes here break cross-version reproducibility
+        let rng = StdRng::seed_from_u64(42);
+        let sample: Vec<_> = percentage_sample_iter(0..20, 50.0, rng).collect();
+        assert_eq!(sample, vec![3, 4, 5, 8, 9, 12, 13, 15, 16, 18]);
+    }
+
+    #[test]
+    fn test_percentage_sample_iter_counts() {
+        let rng = StdRng::seed_from_u64(42);
+        let mut iter = percentage_sample_iter(0..20, 50.0, rng);
+        assert_eq!((iter.seen(), iter.emitted()), (0, 0));
+
+        // The first selected item is 3, after four items were seen
+        assert_eq!(iter.next(), Some(3));
+        assert_eq!((iter.seen(), iter.emitted()), (4, 1));
+
+        let rest: Vec<_> = iter.by_ref().collect();
+        assert_eq!(rest, vec![4, 5, 8, 9, 12, 13, 15, 16, 18]);
+        assert_eq!((iter.seen(), iter.emitted()), (20, 10));
+    }
+
+    /// Variance of the number of items selected out of 100 across many seeds
+    fn selected_count_variance(percentage: f64, antithetic: bool) -> f64 {
+        let counts: Vec<f64> = (0..500)
+            .map(|seed| {
+                let rng = StdRng::seed_from_u64(seed);
+                percentage_sample_iter(0..100, percentage, rng)
+                    .with_antithetic(antithetic)
+                    .count() as f64
+            })
+            .collect();
+        let mean = counts.iter().sum::<f64>() / counts.len() as f64;
+        counts.iter().map(|c| (c - mean).powi(2)).sum::<f64>() / counts.len() as f64
+    }
+
+    #[test]
+    fn test_antithetic_lowers_variance() {
+        for percentage in [10.0, 30.0, 50.0, 80.0] {
+            let plain = selected_count_variance(percentage, false);
+            let antithetic = selected_count_variance(percentage, true);
+            assert!(
+                antithetic < plain,
+                "{}: {} vs {}",
+                percentage,
+                antithetic,
+                plain
+            );
+        }
+    }
+
+    #[test]
+    fn test_antithetic_keeps_inclusion_rate() {
+        let rng = StdRng::seed_from_u64(7);
+        let count = percentage_sample_iter(0..10_000, 30.0, rng)
+            .with_antithetic(true)
+            .count();
+        assert!((2800..3200).contains(&count), "{}", count);
+    }
+
+    /// An `Rng` that returns the given `u64` draws in order
+    struct FixedDraws(std::vec::IntoIter<u64>);
+
+    impl rand::RngCore for FixedDraws {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0.next().expect("ran out of fixed draws")
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for chunk in dest.chunks_mut(8) {
+                let bytes = self.next_u64().to_le_bytes();
+                chunk.copy_from_slice(&bytes[..chunk.len()]);
+            }
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_draw_included_at_threshold_boundaries() {
+        let half = inclusion_threshold(0.5);
+        assert!(draw_included(0, half));
+        assert!(draw_included((1 << 63) - 1, half));
+        assert!(!draw_included(1 << 63, half));
+        assert!(draw_included(u64::MAX, inclusion_threshold(1.0)));
+        assert!(!draw_included(0, inclusion_threshold(0.0)));
+
+        let draws = vec![0, (1 << 63) - 1, 1 << 63, u64::MAX];
+        let mut sampler = PercentageSampler::new(50.0, FixedDraws(draws.into_iter()));
+        let decisions: Vec<bool> = (0..4).map(|_| sampler.include()).collect();
+        assert_eq!(decisions, vec![true, true, false, false]);
+
+        // Antithetic mode answers every second item with the complement of the last draw
+        let draws = vec![10, u64::MAX - 10];
+        let mut sampler =
+            PercentageSampler::new(50.0, FixedDraws(draws.into_iter())).with_antithetic(true);
+        let decisions: Vec<bool> = (0..4).map(|_| sampler.include()).collect();
+        assert_eq!(decisions, vec![true, false, false, true]);
+    }
+
+    #[test]
+    fn test_inclusion_threshold_extremes() {
+        assert_eq!(inclusion_threshold(0.0), 0);
+        assert_eq!(inclusion_threshold(1.0), 1u128 << 64);
+        assert_eq!(inclusion_threshold(0.5), 1u128 << 63);
+    }
+
+    #[test]
+    fn test_percentage_sample_iter_full_and_empty() {
+        let rng = StdRng::seed_from_u64(1);
+        assert_eq!(percentage_sample_iter(0..1000, 100.0, rng).count(), 1000);
+        let rng = StdRng::seed_from_u64(1);
+        assert_eq!(percentage_sample_iter(0..1000, 0.0, rng).count(), 0);
+    }
+
+    #[test]
+    fn test_percentage_sample_iter_ref_shares_rng_with_reservoir() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let sampled: Vec<_> = percentage_sample_iter_ref(0..1000, 10.0, &mut rng).collect();
+        let reserved = crate::reservoir_sample(0..1000, 5, &mut rng);
+
+        // Same as lending the RNG to the by-value sampler, and the reservoir continues
+        // from where the percentage sampler left the stream
+        let mut expected_rng = StdRng::seed_from_u64(7);
+        let expected: Vec<_> = percentage_sample_iter(0..1000, 10.0, &mut expected_rng).collect();
+        assert_eq!(sampled, expected);
+        assert_eq!(
+            reserved,
+            crate::reservoir_sample(0..1000, 5, &mut expected_rng)
+        );
+        assert_ne!(
+            reserved,
+            crate::reservoir_sample(0..1000, 5, &mut StdRng::seed_from_u64(7))
+        );
+    }
+
     #[test]
     #[should_panic(expected = "Percentage must be between 0 and 100")]
     fn test_percentage_sample_iter_invalid_percentage() {
@@ -0,0 +1,154 @@
+use rand::Rng;
+
+/// A streaming iterator that performs Poisson sampling with per-item weights.
+///
+/// Each item is included independently with probability `min(1, weight * scale)`.
+/// Choosing `scale = k / total_weight` gives an expected sample size of `k`, as long
+/// as no single item's weight is large enough to be capped at probability 1.
+pub struct PoissonSampleIter<I, F, R> {
+    iter: I,
+    weight: F,
+    scale: f64,
+    rng: R,
+}
+
+impl<I, F, R> PoissonSampleIter<I, F, R> {
+    pub fn new(iter: I, weight: F, scale: f64, rng: R) -> Self {
+        assert!(scale >= 0.0, "Scale must not be negative");
+        PoissonSampleIter {
+            iter,
+            weight,
+            scale,
+            rng,
+        }
+    }
+}
+
+impl<T, I, F, R> Iterator for PoissonSampleIter<I, F, R>
+where
+    I: Iterator<Item = T>,
+    F: FnMut(&T) -> f64,
+    R: Rng,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let item = self.iter.next()?;
+            let weight = (self.weight)(&item);
+            if poisson_include(weight, self.scale, &mut self.rng) {
+                return Some(item);
+            }
+        }
+    }
+}
+
+/// Creates a streaming Poisson sampler that returns an iterator.
+///
+/// The iterator owns `rng`; use `poisson_sample_iter_ref` to borrow it instead.
+pub fn poisson_sample_iter<T, I, F, R>(
+    iter: I,
+    weight: F,
+    scale: f64,
+    rng: R,
+) -> PoissonSampleIter<I, F, R>
+where
+    I: Iterator<Item = T>,
+    F: FnMut(&T) -> f64,
+    R: Rng,
+{
+    PoissonSampleIter::new(iter, weight, scale, rng)
+}
+
+/// Like `poisson_sample_iter`, but borrows `rng` for the iterator's lifetime, so
+/// the caller can draw from it again once the iterator is dropped
+pub fn poisson_sample_iter_ref<T, I, F, R>(
+    iter: I,
+    weight: F,
+    scale: f64,
+    rng: &mut R,
+) -> PoissonSampleIter<I, F, &mut R>
+where
+    I: Iterator<Item = T>,
+    F: FnMut(&T) -> f64,
+    R: Rng,
+{
+    PoissonSampleIter::new(iter, weight, scale, rng)
+}
+
+/// Returns the scale that yields an expected sample size of `expected_size`
+/// for items whose weights sum to `total_weight`
+pub fn poisson_scale(expected_size: f64, total_weight: f64) -> f64 {
+    if total_weight > 0.0 {
+        expected_size / total_weight
+    } else {
+        0.0
+    }
+}
+
+/// Decides whether an item with the given weight is included
+pub fn poisson_include<R: Rng>(weight: f64, scale: f64, rng: &mut R) -> bool {
+    let probability = (weight * scale).min(1.0);
+    rng.gen::<f64>() < probability
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_poisson_sample_iter_hits_expected_size() {
+        // Zipf-like weights: item i has weight 1 / sqrt(i)
+        let items: Vec<f64> = (1..=10_000).map(|i| 1.0 / (i as f64).sqrt()).collect();
+        let total: f64 = items.iter().sum();
+        let scale = poisson_scale(500.0, total);
+        let rng = StdRng::seed_from_u64(42);
+
+        let sample: Vec<_> = poisson_sample_iter(items.iter(), |w| **w, scale, rng).collect();
+
+        assert!(sample.len() > 450 && sample.len() < 550, "{}", sample.len());
+    }
+
+    #[test]
+    fn test_poisson_sample_iter_prefers_heavy_items() {
+        let items: Vec<(usize, f64)> = (0..2000)
+            .map(|i| (i, if i < 1000 { 1.0 } else { 9.0 }))
+            .collect();
+        let scale = poisson_scale(200.0, 10_000.0);
+        let rng = StdRng::seed_from_u64(7);
+
+        let sample: Vec<_> =
+            poisson_sample_iter(items.into_iter(), |(_, w)| *w, scale, rng).collect();
+        let heavy = sample.iter().filter(|(i, _)| *i >= 1000).count();
+        let light = sample.len() - heavy;
+
+        assert!(heavy > light * 5, "heavy={} light={}", heavy, light);
+    }
+
+    #[test]
+    fn test_poisson_sample_iter_ref_leaves_rng_usable() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let sampled: Vec<_> = poisson_sample_iter_ref(0..100, |_| 1.0, 0.5, &mut rng).collect();
+        let next = rng.gen::<u64>();
+
+        let mut expected_rng = StdRng::seed_from_u64(3);
+        let expected: Vec<_> =
+            poisson_sample_iter(0..100, |_| 1.0, 0.5, &mut expected_rng).collect();
+        assert_eq!(sampled, expected);
+        assert_eq!(next, expected_rng.gen::<u64>());
+    }
+
+    #[test]
+    fn test_poisson_scale_with_zero_total_weight() {
+        assert_eq!(poisson_scale(10.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_poisson_include_caps_probability() {
+        let mut rng = StdRng::seed_from_u64(1);
+        assert!((0..100).all(|_| poisson_include(5.0, 1.0, &mut rng)));
+        assert!((0..100).all(|_| !poisson_include(0.0, 1.0, &mut rng)));
+    }
+}
@@ -0,0 +1,89 @@
+/// A streaming iterator that keeps the items for which a predicate returns true.
+///
+/// It is the deterministic counterpart of `PercentageSampleIter`: the predicate is
+/// called once per item, in order, and only when the next item is requested, so it
+/// may carry state such as counters or an RNG of its own.
+pub struct PredicateSampleIter<I, F> {
+    iter: I,
+    predicate: F,
+    seen: usize,
+    emitted: usize,
+}
+
+impl<I, F> PredicateSampleIter<I, F> {
+    pub fn new(iter: I, predicate: F) -> Self {
+        PredicateSampleIter {
+            iter,
+            predicate,
+            seen: 0,
+            emitted: 0,
+        }
+    }
+
+    /// Returns the number of items taken from the underlying iterator so far
+    pub fn seen(&self) -> usize {
+        self.seen
+    }
+
+    /// Returns the number of items yielded so far
+    pub fn emitted(&self) -> usize {
+        self.emitted
+    }
+}
+
+impl<T, I: Iterator<Item = T>, F: FnMut(&T) -> bool> Iterator for PredicateSampleIter<I, F> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for item in self.iter.by_ref() {
+            self.seen += 1;
+            if (self.predicate)(&item) {
+                self.emitted += 1;
+                return Some(item);
+            }
+        }
+        None
+    }
+}
+
+/// Creates a streaming sampler that yields the items for which `predicate` returns true
+pub fn predicate_sample_iter<T, I, F>(iter: I, predicate: F) -> PredicateSampleIter<I, F>
+where
+    I: Iterator<Item = T>,
+    F: FnMut(&T) -> bool,
+{
+    PredicateSampleIter::new(iter, predicate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_predicate_sample_iter_keeps_matching_items() {
+        let sampled: Vec<i32> = predicate_sample_iter(1..=20, |n| n % 3 == 0).collect();
+        assert_eq!(sampled, vec![3, 6, 9, 12, 15, 18]);
+
+        let mut iter = predicate_sample_iter(1..=10, |n| *n > 4);
+        assert_eq!(iter.by_ref().count(), 6);
+        assert_eq!(iter.seen(), 10);
+        assert_eq!(iter.emitted(), 6);
+    }
+
+    #[test]
+    fn test_predicate_sample_iter_is_lazy() {
+        let calls = Cell::new(0);
+        let mut iter = predicate_sample_iter(1.., |n: &u32| {
+            calls.set(calls.get() + 1);
+            n.is_multiple_of(10)
+        });
+        assert_eq!(calls.get(), 0);
+
+        assert_eq!(iter.next(), Some(10));
+        assert_eq!(calls.get(), 10);
+        assert_eq!(iter.next(), Some(20));
+        assert_eq!(calls.get(), 20);
+        assert_eq!((iter.seen(), iter.emitted()), (20, 2));
+    }
+}
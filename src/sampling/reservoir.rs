@@ -1,4 +1,173 @@
+use rand::distributions::Open01;
 use rand::Rng;
+use std::mem;
+
+use super::weighted::TopK;
+
+/// Order in which the items of a reservoir sample are output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReservoirOrder {
+    /// Reservoir slot order, which reflects which slots later items replaced
+    #[default]
+    Slot,
+    /// The order the items had in the input
+    Input,
+    /// A random order drawn from the sampling RNG after the sample is complete
+    Shuffled,
+}
+
+/// A fixed-capacity reservoir that keeps a uniform random sample of the items added to it
+#[derive(Debug, Clone)]
+pub struct Reservoir<T> {
+    items: Vec<T>,
+    capacity: usize,
+    seen: usize,
+}
+
+impl<T> Reservoir<T> {
+    pub fn new(capacity: usize) -> Self {
+        Reservoir {
+            items: Vec::with_capacity(capacity),
+            capacity,
+            seen: 0,
+        }
+    }
+
+    /// Creates an empty reservoir that keeps its items in `buffer`, reusing its
+    /// allocation. The buffer is cleared first and only grows if it holds fewer than
+    /// `capacity` items.
+    pub fn with_buffer(mut buffer: Vec<T>, capacity: usize) -> Self {
+        buffer.clear();
+        Reservoir {
+            items: buffer,
+            capacity,
+            seen: 0,
+        }
+    }
+
+    /// Samples `k` items from an iterator and returns the filled reservoir, which also
+    /// records how many items were seen. `reservoir_sample` returns just the items.
+    pub fn sample<I, R>(iter: I, k: usize, rng: &mut R) -> Self
+    where
+        I: Iterator<Item = T>,
+        R: Rng,
+    {
+        let mut reservoir = Reservoir::new(k);
+        for item in iter {
+            reservoir.add(item, rng);
+        }
+        reservoir
+    }
+
+    /// Offers an item to the reservoir
+    pub fn add<R: Rng>(&mut self, item: T, rng: &mut R) {
+        self.add_with_eviction(item, rng);
+    }
+
+    /// Offers an item to the reservoir and returns the item it displaced, if any.
+    ///
+    /// Returns `None` while the reservoir is filling up and when the new item is
+    /// not selected (in which case the new item is dropped).
+    pub fn add_with_eviction<R: Rng>(&mut self, item: T, rng: &mut R) -> Option<T> {
+        self.seen += 1;
+
+        if self.seen <= self.capacity {
+            // Fill the reservoir with the first k items
+            self.items.push(item);
+            return None;
+        }
+
+        // Replace elements with decreasing probability
+        let j = rng.gen_range(0..self.seen);
+        if j < self.capacity {
+            Some(mem::replace(&mut self.items[j], item))
+        } else {
+            None
+        }
+    }
+
+    /// Offers an item that is only built if it is retained: `fill` writes it into a
+    /// fresh default slot while the reservoir fills up, and over the item it replaces
+    /// afterwards, so the evicted item's allocations can be reused. Draws from `rng`
+    /// exactly like `add`.
+    pub fn add_in_place<R, F>(&mut self, rng: &mut R, fill: F)
+    where
+        T: Default,
+        R: Rng,
+        F: FnOnce(&mut T),
+    {
+        self.seen += 1;
+
+        if self.seen <= self.capacity {
+            self.items.push(T::default());
+            fill(self.items.last_mut().unwrap());
+            return;
+        }
+
+        let j = rng.gen_range(0..self.seen);
+        if j < self.capacity {
+            fill(&mut self.items[j]);
+        }
+    }
+
+    /// Returns the items currently retained
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// Returns the number of items offered so far
+    pub fn seen(&self) -> usize {
+        self.seen
+    }
+
+    /// Returns true if every item offered so far was retained, i.e. no more than
+    /// `capacity` items were seen and the "sample" is the whole input
+    pub fn is_saturated(&self) -> bool {
+        self.seen <= self.capacity
+    }
+
+    /// Consumes the reservoir and returns the retained items
+    pub fn into_vec(self) -> Vec<T> {
+        self.items
+    }
+
+    /// Consumes the reservoir and returns the retained items along with whether
+    /// the reservoir was saturated
+    pub fn into_sample(self) -> ReservoirSample<T> {
+        ReservoirSample {
+            saturated: self.is_saturated(),
+            items: self.items,
+        }
+    }
+}
+
+/// The items of a finished reservoir sample
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReservoirSample<T> {
+    pub items: Vec<T>,
+    /// True if the input had no more than `k` items, so all of them were kept and
+    /// the result is the complete input rather than a subsample
+    pub saturated: bool,
+}
+
+impl<T> IntoIterator for Reservoir<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    /// Consumes the reservoir and yields the retained items, in reservoir order
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Reservoir<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.iter()
+    }
+}
 
 /// Performs reservoir sampling on an iterator of items
 pub fn reservoir_sample<T, I, R>(iter: I, k: usize, rng: &mut R) -> Vec<T>
@@ -6,27 +175,163 @@ where
     I: Iterator<Item = T>,
     R: Rng,
 {
-    let mut reservoir: Vec<T> = Vec::with_capacity(k);
-    let mut count: usize = 0;
+    Reservoir::sample(iter, k, rng).into_vec()
+}
 
+/// Like `reservoir_sample`, but writes the sample into `buffer`, replacing its contents
+/// and reusing its allocation, so sampling repeatedly into the same buffer does not
+/// allocate once it has room for `k` items. Draws from `rng` exactly like
+/// `reservoir_sample`.
+pub fn reservoir_sample_into<T, I, R>(buffer: &mut Vec<T>, iter: I, k: usize, rng: &mut R)
+where
+    I: Iterator<Item = T>,
+    R: Rng,
+{
+    let mut reservoir = Reservoir::with_buffer(mem::take(buffer), k);
     for item in iter {
-        count += 1;
+        reservoir.add(item, rng);
+    }
+    *buffer = reservoir.into_vec();
+}
 
-        if count <= k {
-            // Fill the reservoir with the first k items
-            reservoir.push(item);
-        } else {
-            // Replace elements with decreasing probability
-            let j = rng.gen_range(0..count);
-            if j < k {
-                reservoir[j] = item;
-            }
+/// Like `reservoir_sample`, but also reports whether the input fit in the reservoir
+pub fn reservoir_sample_with_status<T, I, R>(iter: I, k: usize, rng: &mut R) -> ReservoirSample<T>
+where
+    I: Iterator<Item = T>,
+    R: Rng,
+{
+    Reservoir::sample(iter, k, rng).into_sample()
+}
+
+/// Returns one uniformly random item in a single pass, or `None` if there are none.
+///
+/// The `i`-th item replaces the current choice with probability `1/i`, which is
+/// reservoir sampling with `k = 1` without the reservoir.
+pub fn sample_one<T, I, R>(iter: I, rng: &mut R) -> Option<T>
+where
+    I: Iterator<Item = T>,
+    R: Rng,
+{
+    let mut chosen = None;
+    for (index, item) in iter.enumerate() {
+        if rng.gen_range(0..=index) == 0 {
+            chosen = Some(item);
         }
     }
+    chosen
+}
+
+/// Number of skips whose random draws `reservoir_sample_chunked` generates at a time
+const SKIP_BATCH: usize = 64;
+
+/// Performs reservoir sampling on a slice with Algorithm L (Li, 1994).
+///
+/// After the first `k` items fill the reservoir, the gap to the next item that
+/// replaces one is drawn directly from its geometric-like distribution, so only
+/// about `k * ln(n / k)` items are visited instead of all `n`. The three random
+/// draws each jump needs are generated in batches of `SKIP_BATCH` jumps. The sample
+/// has the same distribution as `reservoir_sample`, but not the same items for a
+/// given RNG.
+pub fn reservoir_sample_chunked<T, R>(items: &[T], k: usize, rng: &mut R) -> Vec<T>
+where
+    T: Clone,
+    R: Rng,
+{
+    let mut reservoir: Vec<T> = items.iter().take(k).cloned().collect();
+    if k == 0 || items.len() <= k {
+        return reservoir;
+    }
 
+    let mut draws: Vec<f64> = Vec::with_capacity(3 * SKIP_BATCH);
+    let mut next_draw = || {
+        if draws.is_empty() {
+            draws.extend((0..3 * SKIP_BATCH).map(|_| rng.sample::<f64, _>(Open01)));
+        }
+        draws.pop().expect("the batch was just refilled")
+    };
+
+    let k_f64 = k as f64;
+    let mut w = (next_draw().ln() / k_f64).exp();
+    let mut index = k - 1;
+    loop {
+        let skip = (next_draw().ln() / (-w).ln_1p()).floor() as usize;
+        index = index.saturating_add(skip).saturating_add(1);
+        if index >= items.len() {
+            break;
+        }
+        let slot = ((next_draw() * k_f64) as usize).min(k - 1);
+        reservoir[slot] = items[index].clone();
+        w *= (next_draw().ln() / k_f64).exp();
+    }
     reservoir
 }
 
+/// Performs reservoir sampling keeping at most `k` items totalling at most `max_bytes`,
+/// as measured by `size_of`.
+///
+/// Each item draws a random key and the sample is the longest run of items, in
+/// descending key order, that fits both limits. This is a uniform random order cut
+/// off at the budget, so the sample stays unbiased with respect to input position.
+/// An item larger than `max_bytes` on its own can never fit and is skipped. The
+/// sample is returned in random order.
+pub fn reservoir_sample_bounded<T, I, R, F>(
+    iter: I,
+    k: usize,
+    max_bytes: usize,
+    size_of: F,
+    rng: &mut R,
+) -> Vec<T>
+where
+    I: Iterator<Item = T>,
+    R: Rng,
+    F: Fn(&T) -> usize,
+{
+    let mut top = TopK::new(k);
+    let mut bytes = 0;
+    // Keys at or below this were cut off by the byte budget, which only ever tightens
+    let mut threshold = f64::NEG_INFINITY;
+
+    for item in iter {
+        // Draw for every item, even skipped ones, so each index consumes one value
+        let key = rng.gen::<f64>();
+        let size = size_of(&item);
+        if size > max_bytes || key <= threshold {
+            top.skip();
+            continue;
+        }
+
+        bytes += size;
+        if let Some(dropped) = top.offer(key, item) {
+            bytes -= size_of(&dropped);
+        }
+        while bytes > max_bytes {
+            let (key, evicted) = top
+                .pop_smallest()
+                .expect("bytes are only counted for kept items");
+            bytes -= size_of(&evicted);
+            threshold = key;
+        }
+    }
+    top.into_sorted_vec()
+}
+
+/// Performs reservoir sampling on an async stream of items
+#[cfg(feature = "async")]
+pub async fn reservoir_sample_stream<T, S, R>(stream: S, k: usize, rng: &mut R) -> Vec<T>
+where
+    S: futures_util::Stream<Item = T>,
+    R: Rng,
+{
+    use futures_util::StreamExt;
+
+    futures_util::pin_mut!(stream);
+    let mut reservoir = Reservoir::new(k);
+    while let Some(item) = stream.next().await {
+        reservoir.add(item, rng);
+    }
+    reservoir.into_vec()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -111,4 +416,274 @@ mod tests {
         let sample = reservoir_sample(lines[1..].iter(), k, &mut rng);
         assert_eq!(sample.len(), k);
     }
+
+    #[test]
+    fn test_reservoir_sample_into_reuses_buffer() {
+        let mut buffer: Vec<u32> = Vec::with_capacity(16);
+        let pointer = buffer.as_ptr();
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            reservoir_sample_into(&mut buffer, 0..1000, 16, &mut rng);
+
+            let mut rng = StdRng::seed_from_u64(seed);
+            assert_eq!(buffer, reservoir_sample(0..1000, 16, &mut rng));
+            assert_eq!(buffer.capacity(), 16);
+            assert_eq!(buffer.as_ptr(), pointer);
+        }
+
+        // Earlier contents are replaced, not appended to
+        let mut rng = StdRng::seed_from_u64(1);
+        reservoir_sample_into(&mut buffer, 0..3, 16, &mut rng);
+        assert_eq!(buffer, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_reservoir_sample_with_status_flags_saturation() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for (n, saturated) in [(0, true), (3, true), (5, true), (6, false), (100, false)] {
+            let sample = reservoir_sample_with_status(0..n, 5, &mut rng);
+            assert_eq!(sample.saturated, saturated, "n = {}", n);
+            assert_eq!(sample.items.len(), n.min(5));
+            if saturated {
+                assert_eq!(sample.items, (0..n).collect::<Vec<_>>());
+            }
+        }
+
+        let mut reservoir = Reservoir::new(2);
+        reservoir.add('a', &mut rng);
+        reservoir.add('b', &mut rng);
+        assert!(reservoir.is_saturated());
+        reservoir.add('c', &mut rng);
+        assert!(!reservoir.is_saturated());
+    }
+
+    #[test]
+    fn test_add_in_place_matches_add() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let mut reservoir = Reservoir::new(4);
+        for i in 0..50 {
+            reservoir.add_in_place(&mut rng, |slot: &mut String| {
+                slot.clear();
+                slot.push_str(&i.to_string());
+            });
+        }
+
+        let mut rng = StdRng::seed_from_u64(9);
+        let expected = reservoir_sample((0..50).map(|i| i.to_string()), 4, &mut rng);
+        assert_eq!(reservoir.seen(), 50);
+        assert_eq!(reservoir.into_vec(), expected);
+    }
+
+    #[test]
+    fn test_add_with_eviction_returns_none_while_filling() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut reservoir = Reservoir::new(3);
+
+        for item in 0..3 {
+            assert_eq!(reservoir.add_with_eviction(item, &mut rng), None);
+        }
+        assert_eq!(reservoir.items(), &[0, 1, 2]);
+    }
+
+    #[test]
+    fn test_add_with_eviction_returns_replaced_slot() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut reservoir = Reservoir::new(3);
+
+        for item in 0..100 {
+            let before = reservoir.items().to_vec();
+            match reservoir.add_with_eviction(item, &mut rng) {
+                Some(evicted) => {
+                    // The new item took exactly the slot of the evicted one
+                    let slot = before.iter().position(|&x| x == evicted).unwrap();
+                    assert_eq!(reservoir.items()[slot], item);
+                    let mut expected = before.clone();
+                    expected[slot] = item;
+                    assert_eq!(reservoir.items(), expected.as_slice());
+                }
+                None if item >= 3 => assert_eq!(reservoir.items(), before.as_slice()),
+                None => {}
+            }
+        }
+        assert_eq!(reservoir.seen(), 100);
+    }
+
+    #[test]
+    fn test_reservoir_matches_reservoir_sample() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut reservoir = Reservoir::new(5);
+        for item in 0..50 {
+            reservoir.add(item, &mut rng);
+        }
+
+        let mut rng = StdRng::seed_from_u64(7);
+        assert_eq!(reservoir.into_vec(), reservoir_sample(0..50, 5, &mut rng));
+    }
+
+    #[test]
+    fn test_reservoir_into_iter_yields_retained_items() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let reservoir = Reservoir::sample(0..100, 10, &mut rng);
+        assert_eq!(reservoir.seen(), 100);
+
+        let retained = reservoir.items().to_vec();
+        let borrowed: Vec<i32> = (&reservoir).into_iter().copied().collect();
+        assert_eq!(borrowed, retained);
+
+        let mut yielded = Vec::new();
+        for item in reservoir {
+            yielded.push(item);
+        }
+        assert_eq!(yielded, retained);
+        assert_eq!(
+            yielded,
+            reservoir_sample(0..100, 10, &mut StdRng::seed_from_u64(9))
+        );
+    }
+
+    #[test]
+    fn test_sample_one_is_uniform() {
+        let mut counts = [0; 10];
+        for seed in 0..10_000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            counts[sample_one(0..10, &mut rng).unwrap()] += 1;
+        }
+        // Each position is chosen about 1000 times
+        assert!(
+            counts.iter().all(|&c| (880..1120).contains(&c)),
+            "{:?}",
+            counts
+        );
+
+        assert_eq!(
+            sample_one(std::iter::empty::<i32>(), &mut rand::thread_rng()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_reservoir_sample_bounded_respects_byte_budget() {
+        let items: Vec<String> = (0..500).map(|i| "x".repeat(1 + i * 7 % 40)).collect();
+        for seed in 0..50 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let sample =
+                reservoir_sample_bounded(items.iter().cloned(), 20, 200, String::len, &mut rng);
+            let bytes: usize = sample.iter().map(String::len).sum();
+            assert!(!sample.is_empty());
+            assert!(sample.len() <= 20);
+            assert!(bytes <= 200, "{}", bytes);
+        }
+
+        // A generous budget leaves only the count limit
+        let mut rng = StdRng::seed_from_u64(1);
+        let sample =
+            reservoir_sample_bounded(items.into_iter(), 20, 1 << 20, String::len, &mut rng);
+        assert_eq!(sample.len(), 20);
+    }
+
+    #[test]
+    fn test_reservoir_sample_bounded_skips_oversized_items() {
+        let items = vec!["a", "bbbbbbbbbb", "c"];
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut sample = reservoir_sample_bounded(items.into_iter(), 5, 4, |s| s.len(), &mut rng);
+        sample.sort_unstable();
+        assert_eq!(sample, vec!["a", "c"]);
+    }
+
+    #[test]
+    fn test_reservoir_sample_bounded_is_uniform_within_budget() {
+        // Mixed lengths; the budget fits about 3 items, far fewer than k
+        let items: Vec<String> = (0..10)
+            .map(|i| "x".repeat(if i % 2 == 0 { 4 } else { 6 }))
+            .collect();
+        let mut counts = [0; 10];
+        for seed in 0..4000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let indexed = items.iter().enumerate();
+            let sample = reservoir_sample_bounded(indexed, 10, 16, |(_, s)| s.len(), &mut rng);
+            for (i, _) in sample {
+                counts[i] += 1;
+            }
+        }
+
+        // Items of the same length are selected equally often, whatever their position
+        for parity in 0..2 {
+            let same_length: Vec<i32> = counts.iter().skip(parity).step_by(2).copied().collect();
+            let mean = same_length.iter().sum::<i32>() as f64 / same_length.len() as f64;
+            assert!(
+                same_length
+                    .iter()
+                    .all(|&c| (c as f64 - mean).abs() < mean * 0.12),
+                "{:?}",
+                counts
+            );
+        }
+    }
+
+    #[test]
+    fn test_reservoir_sample_chunked_small_inputs() {
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(
+            reservoir_sample_chunked(&[1, 2, 3], 5, &mut rng),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            reservoir_sample_chunked(&[1, 2, 3], 3, &mut rng),
+            vec![1, 2, 3]
+        );
+        assert!(reservoir_sample_chunked(&[1, 2, 3], 0, &mut rng).is_empty());
+        assert!(reservoir_sample_chunked::<u32, _>(&[], 2, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn test_reservoir_sample_chunked_matches_per_item_distribution() {
+        let items: Vec<usize> = (0..50).collect();
+        let k = 5;
+        let trials = 20_000;
+        let mut chunked = vec![0usize; items.len()];
+        let mut per_item = vec![0usize; items.len()];
+        for seed in 0..trials {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let sample = reservoir_sample_chunked(&items, k, &mut rng);
+            assert_eq!(sample.len(), k);
+            for &item in &sample {
+                chunked[item] += 1;
+            }
+            for item in reservoir_sample(items.iter().copied(), k, &mut rng) {
+                per_item[item] += 1;
+            }
+        }
+
+        // Each item is kept with probability k/n under both
+        let expected = (trials as usize * k) as f64 / items.len() as f64;
+        for (item, (&a, &b)) in chunked.iter().zip(&per_item).enumerate() {
+            for count in [a, b] {
+                let deviation = (count as f64 - expected).abs() / expected;
+                assert!(
+                    deviation < 0.08,
+                    "item {}: {} vs expected {}",
+                    item,
+                    count,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_reservoir_sample_stream_matches_sync() {
+        let items: Vec<i32> = (1..=100).collect();
+        let k = 10;
+
+        let mut stream_rng = StdRng::seed_from_u64(42);
+        let stream = futures_util::stream::iter(items.clone());
+        let from_stream = reservoir_sample_stream(stream, k, &mut stream_rng).await;
+
+        let mut sync_rng = StdRng::seed_from_u64(42);
+        let from_iter = reservoir_sample(items.into_iter(), k, &mut sync_rng);
+
+        assert_eq!(from_stream.len(), k);
+        assert_eq!(from_stream, from_iter);
+    }
 }
@@ -0,0 +1,199 @@
+use rand::Rng;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+use std::vec;
+
+/// A reservoir of strings that keeps at most `memory_budget` bytes of them in memory
+/// and spills the rest to an anonymous temporary file.
+///
+/// It selects exactly like `Reservoir`, drawing from the RNG the same way, so a seed
+/// gives the same sample whatever the budget. The spill file is append-only: a
+/// spilled item that is replaced stays in the file as garbage, which bounds the file
+/// by the total size of the items that ever entered the reservoir. The file is
+/// removed by the operating system once the reservoir and its items are dropped.
+#[derive(Debug)]
+pub struct SpillingReservoir {
+    slots: Vec<Slot>,
+    capacity: usize,
+    seen: usize,
+    memory_budget: usize,
+    memory_used: usize,
+    spill: File,
+    spill_len: u64,
+}
+
+/// Where a retained item is kept
+#[derive(Debug)]
+enum Slot {
+    Memory(String),
+    Disk { offset: u64, len: usize },
+}
+
+impl SpillingReservoir {
+    /// Creates a reservoir of `capacity` items whose spill file lives in `dir`
+    pub fn new(capacity: usize, memory_budget: usize, dir: &Path) -> io::Result<Self> {
+        // The slots grow as items arrive; only what fits the budget is reserved up front
+        let reserved = capacity.min(memory_budget / mem::size_of::<Slot>());
+        Ok(SpillingReservoir {
+            slots: Vec::with_capacity(reserved),
+            capacity,
+            seen: 0,
+            memory_budget,
+            memory_used: 0,
+            spill: tempfile::tempfile_in(dir)?,
+            spill_len: 0,
+        })
+    }
+
+    /// Offers an item to the reservoir
+    pub fn add<R: Rng>(&mut self, item: String, rng: &mut R) -> io::Result<()> {
+        self.seen += 1;
+
+        if self.seen <= self.capacity {
+            let slot = self.store(item)?;
+            self.slots.push(slot);
+            return Ok(());
+        }
+
+        // Replace elements with decreasing probability
+        let j = rng.gen_range(0..self.seen);
+        if j < self.capacity {
+            if let Slot::Memory(old) = &self.slots[j] {
+                self.memory_used -= old.len();
+            }
+            self.slots[j] = self.store(item)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of items offered so far
+    pub fn seen(&self) -> usize {
+        self.seen
+    }
+
+    /// Returns how many of the retained items are on disk
+    pub fn spilled(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| matches!(slot, Slot::Disk { .. }))
+            .count()
+    }
+
+    /// Consumes the reservoir and yields the retained items in reservoir order,
+    /// reading spilled ones back from disk one at a time
+    pub fn into_items(self) -> SpilledItems {
+        SpilledItems {
+            slots: self.slots.into_iter(),
+            spill: self.spill,
+        }
+    }
+
+    /// Keeps an item in memory if it fits the budget, and appends it to the spill
+    /// file otherwise
+    fn store(&mut self, item: String) -> io::Result<Slot> {
+        if self.memory_used + item.len() <= self.memory_budget {
+            self.memory_used += item.len();
+            return Ok(Slot::Memory(item));
+        }
+
+        self.spill.seek(SeekFrom::Start(self.spill_len))?;
+        self.spill.write_all(item.as_bytes())?;
+        let slot = Slot::Disk {
+            offset: self.spill_len,
+            len: item.len(),
+        };
+        self.spill_len += item.len() as u64;
+        Ok(slot)
+    }
+}
+
+/// Iterator over the items of a `SpillingReservoir`
+#[derive(Debug)]
+pub struct SpilledItems {
+    slots: vec::IntoIter<Slot>,
+    spill: File,
+}
+
+impl Iterator for SpilledItems {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(match self.slots.next()? {
+            Slot::Memory(item) => Ok(item),
+            Slot::Disk { offset, len } => self.read_spilled(offset, len),
+        })
+    }
+}
+
+impl SpilledItems {
+    fn read_spilled(&mut self, offset: u64, len: usize) -> io::Result<String> {
+        self.spill.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0; len];
+        self.spill.read_exact(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::reservoir_sample;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn lines(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("line number {}", i)).collect()
+    }
+
+    #[test]
+    fn test_spilling_reservoir_matches_in_memory_sample() {
+        let dir = tempfile::tempdir().unwrap();
+        for budget in [0, 40, 1 << 20] {
+            let mut rng = StdRng::seed_from_u64(7);
+            let mut reservoir = SpillingReservoir::new(10, budget, dir.path()).unwrap();
+            for line in lines(1000) {
+                reservoir.add(line, &mut rng).unwrap();
+            }
+            assert_eq!(reservoir.seen(), 1000);
+            let spilled = reservoir.spilled();
+            let sample: Vec<String> = reservoir.into_items().map(Result::unwrap).collect();
+
+            let mut rng = StdRng::seed_from_u64(7);
+            let expected = reservoir_sample(lines(1000).into_iter(), 10, &mut rng);
+            assert_eq!(sample, expected, "budget {}", budget);
+            match budget {
+                0 => assert_eq!(spilled, 10),
+                40 => assert!(spilled > 0 && spilled < 10, "{}", spilled),
+                _ => assert_eq!(spilled, 0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_spilling_reservoir_fewer_items_than_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut reservoir = SpillingReservoir::new(10, 0, dir.path()).unwrap();
+        for line in lines(3) {
+            reservoir.add(line, &mut rng).unwrap();
+        }
+        let sample: Vec<String> = reservoir.into_items().map(Result::unwrap).collect();
+        assert_eq!(sample, lines(3));
+    }
+
+    #[test]
+    fn test_spilling_reservoir_huge_capacity_small_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut reservoir = SpillingReservoir::new(usize::MAX / 2, 1024, dir.path()).unwrap();
+        assert!(reservoir.slots.capacity() <= 1024 / mem::size_of::<Slot>());
+        for line in lines(100) {
+            reservoir.add(line, &mut rng).unwrap();
+        }
+        assert!(reservoir.spilled() > 0);
+        let sample: Vec<String> = reservoir.into_items().map(Result::unwrap).collect();
+        assert_eq!(sample, lines(100));
+    }
+}
@@ -0,0 +1,130 @@
+use rand::Rng;
+
+use super::percentage::{draw_included, inclusion_threshold};
+
+/// A streaming sampler that aims for about `target` items from a stream of unknown
+/// length, without a counting pass.
+///
+/// Each item is included with probability `p × (target − accepted) / target`: the
+/// requested rate while little has been accepted, falling to zero as the accepted
+/// count reaches the target, so at most `target` items are yielded. The count is
+/// approximate. After `m` items about `target × (1 − e^(−p·m/target))` have been
+/// accepted, so a stream much longer than `target / p` yields close to `target`
+/// items and a shorter one fewer. Items are not equally likely to be kept: early
+/// ones are favored over late ones.
+pub struct TargetCountSampleIter<I, R> {
+    iter: I,
+    rng: R,
+    probability: f64,
+    target: usize,
+    emitted: usize,
+}
+
+impl<I, R> TargetCountSampleIter<I, R> {
+    pub fn new(iter: I, percentage: f64, target: usize, rng: R) -> Self {
+        assert!(
+            (0.0..=100.0).contains(&percentage),
+            "Percentage must be between 0 and 100"
+        );
+        TargetCountSampleIter {
+            iter,
+            rng,
+            probability: percentage / 100.0,
+            target,
+            emitted: 0,
+        }
+    }
+
+    /// Returns the number of items yielded so far
+    pub fn emitted(&self) -> usize {
+        self.emitted
+    }
+
+    /// Returns the inclusion probability of the next item
+    pub fn current_probability(&self) -> f64 {
+        if self.target == 0 {
+            return 0.0;
+        }
+        let remaining = self.target.saturating_sub(self.emitted);
+        self.probability * remaining as f64 / self.target as f64
+    }
+}
+
+impl<T, I: Iterator<Item = T>, R: Rng> Iterator for TargetCountSampleIter<I, R> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Nothing more can be accepted once the target is reached
+            if self.emitted >= self.target {
+                return None;
+            }
+            let item = self.iter.next()?;
+            let threshold = inclusion_threshold(self.current_probability());
+            if draw_included(self.rng.gen::<u64>(), threshold) {
+                self.emitted += 1;
+                return Some(item);
+            }
+        }
+    }
+}
+
+/// Creates a streaming sampler that keeps items at `percentage` at first and slows
+/// down to yield about `target` items in total, see `TargetCountSampleIter`
+pub fn target_count_sample_iter<T, I, R>(
+    iter: I,
+    percentage: f64,
+    target: usize,
+    rng: R,
+) -> TargetCountSampleIter<I, R>
+where
+    I: Iterator<Item = T>,
+    R: Rng,
+{
+    TargetCountSampleIter::new(iter, percentage, target, rng)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_target_count_clusters_near_target() {
+        let counts: Vec<usize> = (0..200)
+            .map(|seed| {
+                let rng = StdRng::seed_from_u64(seed);
+                target_count_sample_iter(0..20_000, 50.0, 100, rng).count()
+            })
+            .collect();
+        assert!(counts.iter().all(|&count| (95..=100).contains(&count)));
+        let mean = counts.iter().sum::<usize>() as f64 / counts.len() as f64;
+        assert!(mean > 98.0, "{}", mean);
+    }
+
+    #[test]
+    fn test_target_count_short_stream_follows_decay_curve() {
+        // p·m/target = 1, so about 1 − 1/e of the target is expected
+        let counts: Vec<usize> = (0..500)
+            .map(|seed| {
+                let rng = StdRng::seed_from_u64(seed);
+                target_count_sample_iter(0..2_000, 10.0, 200, rng).count()
+            })
+            .collect();
+        let mean = counts.iter().sum::<usize>() as f64 / counts.len() as f64;
+        let expected = 200.0 * (1.0 - (-1.0f64).exp());
+        assert!((mean - expected).abs() < 3.0, "{} vs {}", mean, expected);
+    }
+
+    #[test]
+    fn test_target_count_stops_at_target() {
+        let rng = StdRng::seed_from_u64(1);
+        let mut iter = target_count_sample_iter(0.., 100.0, 5, rng);
+        assert_eq!(iter.current_probability(), 1.0);
+        let first: Vec<u32> = iter.by_ref().collect();
+        assert_eq!(first.len(), 5);
+        assert_eq!(iter.emitted(), 5);
+        assert_eq!(iter.current_probability(), 0.0);
+    }
+}
@@ -0,0 +1,346 @@
+use rand::Rng;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::io;
+
+/// Performs weighted reservoir sampling (Efraimidis-Spirakis A-Res) on `(item, weight)` pairs.
+///
+/// Each item draws a key `u^(1/w)` and the `k` items with the largest keys are kept,
+/// so heavier items are proportionally more likely to be selected. Items with a
+/// non-positive or NaN weight are never selected. Equal keys are broken in favor of
+/// the earlier item, so the result is fully determined by the RNG seed.
+/// The sample is returned in descending key order.
+pub fn weighted_reservoir_sample<T, I, R>(iter: I, k: usize, rng: &mut R) -> Vec<T>
+where
+    I: Iterator<Item = (T, f64)>,
+    R: Rng,
+{
+    let mut top = TopK::new(k);
+    for (item, weight) in iter {
+        // Draw for every item, even skipped ones, so each index consumes one value
+        let u = rng.gen::<f64>();
+        if weight > 0.0 {
+            top.offer(a_res_key(u, weight), item);
+        } else {
+            top.skip();
+        }
+    }
+    top.into_sorted_vec()
+}
+
+/// Keeps the `k` items with the highest `score` in one streaming pass.
+///
+/// This is `weighted_reservoir_sample` with a deterministic key: the score itself. Items
+/// with equal scores are ranked by a random draw instead of their order, so ties are
+/// broken uniformly at random yet reproducibly for a given RNG seed. Items scored NaN
+/// are never kept. The items are returned from the highest score to the lowest.
+pub fn top_k_by_score<T, I, F, R>(iter: I, k: usize, mut score: F, rng: &mut R) -> Vec<T>
+where
+    I: Iterator<Item = T>,
+    F: FnMut(&T) -> f64,
+    R: Rng,
+{
+    let mut top = TopK::new(k);
+    for item in iter {
+        // Draw for every item, even skipped ones, so each index consumes one value
+        let tiebreak = rng.gen::<u64>();
+        let score = score(&item);
+        if !score.is_nan() {
+            top.offer_with_tiebreak(score, tiebreak, item);
+        }
+    }
+    top.into_sorted_vec()
+}
+
+/// Performs weighted reservoir sampling on parallel slices, where `weights[i]` is the
+/// weight of `items[i]`, and clones the selected items.
+///
+/// Selects exactly what `weighted_reservoir_sample` selects for the zipped pairs.
+/// Fails with `InvalidInput` if the slices differ in length.
+pub fn reservoir_sample_weighted_slices<T, R>(
+    items: &[T],
+    weights: &[f64],
+    k: usize,
+    rng: &mut R,
+) -> io::Result<Vec<T>>
+where
+    T: Clone,
+    R: Rng,
+{
+    if items.len() != weights.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} items but {} weights; the slices must have the same length",
+                items.len(),
+                weights.len()
+            ),
+        ));
+    }
+
+    let pairs = items.iter().zip(weights.iter().copied());
+    let sample = weighted_reservoir_sample(pairs, k, rng);
+    Ok(sample.into_iter().cloned().collect())
+}
+
+/// Returns a key that orders items like `u^(1/weight)`, computed in log space to avoid underflow
+fn a_res_key(u: f64, weight: f64) -> f64 {
+    u.ln() / weight
+}
+
+/// Keeps the `k` offered items with the largest keys.
+///
+/// Each offer is numbered in arrival order and equal keys rank the earlier item
+/// higher, so ties never depend on heap layout.
+#[derive(Debug)]
+pub(crate) struct TopK<T> {
+    k: usize,
+    heap: BinaryHeap<Reverse<Entry<T>>>,
+    next_index: u64,
+}
+
+impl<T> TopK<T> {
+    pub(crate) fn new(k: usize) -> Self {
+        TopK {
+            k,
+            heap: BinaryHeap::with_capacity(k),
+            next_index: 0,
+        }
+    }
+
+    /// Offers an item with the given key and returns the item that was displaced or
+    /// rejected, if any
+    pub(crate) fn offer(&mut self, key: f64, item: T) -> Option<T> {
+        let index = self.next_index;
+        self.next_index += 1;
+        self.offer_with_tiebreak(key, index, item)
+    }
+
+    /// Offers an item like `offer`, but ranks it among equal keys by `tiebreak` instead
+    /// of its arrival index; the smaller tiebreak ranks higher
+    pub(crate) fn offer_with_tiebreak(&mut self, key: f64, tiebreak: u64, item: T) -> Option<T> {
+        let entry = Entry {
+            key,
+            index: tiebreak,
+            item,
+        };
+
+        if self.heap.len() < self.k {
+            self.heap.push(Reverse(entry));
+            return None;
+        }
+        match self.heap.peek_mut() {
+            Some(mut smallest) if entry > smallest.0 => {
+                Some(std::mem::replace(&mut *smallest, Reverse(entry)).0.item)
+            }
+            _ => Some(entry.item),
+        }
+    }
+
+    /// Removes the kept item with the smallest key and returns it with its key
+    pub(crate) fn pop_smallest(&mut self) -> Option<(f64, T)> {
+        self.heap
+            .pop()
+            .map(|Reverse(entry)| (entry.key, entry.item))
+    }
+
+    /// Consumes an arrival index without offering an item
+    pub(crate) fn skip(&mut self) {
+        self.next_index += 1;
+    }
+
+    /// Returns the kept items from the largest key to the smallest
+    pub(crate) fn into_sorted_vec(self) -> Vec<T> {
+        // Ascending order of Reverse(entry) is descending order of entry
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(entry)| entry.item)
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    key: f64,
+    /// Arrival index, or a random rank, that orders equal keys
+    index: u64,
+    item: T,
+}
+
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key
+            .total_cmp(&other.key)
+            // On equal keys the earlier index ranks higher
+            .then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_weighted_reservoir_sample_prefers_heavy_items() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut heavy_count = 0;
+        for _ in 0..200 {
+            let items = (0..10).map(|i| (i, if i == 9 { 50.0 } else { 1.0 }));
+            let sample = weighted_reservoir_sample(items, 1, &mut rng);
+            if sample == vec![9] {
+                heavy_count += 1;
+            }
+        }
+        // Item 9 carries 50/59 of the total weight
+        assert!(heavy_count > 150, "{}", heavy_count);
+    }
+
+    #[test]
+    fn test_weighted_reservoir_sample_is_reproducible() {
+        let items: Vec<(usize, f64)> = (0..100).map(|i| (i, (i % 7 + 1) as f64)).collect();
+
+        let first =
+            weighted_reservoir_sample(items.clone().into_iter(), 10, &mut StdRng::seed_from_u64(3));
+        let second =
+            weighted_reservoir_sample(items.into_iter(), 10, &mut StdRng::seed_from_u64(3));
+
+        assert_eq!(first.len(), 10);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_weighted_reservoir_sample_skips_non_positive_weights() {
+        let items = vec![("a", 0.0), ("b", -1.0), ("c", f64::NAN), ("d", 1.0)];
+        let sample = weighted_reservoir_sample(items.into_iter(), 4, &mut StdRng::seed_from_u64(1));
+        assert_eq!(sample, vec!["d"]);
+    }
+
+    #[test]
+    fn test_weighted_slices_match_paired_sampling() {
+        let items: Vec<String> = (0..50).map(|i| format!("item{}", i)).collect();
+        let weights: Vec<f64> = (0..50).map(|i| (i % 5) as f64).collect();
+
+        let from_slices =
+            reservoir_sample_weighted_slices(&items, &weights, 8, &mut StdRng::seed_from_u64(4))
+                .unwrap();
+        let pairs = items.iter().cloned().zip(weights.iter().copied());
+        let from_pairs = weighted_reservoir_sample(pairs, 8, &mut StdRng::seed_from_u64(4));
+        assert_eq!(from_slices, from_pairs);
+        // Zero-weight items (every fifth) are never selected
+        assert!(from_slices.iter().all(|item| {
+            let i: usize = item["item".len()..].parse().unwrap();
+            !i.is_multiple_of(5)
+        }));
+    }
+
+    #[test]
+    fn test_weighted_slices_prefer_heavy_items() {
+        let items = ["light", "heavy"];
+        let weights = [1.0, 9.0];
+        let mut rng = StdRng::seed_from_u64(11);
+        let heavy = (0..1000)
+            .filter(|_| {
+                reservoir_sample_weighted_slices(&items, &weights, 1, &mut rng).unwrap()
+                    == vec!["heavy"]
+            })
+            .count();
+        // "heavy" carries 90% of the weight
+        assert!((860..940).contains(&heavy), "{}", heavy);
+    }
+
+    #[test]
+    fn test_weighted_slices_reject_mismatched_lengths() {
+        let err = reservoir_sample_weighted_slices(
+            &[1, 2, 3],
+            &[1.0, 2.0],
+            2,
+            &mut StdRng::seed_from_u64(1),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("3 items but 2 weights"), "{}", err);
+    }
+
+    #[test]
+    fn test_top_k_by_score_keeps_highest_scores() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let items: Vec<u32> = (0..100).collect();
+        let top = top_k_by_score(items.iter().copied(), 25, |&i| (i % 10) as f64, &mut rng);
+
+        let scores: Vec<u32> = top.iter().map(|i| i % 10).collect();
+        assert_eq!(
+            &scores[..20],
+            &[9; 10].iter().chain(&[8; 10]).copied().collect::<Vec<_>>()[..]
+        );
+        assert_eq!(&scores[20..], &[7; 5]);
+
+        let nan = top_k_by_score(
+            0..10,
+            3,
+            |&i| if i < 8 { f64::NAN } else { i as f64 },
+            &mut rng,
+        );
+        assert_eq!(nan, vec![9, 8]);
+    }
+
+    #[test]
+    fn test_top_k_by_score_breaks_ties_reproducibly() {
+        let tied = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            top_k_by_score(0..50, 5, |_| 1.0, &mut rng)
+        };
+        assert_eq!(tied(3), tied(3));
+        assert_ne!(tied(3), tied(4));
+
+        // Ties are broken uniformly, not in favor of early items
+        let mut counts = [0; 10];
+        for seed in 0..2000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            for item in top_k_by_score(0..10, 3, |_| 1.0, &mut rng) {
+                counts[item] += 1;
+            }
+        }
+        assert!(
+            counts.iter().all(|&c| (500..700).contains(&c)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn test_top_k_breaks_key_collisions_by_index() {
+        // Every key collides, so only the arrival order can decide
+        let mut top = TopK::new(3);
+        for item in ["a", "b", "c", "d", "e"] {
+            top.offer(0.5, item);
+        }
+        assert_eq!(top.into_sorted_vec(), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_top_k_collision_with_mixed_keys() {
+        let mut top = TopK::new(2);
+        top.offer(0.1, "low");
+        top.offer(0.9, "first-high");
+        top.offer(0.9, "second-high");
+        top.offer(0.9, "third-high");
+        assert_eq!(top.into_sorted_vec(), vec!["first-high", "second-high"]);
+    }
+}
@@ -0,0 +1,243 @@
+use rand::Rng;
+
+/// A reservoir that keeps a uniform random sample of the items whose timestamps lie
+/// within a sliding window ending at the latest timestamp seen so far.
+///
+/// Each item draws a random priority and the sample is made of the `capacity` items
+/// with the highest priorities inside the window. An item is forgotten once it falls
+/// out of the window, or once `capacity` items that are at least as recent outrank it,
+/// since it can then never be part of a future sample. This keeps memory bounded by
+/// about `capacity * ln(window size / capacity)` items.
+#[derive(Debug, Clone)]
+pub struct WindowedReservoir<T> {
+    capacity: usize,
+    window: f64,
+    latest: f64,
+    entries: Vec<Entry<T>>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry<T> {
+    timestamp: f64,
+    priority: f64,
+    /// Number of at least as recent items with a higher priority
+    outranked_by: usize,
+    item: T,
+}
+
+impl<T> WindowedReservoir<T> {
+    /// Creates a reservoir of `capacity` items over a window of `window` seconds
+    pub fn new(capacity: usize, window: f64) -> Self {
+        assert!(window >= 0.0, "Window must not be negative");
+        WindowedReservoir {
+            capacity,
+            window,
+            latest: f64::NEG_INFINITY,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Offers an item stamped with `timestamp` (in seconds).
+    ///
+    /// Timestamps may arrive out of order; an item that is already older than the
+    /// window is dropped.
+    pub fn add<R: Rng>(&mut self, timestamp: f64, item: T, rng: &mut R) {
+        // Draw for every item, even dropped ones, so each record consumes one value
+        let priority = rng.gen::<f64>();
+
+        self.latest = self.latest.max(timestamp);
+        let cutoff = self.latest - self.window;
+        self.entries.retain(|entry| entry.timestamp >= cutoff);
+        if timestamp < cutoff {
+            return;
+        }
+
+        // Any future window that contains an older item also contains this one
+        let mut outranked_by = 0;
+        for entry in &mut self.entries {
+            if entry.timestamp <= timestamp && entry.priority < priority {
+                entry.outranked_by += 1;
+            }
+            if entry.timestamp >= timestamp && entry.priority > priority {
+                outranked_by += 1;
+            }
+        }
+        let capacity = self.capacity;
+        self.entries.retain(|entry| entry.outranked_by < capacity);
+
+        if outranked_by < capacity {
+            self.entries.push(Entry {
+                timestamp,
+                priority,
+                outranked_by,
+                item,
+            });
+        }
+    }
+
+    /// Consumes the reservoir and returns the sample of the current window in arrival order
+    pub fn into_vec(self) -> Vec<T> {
+        let mut ranked: Vec<(usize, f64)> = self
+            .entries
+            .iter()
+            .map(|entry| entry.priority)
+            .enumerate()
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        let mut selected = vec![false; ranked.len()];
+        for &(index, _) in ranked.iter().take(self.capacity) {
+            selected[index] = true;
+        }
+
+        self.entries
+            .into_iter()
+            .zip(selected)
+            .filter_map(|(entry, keep)| keep.then_some(entry.item))
+            .collect()
+    }
+}
+
+/// A reservoir that keeps a uniform random sample of the last `window` items added,
+/// a sliding window by count rather than by time.
+///
+/// This is a `WindowedReservoir` stamping each item with its arrival index, so an item
+/// is forgotten once it is `window` items old or outranked by `capacity` newer items.
+#[derive(Debug, Clone)]
+pub struct CountWindowedReservoir<T> {
+    inner: WindowedReservoir<T>,
+    seen: u64,
+}
+
+impl<T> CountWindowedReservoir<T> {
+    /// Creates a reservoir of `capacity` items over the last `window` items
+    pub fn new(capacity: usize, window: u64) -> Self {
+        assert!(window > 0, "Window must hold at least one item");
+        CountWindowedReservoir {
+            // The window ends at the latest index and includes both ends
+            inner: WindowedReservoir::new(capacity, (window - 1) as f64),
+            seen: 0,
+        }
+    }
+
+    pub fn add<R: Rng>(&mut self, item: T, rng: &mut R) {
+        self.inner.add(self.seen as f64, item, rng);
+        self.seen += 1;
+    }
+
+    /// Consumes the reservoir and returns the sample of the last `window` items in
+    /// arrival order
+    pub fn into_vec(self) -> Vec<T> {
+        self.inner.into_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_windowed_reservoir_evicts_old_records() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut reservoir = WindowedReservoir::new(3, 9.5);
+        for t in 0..100 {
+            reservoir.add(t as f64, t, &mut rng);
+        }
+
+        let sample = reservoir.into_vec();
+        assert_eq!(sample.len(), 3);
+        assert!(sample.iter().all(|&t| t >= 90), "{:?}", sample);
+    }
+
+    #[test]
+    fn test_windowed_reservoir_keeps_small_window_whole() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut reservoir = WindowedReservoir::new(5, 2.0);
+        for t in 0..20 {
+            reservoir.add(t as f64, t, &mut rng);
+        }
+        assert_eq!(reservoir.into_vec(), vec![17, 18, 19]);
+    }
+
+    #[test]
+    fn test_windowed_reservoir_drops_late_records() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut reservoir = WindowedReservoir::new(5, 10.0);
+        for (t, item) in [(100.0, "a"), (50.0, "late"), (95.0, "b"), (89.0, "old")] {
+            reservoir.add(t, item, &mut rng);
+        }
+        assert_eq!(reservoir.into_vec(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_windowed_reservoir_is_uniform_within_window() {
+        let mut counts = [0; 10];
+        for seed in 0..2000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut reservoir = WindowedReservoir::new(2, 9.0);
+            for t in 0..30 {
+                reservoir.add(t as f64, t, &mut rng);
+            }
+            for t in reservoir.into_vec() {
+                counts[t - 20] += 1;
+            }
+        }
+        // Each of the 10 records in the final window is kept with probability 2/10
+        assert!(
+            counts.iter().all(|&c| (300..500).contains(&c)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn test_count_windowed_reservoir_keeps_last_items() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut reservoir = CountWindowedReservoir::new(3, 10);
+        for item in 0..100 {
+            reservoir.add(item, &mut rng);
+        }
+        let sample = reservoir.into_vec();
+        assert_eq!(sample.len(), 3);
+        assert!(sample.iter().all(|&item| item >= 90), "{:?}", sample);
+
+        let mut reservoir = CountWindowedReservoir::new(5, 3);
+        for item in 0..20 {
+            reservoir.add(item, &mut rng);
+        }
+        assert_eq!(reservoir.into_vec(), vec![17, 18, 19]);
+    }
+
+    #[test]
+    fn test_count_windowed_reservoir_is_uniform_within_window() {
+        let mut counts = [0; 10];
+        for seed in 0..2000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut reservoir = CountWindowedReservoir::new(3, 10);
+            for item in 0..50 {
+                reservoir.add(item, &mut rng);
+            }
+            for item in reservoir.into_vec() {
+                counts[item - 40] += 1;
+            }
+        }
+        // Each of the last 10 items is kept with probability 3/10
+        assert!(
+            counts.iter().all(|&c| (500..700).contains(&c)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn test_windowed_reservoir_memory_is_bounded() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut reservoir = WindowedReservoir::new(5, f64::INFINITY);
+        for t in 0..10_000 {
+            reservoir.add(t as f64, t, &mut rng);
+            assert!(reservoir.entries.len() < 200);
+        }
+        assert_eq!(reservoir.into_vec().len(), 5);
+    }
+}
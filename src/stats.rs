@@ -0,0 +1,366 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::Config;
+
+/// Counts and settings of a finished run, written by `--stats-out` as a JSON document
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunStats {
+    /// Number of input lines sampled from, not counting the CSV header or --skip lines
+    pub total: u64,
+    /// Number of output lines written, not counting the header or embedded seed
+    pub sampled: u64,
+    /// Seed the sampling RNG was created from
+    pub seed: u64,
+    /// Name of the sampling mode, see `mode_name`
+    pub mode: &'static str,
+    pub elapsed: Duration,
+}
+
+impl RunStats {
+    /// Builds the stats from the raw line counts of the input and the output, where
+    /// the input count includes the header and the --skip lines
+    pub fn from_counts(
+        config: &Config,
+        input_lines: u64,
+        output_lines: u64,
+        seed: u64,
+        elapsed: Duration,
+    ) -> Self {
+        let input_header = u64::from(config.csv_mode) + config.skip as u64;
+        let output_header =
+            u64::from(config.csv_mode && !config.no_header_output) + u64::from(config.embed_seed);
+        RunStats {
+            total: input_lines.saturating_sub(input_header),
+            sampled: output_lines.saturating_sub(output_header),
+            seed,
+            mode: mode_name(config),
+            elapsed,
+        }
+    }
+
+    /// Share of the input lines that were written, or 0 for an empty input
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.sampled as f64 / self.total as f64
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "total": self.total,
+            "sampled": self.sampled,
+            "fraction": self.fraction(),
+            "seed": self.seed,
+            "mode": self.mode,
+            "elapsed_ms": self.elapsed.as_millis() as u64,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut json = serde_json::to_string_pretty(&self.to_json())?;
+        json.push('\n');
+        fs::write(path, json)
+    }
+
+    /// Formats the run as one `--log-invocation` line: the UTC time, then the seed,
+    /// mode and counts as key=value pairs, then the command line as a JSON array
+    pub fn invocation_line(&self, argv: &[String], time: DateTime<Utc>) -> String {
+        format!(
+            "{} seed={} mode={} total={} sampled={} argv={}",
+            time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            self.seed,
+            self.mode,
+            self.total,
+            self.sampled,
+            serde_json::Value::from(argv),
+        )
+    }
+
+    /// Appends the `--log-invocation` line for this run to the file at `path`
+    pub fn append_invocation(&self, path: &Path, argv: &[String]) -> io::Result<()> {
+        let line = self.invocation_line(argv, SystemTime::now().into());
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", line)
+    }
+}
+
+/// Running count, sum, minimum and maximum of the numeric values of a `--stats-column`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnStats {
+    pub column: String,
+    /// Number of numeric values seen
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    /// Number of values that were not empty and not a number
+    pub skipped: u64,
+}
+
+impl ColumnStats {
+    pub fn new(column: &str) -> Self {
+        ColumnStats {
+            column: column.to_string(),
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            skipped: 0,
+        }
+    }
+
+    /// Adds one raw field value; empty values are ignored and other values that are
+    /// not numbers are counted as skipped
+    pub fn add(&mut self, value: &str) {
+        let value = value.trim().trim_matches('"').trim();
+        if value.is_empty() {
+            return;
+        }
+        match value.parse::<f64>() {
+            Ok(number) if !number.is_nan() => {
+                self.count += 1;
+                self.sum += number;
+                self.min = self.min.min(number);
+                self.max = self.max.max(number);
+            }
+            _ => self.skipped += 1,
+        }
+    }
+
+    /// Mean of the numeric values, or `None` if there were none
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+}
+
+impl fmt::Display for ColumnStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: count={}", self.column, self.count)?;
+        if let Some(mean) = self.mean() {
+            write!(f, " mean={} min={} max={}", mean, self.min, self.max)?;
+        }
+        if self.skipped > 0 {
+            write!(f, " skipped={}", self.skipped)?;
+        }
+        Ok(())
+    }
+}
+
+/// Short name of the sampling mode selected by the configuration
+pub fn mode_name(config: &Config) -> &'static str {
+    if config.show_columns {
+        "show-columns"
+    } else if config.count_keys {
+        "count-keys"
+    } else if config.probability_report {
+        "probability-report"
+    } else if config.assign.is_some() {
+        "assign"
+    } else if config.weight_column.is_some() {
+        "weighted"
+    } else if config.sample_columns.is_some() {
+        "sample-columns"
+    } else if config.percentile_column.is_some() {
+        "percentile"
+    } else if config.pin_keys.is_some() {
+        "pin-keys"
+    } else if config.has_hash_key() {
+        "hash"
+    } else if config.window.is_some() {
+        "window"
+    } else if config.window_count.is_some() {
+        "window-count"
+    } else if config.decay.is_some() {
+        "decay"
+    } else if config.percentage.is_some() {
+        "percentage"
+    } else if config.first.is_some() {
+        "first"
+    } else if config.shuffle_all {
+        "shuffle-all"
+    } else if config.one {
+        "one"
+    } else {
+        "reservoir"
+    }
+}
+
+/// Reader that counts the lines passing through it; a final line without a
+/// newline counts too.
+///
+/// Every read returns at most one line, so a consumer that buffers its input never
+/// pulls in, and counts, lines beyond the ones it has used when it stops early.
+pub struct LineCountingReader<R> {
+    inner: R,
+    newlines: u64,
+    last: Option<u8>,
+}
+
+impl<R: BufRead> LineCountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        LineCountingReader {
+            inner,
+            newlines: 0,
+            last: None,
+        }
+    }
+
+    /// Returns the number of lines read so far
+    pub fn lines(&self) -> u64 {
+        self.newlines + u64::from(self.last.is_some_and(|byte| byte != b'\n'))
+    }
+}
+
+impl<R: BufRead> Read for LineCountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.inner.fill_buf()?;
+        let line_end = available
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map_or(available.len(), |newline| newline + 1);
+        let n = line_end.min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.inner.consume(n);
+
+        self.newlines += count_newlines(&buf[..n]);
+        if n > 0 {
+            self.last = Some(buf[n - 1]);
+        }
+        Ok(n)
+    }
+}
+
+/// Writer that counts the lines passing through it
+pub struct LineCountingWriter<W> {
+    inner: W,
+    newlines: u64,
+}
+
+impl<W: Write> LineCountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        LineCountingWriter { inner, newlines: 0 }
+    }
+
+    /// Returns the number of complete lines written so far
+    pub fn lines(&self) -> u64 {
+        self.newlines
+    }
+}
+
+impl<W: Write> Write for LineCountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.newlines += count_newlines(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn count_newlines(bytes: &[u8]) -> u64 {
+    bytes.iter().filter(|&&byte| byte == b'\n').count() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_args_for_tests;
+
+    #[test]
+    fn test_line_counting_reader_counts_unterminated_line() {
+        let mut reader = LineCountingReader::new("a\nb\nc".as_bytes());
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(reader.lines(), 3);
+
+        let mut reader = LineCountingReader::new("a\nb\n".as_bytes());
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(reader.lines(), 2);
+    }
+
+    #[test]
+    fn test_line_counting_reader_does_not_read_ahead() {
+        let input: String = (0..10_000).map(|i| format!("{}\n", i)).collect();
+        let mut reader = LineCountingReader::new(input.as_bytes());
+        let first: Vec<String> = io::BufReader::new(&mut reader)
+            .lines()
+            .take(3)
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(first, ["0", "1", "2"]);
+        assert_eq!(reader.lines(), 3);
+    }
+
+    #[test]
+    fn test_run_stats_excludes_headers() {
+        let config = parse_args_for_tests(["sample", "--csv", "--embed-seed", "-p", "50"]).unwrap();
+        let stats = RunStats::from_counts(&config, 11, 7, 42, Duration::from_millis(3));
+        assert_eq!(stats.total, 10);
+        assert_eq!(stats.sampled, 5);
+        assert_eq!(stats.mode, "percentage");
+
+        let json = stats.to_json();
+        assert_eq!(json["fraction"], 0.5);
+        assert_eq!(json["seed"], 42);
+        assert_eq!(json["elapsed_ms"], 3);
+    }
+
+    #[test]
+    fn test_mode_name_of_report_and_pinned_modes() {
+        let cases: [(&[&str], &str); 5] = [
+            (&["--csv", "--show-columns"], "show-columns"),
+            (&["--csv", "--hash", "id", "--count-keys"], "count-keys"),
+            (
+                &["--csv", "-p", "10", "--hash", "id", "--probability-report"],
+                "probability-report",
+            ),
+            (
+                &["--csv", "5", "--hash", "id", "--pin-keys", "keys.txt"],
+                "pin-keys",
+            ),
+            (&["--csv", "-p", "10", "--hash", "id"], "hash"),
+        ];
+        for (args, mode) in cases {
+            let config = parse_args_for_tests(std::iter::once(&"sample").chain(args)).unwrap();
+            assert_eq!(mode_name(&config), mode, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_column_stats_accumulates_numeric_values() {
+        let mut stats = ColumnStats::new("price");
+        assert_eq!(stats.to_string(), "price: count=0");
+        for value in ["3", " 1.5 ", "\"-2\"", "", "n/a", "7.5"] {
+            stats.add(value);
+        }
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.mean(), Some(2.5));
+        assert_eq!((stats.min, stats.max), (-2.0, 7.5));
+        assert_eq!(
+            stats.to_string(),
+            "price: count=4 mean=2.5 min=-2 max=7.5 skipped=1"
+        );
+    }
+
+    #[test]
+    fn test_invocation_line_fields() {
+        let config = parse_args_for_tests(["sample", "-p", "50"]).unwrap();
+        let stats = RunStats::from_counts(&config, 10, 4, 7, Duration::ZERO);
+        let argv = ["sample", "-p", "50", "a b"].map(String::from);
+        let time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        assert_eq!(
+            stats.invocation_line(&argv, time),
+            "2023-11-14T22:13:20Z seed=7 mode=percentage total=10 sampled=4 \
+             argv=[\"sample\",\"-p\",\"50\",\"a b\"]"
+        );
+    }
+}
@@ -0,0 +1,296 @@
+use assert_cmd::Command;
+use predicates::prelude::*;
+use std::io::Write;
+
+fn sample() -> Command {
+    Command::cargo_bin("sample").unwrap()
+}
+
+#[test]
+fn test_exit_code_for_invalid_options() {
+    sample()
+        .args(["-p", "10", "--hash", "id"])
+        .write_stdin("id\n1\n")
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn test_exit_code_for_missing_column() {
+    sample()
+        .args(["-p", "10", "--csv", "--hash", "missing"])
+        .write_stdin("id,value\n1,a\n")
+        .assert()
+        .code(3);
+}
+
+#[test]
+fn test_exit_code_for_non_numeric_key() {
+    sample()
+        .args(["-p", "100", "--csv", "--hash", "id", "--numeric-key"])
+        .write_stdin("id,value\n1,a\nabc,b\n")
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("'abc' in column 'id' on line 3"));
+}
+
+#[test]
+fn test_exit_code_for_io_error() {
+    sample()
+        .args(["-p", "10", "--csv", "--hash", "id"])
+        .args(["--exclude-keys", "/nonexistent/keys.txt"])
+        .write_stdin("id,value\n1,a\n")
+        .assert()
+        .code(4);
+}
+
+#[test]
+fn test_exit_code_for_ragged_rows_under_strict_columns() {
+    let input = "id,v\na,1\nb\n";
+    let hashed = sample()
+        .args(["-p", "100", "--csv", "--hash", "id", "--strict-columns"])
+        .write_stdin(input)
+        .assert()
+        .code(4)
+        .stderr(predicates::str::starts_with("Error: invalid CSV input: "));
+    // Every mode that parses records reports the row the same way
+    sample()
+        .args(["--csv", "--sample-columns", "1", "--strict-columns"])
+        .write_stdin(input)
+        .assert()
+        .code(4)
+        .stderr(String::from_utf8(hashed.get_output().stderr.clone()).unwrap());
+}
+
+#[test]
+fn test_write_errors_name_the_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let stats = dir.path().join("missing").join("stats.json");
+    sample()
+        .args(["-p", "100", "--stats-out"])
+        .arg(&stats)
+        .write_stdin("a\n")
+        .assert()
+        .code(4)
+        .stderr(predicates::str::starts_with(format!(
+            "Error: writing {} failed: ",
+            stats.display()
+        )));
+
+    // /dev/full accepts the open but fails every write
+    #[cfg(target_os = "linux")]
+    {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_sample"))
+            .args(["-p", "100"])
+            .stdin(std::fs::File::open(file!()).unwrap())
+            .stdout(std::fs::File::create("/dev/full").unwrap())
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(4));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.starts_with("Error: writing standard output failed: "),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_manifest_rejects_record_modes() {
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = dir.path().join("manifest.txt");
+    for mode in [
+        &["--sample-columns", "1"][..],
+        &["--percentile-column", "v", "--percentile-range", "0:50"],
+        &["--show-columns"],
+    ] {
+        sample()
+            .args(["--csv", "--manifest"])
+            .arg(&manifest)
+            .args(mode)
+            .write_stdin("id,v\n1,2\n")
+            .assert()
+            .code(2);
+        assert!(!manifest.exists(), "{:?}", mode);
+    }
+}
+
+#[test]
+fn test_decision_out_rejects_show_columns() {
+    let dir = tempfile::tempdir().unwrap();
+    let decisions = dir.path().join("decisions.txt");
+    sample()
+        .args(["--csv", "--show-columns", "--decision-out"])
+        .arg(&decisions)
+        .write_stdin("id,v\n1,2\n")
+        .assert()
+        .code(2);
+    assert!(!decisions.exists());
+}
+
+#[test]
+fn test_mmap_matches_buffered_input() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    for i in 0..1000 {
+        writeln!(file, "{},{}", i % 17, i).unwrap();
+    }
+
+    // Standard input must be the file itself, not a pipe fed from it, to be mapped
+    let run_on_file = |args: &[&str]| {
+        let stdin = std::fs::File::open(file.path()).unwrap();
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_sample"))
+            .args(args)
+            .stdin(stdin.try_clone().unwrap())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", args);
+        (output.stdout, stdin)
+    };
+
+    for args in [
+        &["25", "--seed", "3"][..],
+        &["-p", "10", "--csv", "--seed", "3"],
+    ] {
+        let (buffered, mut read) = run_on_file(args);
+        let (mapped, mut stdin) = run_on_file(&[args, &["--mmap"]].concat());
+
+        assert!(!mapped.is_empty());
+        assert_eq!(mapped, buffered);
+        // The offset is shared with the child, and only reads move it
+        #[cfg(unix)]
+        {
+            assert!(std::io::Seek::stream_position(&mut read).unwrap() > 0);
+            assert_eq!(std::io::Seek::stream_position(&mut stdin).unwrap(), 0);
+        }
+    }
+
+    // Pipes fall back to buffered reads
+    sample()
+        .args(["--first", "2", "--mmap"])
+        .write_stdin("a\nb\nc\n")
+        .assert()
+        .success()
+        .stdout("a\nb\n");
+}
+
+#[test]
+fn test_diagnostics_are_logged_to_stderr() {
+    sample()
+        .args(["-p", "10", "--csv", "--hash", "missing"])
+        .write_stdin("id\n1\n")
+        .assert()
+        .stderr("Error: column 'missing' not found in CSV header\n");
+
+    sample()
+        .args(["-p", "100"])
+        .write_stdin(&b"a\n\xFF\nb\n"[..])
+        .assert()
+        .success()
+        .stdout("a\n")
+        .stderr(predicates::str::starts_with(
+            "Warning: stopped reading at data line 2",
+        ));
+}
+
+#[test]
+fn test_undersized_input_warns_unless_quiet() {
+    sample()
+        .arg("1000")
+        .write_stdin("a\nb\n")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("a\n").and(predicates::str::contains("b\n")))
+        .stderr("Warning: requested a sample of 1000 lines, but the input has only 2\n");
+
+    sample()
+        .args(["1000", "--quiet"])
+        .write_stdin("a\nb\n")
+        .assert()
+        .success()
+        .stderr("");
+
+    sample()
+        .arg("2")
+        .write_stdin("a\nb\nc\n")
+        .assert()
+        .stderr("");
+}
+
+#[test]
+fn test_probe_reports_format_on_stderr() {
+    sample()
+        .arg("--probe")
+        .write_stdin("id\tname\n1\talice\n2\tbob\n")
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("delimiter: tab\nheader: yes\n");
+}
+
+/// Spawn the binary with piped standard streams, returning its input and a channel
+/// that receives each line of its output as soon as it is written
+fn spawn_streaming(
+    args: &[&str],
+) -> (
+    std::process::Child,
+    std::process::ChildStdin,
+    std::sync::mpsc::Receiver<String>,
+) {
+    use std::io::{BufRead, BufReader};
+    use std::process::Stdio;
+
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_sample"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdin = child.stdin.take().unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+
+    let (lines, received) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for line in stdout.lines() {
+            lines.send(line.unwrap()).unwrap();
+        }
+    });
+    (child, stdin, received)
+}
+
+#[test]
+fn test_line_buffered_emits_each_line_while_input_is_open() {
+    use std::time::Duration;
+
+    let (mut child, mut stdin, received) = spawn_streaming(&["-p", "100", "--line-buffered"]);
+
+    // Each line must come out before the next is written and before stdin is closed
+    for line in ["first", "second"] {
+        writeln!(stdin, "{}", line).unwrap();
+        stdin.flush().unwrap();
+        assert_eq!(
+            received.recv_timeout(Duration::from_secs(10)).unwrap(),
+            line
+        );
+    }
+
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn test_output_is_block_buffered_without_line_buffered() {
+    use std::time::Duration;
+
+    let (mut child, mut stdin, received) = spawn_streaming(&["-p", "100"]);
+    writeln!(stdin, "first").unwrap();
+    stdin.flush().unwrap();
+    assert!(received.recv_timeout(Duration::from_millis(500)).is_err());
+
+    drop(stdin);
+    assert_eq!(
+        received.recv_timeout(Duration::from_secs(10)).unwrap(),
+        "first"
+    );
+    assert!(child.wait().unwrap().success());
+}